@@ -0,0 +1,90 @@
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+/// How often the connection count of an adaptive download is reconsidered.
+pub(crate) const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connections an adaptive download starts with.
+const INITIAL_CONNECTIONS: usize = 2;
+
+/// Throughput has to grow by this factor for another connection to be added.
+const MIN_GAIN: f64 = 1.1;
+
+/// Counts responses telling the client to back off, shared by all connections of a download.
+#[derive(Default)]
+pub(crate) struct Congestion(AtomicU32);
+
+impl Congestion {
+    pub fn record(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Scales the number of connections of a download with its measured throughput.
+///
+/// A connection is added as long as that keeps making the download faster. When the server pushes back
+/// with `429`/`503` responses or dropped connections, the count is halved.
+pub(crate) struct ConnectionScaler {
+    limit: usize,
+    max: usize,
+    baseline_speed: f64,
+    last_sample: Instant,
+    last_written: u64,
+    last_congestion: u32,
+}
+
+impl ConnectionScaler {
+    pub fn new(max: usize, written: u64) -> Self {
+        Self {
+            limit: INITIAL_CONNECTIONS.min(max),
+            max,
+            baseline_speed: 0.0,
+            last_sample: Instant::now(),
+            last_written: written,
+            last_congestion: 0,
+        }
+    }
+
+    /// The number of connections the download may currently use.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Updates the limit from the bytes written so far.
+    pub fn sample(&mut self, written: u64, congestion: &Congestion) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_sample).as_secs_f64();
+        let speed = written.saturating_sub(self.last_written) as f64 / elapsed.max(f64::EPSILON);
+        self.last_sample = now;
+        self.last_written = written;
+
+        let congestion_count = congestion.count();
+        if congestion_count > self.last_congestion {
+            self.last_congestion = congestion_count;
+            self.limit = (self.limit / 2).max(1);
+            self.baseline_speed = speed;
+            debug!(
+                limit = self.limit,
+                "server is congested, removing connections"
+            );
+            return;
+        }
+
+        if speed >= self.baseline_speed * MIN_GAIN && speed > 0.0 {
+            self.baseline_speed = speed;
+            if self.limit < self.max {
+                self.limit += 1;
+                debug!(
+                    limit = self.limit,
+                    "throughput increased, adding a connection"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+#[cfg(any(feature = "s3", feature = "azure"))]
+use crate::signing::RequestSigner;
+use futures::future::BoxFuture;
+use reqwest::RequestBuilder;
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+/// Credentials sent in the `Authorization` header.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer(String),
+}
+
+impl Credentials {
+    pub fn basic(username: &str, password: Option<&str>) -> Self {
+        Self::Basic {
+            username: username.to_owned(),
+            password: password.map(str::to_owned),
+        }
+    }
+
+    pub fn bearer(token: &str) -> Self {
+        Self::Bearer(token.to_owned())
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Self::Basic { username, password } => request.basic_auth(username, password.as_ref()),
+            Self::Bearer(token) => request.bearer_auth(token),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .finish_non_exhaustive(),
+            Self::Bearer(_) => f.write_str("Bearer(..)"),
+        }
+    }
+}
+
+/// Supplies new credentials when a server rejects the current ones with `401 Unauthorized`.
+///
+/// Returning `None` fails the download. Implemented for async closures taking the rejected URL.
+pub trait CredentialsProvider: Send + Sync {
+    fn refresh(&self, url: &str) -> BoxFuture<'static, Option<Credentials>>;
+}
+
+impl<F, Fut> CredentialsProvider for F
+where
+    F: Fn(String) -> Fut + Send + Sync,
+    Fut: Future<Output = Option<Credentials>> + Send + 'static,
+{
+    fn refresh(&self, url: &str) -> BoxFuture<'static, Option<Credentials>> {
+        Box::pin(self(url.to_owned()))
+    }
+}
+
+/// The credentials of a download, shared by all of its connections.
+#[derive(Default)]
+pub(crate) struct Auth {
+    current: Mutex<Current>,
+    provider: Option<Arc<dyn CredentialsProvider>>,
+    /// Held while asking the provider, so connections rejected at the same time refresh only once.
+    refreshing: tokio::sync::Mutex<()>,
+    #[cfg(any(feature = "s3", feature = "azure"))]
+    signer: Option<Arc<dyn RequestSigner>>,
+}
+
+#[derive(Default)]
+struct Current {
+    credentials: Option<Credentials>,
+    /// Incremented whenever the credentials are refreshed.
+    generation: u64,
+}
+
+impl Auth {
+    pub fn new(
+        credentials: Option<Credentials>,
+        provider: Option<Arc<dyn CredentialsProvider>>,
+    ) -> Self {
+        Self {
+            current: Mutex::new(Current {
+                credentials,
+                generation: 0,
+            }),
+            provider,
+            refreshing: tokio::sync::Mutex::new(()),
+            #[cfg(any(feature = "s3", feature = "azure"))]
+            signer: None,
+        }
+    }
+
+    /// Returns the same credentials, with requests also signed by `signer`.
+    #[cfg(any(feature = "s3", feature = "azure"))]
+    pub fn with_signer(&self, signer: Arc<dyn RequestSigner>) -> Self {
+        Self {
+            signer: Some(signer),
+            ..Self::new(self.credentials(), self.provider())
+        }
+    }
+
+    pub fn credentials(&self) -> Option<Credentials> {
+        self.current.lock().unwrap().credentials.clone()
+    }
+
+    pub fn provider(&self) -> Option<Arc<dyn CredentialsProvider>> {
+        self.provider.clone()
+    }
+
+    /// Adds the current credentials to `request`, unless it goes somewhere they aren't sent to,
+    /// and returns their generation.
+    pub fn authorize(&self, request: RequestBuilder, credentials: bool) -> (RequestBuilder, u64) {
+        let current = self.current.lock().unwrap();
+        let request = match &current.credentials {
+            Some(current) if credentials => current.authorize(request),
+            _ => request,
+        };
+        #[cfg(any(feature = "s3", feature = "azure"))]
+        let request = match &self.signer {
+            Some(signer) => signer.sign(request),
+            None => request,
+        };
+        (request, current.generation)
+    }
+
+    /// Replaces the credentials of `generation`, which `url` rejected.
+    ///
+    /// Returns whether there are new credentials to try, which may have been fetched by another connection.
+    pub async fn refresh(&self, url: &str, generation: u64) -> bool {
+        let Some(provider) = &self.provider else {
+            return false;
+        };
+
+        let _refreshing = self.refreshing.lock().await;
+        if self.current.lock().unwrap().generation != generation {
+            return true;
+        }
+
+        match provider.refresh(url).await {
+            Some(credentials) => {
+                let mut current = self.current.lock().unwrap();
+                current.credentials = Some(credentials);
+                current.generation += 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auth")
+            .field("credentials", &self.credentials())
+            .field("provider", &self.provider.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,328 @@
+//! Downloads of `az://account/container/blob` URLs from Azure Blob Storage, enabled with the `azure`
+//! feature.
+//!
+//! Blobs are downloaded over HTTPS like any other file. Requests are signed with the account key,
+//! or authorized with a shared access signature appended to the URL.
+
+use crate::{
+    error::DownloadError,
+    options::DownloadOptions,
+    signing::{self, hmac_sha256, host_header, RequestSigner},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{
+    header::{self, HeaderValue, AUTHORIZATION},
+    Request, RequestBuilder,
+};
+use std::{collections::BTreeMap, sync::Arc, time::SystemTime};
+
+/// The version of the Blob service REST API that requests are made with.
+const API_VERSION: &str = "2021-08-06";
+
+/// Which blobs are downloaded and how requests are authorized.
+///
+/// Settings that aren't configured are read from the `AZURE_STORAGE_*` environment variables the
+/// Azure CLI uses.
+#[derive(Clone, Default)]
+pub(crate) struct AzureConfig {
+    pub credentials: Option<AzureCredentials>,
+    /// An emulator like `http://127.0.0.1:10000/devstoreaccount1` that blobs are requested from as
+    /// `{endpoint}/{container}/{blob}`.
+    pub endpoint: Option<String>,
+}
+
+#[derive(Clone)]
+pub(crate) struct AzureCredentials {
+    pub account: String,
+    pub auth: AzureAuth,
+}
+
+#[derive(Clone)]
+pub(crate) enum AzureAuth {
+    /// The base64 encoded access key of the storage account.
+    SharedKey(String),
+    /// A shared access signature, the query string of a SAS URL.
+    Sas(String),
+}
+
+impl AzureCredentials {
+    fn from_env() -> Option<Self> {
+        let account = std::env::var("AZURE_STORAGE_ACCOUNT").ok()?;
+        let auth = match std::env::var("AZURE_STORAGE_KEY") {
+            Ok(key) => AzureAuth::SharedKey(key),
+            Err(_) => AzureAuth::Sas(std::env::var("AZURE_STORAGE_SAS_TOKEN").ok()?),
+        };
+        Some(Self { account, auth })
+    }
+}
+
+/// Checks whether `url` is an `az://` URL or points to a blob endpoint.
+pub(crate) fn is_azure_url(url: &str) -> bool {
+    url::Url::parse(url).is_ok_and(|url| {
+        url.scheme() == "az"
+            || (url.scheme() == "https"
+                && url
+                    .host_str()
+                    .is_some_and(|host| host.ends_with(".blob.core.windows.net")))
+    })
+}
+
+impl AzureConfig {
+    /// Returns the HTTP URL of the blob `url` points to, with `az://` mirrors translated the same
+    /// way, and options that authorize the requests to the configured account.
+    ///
+    /// Without credentials the requests aren't authorized, which works for public containers.
+    pub fn resolve(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<(String, DownloadOptions), DownloadError> {
+        let credentials = self.credentials.clone().or_else(AzureCredentials::from_env);
+        let endpoint = self.endpoint.as_deref().map(|e| e.trim_end_matches('/'));
+
+        // Returns the URL of the blob and the account it's stored in.
+        let blob_url = |url: &str| {
+            let invalid = || DownloadError::InvalidUrl(url.to_owned());
+            let parsed = url::Url::parse(url).map_err(|_| invalid())?;
+            if parsed.scheme() != "az" {
+                let host = parsed.host_str().ok_or_else(invalid)?;
+                let account = host.split('.').next().unwrap_or(host).to_owned();
+                return Ok((parsed, account));
+            }
+
+            let account = parsed.host_str().ok_or_else(invalid)?.to_owned();
+            // Both the container and the blob name are required.
+            if parsed
+                .path()
+                .trim_start_matches('/')
+                .split_once('/')
+                .is_none()
+            {
+                return Err(invalid());
+            }
+            let query = parsed
+                .query()
+                .map(|q| format!("?{}", q))
+                .unwrap_or_default();
+            let resolved = match endpoint {
+                Some(endpoint) => format!("{}{}{}", endpoint, parsed.path(), query),
+                None => format!(
+                    "https://{}.blob.core.windows.net{}{}",
+                    account,
+                    parsed.path(),
+                    query
+                ),
+            };
+            Ok((url::Url::parse(&resolved).map_err(|_| invalid())?, account))
+        };
+
+        let mut hosts = Vec::new();
+        let mut authorize = |(mut url, account): (url::Url, String)| {
+            if let Some(credentials) = credentials.as_ref().filter(|c| c.account == account) {
+                match &credentials.auth {
+                    AzureAuth::Sas(token) => {
+                        let token = token.trim_start_matches('?');
+                        let query = match url.query() {
+                            Some(query) => format!("{}&{}", query, token),
+                            None => token.to_owned(),
+                        };
+                        url.set_query(Some(&query));
+                    }
+                    AzureAuth::SharedKey(_) => hosts.extend(host_header(&url)),
+                }
+            }
+            url.to_string()
+        };
+
+        let resolved = authorize(blob_url(url)?);
+        let mut options = options.clone();
+        options.mirrors = options
+            .mirrors
+            .iter()
+            .map(|mirror| match is_azure_url(mirror) {
+                true => blob_url(mirror).map(&mut authorize),
+                false => Ok(mirror.clone()),
+            })
+            .collect::<Result<_, _>>()?;
+        hosts.sort();
+        hosts.dedup();
+
+        if let Some(AzureCredentials {
+            account,
+            auth: AzureAuth::SharedKey(key),
+        }) = credentials
+        {
+            if !hosts.is_empty() {
+                let key =
+                    STANDARD
+                        .decode(key.trim())
+                        .map_err(|_| DownloadError::InvalidAccountKey {
+                            account: account.clone(),
+                        })?;
+                let signer = SharedKeySigner {
+                    account,
+                    key,
+                    hosts,
+                };
+                options.auth = Arc::new(options.auth.with_signer(Arc::new(signer)));
+            }
+        }
+
+        Ok((resolved, options))
+    }
+}
+
+/// Signs requests to the blob endpoints of a storage account with its access key.
+struct SharedKeySigner {
+    account: String,
+    key: Vec<u8>,
+    hosts: Vec<String>,
+}
+
+impl RequestSigner for SharedKeySigner {
+    fn sign(&self, request: RequestBuilder) -> RequestBuilder {
+        self.sign_at(request, SystemTime::now())
+    }
+}
+
+impl SharedKeySigner {
+    /// Signs `request` as if it's sent at `now`.
+    fn sign_at(&self, request: RequestBuilder, now: SystemTime) -> RequestBuilder {
+        let Some(built) = signing::inspect(&request) else {
+            return request;
+        };
+        if !host_header(built.url()).is_some_and(|host| self.hosts.contains(&host)) {
+            return request;
+        }
+
+        let date = httpdate::fmt_http_date(now);
+        let string_to_sign = self.string_to_sign(&built, &date);
+        let signature = STANDARD.encode(hmac_sha256(&self.key, string_to_sign.as_bytes()));
+        let authorization = format!("SharedKey {}:{}", self.account, signature);
+
+        let mut request = request
+            .header("x-ms-date", date)
+            .header("x-ms-version", API_VERSION);
+        if let Ok(value) = HeaderValue::from_str(&authorization) {
+            request = request.header(AUTHORIZATION, value);
+        }
+        request
+    }
+
+    /// The parts of `built`, sent with `date`, that are signed, in the order and form the Blob
+    /// service expects.
+    fn string_to_sign(&self, built: &Request, date: &str) -> String {
+        let mut ms_headers: BTreeMap<String, String> = built
+            .headers()
+            .iter()
+            .filter(|(name, _)| name.as_str().starts_with("x-ms-"))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect();
+        ms_headers.insert("x-ms-date".to_owned(), date.to_owned());
+        ms_headers.insert("x-ms-version".to_owned(), API_VERSION.to_owned());
+
+        let standard = |name: header::HeaderName| {
+            built
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+        };
+        // Requests without a body are signed with an empty `Content-Length`.
+        let content_length = match standard(header::CONTENT_LENGTH) {
+            "0" => "",
+            length => length,
+        };
+        let mut string_to_sign = [
+            built.method().as_str(),
+            standard(header::CONTENT_ENCODING),
+            standard(header::CONTENT_LANGUAGE),
+            content_length,
+            "",
+            standard(header::CONTENT_TYPE),
+            "",
+            standard(header::IF_MODIFIED_SINCE),
+            standard(header::IF_MATCH),
+            standard(header::IF_NONE_MATCH),
+            standard(header::IF_UNMODIFIED_SINCE),
+            standard(header::RANGE),
+        ]
+        .join("\n");
+        string_to_sign.push('\n');
+        for (name, value) in &ms_headers {
+            string_to_sign.push_str(&format!("{}:{}\n", name, value.trim()));
+        }
+        string_to_sign.push_str(&format!("/{}{}", self.account, built.url().path()));
+
+        let mut parameters: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (name, value) in built.url().query_pairs() {
+            parameters
+                .entry(name.to_lowercase())
+                .or_default()
+                .push(value.into_owned());
+        }
+        for (name, mut values) in parameters {
+            values.sort();
+            string_to_sign.push_str(&format!("\n{}:{}", name, values.join(",")));
+        }
+        string_to_sign
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn signer() -> SharedKeySigner {
+        SharedKeySigner {
+            account: "myaccount".to_owned(),
+            key: b"secret key".to_vec(),
+            hosts: vec!["myaccount.blob.core.windows.net".to_owned()],
+        }
+    }
+
+    /// 2015-08-30T12:36:00Z.
+    fn now() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_440_938_160)
+    }
+
+    fn request(client: &reqwest::Client) -> RequestBuilder {
+        client
+            .get("https://myaccount.blob.core.windows.net/mycontainer/my%20blob.txt?b=2&A=y&a=x")
+            .header(header::RANGE, "bytes=0-99")
+            .header("x-ms-client-request-id", "abc")
+    }
+
+    #[test]
+    fn builds_the_string_to_sign() {
+        let built = request(&reqwest::Client::new()).build().unwrap();
+        assert_eq!(
+            signer().string_to_sign(&built, "Sun, 30 Aug 2015 12:36:00 GMT"),
+            "GET\n\n\n\n\n\n\n\n\n\n\nbytes=0-99\n\
+             x-ms-client-request-id:abc\n\
+             x-ms-date:Sun, 30 Aug 2015 12:36:00 GMT\n\
+             x-ms-version:2021-08-06\n\
+             /myaccount/mycontainer/my%20blob.txt\n\
+             a:x,y\n\
+             b:2"
+        );
+    }
+
+    #[test]
+    fn signs_requests_to_the_account() {
+        let client = reqwest::Client::new();
+        let signed = signer().sign_at(request(&client), now()).build().unwrap();
+        let header = |name| signed.headers()[name].to_str().unwrap();
+        assert_eq!(header("x-ms-date"), "Sun, 30 Aug 2015 12:36:00 GMT");
+        assert_eq!(header("x-ms-version"), API_VERSION);
+        assert_eq!(
+            header("authorization"),
+            "SharedKey myaccount:b9jPI+JxRfK3zfP/XEoLmcKmFWdKnCEAFis5m5dZbuE="
+        );
+
+        let mirror = client.get("https://mirror.example.com/my%20blob.txt");
+        let unsigned = signer().sign_at(mirror, now()).build().unwrap();
+        assert!(!unsigned.headers().contains_key("authorization"));
+    }
+}
@@ -0,0 +1,115 @@
+//! Progress bars drawn with `indicatif`, enabled with the `indicatif` feature.
+
+use crate::progress::{BatchProgress, BatchProgressReporter, Progress, ProgressReporter};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+const BAR_TEMPLATE: &str = "{prefix} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} eta {eta}";
+
+/// A progress bar for every running download, and one summarizing a batch of them.
+///
+/// Register it with [`DownloaderBuilder::progress_bars`](crate::DownloaderBuilder::progress_bars).
+/// The summary is only drawn for batches of more than one file.
+#[derive(Clone)]
+pub struct ProgressBars {
+    multi: MultiProgress,
+    bars: Arc<Mutex<HashMap<String, ProgressBar>>>,
+    summary: Arc<Mutex<Option<ProgressBar>>>,
+}
+
+impl ProgressBars {
+    pub fn new() -> Self {
+        Self::with_multi_progress(MultiProgress::new())
+    }
+
+    /// Draws the bars in `multi`, for example to share it with other bars of the application.
+    pub fn with_multi_progress(multi: MultiProgress) -> Self {
+        Self {
+            multi,
+            bars: Arc::default(),
+            summary: Arc::default(),
+        }
+    }
+
+    pub fn multi_progress(&self) -> &MultiProgress {
+        &self.multi
+    }
+
+    /// Replaces the bar of `url` with `message`, like where the file was saved or why it failed.
+    pub fn finish(&self, url: &str, message: &str) {
+        let bar = self.bar(url);
+        bar.set_style(ProgressStyle::with_template("{prefix} {msg}").unwrap());
+        bar.finish_with_message(message.to_owned());
+    }
+
+    fn bar(&self, url: &str) -> ProgressBar {
+        let mut bars = self.bars.lock().unwrap();
+        bars.entry(url.to_owned())
+            .or_insert_with(|| {
+                let bar = self
+                    .multi
+                    .add(ProgressBar::no_length().with_style(bar_style()));
+                bar.set_prefix(url.to_owned());
+                bar
+            })
+            .clone()
+    }
+}
+
+impl Default for ProgressBars {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressReporter for ProgressBars {
+    fn report(&self, url: &str, progress: &Progress) {
+        let bar = self.bar(url);
+        if let Some(total) = progress.total {
+            bar.set_length(total);
+        }
+        bar.set_position(progress.downloaded);
+    }
+}
+
+impl BatchProgressReporter for ProgressBars {
+    fn report(&self, progress: &BatchProgress) {
+        let files =
+            progress.completed + progress.failed + progress.pending + progress.running.len();
+        if files < 2 {
+            return;
+        }
+
+        let mut summary = self.summary.lock().unwrap();
+        let bar = summary.get_or_insert_with(|| {
+            let style = ProgressStyle::with_template(&format!("{} {{msg}}", BAR_TEMPLATE)).unwrap();
+            let bar = self.multi.insert(
+                0,
+                ProgressBar::no_length().with_style(style.progress_chars("=> ")),
+            );
+            bar.set_prefix("total");
+            bar
+        });
+        if let Some(total) = progress.total {
+            bar.set_length(total);
+        }
+        bar.set_position(progress.downloaded);
+        let mut message = format!("{}/{} files", progress.completed, files);
+        if progress.failed > 0 {
+            message += &format!(", {} failed", progress.failed);
+        }
+        bar.set_message(message);
+        if progress.pending == 0 && progress.running.is_empty() {
+            bar.finish();
+        }
+    }
+}
+
+fn bar_style() -> ProgressStyle {
+    ProgressStyle::with_template(BAR_TEMPLATE)
+        .unwrap()
+        .progress_chars("=> ")
+}
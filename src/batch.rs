@@ -0,0 +1,173 @@
+use crate::{
+    error::DownloadError,
+    event::DownloadEvent,
+    progress::{BatchProgress, ChunkProgress, FileProgress, Progress, SpeedMeter},
+    report::{BatchFileReport, BatchReport, DownloadReport},
+};
+use std::{sync::Mutex, time::Instant};
+
+/// Collects the progress of the files of a batch into [`BatchProgress`] snapshots.
+pub(crate) struct BatchTracker {
+    files: Mutex<Vec<File>>,
+    meter: Mutex<SpeedMeter>,
+}
+
+struct File {
+    url: String,
+    state: FileState,
+    started: Option<Instant>,
+    /// Chunks retried so far, for failed downloads whose report doesn't say.
+    retries: u32,
+    outcome: Option<BatchFileReport>,
+}
+
+enum FileState {
+    Pending,
+    Running(Progress),
+    Completed { size: u64 },
+    Failed,
+}
+
+impl BatchTracker {
+    pub fn new<'a>(urls: impl IntoIterator<Item = &'a str>) -> Self {
+        let files = urls
+            .into_iter()
+            .map(|url| File {
+                url: url.to_owned(),
+                state: FileState::Pending,
+                started: None,
+                retries: 0,
+                outcome: None,
+            })
+            .collect();
+        Self {
+            files: Mutex::new(files),
+            meter: Mutex::new(SpeedMeter::new(0)),
+        }
+    }
+
+    pub fn start(&self, index: usize) {
+        let file = &mut self.files.lock().unwrap()[index];
+        file.state = FileState::Running(empty_progress(None));
+        file.started = Some(Instant::now());
+    }
+
+    /// Updates the progress of the file at `index` with an event of its download.
+    pub fn update(&self, index: usize, event: DownloadEvent) {
+        let progress = match event {
+            DownloadEvent::Started { total, .. } => empty_progress(total),
+            DownloadEvent::ChunkProgress(progress) => progress,
+            DownloadEvent::ChunkRetried { .. } => {
+                self.files.lock().unwrap()[index].retries += 1;
+                return;
+            }
+            _ => return,
+        };
+        self.files.lock().unwrap()[index].state = FileState::Running(progress);
+    }
+
+    pub fn finish(&self, index: usize, result: &Result<DownloadReport, DownloadError>) {
+        let file = &mut self.files.lock().unwrap()[index];
+        let elapsed = file.started.map(|started| started.elapsed());
+        let (state, outcome) = match result {
+            Ok(report) => (
+                FileState::Completed { size: report.size },
+                BatchFileReport {
+                    url: report.url.clone(),
+                    path: Some(report.path.clone()),
+                    size: Some(report.size),
+                    checksum: report.checksum.clone(),
+                    elapsed: elapsed.unwrap_or(report.elapsed),
+                    // Duplicates of another file in the batch made none.
+                    attempts: file.started.map_or(0, |_| report.retries + 1),
+                    error: None,
+                },
+            ),
+            Err(e) => (
+                FileState::Failed,
+                BatchFileReport {
+                    url: file.url.clone(),
+                    path: None,
+                    size: None,
+                    checksum: None,
+                    elapsed: elapsed.unwrap_or_default(),
+                    // Neither did downloads that never started, like invalid lines of URL lists.
+                    attempts: file.started.map_or(0, |_| file.retries + 1),
+                    error: Some(e.to_string()),
+                },
+            ),
+        };
+        file.state = state;
+        file.outcome = Some(outcome);
+    }
+
+    /// Returns the outcome of every finished file.
+    pub fn report(&self) -> BatchReport {
+        let files = self.files.lock().unwrap();
+        BatchReport {
+            files: files
+                .iter()
+                .filter_map(|file| file.outcome.clone())
+                .collect(),
+        }
+    }
+
+    pub fn snapshot(&self) -> BatchProgress {
+        let files = self.files.lock().unwrap();
+        let mut sizes = Vec::new();
+        let mut running = Vec::new();
+        let (mut completed, mut failed, mut pending) = (0, 0, 0);
+        for file in files.iter() {
+            match &file.state {
+                FileState::Pending => {
+                    pending += 1;
+                    sizes.push(ChunkProgress {
+                        downloaded: 0,
+                        total: None,
+                    });
+                }
+                FileState::Running(progress) => {
+                    sizes.push(ChunkProgress {
+                        downloaded: progress.downloaded,
+                        total: progress.total,
+                    });
+                    running.push(FileProgress {
+                        url: file.url.clone(),
+                        progress: progress.clone(),
+                    });
+                }
+                FileState::Completed { size } => {
+                    completed += 1;
+                    sizes.push(ChunkProgress {
+                        downloaded: *size,
+                        total: Some(*size),
+                    });
+                }
+                FileState::Failed => failed += 1,
+            }
+        }
+
+        let total = sizes.iter().map(|file| file.total).sum();
+        let progress = self.meter.lock().unwrap().sample(sizes, total);
+        BatchProgress {
+            downloaded: progress.downloaded,
+            total,
+            completed,
+            failed,
+            pending,
+            running,
+            speed: progress.speed,
+            eta: progress.eta,
+        }
+    }
+}
+
+fn empty_progress(total: Option<u64>) -> Progress {
+    Progress {
+        downloaded: 0,
+        total,
+        chunks: Vec::new(),
+        speed: 0.0,
+        eta: None,
+    }
+}
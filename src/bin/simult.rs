@@ -0,0 +1,751 @@
+use clap::{ArgGroup, Parser, Subcommand};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+use zusammen::{
+    Allocation, CrawlOptions, DownloadError, DownloadOptions, Downloader, DownloaderBuilder,
+    FileChange, OverwritePolicy, ProgressBars, StreamVariant, UrlList, UrlListEntry, UrlPolicy,
+};
+
+/// Write buffer sizes `simult bench` tries, with the spelling `--write-buffer-size` accepts.
+const BENCH_WRITE_BUFFER_SIZES: [(&str, usize); 5] = [
+    ("0", 0),
+    ("64K", 64 * 1024),
+    ("256K", 256 * 1024),
+    ("1M", 1024 * 1024),
+    ("4M", 4 * 1024 * 1024),
+];
+
+/// Downloads files over parallel connections.
+#[derive(Parser)]
+#[command(name = "simult", version, args_conflicts_with_subcommands = true)]
+#[command(group(ArgGroup::new("stream").args(["hls", "dash"])))]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// URLs to download.
+    urls: Vec<String>,
+
+    /// Reads URLs from a file, or the standard input if FILE is `-`, one per line. Lines can set
+    /// `out=<name>` and `checksum=<algorithm>=<digest>` options like in aria2 input files. Empty
+    /// lines and lines starting with `#` are skipped.
+    #[arg(short, long, value_name = "FILE")]
+    input_file: Option<PathBuf>,
+
+    /// Directory the files are saved to.
+    #[arg(short, long, value_name = "DIR", default_value = ".")]
+    output_dir: String,
+
+    /// Writes files to DIR until they're complete, and moves them to the output directory
+    /// afterwards.
+    #[arg(long, value_name = "DIR")]
+    staging_dir: Option<String>,
+
+    /// Number of connections per file.
+    #[arg(short, long, value_name = "N", default_value_t = 8)]
+    connections: usize,
+
+    /// Limits the combined download speed, in bytes per second. Accepts K, M and G suffixes.
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// Continues interrupted downloads instead of starting over.
+    #[arg(long)]
+    resume: bool,
+
+    /// Syncs partial files whenever the resume state is saved and records what's on disk in a
+    /// journal, so resuming after a crash or power loss never trusts data that wasn't written.
+    #[arg(long, requires = "resume")]
+    chunk_journal: bool,
+
+    /// Keeps partial files sparse, so they only take up the space downloaded so far, and checks
+    /// which regions of them hold data when resuming.
+    #[arg(long)]
+    sparse: bool,
+
+    /// Fails downloads of files larger than SIZE bytes. Accepts K, M and G suffixes.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_file_size: Option<u64>,
+
+    /// Only downloads files whose Content-Type is TYPE, like `application/pdf` or `image/*`, or
+    /// another accepted type. Can be repeated.
+    #[arg(long, value_name = "TYPE")]
+    accept_type: Vec<String>,
+
+    /// Gives files whose URL has no extension the one of their Content-Type.
+    #[arg(long)]
+    infer_extensions: bool,
+
+    /// Only downloads from HOST and the other allowed hosts, also when redirected. `*.example.com`
+    /// allows every subdomain of `example.com`. Can be repeated.
+    #[arg(long, value_name = "HOST")]
+    allow_host: Vec<String>,
+
+    /// Never downloads from HOST, also when redirected. Can be repeated.
+    #[arg(long, value_name = "HOST")]
+    block_host: Vec<String>,
+
+    /// Only downloads URLs with the scheme SCHEME and the other allowed schemes, like `https`.
+    /// Can be repeated.
+    #[arg(long, value_name = "SCHEME")]
+    allow_scheme: Vec<String>,
+
+    /// Refuses requests to loopback, private and link-local addresses, including hosts that
+    /// resolve to them.
+    #[arg(long)]
+    block_private_ips: bool,
+
+    /// Keeps files that haven't changed on the server since they were last downloaded, asking
+    /// with `If-None-Match` and `If-Modified-Since`, and replaces the others.
+    #[arg(long)]
+    skip_unchanged: bool,
+
+    /// Keeps the ETag, Last-Modified date and size of every downloaded file in
+    /// `.simult-manifest.json` in the output directory, which `--skip-unchanged` and `--dry-run`
+    /// then ask the server with.
+    #[arg(long)]
+    manifest: bool,
+
+    /// Lists which URLs `--skip-unchanged` would download and why, without downloading anything.
+    #[arg(long, conflicts_with_all = ["recursive", "stream", "torrent", "zsync"])]
+    dry_run: bool,
+
+    /// Prints the size, range support, content type and ETag of every URL and where it would be
+    /// saved, and their total size, without downloading anything.
+    #[arg(long, conflicts_with_all = ["recursive", "stream", "torrent", "zsync", "dry_run"])]
+    probe: bool,
+
+    /// Saves files in a directory named after the host of their URL, keeping the directories of
+    /// the URL path, like `example.com/releases/app.tar.gz`.
+    #[arg(short = 'x', long)]
+    host_directories: bool,
+
+    /// Treats the URLs as directory listings, like the index pages of nginx and Apache, and
+    /// downloads the files in them and their subdirectories, keeping the directory tree.
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Lists the directories of `--recursive` as WebDAV collections and downloads only the files
+    /// that are missing or changed, comparing their sizes, ETags and modification times.
+    #[arg(long, requires = "recursive")]
+    webdav: bool,
+
+    /// Only downloads files of directory listings that match GLOB, like `*.iso` or
+    /// `docs/**/*.pdf`. Can be repeated.
+    #[arg(long, value_name = "GLOB", requires = "recursive")]
+    include: Vec<String>,
+
+    /// Skips files of directory listings that match GLOB. Can be repeated.
+    #[arg(long, value_name = "GLOB", requires = "recursive")]
+    exclude: Vec<String>,
+
+    /// Follows subdirectories of directory listings up to N levels deep.
+    #[arg(long, value_name = "N", requires = "recursive")]
+    max_depth: Option<usize>,
+
+    /// Treats the URLs as HLS playlists and joins the segments of each stream into one file.
+    #[arg(long, conflicts_with = "recursive")]
+    hls: bool,
+
+    /// Treats the URLs as MPEG-DASH manifests and joins the segments of each presentation into
+    /// one file.
+    #[arg(long, conflicts_with = "recursive")]
+    dash: bool,
+
+    /// Treats the URLs as `.torrent` files and downloads their files from the HTTP web seeds they
+    /// list, checking every piece against its hash.
+    #[arg(long, conflicts_with_all = ["recursive", "stream"])]
+    torrent: bool,
+
+    /// Treats the URLs as `.zsync` control files and downloads only the blocks of the files they
+    /// describe that aren't in the older version at OLD.
+    #[arg(long, value_name = "OLD", conflicts_with_all = ["recursive", "stream", "torrent"])]
+    zsync: Option<PathBuf>,
+
+    /// Downloads the variant of a stream with the highest bandwidth of at most BPS bits per
+    /// second, instead of the highest.
+    #[arg(long, value_name = "BPS", requires = "stream")]
+    max_bandwidth: Option<u64>,
+
+    /// Downloads `ipfs://` URLs through the gateway at URL, like `https://ipfs.io`, instead of the
+    /// default public ones. Can be repeated.
+    #[arg(long, value_name = "URL")]
+    ipfs_gateway: Vec<String>,
+
+    /// Writes the outcome of every file to FILE when all downloads are done, as CSV if it ends
+    /// with `.csv` and as JSON otherwise.
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// POSTs a JSON description of every finished or failed download to URL.
+    #[arg(long, value_name = "URL")]
+    webhook: Option<String>,
+
+    #[command(flatten)]
+    tuning: Tuning,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Downloads a URL with different numbers of connections and write buffer sizes, and reports
+    /// the fastest settings.
+    Bench(BenchArgs),
+}
+
+#[derive(clap::Args)]
+struct BenchArgs {
+    /// URL to download.
+    url: String,
+
+    /// Most connections to try. The number of connections doubles from 1 up to this.
+    #[arg(long, value_name = "N", default_value_t = 32)]
+    max_connections: usize,
+
+    /// Downloads per setting, of which the fastest counts.
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    rounds: usize,
+
+    /// Directory the file is downloaded to, and removed from again. Defaults to the temporary
+    /// directory.
+    #[arg(short, long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    tuning: Tuning,
+}
+
+/// Low-level settings for fast links and disks.
+#[derive(clap::Args)]
+struct Tuning {
+    /// Socket receive buffer size of FTP and SFTP connections, which also sizes HTTP/2 flow control
+    /// windows. Accepts K, M and G suffixes.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    tcp_receive_buffer: Option<u64>,
+
+    /// How many bytes are read at once from FTP and SFTP servers and local files. Accepts K, M and
+    /// G suffixes.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    read_buffer_size: Option<u64>,
+
+    /// How many bytes each connection collects before writing them to the file. Accepts K, M and G
+    /// suffixes.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    write_buffer_size: Option<u64>,
+
+    /// Most threads that write files and do other blocking work.
+    #[arg(long, value_name = "N")]
+    blocking_threads: Option<usize>,
+}
+
+impl Tuning {
+    fn apply(&self, mut builder: DownloaderBuilder) -> DownloaderBuilder {
+        if let Some(size) = self.tcp_receive_buffer {
+            builder = builder.tcp_receive_buffer_size(u32::try_from(size).unwrap_or(u32::MAX));
+        }
+        if let Some(size) = self.read_buffer_size {
+            builder = builder.read_buffer_size(usize::try_from(size).unwrap_or(usize::MAX));
+        }
+        if let Some(size) = self.write_buffer_size {
+            builder = builder.write_buffer_size(usize::try_from(size).unwrap_or(usize::MAX));
+        }
+        builder
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let tuning = match &args.command {
+        Some(Command::Bench(bench)) => &bench.tuning,
+        None => &args.tuning,
+    };
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = tuning.blocking_threads {
+        runtime.max_blocking_threads(threads.max(1));
+    }
+    let runtime = match runtime.build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("error: can't start the runtime: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match args.command {
+        Some(Command::Bench(bench)) => runtime.block_on(run_bench(bench)),
+        None => runtime.block_on(download(args)),
+    }
+}
+
+async fn download(args: Args) -> ExitCode {
+    let mut entries: Vec<_> = args
+        .urls
+        .iter()
+        .map(|url| UrlListEntry {
+            urls: vec![url.clone()],
+            ..UrlListEntry::default()
+        })
+        .collect();
+    let mut failed = false;
+    if let Some(path) = &args.input_file {
+        let contents = if path.as_os_str() == "-" {
+            std::io::read_to_string(std::io::stdin())
+        } else {
+            std::fs::read_to_string(path)
+        };
+        let contents = match contents {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("error: can't read {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        };
+        for entry in UrlList::parse(&contents).entries {
+            match entry {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    failed = true;
+                }
+            }
+        }
+    }
+    if entries.is_empty() {
+        if !failed {
+            eprintln!("error: no URLs to download");
+        }
+        return ExitCode::FAILURE;
+    }
+
+    let bars = ProgressBars::new();
+    let mut builder = Downloader::builder(&args.output_dir, args.connections)
+        .resume(args.resume)
+        .progress_bars(&bars);
+    if let Some(rate) = args.limit_rate {
+        builder = builder.max_speed_bytes_per_sec(rate);
+    }
+    if args.chunk_journal {
+        builder = builder.chunk_journal(true);
+    }
+    if args.sparse {
+        builder = builder.allocation(Allocation::Sparse);
+    }
+    if let Some(limit) = args.max_file_size {
+        builder = builder.max_file_size(limit);
+    }
+    if !args.accept_type.is_empty() {
+        builder = builder.allowed_content_types(&args.accept_type);
+    }
+    if args.infer_extensions {
+        builder = builder.infer_extensions(true);
+    }
+    if !args.allow_host.is_empty()
+        || !args.block_host.is_empty()
+        || !args.allow_scheme.is_empty()
+        || args.block_private_ips
+    {
+        let mut policy = UrlPolicy::new();
+        for host in &args.allow_host {
+            policy = policy.allow_host(host);
+        }
+        for host in &args.block_host {
+            policy = policy.block_host(host);
+        }
+        for scheme in &args.allow_scheme {
+            policy = policy.allow_scheme(scheme);
+        }
+        if args.block_private_ips {
+            policy = policy.block_private_ips();
+        }
+        builder = builder.url_policy(policy);
+    }
+    if let Some(dir) = &args.staging_dir {
+        builder = builder.staging_dir(dir);
+    }
+    if !args.dry_run && !args.probe {
+        builder = builder.create_output_dir(true);
+    }
+    if args.host_directories {
+        builder = builder.host_directories(true);
+    }
+    if args.skip_unchanged {
+        builder = builder.overwrite_policy(OverwritePolicy::SkipIfUnchanged);
+    }
+    if args.manifest {
+        builder = builder.manifest(true);
+    }
+    if let Some(path) = &args.report {
+        builder = builder.batch_report(&path.to_string_lossy());
+    }
+    if let Some(endpoint) = &args.webhook {
+        builder = builder.webhook(endpoint);
+    }
+    if !args.ipfs_gateway.is_empty() {
+        builder = builder.ipfs_gateways(&args.ipfs_gateway);
+    }
+    builder = args.tuning.apply(builder);
+    let downloader = match builder.build() {
+        Ok(downloader) => downloader,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if args.recursive {
+        let mut crawl = CrawlOptions::new();
+        for glob in &args.include {
+            crawl = crawl.include(glob);
+        }
+        for glob in &args.exclude {
+            crawl = crawl.exclude(glob);
+        }
+        if let Some(depth) = args.max_depth {
+            crawl = crawl.max_depth(depth);
+        }
+        for entry in &entries {
+            let url = &entry.urls[0];
+            let results = if args.webdav {
+                downloader.sync_webdav(url, &crawl).await
+            } else {
+                downloader.download_directory(url, &crawl).await
+            };
+            let results = match results {
+                Ok(results) => results,
+                Err(e) => {
+                    bars.finish(url, &format!("failed: {}", e));
+                    failed = true;
+                    continue;
+                }
+            };
+            for result in results {
+                match result {
+                    Ok(report) if report.bytes_downloaded == 0 && args.webdav => bars.finish(
+                        &report.url,
+                        &format!("{} is up to date", report.path.display()),
+                    ),
+                    Ok(report) => {
+                        bars.finish(&report.url, &format!("saved {}", report.path.display()))
+                    }
+                    Err(e) => {
+                        let _ = bars.multi_progress().println(format!("failed: {}", e));
+                        failed = true;
+                    }
+                }
+            }
+        }
+        return if failed {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        };
+    }
+
+    if args.torrent {
+        for entry in &entries {
+            let url = &entry.urls[0];
+            match downloader.download_torrent(url).await {
+                Ok(paths) => {
+                    for path in paths {
+                        let _ = bars
+                            .multi_progress()
+                            .println(format!("saved {}", path.display()));
+                    }
+                    bars.finish(url, "done");
+                }
+                Err(e) => {
+                    bars.finish(url, &format!("failed: {}", e));
+                    failed = true;
+                }
+            }
+        }
+        return if failed {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        };
+    }
+
+    if args.probe {
+        // The estimate lists every URL once.
+        let mut seen = HashSet::new();
+        let urls: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.urls[0].clone())
+            .filter(|url| seen.insert(url.clone()))
+            .collect();
+        let estimate = downloader.estimate(&urls).await;
+        for (url, file) in urls.iter().zip(&estimate.files) {
+            match file {
+                Ok(file) => {
+                    let size = file.size.map_or("?".to_owned(), |size| size.to_string());
+                    let ranges = match file.supports_ranges {
+                        true => "ranges",
+                        false => "no-ranges",
+                    };
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}\t{}",
+                        url,
+                        size,
+                        ranges,
+                        file.content_type.as_deref().unwrap_or("-"),
+                        file.etag.as_deref().unwrap_or("-"),
+                        file.path.display()
+                    );
+                }
+                Err(e) => {
+                    eprintln!("error: {}: {}", url, e);
+                    failed = true;
+                }
+            }
+        }
+        println!(
+            "total {} bytes, {} of unknown size",
+            estimate.total_bytes, estimate.unknown_sizes
+        );
+        return if failed {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        };
+    }
+
+    if args.dry_run {
+        for entry in &entries {
+            let url = &entry.urls[0];
+            let mut options = DownloadOptions::new();
+            if let Some(name) = &entry.filename {
+                options = options.filename(name);
+            }
+            match downloader.dry_run(url, &options).await {
+                Ok(planned) => {
+                    let change = match planned.change {
+                        FileChange::Missing => "download",
+                        FileChange::Untracked => "download (untracked)",
+                        FileChange::Modified => "download (modified locally)",
+                        FileChange::Changed => "download (changed)",
+                        FileChange::Unchanged => "unchanged",
+                    };
+                    println!("{}\t{}\t{}", change, url, planned.path.display());
+                }
+                Err(e) => {
+                    eprintln!("error: {}: {}", url, e);
+                    failed = true;
+                }
+            }
+        }
+        return if failed {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        };
+    }
+
+    if let Some(old) = &args.zsync {
+        for entry in &entries {
+            let url = &entry.urls[0];
+            let mut options = DownloadOptions::new();
+            if let Some(name) = &entry.filename {
+                options = options.filename(name);
+            }
+            match downloader.download_zsync_with(url, old, &options).await {
+                Ok(report) => bars.finish(
+                    url,
+                    &format!(
+                        "saved {}, fetched {} of {} bytes",
+                        report.path.display(),
+                        report.bytes_downloaded,
+                        report.size
+                    ),
+                ),
+                Err(e) => {
+                    bars.finish(url, &format!("failed: {}", e));
+                    failed = true;
+                }
+            }
+        }
+        return if failed {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        };
+    }
+
+    if args.hls || args.dash {
+        let variant = args
+            .max_bandwidth
+            .map_or(StreamVariant::Highest, StreamVariant::MaxBandwidth);
+        for entry in &entries {
+            let url = &entry.urls[0];
+            let mut options = DownloadOptions::new();
+            if let Some(name) = &entry.filename {
+                options = options.filename(name);
+            }
+            let result = if args.hls {
+                downloader.download_hls_with(url, variant, &options).await
+            } else {
+                downloader.download_dash_with(url, variant, &options).await
+            };
+            match result {
+                Ok(report) => bars.finish(url, &format!("saved {}", report.path.display())),
+                Err(e) => {
+                    bars.finish(url, &format!("failed: {}", e));
+                    failed = true;
+                }
+            }
+        }
+        return if failed {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        };
+    }
+
+    let urls: Vec<_> = entries.iter().map(|entry| entry.urls[0].clone()).collect();
+    let results = downloader.download_url_list(UrlList {
+        entries: entries.into_iter().map(Ok).collect(),
+    });
+    for (url, result) in urls.iter().zip(results.await) {
+        match result {
+            Ok(report) => bars.finish(url, &format!("saved {}", report.path.display())),
+            Err(e) => {
+                bars.finish(url, &format!("failed: {}", e));
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Finds the number of connections that downloads `args.url` the fastest, and then the fastest
+/// write buffer size with that many connections.
+async fn run_bench(args: BenchArgs) -> ExitCode {
+    let dir = args
+        .output_dir
+        .clone()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("simult-bench-{}", std::process::id()));
+    let result = bench(&args, &dir).await;
+    let _ = std::fs::remove_dir_all(&dir);
+
+    match result {
+        Ok((connections, write_buffer_size)) => {
+            println!();
+            println!(
+                "fastest: --connections {} --write-buffer-size {}",
+                connections, write_buffer_size
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn bench(args: &BenchArgs, dir: &Path) -> Result<(usize, &'static str), DownloadError> {
+    let max_connections = args.max_connections.max(1);
+    let connection_counts = std::iter::successors(Some(1usize), |n| n.checked_mul(2))
+        .take_while(|&n| n < max_connections)
+        .chain([max_connections]);
+
+    println!("{:>11}  {:>12}", "connections", "speed");
+    let mut fastest = (1, 0.0);
+    for connections in connection_counts {
+        let speed = measure(args, dir, connections, None).await?;
+        println!("{:>11}  {:>12}", connections, format_speed(speed));
+        if speed > fastest.1 {
+            fastest = (connections, speed);
+        }
+    }
+    let connections = fastest.0;
+
+    println!();
+    println!("{:>12}  {:>12}", "write buffer", "speed");
+    let mut fastest = (BENCH_WRITE_BUFFER_SIZES[0].0, 0.0);
+    for (name, size) in BENCH_WRITE_BUFFER_SIZES {
+        let speed = measure(args, dir, connections, Some(size)).await?;
+        println!("{:>12}  {:>12}", name, format_speed(speed));
+        if speed > fastest.1 {
+            fastest = (name, speed);
+        }
+    }
+
+    Ok((connections, fastest.0))
+}
+
+/// Downloads `args.url` `args.rounds` times and returns the fastest speed, in bytes per second.
+async fn measure(
+    args: &BenchArgs,
+    dir: &Path,
+    connections: usize,
+    write_buffer_size: Option<usize>,
+) -> Result<f64, DownloadError> {
+    let mut builder = args
+        .tuning
+        .apply(Downloader::builder(&dir.to_string_lossy(), connections));
+    if let Some(size) = write_buffer_size {
+        builder = builder.write_buffer_size(size);
+    }
+    let downloader = builder.build()?;
+
+    let mut fastest: f64 = 0.0;
+    for _ in 0..args.rounds.max(1) {
+        let report = downloader
+            .download_with_report(&args.url, &DownloadOptions::new())
+            .await?;
+        fastest = fastest.max(report.average_speed());
+        let _ = tokio::fs::remove_file(&report.path).await;
+    }
+    Ok(fastest)
+}
+
+fn format_speed(bytes_per_sec: f64) -> String {
+    format!("{:.1} MiB/s", bytes_per_sec / (1024.0 * 1024.0))
+}
+
+/// Parses a rate like `500K` or `2M` into bytes per second.
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    let rate = rate.trim();
+    let (number, multiplier) = split_suffix(rate);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid rate `{}`", rate))?;
+    if number <= 0.0 {
+        return Err("the rate must be positive".to_owned());
+    }
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parses a size like `256K` or `4M` into bytes.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, multiplier) = split_suffix(size);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size `{}`", size))?;
+    if number < 0.0 {
+        return Err("the size can't be negative".to_owned());
+    }
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Splits a K, M or G suffix off `value`, returning the rest and what the suffix multiplies by.
+fn split_suffix(value: &str) -> (&str, u64) {
+    match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1024),
+        Some((i, 'm' | 'M')) => (&value[..i], 1024 * 1024),
+        Some((i, 'g' | 'G')) => (&value[..i], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    }
+}
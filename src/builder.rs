@@ -0,0 +1,1265 @@
+#[cfg(feature = "azure")]
+use crate::azure::{AzureAuth, AzureConfig, AzureCredentials};
+#[cfg(feature = "extract")]
+use crate::extract::ExtractConfig;
+#[cfg(feature = "gcs")]
+use crate::gcs::{GcsAuth, GcsConfig};
+#[cfg(feature = "http3")]
+use crate::http3::Http3;
+#[cfg(feature = "ipfs")]
+use crate::ipfs::IpfsConfig;
+#[cfg(feature = "lfs")]
+use crate::lfs::HfConfig;
+#[cfg(feature = "oci")]
+use crate::oci::OciConfig;
+#[cfg(feature = "s3")]
+use crate::s3::{AwsCredentials, S3Config};
+#[cfg(feature = "sftp")]
+use crate::sftp::{SftpAuth, SftpConfig};
+use crate::{
+    digest,
+    download::{Downloader, DEFAULT_PROGRESS_INTERVAL},
+    error::DownloadError,
+    hooks::{DownloadHook, Hooks},
+    hosts::HostLimiter,
+    manifest::Manifest,
+    metrics,
+    net::{self, IpVersion, Network, Resolver},
+    options::{Allocation, CancelPolicy, DuplicatePolicy, Durability, OverwritePolicy},
+    pinning::CertificatePins,
+    policy::UrlPolicy,
+    progress::{BatchProgressReporter, ProgressReporter},
+    redirect::{self, CrossOriginRedirects, DEFAULT_MAX_REDIRECTS},
+    reservation::Reservations,
+    retry::RetryPolicy,
+    shutdown::Shutdown,
+    staging::Staging,
+    stall::MinSpeed,
+    template,
+    throttle::{RateLimiter, Throttle},
+    tls::TlsConfig,
+    webhook::Webhook,
+};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    redirect::Policy,
+};
+#[cfg(feature = "sftp")]
+use std::path::Path;
+use std::{net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
+
+/// The most chunks a file is split into with a chunk size, unless configured otherwise.
+const DEFAULT_MAX_CHUNKS: usize = 1024;
+
+/// How many bytes are read at once from FTP and SFTP servers and `file://` and `data:` URLs,
+/// unless configured otherwise.
+const DEFAULT_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How many bytes a connection collects before writing them, unless configured otherwise.
+const DEFAULT_WRITE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Configures and creates a [`Downloader`].
+pub struct DownloaderBuilder {
+    output_dir: PathBuf,
+    conn_count: usize,
+    chunk_size: Option<u64>,
+    chunk_count_bounds: (usize, usize),
+    parallel_threshold: Option<u64>,
+    work_stealing: bool,
+    adaptive_connections: bool,
+    max_concurrent_files: Option<usize>,
+    max_connections_per_host: Option<usize>,
+    request_delay: Option<Duration>,
+    client: Option<reqwest::Client>,
+    http2_multiplex: bool,
+    #[cfg(feature = "http3")]
+    http3: bool,
+    #[cfg(feature = "decompression")]
+    decompress: bool,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    io_uring: bool,
+    #[cfg(feature = "mmap")]
+    memory_map: bool,
+    network: Network,
+    interface: Option<String>,
+    tls: TlsConfig,
+    certificate_pins: Vec<String>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    min_speed: Option<MinSpeed>,
+    retry: RetryPolicy,
+    max_speed: Option<u64>,
+    max_speed_per_connection: Option<u64>,
+    redirect_policy: Option<reqwest::redirect::Policy>,
+    max_redirects: usize,
+    cross_origin_redirects: CrossOriginRedirects,
+    user_agent: Option<String>,
+    default_headers: HeaderMap,
+    #[cfg(feature = "cookies")]
+    cookie_jar: Option<Arc<reqwest::cookie::Jar>>,
+    proxy: Option<String>,
+    proxy_auth: Option<(String, String)>,
+    system_proxy: bool,
+    resume: bool,
+    chunk_journal: bool,
+    allocation: Allocation,
+    read_buffer_size: usize,
+    write_buffer_size: usize,
+    verify_digests: bool,
+    discover_checksums: bool,
+    cancel_policy: CancelPolicy,
+    durability: Durability,
+    overwrite_policy: OverwritePolicy,
+    manifest: bool,
+    max_file_size: Option<u64>,
+    allowed_content_types: Option<Vec<String>>,
+    infer_extensions: bool,
+    duplicate_policy: DuplicatePolicy,
+    output_template: Option<String>,
+    host_directories: bool,
+    create_output_dir: bool,
+    staging_dir: Option<PathBuf>,
+    progress: Option<Arc<dyn ProgressReporter>>,
+    progress_interval: Duration,
+    batch_progress: Option<Arc<dyn BatchProgressReporter>>,
+    batch_report: Option<PathBuf>,
+    hooks: Vec<Arc<dyn DownloadHook>>,
+    hook_concurrency: Option<usize>,
+    #[cfg(feature = "extract")]
+    extract: ExtractConfig,
+    #[cfg(feature = "sftp")]
+    sftp: SftpConfig,
+    #[cfg(feature = "s3")]
+    s3: S3Config,
+    #[cfg(feature = "gcs")]
+    gcs: GcsConfig,
+    #[cfg(feature = "azure")]
+    azure: AzureConfig,
+    #[cfg(feature = "ipfs")]
+    ipfs: IpfsConfig,
+    #[cfg(feature = "lfs")]
+    hf: HfConfig,
+    #[cfg(feature = "oci")]
+    oci: OciConfig,
+}
+
+impl DownloaderBuilder {
+    /// Creates a new builder with the same defaults as [`Downloader::new`].
+    pub fn new(output_dir: &str, conn_count: usize) -> Self {
+        Self {
+            output_dir: PathBuf::from(output_dir),
+            conn_count,
+            chunk_size: None,
+            chunk_count_bounds: (1, DEFAULT_MAX_CHUNKS),
+            parallel_threshold: None,
+            work_stealing: true,
+            adaptive_connections: false,
+            max_concurrent_files: None,
+            max_connections_per_host: None,
+            request_delay: None,
+            client: None,
+            http2_multiplex: false,
+            #[cfg(feature = "http3")]
+            http3: false,
+            #[cfg(feature = "decompression")]
+            decompress: false,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            io_uring: false,
+            #[cfg(feature = "mmap")]
+            memory_map: false,
+            network: Network::default(),
+            interface: None,
+            tls: TlsConfig::default(),
+            certificate_pins: Vec::new(),
+            connect_timeout: None,
+            read_timeout: None,
+            min_speed: None,
+            retry: RetryPolicy::default(),
+            max_speed: None,
+            max_speed_per_connection: None,
+            redirect_policy: None,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            cross_origin_redirects: CrossOriginRedirects::default(),
+            user_agent: None,
+            default_headers: HeaderMap::new(),
+            #[cfg(feature = "cookies")]
+            cookie_jar: None,
+            proxy: None,
+            proxy_auth: None,
+            system_proxy: true,
+            resume: false,
+            chunk_journal: false,
+            allocation: Allocation::default(),
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            verify_digests: false,
+            discover_checksums: false,
+            cancel_policy: CancelPolicy::default(),
+            durability: Durability::default(),
+            overwrite_policy: OverwritePolicy::default(),
+            manifest: false,
+            max_file_size: None,
+            allowed_content_types: None,
+            infer_extensions: false,
+            duplicate_policy: DuplicatePolicy::default(),
+            output_template: None,
+            host_directories: false,
+            create_output_dir: false,
+            staging_dir: None,
+            progress: None,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            batch_progress: None,
+            batch_report: None,
+            hooks: Vec::new(),
+            hook_concurrency: None,
+            #[cfg(feature = "extract")]
+            extract: ExtractConfig::default(),
+            #[cfg(feature = "sftp")]
+            sftp: SftpConfig::default(),
+            #[cfg(feature = "s3")]
+            s3: S3Config::default(),
+            #[cfg(feature = "gcs")]
+            gcs: GcsConfig::default(),
+            #[cfg(feature = "azure")]
+            azure: AzureConfig::default(),
+            #[cfg(feature = "ipfs")]
+            ipfs: IpfsConfig::default(),
+            #[cfg(feature = "lfs")]
+            hf: HfConfig::default(),
+            #[cfg(feature = "oci")]
+            oci: OciConfig::default(),
+        }
+    }
+
+    /// Sets the directory downloaded files are written to.
+    pub fn output_dir(mut self, output_dir: &str) -> Self {
+        self.output_dir = PathBuf::from(output_dir);
+        self
+    }
+
+    /// Creates the output directory when the [`Downloader`] is built, with the directories at the
+    /// start of the [`output_template`](Self::output_template) that are the same for every file,
+    /// and fails if files can't be created in it or the [staging directory](Self::staging_dir). Off by default, which creates directories when
+    /// the first file is saved in them.
+    pub fn create_output_dir(mut self, enabled: bool) -> Self {
+        self.create_output_dir = enabled;
+        self
+    }
+
+    /// Writes downloads to `dir` until they're complete, and moves them to the output directory
+    /// afterwards, so the output directory never holds partial files or their resume state.
+    ///
+    /// The staging directory may be on another filesystem, in which case complete files are
+    /// copied next to their output path under a hidden name and then renamed.
+    pub fn staging_dir(mut self, dir: &str) -> Self {
+        self.staging_dir = Some(PathBuf::from(dir));
+        self
+    }
+
+    /// Sets the number of parallel connections used per file.
+    pub fn conn_count(mut self, conn_count: usize) -> Self {
+        self.conn_count = conn_count;
+        self
+    }
+
+    /// Splits parallel downloads into chunks of about `size` bytes, instead of one chunk per
+    /// connection. The chunks are fetched over `conn_count` connections, which move on to the next
+    /// chunk once theirs is done.
+    pub fn chunk_size(mut self, size: u64) -> Self {
+        self.chunk_size = Some(size.max(1));
+        self
+    }
+
+    /// Bounds the number of chunks a file is split into with [`DownloaderBuilder::chunk_size`], so
+    /// small files still get `min` chunks and huge files no more than `max`. Defaults to 1 and 1024.
+    pub fn chunk_count_bounds(mut self, min: usize, max: usize) -> Self {
+        self.chunk_count_bounds = (min.max(1), max.max(min).max(1));
+        self
+    }
+
+    /// Downloads files smaller than `size` bytes sequentially, without probing them first.
+    ///
+    /// Every HTTP download then starts with a request for the whole file. Only files that turn out
+    /// to be at least `size` bytes large and support range requests are downloaded in parallel,
+    /// which saves a request and a round trip for each small file of a batch. Disabled by default.
+    pub fn parallel_threshold(mut self, size: u64) -> Self {
+        self.parallel_threshold = Some(size);
+        self
+    }
+
+    /// Lets connections that finished their chunk take over half of the largest remaining one,
+    /// so a single slow connection doesn't hold up the whole download. Enabled by default.
+    pub fn work_stealing(mut self, enabled: bool) -> Self {
+        self.work_stealing = enabled;
+        self
+    }
+
+    /// Starts parallel downloads with two connections and adds more, up to `conn_count`, as long as
+    /// that increases throughput. The count is halved when the server responds with `429` or `503`
+    /// or drops connections. Needs work stealing to grow past the initial chunks.
+    pub fn adaptive_connections(mut self, enabled: bool) -> Self {
+        self.adaptive_connections = enabled;
+        self
+    }
+
+    /// Sets how many files [`Downloader::download_multiple`] downloads at once. Defaults to `conn_count`.
+    pub fn max_concurrent_files(mut self, max_concurrent_files: usize) -> Self {
+        self.max_concurrent_files = Some(max_concurrent_files);
+        self
+    }
+
+    /// Limits the open connections to every host, across all downloads. Unlimited by default.
+    ///
+    /// Unlike `conn_count`, which applies to each file, this keeps batch downloads from one server polite.
+    pub fn max_connections_per_host(mut self, max_connections: usize) -> Self {
+        self.max_connections_per_host = Some(max_connections);
+        self
+    }
+
+    /// Waits at least `delay` between the starts of two requests to the same host.
+    pub fn request_delay(mut self, delay: Duration) -> Self {
+        self.request_delay = Some(delay);
+        self
+    }
+
+    /// Uses a preconfigured `reqwest::Client`.
+    ///
+    /// The client is used as is, so `ip_version`, the local address, DNS and TLS settings,
+    /// `connect_timeout`, `http2_multiplex`, `redirect_policy`, `user_agent`, default headers, the
+    /// cookie jar and the proxy settings are ignored.
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Multiplexes the range requests of parallel downloads over a single HTTP/2 connection instead
+    /// of opening `conn_count` connections, for servers and CDNs that limit the connections per
+    /// client. Disabled by default.
+    ///
+    /// HTTP/2 is negotiated during the TLS handshake, so `http://` URLs and servers that don't
+    /// support it still get one HTTP/1.1 connection per chunk.
+    pub fn http2_multiplex(mut self, enabled: bool) -> Self {
+        self.http2_multiplex = enabled;
+        self
+    }
+
+    /// Requests servers that advertise HTTP/3 with an `Alt-Svc` header over QUIC. The first request
+    /// to a server, and all of them once QUIC fails, are sent over TCP. Disabled by default.
+    ///
+    /// HTTP/3 doesn't go through proxies, so it isn't used when one is set.
+    #[cfg(feature = "http3")]
+    pub fn http3(mut self, enabled: bool) -> Self {
+        self.http3 = enabled;
+        self
+    }
+
+    /// Asks servers for gzip, Brotli, zstd or deflate compressed files and decompresses them while
+    /// they're written, so the output is the file itself. Disabled by default.
+    ///
+    /// Compressed files are downloaded over a single connection, since ranges of them can't be
+    /// combined. Checksums and sizes are those of the decompressed file.
+    #[cfg(feature = "decompression")]
+    pub fn decompress(mut self, enabled: bool) -> Self {
+        self.decompress = enabled;
+        self
+    }
+
+    /// Writes the chunks of parallel downloads through io_uring instead of tokio's blocking
+    /// thread pool. Disabled by default, and ignored if the kernel doesn't support io_uring.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn io_uring(mut self, enabled: bool) -> Self {
+        self.io_uring = enabled;
+        self
+    }
+
+    /// Copies the chunks of parallel downloads straight into a memory map of the output file,
+    /// instead of writing them with a system call each. Disabled by default, and takes precedence
+    /// over [`io_uring`](Self::io_uring).
+    ///
+    /// A process that shortens the file during the download crashes this one, so only enable this
+    /// for output directories nothing else writes to.
+    #[cfg(feature = "mmap")]
+    pub fn memory_map(mut self, enabled: bool) -> Self {
+        self.memory_map = enabled;
+        self
+    }
+
+    /// Restricts connections to IPv4 or IPv6, for hosts that are unreachable over the other
+    /// version. Defaults to [`IpVersion::Any`].
+    pub fn ip_version(mut self, version: IpVersion) -> Self {
+        self.network.ip_version = version;
+        self
+    }
+
+    /// Makes connections from the local address `ip`, so downloads go out over the network
+    /// interface it belongs to. Only hosts reachable over the IP version of `ip` can be downloaded
+    /// from.
+    pub fn local_address(mut self, ip: IpAddr) -> Self {
+        self.network.local_address = Some(ip);
+        self
+    }
+
+    /// Makes connections from an address of the network interface `name`, like `eth1`, which is
+    /// looked up when the downloader is built. Its IPv4 address is used, unless `ip_version` asks
+    /// for IPv6. Only supported on Linux.
+    pub fn interface(mut self, name: &str) -> Self {
+        self.interface = Some(name.to_owned());
+        self
+    }
+
+    /// Connects to `ip` instead of the addresses `host` resolves to, like curl's `--resolve`, to
+    /// pin a CDN edge or reach a staging server under the production name. Calling this again for
+    /// the same host adds another address.
+    ///
+    /// The override applies to every port of the host.
+    pub fn resolve(mut self, host: &str, ip: IpAddr) -> Self {
+        self.network
+            .overrides
+            .entry(host.to_ascii_lowercase())
+            .or_default()
+            .push(ip);
+        self
+    }
+
+    /// Asks for socket receive buffers of `size` bytes instead of letting the system size them,
+    /// for fast links with a long round trip time.
+    ///
+    /// Applies to FTP and SFTP connections, and sizes the HTTP/2 flow control windows. reqwest
+    /// doesn't expose the sockets of HTTP connections, which keep the system's buffers.
+    pub fn tcp_receive_buffer_size(mut self, size: u32) -> Self {
+        self.network.receive_buffer_size = Some(size);
+        self
+    }
+
+    /// Resolves host names with `resolver` instead of the system resolver. Hosts passed to
+    /// [`DownloaderBuilder::resolve`] aren't looked up.
+    pub fn dns_resolver(mut self, resolver: impl Resolver + 'static) -> Self {
+        self.network.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Trusts the PEM encoded CA certificates in `pem` in addition to the system's, for servers
+    /// with certificates from a private CA. Invalid certificates fail [`DownloaderBuilder::build`].
+    pub fn add_root_certificate(mut self, pem: &[u8]) -> Self {
+        self.tls.root_certificates.push(pem.to_vec());
+        self
+    }
+
+    /// Presents the PEM encoded certificate chain `cert` with its PKCS #8 private key `key` to
+    /// servers that ask for a client certificate (mutual TLS).
+    pub fn client_certificate(mut self, cert: &[u8], key: &[u8]) -> Self {
+        self.tls.identity = Some((cert.to_vec(), key.to_vec()));
+        self
+    }
+
+    /// Accepts any server certificate, including expired and self-signed ones and those issued for
+    /// another host. Disabled by default.
+    ///
+    /// This makes connections open to man-in-the-middle attacks, so it's best kept to testing.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.tls.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Only accepts responses from servers whose certificate has the public key `pin`, given as the
+    /// base64 encoded SHA-256 of its `SubjectPublicKeyInfo` like curl's `--pinnedpubkey`
+    /// (`sha256//` followed by the hash), for downloads that must come from a known server. Calling
+    /// this again pins another key, for mirrors and key rotation.
+    ///
+    /// Connections to other servers fail the download with
+    /// [`DownloadError::CertificateNotPinned`] during the TLS handshake, before a request is sent
+    /// to them. They're made with rustls then, whatever TLS backend is used otherwise. Plain HTTP
+    /// responses fail the same way, but only once the request was sent. Pins only apply to HTTP
+    /// downloads, and HTTP/3 isn't used while there are any. A client passed to
+    /// [`DownloaderBuilder::client`] is kept as it is, so only its responses are checked, and it
+    /// needs `tls_info(true)`.
+    pub fn pin_certificate(mut self, pin: &str) -> Self {
+        self.certificate_pins.push(pin.to_owned());
+        self
+    }
+
+    /// Sets the timeout for establishing a connection.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum time to wait for the next piece of the response body.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Aborts a connection that transfers less than `bytes_per_sec` bytes per second for `time`,
+    /// like curl's `--speed-limit` and `--speed-time`. Chunks of parallel downloads are retried,
+    /// on another mirror if there is one.
+    pub fn min_speed(mut self, bytes_per_sec: u64, time: Duration) -> Self {
+        self.min_speed = Some(MinSpeed {
+            bytes_per_sec,
+            time,
+        });
+        self
+    }
+
+    /// Sets how failed chunk requests are retried. Defaults to [`RetryPolicy::default`].
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Limits the combined speed of all downloads started by the [`Downloader`].
+    pub fn max_speed_bytes_per_sec(mut self, bytes_per_sec: u64) -> Self {
+        self.max_speed = Some(bytes_per_sec);
+        self
+    }
+
+    /// Limits the speed of every single connection.
+    pub fn max_speed_per_connection(mut self, bytes_per_sec: u64) -> Self {
+        self.max_speed_per_connection = Some(bytes_per_sec);
+        self
+    }
+
+    /// Only requests the URLs `policy` allows, for example to keep URLs provided by users from
+    /// reaching private addresses. Downloads that aren't allowed fail with
+    /// [`DownloadError::Blocked`], also if a redirect leads to a URL that isn't allowed.
+    ///
+    /// The policy isn't applied to a client passed with [`client`](Self::client).
+    pub fn url_policy(mut self, policy: UrlPolicy) -> Self {
+        self.network.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Sets the redirect policy, instead of [`max_redirects`](Self::max_redirects) and
+    /// [`cross_origin_redirects`](Self::cross_origin_redirects).
+    pub fn redirect_policy(mut self, policy: reqwest::redirect::Policy) -> Self {
+        self.redirect_policy = Some(policy);
+        self
+    }
+
+    /// Follows at most `max` redirects per request, 10 by default. More fail the download with
+    /// [`DownloadError::TooManyRedirects`].
+    pub fn max_redirects(mut self, max: usize) -> Self {
+        self.max_redirects = max;
+        self
+    }
+
+    /// Decides what happens when a redirect leads to another origin. By default it's followed
+    /// without credentials and cookies.
+    pub fn cross_origin_redirects(mut self, policy: CrossOriginRedirects) -> Self {
+        self.cross_origin_redirects = policy;
+        self
+    }
+
+    /// Sets the `User-Agent` header sent with every request.
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.to_owned());
+        self
+    }
+
+    /// Sends `name: value` with the requests of every download. Like the
+    /// [headers](crate::DownloadOptions::header) of a single download, which are sent as well, it
+    /// only goes to the origin of the download URL and the hosts listed for its credentials.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.default_headers.append(name, value);
+        self
+    }
+
+    /// Stores cookies set by servers in `jar` and sends them with later requests.
+    #[cfg(feature = "cookies")]
+    pub fn cookie_jar(mut self, jar: Arc<reqwest::cookie::Jar>) -> Self {
+        self.cookie_jar = Some(jar);
+        self
+    }
+
+    /// Sends all requests through the proxy at `url`, like `http://proxy:3128`. `socks5://` proxies
+    /// need the `socks` feature.
+    ///
+    /// Hosts listed in the `NO_PROXY` environment variable are still connected to directly.
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.proxy = Some(url.to_owned());
+        self
+    }
+
+    /// Authenticates at the proxy with basic auth.
+    pub fn proxy_auth(mut self, username: &str, password: &str) -> Self {
+        self.proxy_auth = Some((username.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// Uses the proxies configured in the `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY` environment
+    /// variables unless a proxy is set explicitly. Enabled by default.
+    pub fn system_proxy(mut self, enabled: bool) -> Self {
+        self.system_proxy = enabled;
+        self
+    }
+
+    /// Keeps track of finished byte ranges in a `.simult` sidecar file so interrupted downloads can be resumed.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Keeps a journal next to the resume sidecar of which bytes of every chunk are on disk, so a
+    /// download resumed after a crash or power loss never trusts bytes that were still in the
+    /// operating system's cache. Every time the resume state is saved, the partial file is synced
+    /// and the journal replaced atomically. Only has an effect with [`resume`](Self::resume).
+    pub fn chunk_journal(mut self, enabled: bool) -> Self {
+        self.chunk_journal = enabled;
+        self
+    }
+
+    /// Reserves disk space for the whole file before a parallel download starts, so a full disk is
+    /// detected right away. Only has an effect on Linux; elsewhere files are just resized.
+    ///
+    /// Shorthand for [`allocation`](Self::allocation) with [`Allocation::Reserve`].
+    pub fn allocate_disk_space(mut self, allocate: bool) -> Self {
+        self.allocation = match allocate {
+            true => Allocation::Reserve,
+            false => Allocation::Resize,
+        };
+        self
+    }
+
+    /// Decides how the output files of parallel downloads are allocated. Defaults to
+    /// [`Allocation::Resize`].
+    pub fn allocation(mut self, allocation: Allocation) -> Self {
+        self.allocation = allocation;
+        self
+    }
+
+    /// How many bytes are read at once from FTP and SFTP servers and `file://` and `data:` URLs.
+    /// Defaults to 64 KiB. HTTP responses arrive in the pieces the connection delivers them in.
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = size.max(1);
+        self
+    }
+
+    /// How many bytes each connection of a parallel download collects before writing them to the
+    /// file, so small reads from the network don't each become a write. Defaults to 256 KiB; `0`
+    /// writes every read right away.
+    ///
+    /// Bytes only count as written, for progress and resuming, once they have reached the file.
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = size;
+        self
+    }
+
+    /// Asks servers for digests of what they send, with `Want-Content-Digest`, `Want-Repr-Digest`
+    /// and `Want-Digest`, and checks the ones they send back.
+    ///
+    /// Each range of a parallel download is checked against its `Content-Digest` and fetched again
+    /// if it doesn't match. A `Repr-Digest` or `Digest` of the whole file is checked like a
+    /// [`checksum`](crate::DownloadOptions::checksum), unless the download has one already. Only SHA-256
+    /// and MD5 digests are understood.
+    pub fn verify_digests(mut self, verify: bool) -> Self {
+        self.verify_digests = verify;
+        self
+    }
+
+    /// Looks for a checksum next to each file downloaded over HTTP that doesn't have one, in
+    /// `<url>.sha256`, `SHA256SUMS`, `<url>.md5` and `MD5SUMS`, and verifies the file against the
+    /// first one found. [`DownloadReport::checksum_source`](crate::DownloadReport::checksum_source)
+    /// tells which it was.
+    pub fn discover_checksums(mut self, discover: bool) -> Self {
+        self.discover_checksums = discover;
+        self
+    }
+
+    /// Decides whether partial files are kept or removed when a download is cancelled.
+    pub fn cancel_policy(mut self, policy: CancelPolicy) -> Self {
+        self.cancel_policy = policy;
+        self
+    }
+
+    /// Decides when downloaded data is forced to disk, for example for network file systems or
+    /// mirrors that have to survive a crash. Nothing is by default.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Decides what happens when a file that is downloaded already exists. Defaults to
+    /// [`OverwritePolicy::Rename`].
+    pub fn overwrite_policy(mut self, policy: OverwritePolicy) -> Self {
+        self.overwrite_policy = policy;
+        self
+    }
+
+    /// Keeps the `ETag`, `Last-Modified` date and size of every downloaded file in a manifest in
+    /// the output directory, `.simult-manifest.json`, so repeated runs over the same URLs with
+    /// [`OverwritePolicy::SkipIfUnchanged`] only fetch what changed. Off by default.
+    ///
+    /// With a manifest, files are no longer asked about with an `.etag` file next to them and
+    /// their modification time, and files that aren't the size they were downloaded with are
+    /// downloaded again. [`Downloader::dry_run`] tells what a run would fetch.
+    pub fn manifest(mut self, manifest: bool) -> Self {
+        self.manifest = manifest;
+        self
+    }
+
+    /// Fails downloads of files larger than `limit` bytes with [`DownloadError::FileTooLarge`],
+    /// for example to protect a service that downloads URLs its users supply. No limit by
+    /// default.
+    ///
+    /// Downloads whose size is known don't start. Files without a `Content-Length` are aborted
+    /// once more than `limit` bytes were written, counting the decompressed bytes of compressed
+    /// responses and the segments of playlists. Partial files are removed. The limit also caps
+    /// the bytes written when extracting an archive or decompressing a file.
+    pub fn max_file_size(mut self, limit: u64) -> Self {
+        self.max_file_size = Some(limit);
+        self
+    }
+
+    /// Only downloads files whose `Content-Type` matches one of `types`, like `application/pdf`
+    /// or `image/*` for every image type, and fails the others with
+    /// [`DownloadError::UnexpectedContentType`] before they start. Files without a `Content-Type`
+    /// fail as well. All types are allowed by default.
+    ///
+    /// Only HTTP downloads are checked, other protocols don't tell the type of a file.
+    pub fn allowed_content_types<I, S>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allowed_content_types = Some(types.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Gives files whose name from the URL has no extension the one of their `Content-Type`,
+    /// like `report.pdf` for `https://example.com/report` served as `application/pdf`. The
+    /// extension is part of the name the overwrite policy looks for. Off by default.
+    pub fn infer_extensions(mut self, infer: bool) -> Self {
+        self.infer_extensions = infer;
+        self
+    }
+
+    /// Decides what URLs that are listed more than once in a batch get instead of another
+    /// download. Defaults to [`DuplicatePolicy::SamePath`].
+    pub fn duplicate_policy(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicate_policy = policy;
+        self
+    }
+
+    /// Organizes downloaded files with a path template like `{host}/{date}/{filename}`, relative to
+    /// the output directory. Missing directories are created.
+    ///
+    /// Available tokens are `{filename}`, `{stem}`, `{ext}` (preferring the one implied by the
+    /// `Content-Type`), `{host}`, `{path}` (the directories of the URL path), `{date}` (`YYYY-MM-DD`, UTC)
+    /// and `{hash}` (8 hex digits of the SHA-256 of the URL).
+    pub fn output_template(mut self, template: &str) -> Self {
+        self.output_template = Some(template.to_owned());
+        self
+    }
+
+    /// Saves files in a directory named after the host of their URL, with the directories of the
+    /// URL path below it, like `example.com/releases/v1/app.tar.gz` for
+    /// `https://example.com/releases/v1/app.tar.gz`. Missing directories are created. Off by
+    /// default, and ignored if there's an [`output_template`](Self::output_template).
+    ///
+    /// Recursive downloads keep the tree of the server below the host directory, instead of the
+    /// tree below the directory they start at.
+    pub fn host_directories(mut self, enabled: bool) -> Self {
+        self.host_directories = enabled;
+        self
+    }
+
+    /// Registers a reporter that receives progress updates while downloads are running.
+    pub fn progress(mut self, reporter: impl ProgressReporter + 'static) -> Self {
+        self.progress = Some(Arc::new(reporter));
+        self
+    }
+
+    /// Registers a reporter that receives the combined progress of all files while
+    /// [`Downloader::download_multiple`], a Metalink or a URL list download is running, in
+    /// addition to the progress of every file.
+    pub fn batch_progress(mut self, reporter: impl BatchProgressReporter + 'static) -> Self {
+        self.batch_progress = Some(Arc::new(reporter));
+        self
+    }
+
+    /// Writes a [`BatchReport`](crate::BatchReport) of every file to `path` once
+    /// [`Downloader::download_multiple`], a Metalink or a URL list download has finished, for
+    /// example for audit logs. The report is CSV if `path` ends with `.csv` and JSON otherwise,
+    /// and replaces the one of the previous batch.
+    ///
+    /// A report that can't be written is logged, it doesn't fail the batch.
+    pub fn batch_report(mut self, path: &str) -> Self {
+        self.batch_report = Some(PathBuf::from(path));
+        self
+    }
+
+    /// Draws the progress of every download, and of batches, with `bars`.
+    #[cfg(feature = "indicatif")]
+    pub fn progress_bars(self, bars: &crate::ProgressBars) -> Self {
+        self.progress(bars.clone()).batch_progress(bars.clone())
+    }
+
+    /// Runs `hook` once each download has finished, successfully or not, before the download
+    /// returns. Hooks run in the order they were added.
+    pub fn hook(mut self, hook: impl DownloadHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// POSTs a JSON description of every finished download to `endpoint`, see [`Webhook`].
+    pub fn webhook(self, endpoint: &str) -> Self {
+        self.hook(Webhook::new(endpoint))
+    }
+
+    /// Runs the hooks of at most `limit` downloads at once. Other downloads wait for their turn
+    /// before returning. Unlimited by default.
+    pub fn hook_concurrency(mut self, limit: usize) -> Self {
+        self.hook_concurrency = Some(limit);
+        self
+    }
+
+    /// Extracts downloaded `.zip`, `.tar`, `.tar.gz` and `.tar.zst` archives into `dir`, which is
+    /// created if it doesn't exist. Other files are left alone.
+    ///
+    /// Entries that would end up outside of `dir` are skipped, and so are links. Archives are
+    /// extracted before hooks run.
+    #[cfg(feature = "extract")]
+    pub fn extract_archives(mut self, dir: &str) -> Self {
+        self.extract.dir = Some(PathBuf::from(dir));
+        self
+    }
+
+    /// Removes archives once they have been extracted with
+    /// [`extract_archives`](Self::extract_archives).
+    #[cfg(feature = "extract")]
+    pub fn remove_extracted_archives(mut self, remove: bool) -> Self {
+        self.extract.remove_archive = remove;
+        self
+    }
+
+    /// Decompresses downloaded `.gz`, `.zst` and `.xz` files that aren't archives next to them,
+    /// under the name without the extension: `data.json.gz` becomes `data.json`. An existing file
+    /// of that name is handled by the [overwrite policy](Self::overwrite_policy); if it's kept,
+    /// so is the compressed file. The report describes the decompressed file.
+    #[cfg(feature = "extract")]
+    pub fn decompress_files(mut self, decompress: bool) -> Self {
+        self.extract.decompress = decompress;
+        self
+    }
+
+    /// Keeps compressed files once they have been decompressed with
+    /// [`decompress_files`](Self::decompress_files), instead of removing them.
+    #[cfg(feature = "extract")]
+    pub fn keep_compressed_files(mut self, keep: bool) -> Self {
+        self.extract.keep_compressed = keep;
+        self
+    }
+
+    /// Sets how often progress is reported. Defaults to 200ms.
+    pub fn progress_interval(mut self, interval: Duration) -> Self {
+        self.progress_interval = interval;
+        self
+    }
+
+    /// Logs in to SFTP servers with a password. A username or password in the URL takes precedence.
+    ///
+    /// Without a configured login, the keys `id_ed25519`, `id_ecdsa` and `id_rsa` in `~/.ssh` are tried.
+    #[cfg(feature = "sftp")]
+    pub fn sftp_password(mut self, username: &str, password: &str) -> Self {
+        self.sftp.auth = Some(SftpAuth::Password {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        });
+        self
+    }
+
+    /// Logs in to SFTP servers with the private key in the OpenSSH file at `path`, decrypted with
+    /// `passphrase` if it is encrypted. A username or password in the URL takes precedence.
+    #[cfg(feature = "sftp")]
+    pub fn sftp_private_key(
+        mut self,
+        username: &str,
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> Self {
+        self.sftp.auth = Some(SftpAuth::PrivateKey {
+            username: username.to_owned(),
+            path: path.as_ref().to_owned(),
+            passphrase: passphrase.map(str::to_owned),
+        });
+        self
+    }
+
+    /// Reads the host keys SFTP servers are checked against from `path` instead of
+    /// `~/.ssh/known_hosts`. Servers whose key isn't listed are not connected to.
+    #[cfg(feature = "sftp")]
+    pub fn sftp_known_hosts(mut self, path: impl AsRef<Path>) -> Self {
+        self.sftp.known_hosts = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Signs requests for `s3://bucket/key` URLs with these credentials instead of the ones in the
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` environment variables.
+    /// Without any credentials, only public objects can be downloaded.
+    #[cfg(feature = "s3")]
+    pub fn s3_credentials(
+        mut self,
+        access_key_id: &str,
+        secret_access_key: &str,
+        session_token: Option<&str>,
+    ) -> Self {
+        self.s3.credentials = Some(AwsCredentials {
+            access_key_id: access_key_id.to_owned(),
+            secret_access_key: secret_access_key.to_owned(),
+            session_token: session_token.map(str::to_owned),
+        });
+        self
+    }
+
+    /// Sets the region of S3 buckets. Defaults to `AWS_REGION`, `AWS_DEFAULT_REGION` or `us-east-1`.
+    #[cfg(feature = "s3")]
+    pub fn s3_region(mut self, region: &str) -> Self {
+        self.s3.region = Some(region.to_owned());
+        self
+    }
+
+    /// Downloads `s3://` URLs from an S3 compatible service at `url`, like `http://localhost:9000`,
+    /// instead of AWS. Objects are requested as `{url}/{bucket}/{key}`. Defaults to `AWS_ENDPOINT_URL`.
+    #[cfg(feature = "s3")]
+    pub fn s3_endpoint(mut self, url: &str) -> Self {
+        self.s3.endpoint = Some(url.to_owned());
+        self
+    }
+
+    /// Signs requests for `gs://bucket/object` URLs with the HMAC key `access_id` and `secret`.
+    /// Without credentials, only public objects can be downloaded.
+    #[cfg(feature = "gcs")]
+    pub fn gcs_hmac_key(mut self, access_id: &str, secret: &str) -> Self {
+        self.gcs.auth = Some(GcsAuth::Hmac(AwsCredentials {
+            access_key_id: access_id.to_owned(),
+            secret_access_key: secret.to_owned(),
+            session_token: None,
+        }));
+        self
+    }
+
+    /// Authorizes requests for `gs://bucket/object` URLs with an OAuth 2.0 access token, like the
+    /// one printed by `gcloud auth print-access-token`.
+    #[cfg(feature = "gcs")]
+    pub fn gcs_access_token(mut self, token: &str) -> Self {
+        self.gcs.auth = Some(GcsAuth::Token(token.to_owned()));
+        self
+    }
+
+    /// Downloads `gs://` URLs from an emulator at `url`, like `http://localhost:4443`, instead of
+    /// Cloud Storage. Defaults to `STORAGE_EMULATOR_HOST`.
+    #[cfg(feature = "gcs")]
+    pub fn gcs_endpoint(mut self, url: &str) -> Self {
+        self.gcs.endpoint = Some(url.to_owned());
+        self
+    }
+
+    /// Signs requests for blobs in the storage account `account` with its base64 encoded access
+    /// key, instead of the one in `AZURE_STORAGE_ACCOUNT` and `AZURE_STORAGE_KEY`.
+    #[cfg(feature = "azure")]
+    pub fn azure_shared_key(mut self, account: &str, key: &str) -> Self {
+        self.azure.credentials = Some(AzureCredentials {
+            account: account.to_owned(),
+            auth: AzureAuth::SharedKey(key.to_owned()),
+        });
+        self
+    }
+
+    /// Authorizes requests for blobs in the storage account `account` with a shared access
+    /// signature, instead of the one in `AZURE_STORAGE_SAS_TOKEN`.
+    #[cfg(feature = "azure")]
+    pub fn azure_sas_token(mut self, account: &str, token: &str) -> Self {
+        self.azure.credentials = Some(AzureCredentials {
+            account: account.to_owned(),
+            auth: AzureAuth::Sas(token.to_owned()),
+        });
+        self
+    }
+
+    /// Downloads `az://account/container/blob` URLs from an emulator at `url`, like
+    /// `http://127.0.0.1:10000/devstoreaccount1`, as `{url}/{container}/{blob}`.
+    #[cfg(feature = "azure")]
+    pub fn azure_endpoint(mut self, url: &str) -> Self {
+        self.azure.endpoint = Some(url.to_owned());
+        self
+    }
+
+    /// Downloads `ipfs://` URLs through `gateways`, like `https://ipfs.io`, instead of a few
+    /// public ones. They must support trustless block requests (`/ipfs/{cid}?format=raw`), and are
+    /// tried in order after failures.
+    #[cfg(feature = "ipfs")]
+    pub fn ipfs_gateways<I, S>(mut self, gateways: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.ipfs.gateways = gateways.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Authorizes requests for `hf://` URLs with a Hugging Face access token, instead of the one
+    /// in `HF_TOKEN`. Without a token, only public repositories can be downloaded.
+    #[cfg(feature = "lfs")]
+    pub fn hf_token(mut self, token: &str) -> Self {
+        self.hf.token = Some(token.to_owned());
+        self
+    }
+
+    /// Downloads `hf://` URLs from a Hub at `url`, like a mirror, instead of
+    /// `https://huggingface.co`. Defaults to `HF_ENDPOINT`.
+    #[cfg(feature = "lfs")]
+    pub fn hf_endpoint(mut self, url: &str) -> Self {
+        self.hf.endpoint = Some(url.to_owned());
+        self
+    }
+
+    /// Speaks plain HTTP to the OCI registry at `registry`, like `localhost:5000`, instead of
+    /// HTTPS. Can be called for several registries.
+    #[cfg(feature = "oci")]
+    pub fn oci_insecure_registry(mut self, registry: &str) -> Self {
+        self.oci.insecure_registries.push(registry.to_owned());
+        self
+    }
+
+    /// Creates the output directory with the fixed directories of the output template, and the
+    /// staging directory, and checks that files can be created in them.
+    fn prepare_output_dir(&self) -> Result<(), DownloadError> {
+        let mut dir = self.output_dir.clone();
+        if let Some(template) = &self.output_template {
+            dir.extend(template::fixed_directories(template));
+        }
+        for dir in std::iter::once(dir).chain(self.staging_dir.clone()) {
+            let failed = |source| DownloadError::OutputDir {
+                path: dir.clone(),
+                source,
+            };
+            std::fs::create_dir_all(&dir).map_err(failed)?;
+            let probe = dir.join(format!(".simult-{}.tmp", std::process::id()));
+            std::fs::File::create(&probe).map_err(failed)?;
+            std::fs::remove_file(&probe).map_err(failed)?;
+        }
+        Ok(())
+    }
+
+    /// Builds the [`Downloader`].
+    pub fn build(mut self) -> Result<Downloader, DownloadError> {
+        metrics::describe();
+        if self.create_output_dir {
+            self.prepare_output_dir()?;
+        }
+        if let Some(name) = &self.interface {
+            let ip = net::interface_address(name, self.network.ip_version)
+                .ok_or_else(|| DownloadError::UnknownInterface(name.clone()))?;
+            self.network.local_address = Some(ip);
+        }
+        if self.redirect_policy.is_none() {
+            let policy = redirect::policy(self.max_redirects, self.cross_origin_redirects);
+            self.redirect_policy = Some(policy);
+        }
+        if let Some(url_policy) = &self.network.policy {
+            let policy = self
+                .redirect_policy
+                .take()
+                .map(|p| url_policy.clone().redirects(p));
+            self.redirect_policy = policy;
+        }
+
+        #[cfg(feature = "http3")]
+        let http3 = match (self.http3, &self.client, &self.proxy) {
+            // HTTP/3 responses don't tell which certificate the server presented.
+            (true, None, None) if self.certificate_pins.is_empty() => {
+                // Both clients follow the same redirect policy, which can't be cloned.
+                let policy = self.redirect_policy.take().map(Arc::new);
+                let shared = |policy: Option<Arc<Policy>>| {
+                    policy.map(|policy| Policy::custom(move |attempt| policy.redirect(attempt)))
+                };
+                self.redirect_policy = shared(policy.clone());
+                let client = self
+                    .client_builder(shared(policy))?
+                    .use_rustls_tls()
+                    .http3_prior_knowledge()
+                    .build()?;
+                Some(Arc::new(Http3::new(client)))
+            }
+            _ => None,
+        };
+
+        let (client, headers) = match self.client.take() {
+            Some(client) => (client, HeaderMap::new()),
+            None => {
+                let policy = self.redirect_policy.take();
+                let client = self.client_builder(policy)?.build()?;
+                (client, std::mem::take(&mut self.default_headers))
+            }
+        };
+
+        let pins = match self.certificate_pins.is_empty() {
+            true => None,
+            false => Some(Arc::new(CertificatePins::parse(&self.certificate_pins)?)),
+        };
+
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let uring = match self.io_uring {
+            true => match crate::uring::Uring::new() {
+                Ok(ring) => Some(Arc::new(ring)),
+                Err(_e) => {
+                    warn!(error = %_e, "io_uring is unavailable, writing files as usual");
+                    None
+                }
+            },
+            false => None,
+        };
+
+        let conn_count = if self.conn_count > 0 {
+            self.conn_count
+        } else {
+            1
+        };
+
+        let manifest = self.manifest.then(|| Manifest::new(&self.output_dir));
+        let url_policy = self.network.policy.clone();
+        Ok(Downloader {
+            client,
+            headers,
+            output_dir: self.output_dir,
+            conn_count,
+            chunk_size: self.chunk_size,
+            chunk_count_bounds: self.chunk_count_bounds,
+            parallel_threshold: self.parallel_threshold,
+            work_stealing: self.work_stealing,
+            adaptive_connections: self.adaptive_connections,
+            max_concurrent_files: self.max_concurrent_files.unwrap_or(conn_count).max(1),
+            #[cfg(any(feature = "ftp", feature = "sftp"))]
+            network: self.network,
+            #[cfg(feature = "ftp")]
+            tls: self.tls,
+            read_timeout: self.read_timeout,
+            min_speed: self.min_speed,
+            retry: self.retry,
+            throttle: Throttle::new(
+                self.max_speed.map(|rate| Arc::new(RateLimiter::new(rate))),
+                self.max_speed_per_connection,
+            ),
+            hosts: Arc::new(HostLimiter::new(
+                self.max_connections_per_host,
+                self.request_delay,
+            )),
+            resume: self.resume,
+            chunk_journal: self.chunk_journal,
+            allocation: self.allocation,
+            read_buffer_size: self.read_buffer_size,
+            write_buffer_size: self.write_buffer_size,
+            verify_digests: self.verify_digests,
+            discover_checksums: self.discover_checksums,
+            cancel_policy: self.cancel_policy,
+            durability: self.durability,
+            overwrite_policy: self.overwrite_policy,
+            manifest,
+            max_file_size: self.max_file_size,
+            allowed_content_types: self.allowed_content_types,
+            infer_extensions: self.infer_extensions,
+            url_policy,
+            reservations: Reservations::default(),
+            duplicate_policy: self.duplicate_policy,
+            output_template: self.output_template,
+            host_directories: self.host_directories,
+            staging: self.staging_dir.map(|dir| Staging { dir }),
+            progress: self.progress,
+            progress_interval: self.progress_interval,
+            batch_progress: self.batch_progress,
+            batch_report: self.batch_report,
+            hooks: Hooks::new(self.hooks, self.hook_concurrency),
+            #[cfg(feature = "extract")]
+            extract: self.extract,
+            #[cfg(feature = "sftp")]
+            sftp: self.sftp,
+            #[cfg(feature = "s3")]
+            s3: self.s3,
+            #[cfg(feature = "gcs")]
+            gcs: self.gcs,
+            #[cfg(feature = "azure")]
+            azure: self.azure,
+            #[cfg(feature = "ipfs")]
+            ipfs: self.ipfs,
+            #[cfg(feature = "lfs")]
+            hf: self.hf,
+            #[cfg(feature = "oci")]
+            oci: self.oci,
+            #[cfg(feature = "http3")]
+            http3,
+            pins,
+            #[cfg(feature = "decompression")]
+            decompress: self.decompress,
+            shutdown: Shutdown::new(),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            uring,
+            #[cfg(feature = "mmap")]
+            memory_map: self.memory_map,
+        })
+    }
+
+    /// Configures an HTTP client with the connection, redirect and proxy settings.
+    fn client_builder(
+        &self,
+        redirect_policy: Option<Policy>,
+    ) -> Result<reqwest::ClientBuilder, DownloadError> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        builder = builder.local_address(self.network.local_address());
+        if let Some(size) = self.network.receive_buffer_size {
+            builder = builder
+                .http2_initial_stream_window_size(size)
+                .http2_initial_connection_window_size(size);
+        }
+        if self.network.is_custom() {
+            builder = builder.dns_resolver(Arc::new(self.network.clone()));
+        }
+        if let Some(policy) = redirect_policy {
+            builder = builder.redirect(policy);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        // The other default headers are only sent where the download's own headers are.
+        let mut headers = HeaderMap::new();
+        if self.verify_digests {
+            digest::want_digests(&mut headers);
+        }
+        builder = builder.default_headers(headers);
+        if !self.http2_multiplex {
+            builder = builder.http1_only();
+        }
+        // With the `http3` feature, reqwest only negotiates HTTP/2 over rustls.
+        #[cfg(feature = "http3")]
+        let rustls = self.http2_multiplex || self.http3;
+        #[cfg(not(feature = "http3"))]
+        let rustls = false;
+        #[cfg(feature = "http3")]
+        if rustls {
+            builder = builder.use_rustls_tls();
+        }
+        builder = self.tls.apply(builder, rustls)?;
+        if !self.certificate_pins.is_empty() {
+            let pins = Arc::new(CertificatePins::parse(&self.certificate_pins)?);
+            builder = builder
+                .use_preconfigured_tls(self.tls.pinned(pins, self.http2_multiplex)?)
+                .tls_info(true);
+        }
+        #[cfg(feature = "cookies")]
+        if let Some(jar) = &self.cookie_jar {
+            builder = builder.cookie_provider(jar.clone());
+        }
+        if let Some(url) = &self.proxy {
+            let mut proxy = reqwest::Proxy::all(url)?.no_proxy(reqwest::NoProxy::from_env());
+            if let Some((username, password)) = &self.proxy_auth {
+                proxy = proxy.basic_auth(username, password);
+            }
+            builder = builder.proxy(proxy);
+        } else if !self.system_proxy {
+            builder = builder.no_proxy();
+        }
+        Ok(builder)
+    }
+}
@@ -0,0 +1,293 @@
+use crate::{
+    error::{DownloadError, IoResultExt},
+    storage::SharedFile,
+};
+use blake3::Hasher as Blake3;
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tokio::{fs, io::AsyncReadExt, task::JoinHandle};
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How many bytes [`OrderedHasher`] reads back from the file at once.
+const READ_BACK_SIZE: usize = 1024 * 1024;
+
+/// Hash algorithms supported for verifying downloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Md5,
+    Blake3,
+}
+
+/// The digest a downloaded file is expected to have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    /// Hex encoded digest.
+    pub expected: String,
+}
+
+/// Where the checksum a download was verified against came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChecksumSource {
+    /// Set with [`DownloadOptions::checksum`](crate::DownloadOptions::checksum), listed in a
+    /// Metalink document or named by a git LFS pointer.
+    Options,
+    /// Sent by the server in a `Repr-Digest`, `Digest` or `Content-Digest` header.
+    DigestHeader,
+    /// Read from the sidecar file at this URL, like `<url>.sha256` or `SHA256SUMS`.
+    Sidecar(String),
+}
+
+impl ChecksumAlgorithm {
+    /// Returns the lowercase name of the algorithm, like `sha256`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Md5 => "md5",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    /// Parses names like `sha256`, `SHA-256` or `md5`.
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Some(Self::Sha256),
+            "md5" => Some(Self::Md5),
+            "blake3" => Some(Self::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Checks whether `digest` is a hex encoded digest of this algorithm.
+    pub(crate) fn is_hex_digest(self, digest: &str) -> bool {
+        let len = match self {
+            Self::Sha256 | Self::Blake3 => 64,
+            Self::Md5 => 32,
+        };
+        digest.len() == len && digest.bytes().all(|b| b.is_ascii_hexdigit())
+    }
+}
+
+impl Checksum {
+    pub fn new(algorithm: ChecksumAlgorithm, expected: &str) -> Self {
+        Self {
+            algorithm,
+            expected: expected.trim().to_ascii_lowercase(),
+        }
+    }
+
+    pub fn sha256(expected: &str) -> Self {
+        Self::new(ChecksumAlgorithm::Sha256, expected)
+    }
+
+    pub fn md5(expected: &str) -> Self {
+        Self::new(ChecksumAlgorithm::Md5, expected)
+    }
+
+    pub fn blake3(expected: &str) -> Self {
+        Self::new(ChecksumAlgorithm::Blake3, expected)
+    }
+
+    /// Compares the expected digest with the one computed by `hasher`.
+    pub(crate) fn verify(&self, hasher: Hasher) -> Result<(), DownloadError> {
+        let actual = hasher.finalize();
+        if actual == self.expected {
+            Ok(())
+        } else {
+            Err(DownloadError::ChecksumMismatch {
+                expected: self.expected.clone(),
+                actual,
+            })
+        }
+    }
+
+    /// Hashes the file at `path` and compares it with the expected digest.
+    pub(crate) async fn verify_file(&self, path: &Path) -> Result<(), DownloadError> {
+        let mut hasher = Hasher::new(self.algorithm);
+        let mut file = fs::File::open(path).await.with_path(path)?;
+        let mut buf = vec![0; READ_BUFFER_SIZE];
+
+        loop {
+            let n = file.read(&mut buf).await.with_path(path)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+
+        self.verify(hasher)
+    }
+}
+
+/// Incrementally computes the digest of a download.
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Md5(Md5),
+    Blake3(Box<Blake3>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Md5 => Self::Md5(Md5::new()),
+            ChecksumAlgorithm::Blake3 => Self::Blake3(Box::new(Blake3::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(h) => h.update(data),
+            Self::Md5(h) => h.update(data),
+            Self::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    /// Returns the hex encoded digest.
+    pub fn finalize(self) -> String {
+        match self {
+            Self::Sha256(h) => to_hex(&h.finalize()),
+            Self::Md5(h) => to_hex(&h.finalize()),
+            Self::Blake3(h) => to_hex(h.finalize().as_bytes()),
+        }
+    }
+}
+
+/// Computes the digest of a file whose ranges are written in any order, so it doesn't have to be
+/// read again once the download is done.
+///
+/// Bytes written right where the hashed part of the file ends are hashed as they are written. The
+/// rest is read back from the file once everything before it has been written, usually while it's
+/// still cached in memory.
+pub(crate) struct OrderedHasher {
+    algorithm: ChecksumAlgorithm,
+    hashed: Mutex<Hashed>,
+    read_back: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct Hashed {
+    hasher: Hasher,
+    /// Bytes from the start of the file on that have been hashed.
+    len: u64,
+    /// Counts rewinds, so bytes read back before one aren't hashed after it.
+    generation: u64,
+}
+
+impl OrderedHasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        Self {
+            algorithm,
+            hashed: Mutex::new(Hashed {
+                hasher: Hasher::new(algorithm),
+                len: 0,
+                generation: 0,
+            }),
+            read_back: Mutex::new(None),
+        }
+    }
+
+    /// Hashes `bytes` that were written at `offset`, if that's where the hashed part of the file
+    /// ends. Otherwise they are read back later.
+    pub fn update_at(&self, offset: u64, bytes: &[u8]) {
+        // Bytes skipped while the file is read back are read back too.
+        let Ok(mut hashed) = self.hashed.try_lock() else {
+            return;
+        };
+        if hashed.len == offset {
+            hashed.hasher.update(bytes);
+            hashed.len += bytes.len() as u64;
+        }
+    }
+
+    /// Forgets what has been hashed from `offset` on, because those bytes are written again.
+    ///
+    /// Hashes can't be taken back, so this starts over from the start of the file, which is read
+    /// back once it's written.
+    pub fn rewind(&self, offset: u64) {
+        let mut hashed = self.hashed.lock().unwrap();
+        if hashed.len > offset {
+            hashed.hasher = Hasher::new(self.algorithm);
+            hashed.len = 0;
+            hashed.generation += 1;
+        }
+    }
+
+    /// Starts reading back and hashing what has been written of `file` up to `written_prefix()`,
+    /// unless that's still going on.
+    pub fn read_back_in_background(
+        self: &Arc<Self>,
+        file: &SharedFile,
+        written_prefix: impl Fn() -> u64 + Send + 'static,
+    ) {
+        let mut task = self.read_back.lock().unwrap();
+        if task.as_ref().is_some_and(|task| !task.is_finished()) {
+            return;
+        }
+        let this = self.clone();
+        let file = file.clone();
+        *task = Some(tokio::task::spawn_blocking(move || {
+            // Whatever fails here is read again by `finish`, which reports the error.
+            let _ = this.read_back(&file, written_prefix);
+        }));
+    }
+
+    /// Hashes the rest of the first `len` bytes of `file`, which have all been written, and
+    /// returns the hasher.
+    pub async fn finish(self: &Arc<Self>, file: &SharedFile, len: u64) -> io::Result<Hasher> {
+        let task = self.read_back.lock().unwrap().take();
+        if let Some(task) = task {
+            let _ = task.await;
+        }
+        let this = self.clone();
+        let file = file.clone();
+        tokio::task::spawn_blocking(move || this.read_back(&file, || len)).await??;
+
+        let mut hashed = self.hashed.lock().unwrap();
+        Ok(std::mem::replace(
+            &mut hashed.hasher,
+            Hasher::new(self.algorithm),
+        ))
+    }
+
+    /// Reads and hashes `file` from the end of the hashed part on, until it reaches `end()`.
+    fn read_back(&self, file: &SharedFile, end: impl Fn() -> u64) -> io::Result<()> {
+        let mut buf = vec![0; READ_BACK_SIZE];
+        loop {
+            let (start, generation) = {
+                let hashed = self.hashed.lock().unwrap();
+                (hashed.len, hashed.generation)
+            };
+            let end = end();
+            if start >= end {
+                return Ok(());
+            }
+            let len = (end - start).min(buf.len() as u64) as usize;
+            file.read_exact_at(&mut buf[..len], start)?;
+
+            // A connection may have hashed some of these bytes in the meantime, or they may have
+            // been rewound.
+            let mut hashed = self.hashed.lock().unwrap();
+            if hashed.generation != generation {
+                continue;
+            }
+            let skip = (hashed.len - start) as usize;
+            if skip < len {
+                hashed.hasher.update(&buf[skip..len]);
+                hashed.len = start + len as u64;
+            }
+        }
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
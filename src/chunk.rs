@@ -0,0 +1,610 @@
+use crate::{
+    adaptive::Congestion,
+    checksum::{Hasher, OrderedHasher},
+    digest,
+    download::next_chunk,
+    error::{DownloadError, IoResultExt},
+    event::DownloadEvent,
+    hosts::HostLimiter,
+    metrics,
+    mirrors::Mirrors,
+    options::DownloadOptions,
+    pinning::CertificatePins,
+    probe,
+    report::ChunkReport,
+    resume::ChunkState,
+    retry::RetryPolicy,
+    stall::{MinSpeed, StallDetector},
+    storage::SharedFile,
+    throttle::Throttle,
+};
+use bytes::{Bytes, BytesMut};
+use reqwest::StatusCode;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Where the bytes of a chunk are written to.
+#[derive(Clone)]
+pub(crate) enum ChunkOutput {
+    /// The chunk's range of the output file.
+    File { file: SharedFile, path: PathBuf },
+    /// A buffer that is handed to a writer once the chunk is done. Holds exactly the written bytes.
+    Memory(Arc<Mutex<Vec<u8>>>),
+    /// The chunk's range of the output file, written through io_uring.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring {
+        file: SharedFile,
+        path: PathBuf,
+        ring: Arc<crate::uring::Uring>,
+    },
+    /// The chunk's range of the output file, copied into a memory map of it.
+    #[cfg(feature = "mmap")]
+    Mapped {
+        map: Arc<crate::mmap::MappedFile>,
+        path: PathBuf,
+    },
+}
+
+/// Downloads one byte range of a parallel download.
+pub(crate) struct ChunkJob {
+    pub client: reqwest::Client,
+    /// The downloader's headers, sent where the download's own headers are.
+    pub headers: reqwest::header::HeaderMap,
+    pub mirrors: Arc<Mirrors>,
+    pub output: ChunkOutput,
+    pub chunk: Arc<ChunkState>,
+    pub options: DownloadOptions,
+    pub read_timeout: Option<Duration>,
+    pub min_speed: Option<MinSpeed>,
+    pub retry: RetryPolicy,
+    pub throttle: Throttle,
+    pub hosts: Arc<HostLimiter>,
+    pub congestion: Arc<Congestion>,
+    /// Sent as `If-Range`, so a changed file isn't stitched together from different versions.
+    /// Only used with the primary mirror, since other mirrors may tag the file differently.
+    pub validator: Option<String>,
+    /// Forces the range to disk once it has been written.
+    pub sync: bool,
+    /// How many bytes are collected before they are written to the file.
+    pub write_buffer_size: usize,
+    /// Hashes the file while it is written, if it has a checksum.
+    pub hasher: Option<Arc<OrderedHasher>>,
+    /// Checks responses against their `Content-Digest`, and fetches them again if they differ.
+    pub verify_digests: bool,
+    #[cfg(feature = "http3")]
+    pub http3: Option<Arc<crate::http3::Http3>>,
+    pub pins: Option<Arc<CertificatePins>>,
+}
+
+impl ChunkJob {
+    /// Fetches the chunk, retrying failed attempts according to the retry policy.
+    ///
+    /// Every attempt continues from the last byte that was written. When the server fails and other
+    /// mirrors are available, the next attempt is made right away on a different mirror.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "chunk",
+            skip_all,
+            fields(start = self.chunk.start, end = self.chunk.end())
+        )
+    )]
+    pub async fn run(self) -> Result<ChunkReport, DownloadError> {
+        let started = Instant::now();
+        let initially_written = self.chunk.written();
+        let mut mirror = self.mirrors.assign();
+        let mut attempt = 1;
+        let mut retry_after_waited = Duration::ZERO;
+
+        loop {
+            let url = self.mirrors.url(mirror);
+            let validator = self.validator.as_deref().filter(|_| mirror == 0);
+            let result = self.fetch(url, validator).await;
+            if let Err(e) = &result {
+                if is_congestion(e) {
+                    self.congestion.record();
+                }
+                if matches!(
+                    e,
+                    DownloadError::TooSlow { .. } | DownloadError::TimeoutError(_)
+                ) {
+                    self.options.emit(|| DownloadEvent::Stalled {
+                        start: self.chunk.start,
+                        end: self.chunk.end(),
+                    });
+                }
+            }
+
+            match result {
+                Ok(()) => {
+                    debug!(retries = attempt - 1, "chunk finished");
+                    return Ok(ChunkReport {
+                        start: self.chunk.start,
+                        end: self.chunk.end(),
+                        bytes_downloaded: self.chunk.written() - initially_written,
+                        elapsed: started.elapsed(),
+                        retries: attempt - 1,
+                    });
+                }
+                Err(e) if self.can_failover(attempt, &e) => {
+                    warn!(mirror = url, error = %e, "chunk failed, switching mirrors");
+                    mirror = self.mirrors.failover(mirror);
+                    attempt += 1;
+                    self.record_retry(attempt, &e);
+                }
+                Err(e) => match self.retry.retry_delay(attempt, &e, &mut retry_after_waited) {
+                    Some(delay) => {
+                        warn!(attempt, error = %e, "chunk failed, retrying");
+                        attempt += 1;
+                        self.record_retry(attempt, &e);
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => {
+                        return Err(DownloadError::Chunk {
+                            url: url.to_owned(),
+                            start: self.chunk.start,
+                            end: self.chunk.end(),
+                            source: Box::new(e),
+                        })
+                    }
+                },
+            }
+        }
+    }
+
+    fn record_retry(&self, attempt: u32, error: &DownloadError) {
+        metrics::record_retry();
+        self.options.emit(|| DownloadEvent::ChunkRetried {
+            start: self.chunk.start,
+            end: self.chunk.end(),
+            attempt,
+            error: error.to_string(),
+        });
+    }
+
+    /// Every mirror gets a chance before a download fails, even with few retries allowed.
+    fn can_failover(&self, attempt: u32, error: &DownloadError) -> bool {
+        let max_attempts = self.retry.max_attempts.max(self.mirrors.len() as u32);
+        self.mirrors.len() > 1 && attempt < max_attempts && error.is_remote()
+    }
+
+    async fn fetch(&self, url: &str, validator: Option<&str>) -> Result<(), DownloadError> {
+        let chunk = &self.chunk;
+        if chunk.is_complete() {
+            return Ok(());
+        }
+
+        chunk.reset_claims();
+        let start = chunk.start + chunk.written();
+        let end = chunk.end();
+        let range = format!("bytes={}-{}", start, end);
+
+        let _permit = self.hosts.acquire(url).await;
+        let request = |client: &reqwest::Client| {
+            let request = client.get(url).header(reqwest::header::RANGE, &range);
+            let request = self.options.shared_headers(url, &self.headers, request);
+            match validator {
+                Some(validator) => request.header(reqwest::header::IF_RANGE, validator),
+                None => request,
+            }
+        };
+        #[cfg(feature = "http3")]
+        let response = match &self.http3 {
+            Some(http3) => {
+                http3
+                    .send(&self.client, url, &self.options, request)
+                    .await?
+            }
+            None => self.options.send(url, || request(&self.client)).await?,
+        };
+        #[cfg(not(feature = "http3"))]
+        let response = self.options.send(url, || request(&self.client)).await?;
+        if let Some(pins) = &self.pins {
+            pins.check(&response)?;
+        }
+        // Servers answer with the whole file instead of the range if it doesn't match `If-Range`,
+        // and some always do.
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            let unchanged = validator.is_none_or(|validator| {
+                probe::validator(response.headers()).as_deref() == Some(validator)
+            });
+            return Err(match unchanged {
+                true => DownloadError::RangeIgnored {
+                    url: url.to_owned(),
+                },
+                false => DownloadError::RemoteChanged {
+                    url: url.to_owned(),
+                },
+            });
+        }
+        let served = response
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(probe::parse_content_range);
+        let Some(served) = served.filter(|range| range.start == start && range.end <= end) else {
+            return Err(DownloadError::RangeIgnored {
+                url: url.to_owned(),
+            });
+        };
+        // The digest covers the whole response, so it can only be checked if all of it is written.
+        let mut digest = self
+            .verify_digests
+            .then(|| digest::content_digest(response.headers()))
+            .flatten()
+            .map(|expected| (Hasher::new(expected.algorithm), expected));
+        let mut stream = response.bytes_stream();
+
+        let mut writer = ChunkWriter::new(
+            &self.output,
+            start,
+            self.write_buffer_size,
+            self.hasher.as_deref(),
+        );
+        let throttle = self.throttle.connection();
+        let mut stall = StallDetector::new(self.min_speed);
+
+        let result = async {
+            loop {
+                if self.options.unpaused().await {
+                    stall.reset();
+                }
+                let Some(bytes) = next_chunk(&mut stream, self.read_timeout, &mut stall).await?
+                else {
+                    break;
+                };
+                throttle.acquire(bytes.len()).await;
+                if let Some((hasher, _)) = &mut digest {
+                    hasher.update(&bytes);
+                }
+
+                // Another connection may have taken over the end of the range.
+                let claimed = chunk.claim(bytes.len() as u64) as usize;
+                let flushed = writer.write(bytes.slice(..claimed)).await?;
+                chunk.add_written(flushed);
+
+                if claimed < bytes.len() {
+                    digest = None;
+                    break;
+                }
+            }
+            Ok::<(), DownloadError>(())
+        }
+        .await;
+        // What the response delivered before failing is kept, the next attempt continues after it.
+        chunk.add_written(writer.flush().await?);
+        result?;
+
+        // A response that ended early is continued rather than checked.
+        let digest = digest.filter(|_| writer.offset == served.end + 1);
+        if let Some((hasher, expected)) = digest {
+            let actual = hasher.finalize();
+            if actual != expected.expected {
+                // Everything this response wrote is fetched again.
+                writer.discard(chunk.start);
+                chunk.rewind(start - chunk.start);
+                if let Some(hasher) = &self.hasher {
+                    hasher.rewind(start);
+                }
+                return Err(DownloadError::DigestMismatch {
+                    url: url.to_owned(),
+                    expected: expected.expected,
+                    actual,
+                });
+            }
+        }
+
+        // The response ended early, the next attempt continues where it stopped.
+        if !chunk.is_complete() {
+            return Err(DownloadError::ContentLengthMismatch {
+                expected: chunk.len(),
+                actual: chunk.written(),
+            });
+        }
+        if self.sync {
+            writer.sync().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes a chunk from its start on, collecting small writes into `buffer` first.
+struct ChunkWriter<'a> {
+    sink: Sink<'a>,
+    start: u64,
+    /// Where the next write to `sink` goes. The buffered bytes follow it.
+    offset: u64,
+    buffer: BytesMut,
+    buffer_size: usize,
+    hasher: Option<&'a OrderedHasher>,
+}
+
+impl<'a> ChunkWriter<'a> {
+    /// Writes to `output` from offset `start` of the file on, in writes of at least `buffer_size`
+    /// bytes unless the chunk ends before. Written bytes are passed on to `hasher`.
+    fn new(
+        output: &'a ChunkOutput,
+        start: u64,
+        buffer_size: usize,
+        hasher: Option<&'a OrderedHasher>,
+    ) -> Self {
+        let sink = match output {
+            ChunkOutput::File { file, path } => Sink::File { file, path },
+            ChunkOutput::Memory(buffer) => Sink::Memory(buffer),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ChunkOutput::Uring { file, path, ring } => Sink::Uring { ring, file, path },
+            #[cfg(feature = "mmap")]
+            ChunkOutput::Mapped { map, path } => Sink::Mapped { map, path },
+        };
+        // Copies into memory are cheap enough on their own.
+        let buffer_size = match sink {
+            Sink::Memory(_) => 0,
+            #[cfg(feature = "mmap")]
+            Sink::Mapped { .. } => 0,
+            _ => buffer_size,
+        };
+        Self {
+            sink,
+            start,
+            offset: start,
+            buffer: BytesMut::new(),
+            buffer_size,
+            hasher,
+        }
+    }
+
+    /// Writes or buffers `bytes`, returning how many bytes reached the file.
+    async fn write(&mut self, bytes: Bytes) -> Result<u64, DownloadError> {
+        if self.buffer.is_empty() && bytes.len() >= self.buffer_size {
+            return self.write_through(bytes).await;
+        }
+        if self.buffer.capacity() == 0 {
+            self.buffer.reserve(self.buffer_size);
+        }
+        self.buffer.extend_from_slice(&bytes);
+        match self.buffer.len() >= self.buffer_size {
+            true => self.flush().await,
+            false => Ok(0),
+        }
+    }
+
+    /// Writes the buffered bytes, returning how many there were.
+    async fn flush(&mut self) -> Result<u64, DownloadError> {
+        match self.buffer.is_empty() {
+            true => Ok(0),
+            false => {
+                let bytes = self.buffer.split().freeze();
+                self.write_through(bytes).await
+            }
+        }
+    }
+
+    async fn write_through(&mut self, bytes: Bytes) -> Result<u64, DownloadError> {
+        let len = bytes.len() as u64;
+        if let Some(hasher) = self.hasher {
+            hasher.update_at(self.offset, &bytes);
+        }
+        self.sink.write_at(bytes, self.offset).await?;
+        self.offset += len;
+        Ok(len)
+    }
+
+    /// Forgets what has been written, so it can be written again. Only memory has to be cleared,
+    /// files are simply overwritten. The buffer has to be flushed first.
+    fn discard(&mut self, chunk_start: u64) {
+        if let Sink::Memory(buffer) = self.sink {
+            buffer
+                .lock()
+                .unwrap()
+                .truncate((self.start - chunk_start) as usize);
+        }
+        self.offset = self.start;
+    }
+
+    /// Forces what has been written to disk. The buffer has to be flushed first.
+    async fn sync(&self) -> Result<(), DownloadError> {
+        self.sink.sync(self.start, self.offset - self.start).await
+    }
+}
+
+enum Sink<'a> {
+    File {
+        file: &'a SharedFile,
+        path: &'a Path,
+    },
+    Memory(&'a Mutex<Vec<u8>>),
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring {
+        ring: &'a crate::uring::Uring,
+        file: &'a SharedFile,
+        path: &'a Path,
+    },
+    #[cfg(feature = "mmap")]
+    Mapped {
+        map: &'a Arc<crate::mmap::MappedFile>,
+        path: &'a Path,
+    },
+}
+
+impl Sink<'_> {
+    /// Writes all of `bytes` at `offset` of the file. They have reached the file once this
+    /// returns, so there's nothing to flush.
+    async fn write_at(&self, bytes: Bytes, offset: u64) -> Result<(), DownloadError> {
+        match self {
+            Self::File { file, path } => file.write_all_at(bytes, offset).await.with_path(path),
+            Self::Memory(buffer) => {
+                buffer.lock().unwrap().extend_from_slice(&bytes);
+                Ok(())
+            }
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            Self::Uring { ring, file, path } => ring
+                .write_all_at(file.handle(), bytes, offset)
+                .await
+                .with_path(path),
+            #[cfg(feature = "mmap")]
+            Self::Mapped { map, path } => map.write_at(&bytes, offset).with_path(path),
+        }
+    }
+
+    /// Forces `len` bytes from `offset` to disk.
+    async fn sync(&self, _offset: u64, _len: u64) -> Result<(), DownloadError> {
+        match self {
+            Self::File { file, path } => file.sync_data().await.with_path(path),
+            Self::Memory(_) => Ok(()),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            Self::Uring { file, path, .. } => file.sync_data().await.with_path(path),
+            #[cfg(feature = "mmap")]
+            Self::Mapped { map, path } => map.sync_range(_offset, _len).await.with_path(path),
+        }
+    }
+}
+
+/// Checks whether the server asked the client to back off, or dropped the connection.
+pub(crate) fn is_congestion(error: &DownloadError) -> bool {
+    match error {
+        DownloadError::Status { status, .. } => {
+            *status == StatusCode::TOO_MANY_REQUESTS || *status == StatusCode::SERVICE_UNAVAILABLE
+        }
+        DownloadError::RequestError(e) => e.is_connect() || e.is_body(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mirrors::Mirrors;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn file_data() -> Vec<u8> {
+        (0..200u32).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    /// Serves ranges of [`file_data`]. The first response announces the whole range but breaks off
+    /// after `cut` bytes. Returns the URL and the `Range` headers of the requests.
+    async fn serve(cut: usize) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let seen = ranges.clone();
+        tokio::spawn(async move {
+            let data = file_data();
+            for attempt in 0.. {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0];
+                    if socket.read(&mut byte).await.unwrap() == 0 {
+                        break;
+                    }
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let range = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: "))
+                    .unwrap()
+                    .to_owned();
+                seen.lock().unwrap().push(range.clone());
+
+                let (start, end) = range
+                    .strip_prefix("bytes=")
+                    .and_then(|range| range.split_once('-'))
+                    .unwrap();
+                let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                let body = &data[start..=end];
+                let head = format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    start,
+                    end,
+                    data.len(),
+                    body.len()
+                );
+                socket.write_all(head.as_bytes()).await.unwrap();
+                let body = if attempt == 0 { &body[..cut] } else { body };
+                socket.write_all(body).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+        (url, ranges)
+    }
+
+    fn job(url: &str, output: ChunkOutput, chunk: Arc<ChunkState>) -> ChunkJob {
+        ChunkJob {
+            client: reqwest::Client::new(),
+            headers: Default::default(),
+            mirrors: Arc::new(Mirrors::new(url, &[])),
+            output,
+            chunk,
+            options: DownloadOptions::new(),
+            read_timeout: None,
+            min_speed: None,
+            retry: RetryPolicy {
+                initial_backoff: Duration::from_millis(1),
+                jitter: false,
+                ..RetryPolicy::default()
+            },
+            throttle: Throttle::default(),
+            hosts: Arc::new(HostLimiter::new(None, None)),
+            congestion: Arc::default(),
+            validator: None,
+            sync: false,
+            write_buffer_size: 64 * 1024,
+            hasher: None,
+            verify_digests: false,
+            #[cfg(feature = "http3")]
+            http3: None,
+            pins: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_continue_after_the_written_bytes() {
+        let (url, ranges) = serve(40).await;
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let chunk = Arc::new(ChunkState::new(100, 199, 0));
+
+        let report = job(&url, ChunkOutput::Memory(buffer.clone()), chunk.clone())
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(*ranges.lock().unwrap(), ["bytes=100-199", "bytes=140-199"]);
+        assert_eq!((report.retries, report.bytes_downloaded), (1, 100));
+        assert_eq!(*buffer.lock().unwrap(), file_data()[100..]);
+        assert!(chunk.is_complete());
+    }
+
+    #[tokio::test]
+    async fn buffered_bytes_are_written_before_retrying() {
+        let (url, ranges) = serve(25).await;
+        let path = std::env::temp_dir().join(format!("zusammen-chunk-{}.bin", std::process::id()));
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(200).unwrap();
+        let output = ChunkOutput::File {
+            file: SharedFile::new(file),
+            path: path.clone(),
+        };
+        // Already written by an earlier run.
+        let chunk = Arc::new(ChunkState::new(100, 199, 10));
+
+        let report = job(&url, output, chunk).run().await.unwrap();
+        assert_eq!(*ranges.lock().unwrap(), ["bytes=110-199", "bytes=135-199"]);
+        assert_eq!((report.retries, report.bytes_downloaded), (1, 90));
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written[110..], file_data()[110..]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
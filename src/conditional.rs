@@ -0,0 +1,249 @@
+use crate::{
+    download::{self, Downloader},
+    error::{DownloadError, IoResultExt},
+    filename,
+    manifest::ManifestEntry,
+    options::{DownloadOptions, OverwritePolicy},
+    report::DownloadReport,
+    resume,
+};
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    Response, StatusCode,
+};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::fs;
+
+/// Whether the local copy of a file is current, according to [`Downloader::dry_run`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChange {
+    /// There's no local copy.
+    Missing,
+    /// There's a local copy, but nothing to ask the server whether it's current with. With a
+    /// manifest, that's files that aren't in it.
+    Untracked,
+    /// The local copy isn't the size the manifest says it was downloaded with.
+    Modified,
+    /// The server has a different version.
+    Changed,
+    /// The server responded with `304 Not Modified`.
+    Unchanged,
+}
+
+impl FileChange {
+    /// Whether the file would be downloaded.
+    pub fn needs_download(self) -> bool {
+        self != FileChange::Unchanged
+    }
+}
+
+/// What [`Downloader::dry_run`] found out about a URL.
+#[derive(Debug, Clone)]
+pub struct PlannedDownload {
+    pub url: String,
+    /// Where the file is, or would be, saved.
+    pub path: PathBuf,
+    pub change: FileChange,
+}
+
+impl Downloader {
+    /// Asks the server whether the file at `url` changed since it was last downloaded, if the
+    /// overwrite policy is [`OverwritePolicy::SkipIfUnchanged`] and the file exists.
+    ///
+    /// Returns a report for the existing file if the server responded with `304 Not Modified`.
+    pub(crate) async fn check_unchanged(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<Option<DownloadReport>, DownloadError> {
+        if self.overwrite_policy(options) != OverwritePolicy::SkipIfUnchanged {
+            return Ok(None);
+        }
+        let path = self.unchanged_path(url, options);
+        let (_, response) = self.revalidate(url, &path, options).await?;
+        let (Some(response), Ok(metadata)) = (response, fs::metadata(&path).await) else {
+            return Ok(None);
+        };
+
+        info!(path = %path.display(), "file is up to date, skipping download");
+        Ok(Some(DownloadReport {
+            path,
+            url: url.to_owned(),
+            final_url: response.url().to_string(),
+            status: Some(response.status()),
+            headers: response.headers().clone(),
+            size: metadata.len(),
+            bytes_downloaded: 0,
+            elapsed: Duration::ZERO,
+            retries: 0,
+            checksum: None,
+            checksum_source: None,
+            extracted_to: None,
+            chunks: Vec::new(),
+        }))
+    }
+
+    /// Checks whether downloading `url` with [`OverwritePolicy::SkipIfUnchanged`] would fetch
+    /// it, without downloading anything.
+    ///
+    /// Files that exist are asked about with a conditional request, like the download would, so
+    /// the server is still contacted for them. Only `http://` and `https://` URLs can be checked.
+    pub async fn dry_run(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<PlannedDownload, DownloadError> {
+        download::check_scheme(url)?;
+        let options = &options
+            .clone()
+            .overwrite_policy(OverwritePolicy::SkipIfUnchanged);
+        let path = self.unchanged_path(url, options);
+        let (change, _) = self.revalidate(url, &path, options).await?;
+        Ok(PlannedDownload {
+            url: url.to_owned(),
+            path,
+            change,
+        })
+    }
+
+    /// Returns the path a download of `url` is saved to, before the server suggests a name.
+    ///
+    /// If extensions are inferred, that's the file with the name and an extension if there's
+    /// one.
+    fn unchanged_path(&self, url: &str, options: &DownloadOptions) -> PathBuf {
+        let name = self.output_name(
+            url,
+            options.filename.as_deref(),
+            &Default::default(),
+            options,
+        );
+        let path = self.get_output_path(&name, options);
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !self.infer_extensions || filename::has_extension(url, &file_name) || path.exists() {
+            return path;
+        }
+        with_inferred_extension(&path).unwrap_or(path)
+    }
+
+    /// Asks the server whether the file at `url` changed since it was downloaded to `path`, with
+    /// the validators in the manifest if there is one, or else the `ETag` next to the file and
+    /// its modification time. Returns the response if it didn't.
+    async fn revalidate(
+        &self,
+        url: &str,
+        path: &Path,
+        options: &DownloadOptions,
+    ) -> Result<(FileChange, Option<Response>), DownloadError> {
+        let Ok(metadata) = fs::metadata(path).await else {
+            return Ok((FileChange::Missing, None));
+        };
+        let (etag, modified) = match &self.manifest {
+            Some(manifest) => match manifest.get(path).await {
+                Some(entry) if entry.size == metadata.len() => (entry.etag, entry.last_modified),
+                Some(_) => return Ok((FileChange::Modified, None)),
+                None => return Ok((FileChange::Untracked, None)),
+            },
+            None => (
+                fs::read_to_string(etag_path(path)).await.ok(),
+                metadata.modified().ok().map(httpdate::fmt_http_date),
+            ),
+        };
+        if etag.is_none() && modified.is_none() {
+            return Ok((FileChange::Untracked, None));
+        }
+
+        let request = |client: &reqwest::Client| {
+            let mut request = client.get(url);
+            if let Some(etag) = &etag {
+                request = request.header(IF_NONE_MATCH, etag.trim());
+            }
+            if let Some(modified) = &modified {
+                request = request.header(IF_MODIFIED_SINCE, modified);
+            }
+            request
+        };
+        let _permit = options.or_cancelled(self.hosts.acquire(url)).await?;
+        let response = options
+            .or_cancelled(self.send_request(url, options, request))
+            .await??;
+        if response.status() != StatusCode::NOT_MODIFIED {
+            debug!(status = %response.status(), "remote file changed");
+            return Ok((FileChange::Changed, None));
+        }
+        Ok((FileChange::Unchanged, Some(response)))
+    }
+
+    /// Keeps what the next download of the file of `report` needs to ask whether it changed: its
+    /// validators in the manifest if there is one, or else its `Last-Modified` date as the
+    /// modification time of the file and its `ETag` next to it.
+    ///
+    /// The manifest is kept up to date whatever the overwrite policy.
+    pub(crate) async fn remember_validators(
+        &self,
+        report: &DownloadReport,
+        options: &DownloadOptions,
+    ) -> Result<(), DownloadError> {
+        if let Some(manifest) = &self.manifest {
+            return manifest
+                .insert(&report.path, ManifestEntry::from_report(report))
+                .await;
+        }
+        if self.overwrite_policy(options) != OverwritePolicy::SkipIfUnchanged {
+            return Ok(());
+        }
+        save_validators(report).await
+    }
+}
+
+/// Saves the `ETag` of the file of `report` next to it, and sets its modification time to its
+/// `Last-Modified` date.
+pub(crate) async fn save_validators(report: &DownloadReport) -> Result<(), DownloadError> {
+    let header = |name| report.headers.get(name).and_then(|v| v.to_str().ok());
+
+    let etag_path = etag_path(&report.path);
+    match header(ETAG) {
+        Some(etag) => fs::write(&etag_path, etag).await.with_path(&etag_path)?,
+        None => {
+            let _ = fs::remove_file(&etag_path).await;
+        }
+    }
+
+    if let Some(modified) = header(LAST_MODIFIED).and_then(|v| httpdate::parse_http_date(v).ok()) {
+        let path = report.path.clone();
+        tokio::task::spawn_blocking(move || {
+            std::fs::File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(modified)
+        })
+        .await?
+        .with_path(&report.path)?;
+    }
+    Ok(())
+}
+
+/// Finds the file a download to `path` was saved to with an inferred extension, like
+/// `report.pdf` for `report`.
+fn with_inferred_extension(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?;
+    let sidecars = ["etag", resume::SIDECAR_EXTENSION];
+    std::fs::read_dir(path.parent()?)
+        .ok()?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .find(|candidate| {
+            candidate.file_stem() == Some(name)
+                && candidate
+                    .extension()
+                    .is_some_and(|extension| !sidecars.iter().any(|s| extension == *s))
+        })
+}
+
+/// Returns the path the `ETag` of the file at `path` is kept at.
+pub(crate) fn etag_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".etag");
+    PathBuf::from(name)
+}
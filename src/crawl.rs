@@ -0,0 +1,397 @@
+//! Recursive downloads of the directory listings HTTP servers generate, like the autoindex pages
+//! of nginx and Apache, enabled with the `crawl` feature.
+//!
+//! Only links below the directory a crawl starts at are followed, so it never wanders off to
+//! parent directories or other hosts.
+
+use crate::{
+    batch::BatchTracker, download::Downloader, error::DownloadError, filename,
+    options::DownloadOptions, report::DownloadReport,
+};
+use futures::{stream, StreamExt};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use percent_encoding::percent_decode_str;
+use std::collections::{HashSet, VecDeque};
+use url::Url;
+
+/// Directory listings are only read up to this size.
+const MAX_LISTING_SIZE: usize = 16 * 1024 * 1024;
+
+/// Which files [`Downloader::download_directory`] downloads.
+///
+/// Globs with a `/` are matched against the path of a file relative to the directory the crawl
+/// starts at, like `docs/**/*.pdf`, and others against its name, like `*.iso`. A file is
+/// downloaded if it matches an include glob, or there are none, and no exclude glob.
+#[derive(Debug, Clone, Default)]
+pub struct CrawlOptions {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    pub(crate) max_depth: Option<usize>,
+}
+
+impl CrawlOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only downloads files that match `glob` or another include glob.
+    pub fn include(mut self, glob: &str) -> Self {
+        self.include.push(glob.to_owned());
+        self
+    }
+
+    /// Skips files that match `glob`.
+    pub fn exclude(mut self, glob: &str) -> Self {
+        self.exclude.push(glob.to_owned());
+        self
+    }
+
+    /// Follows subdirectories up to `depth` levels below the directory the crawl starts at, `0`
+    /// only downloads the files listed in it. Unlimited by default.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Compiles the include and exclude globs.
+    pub(crate) fn filter(&self) -> Result<Filter, DownloadError> {
+        Ok(Filter {
+            include: Globs::new(&self.include)?,
+            exclude: Globs::new(&self.exclude)?,
+        })
+    }
+}
+
+/// The compiled globs of [`CrawlOptions`].
+pub(crate) struct Filter {
+    include: Globs,
+    exclude: Globs,
+}
+
+impl Filter {
+    /// Checks whether the file called `name` at `path`, relative to the directory the crawl
+    /// started at, should be downloaded.
+    pub fn matches(&self, path: &str, name: &str) -> bool {
+        (self.include.is_empty() || self.include.matches(path, name))
+            && !self.exclude.matches(path, name)
+    }
+}
+
+/// Globs matched against the names or relative paths of files.
+struct Globs {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Globs {
+    fn new(globs: &[String]) -> Result<Self, DownloadError> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for glob in globs {
+            let compiled = GlobBuilder::new(glob.trim_start_matches('/'))
+                .literal_separator(true)
+                .build()?;
+            if glob.contains('/') {
+                paths.add(compiled);
+            } else {
+                names.add(compiled);
+            }
+        }
+        Ok(Self {
+            names: names.build()?,
+            paths: paths.build()?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.paths.is_empty()
+    }
+
+    fn matches(&self, path: &str, name: &str) -> bool {
+        self.names.is_match(name) || self.paths.is_match(path)
+    }
+}
+
+impl Downloader {
+    /// Downloads the files listed in the directory listing at `url`, and in those of its
+    /// subdirectories, keeping the directory tree below the output directory.
+    ///
+    /// Fails only if the globs of `crawl` are invalid or the listing at `url` can't be fetched.
+    /// The results are those of the files in the order they were found, followed by errors for
+    /// the listings of subdirectories that couldn't be fetched.
+    pub async fn download_directory(
+        &self,
+        url: &str,
+        crawl: &CrawlOptions,
+    ) -> Result<Vec<Result<DownloadReport, DownloadError>>, DownloadError> {
+        self.download_directory_with(url, crawl, &DownloadOptions::default())
+            .await
+    }
+
+    /// Like [`Downloader::download_directory`], with settings for the listings and every file,
+    /// like credentials, which are only sent to the host of `url`. Each file is saved under its
+    /// own name, below the directory `options` sets.
+    pub async fn download_directory_with(
+        &self,
+        url: &str,
+        crawl: &CrawlOptions,
+        options: &DownloadOptions,
+    ) -> Result<Vec<Result<DownloadReport, DownloadError>>, DownloadError> {
+        let filter = crawl.filter()?;
+        let mut root = directory_url(url)?;
+
+        let options = options.scoped(root.as_str());
+        let mut files = Vec::new();
+        let mut failed = Vec::new();
+        let mut seen = HashSet::from([root.clone()]);
+        let mut directories = VecDeque::from([(root.clone(), 0)]);
+        while let Some((directory, depth)) = directories.pop_front() {
+            let (base, listing) = match self.fetch_listing(directory.as_str(), &options).await {
+                Ok(listing) => listing,
+                Err(e) if directory == root => return Err(e),
+                Err(e) => {
+                    warn!(url = %directory, error = %e, "failed to fetch directory listing");
+                    failed.push((directory.to_string(), Err(e)));
+                    continue;
+                }
+            };
+            // Links are resolved against the URL the listing came from, so the tree starts
+            // wherever the first listing was redirected to.
+            if directory == root && base != root {
+                root = base.join(".").unwrap_or_else(|_| base.clone());
+                seen.insert(root.clone());
+            }
+            for link in links(&listing) {
+                let Ok(mut target) = base.join(&link) else {
+                    continue;
+                };
+                target.set_fragment(None);
+                // Listings link to sorted versions of themselves, like `?C=N;O=D`.
+                if target.query().is_some() || !seen.insert(target.clone()) {
+                    continue;
+                }
+                let Some(segments) = relative_segments(&root, &target) else {
+                    continue;
+                };
+                let Some((name, dirs)) = segments.split_last() else {
+                    continue;
+                };
+                if name.is_empty() {
+                    if crawl.max_depth.is_none_or(|max| depth < max) {
+                        directories.push_back((target, depth + 1));
+                    }
+                    continue;
+                }
+
+                let wanted = filter.matches(&segments.join("/"), name);
+                let Some(name) = filename::base_name(name).filter(|_| wanted) else {
+                    continue;
+                };
+                files.push((target.to_string(), self.found_file(&options, &name, dirs)));
+            }
+        }
+        debug!(files = files.len(), "crawled directory listing");
+        Ok(self.download_found(files, failed).await)
+    }
+
+    /// The options of a file found by a crawl, which is saved as `name` in `dirs` below the
+    /// directory of `options`.
+    pub(crate) fn found_file(
+        &self,
+        options: &DownloadOptions,
+        name: &str,
+        dirs: &[String],
+    ) -> DownloadOptions {
+        let mut dirs = dirs.to_vec();
+        // The host directories already keep the tree.
+        if self.host_directories {
+            dirs.clear();
+        }
+        let directory = options.directory.iter().cloned().chain(dirs);
+        options
+            .clone()
+            .filename(name)
+            .directory(&directory.collect::<Vec<_>>().join("/"))
+    }
+
+    /// Downloads the `files` found by a crawl as a batch, with their options. The results are
+    /// followed by those of the files that were `done` without a download.
+    pub(crate) async fn download_found(
+        &self,
+        files: Vec<(String, DownloadOptions)>,
+        done: Vec<(String, Result<DownloadReport, DownloadError>)>,
+    ) -> Vec<Result<DownloadReport, DownloadError>> {
+        let urls = files
+            .iter()
+            .map(|(url, _)| url)
+            .chain(done.iter().map(|(url, _)| url));
+        let batch = BatchTracker::new(urls.map(String::as_str));
+        let downloads = async {
+            let mut results: Vec<_> = stream::iter(files.iter().enumerate())
+                .map(|(index, (url, options))| {
+                    let batch = &batch;
+                    async move {
+                        let result = self.download_in_batch(batch, index, url, options).await;
+                        batch.finish(index, &result);
+                        result
+                    }
+                })
+                .buffered(self.max_concurrent_files)
+                .collect()
+                .await;
+            for (_, result) in done {
+                batch.finish(results.len(), &result);
+                results.push(result);
+            }
+            results
+        };
+        self.report_batch(&batch, downloads).await
+    }
+
+    /// Fetches the directory listing at `url`, returning the URL it was served from, which its
+    /// links are relative to, and its contents.
+    async fn fetch_listing(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<(Url, String), DownloadError> {
+        let _permit = options.or_cancelled(self.hosts.acquire(url)).await?;
+        let fetch = async {
+            let mut response = self
+                .send_request(url, options, |client| client.get(url))
+                .await?;
+            let base = response.url().clone();
+            let mut body = Vec::new();
+            while let Some(bytes) = response.chunk().await? {
+                body.extend_from_slice(&bytes);
+                if body.len() > MAX_LISTING_SIZE {
+                    warn!(url, "directory listing is too big, reading only part of it");
+                    break;
+                }
+            }
+            Ok((base, String::from_utf8_lossy(&body).into_owned()))
+        };
+        options.or_cancelled(fetch).await?
+    }
+}
+
+/// Parses the URL of a directory, adding the `/` its path should end with if it's missing.
+pub(crate) fn directory_url(url: &str) -> Result<Url, DownloadError> {
+    let mut parsed = Url::parse(url).map_err(|_| DownloadError::InvalidUrl(url.to_owned()))?;
+    if !parsed.path().ends_with('/') {
+        let path = format!("{}/", parsed.path());
+        parsed.set_path(&path);
+    }
+    Ok(parsed)
+}
+
+/// Returns the percent-decoded path segments of `target` below `root`, ending with an empty one
+/// if it's a directory, or `None` if it isn't below `root`.
+pub(crate) fn relative_segments(root: &Url, target: &Url) -> Option<Vec<String>> {
+    if target.origin() != root.origin() {
+        return None;
+    }
+    let relative = target.path().strip_prefix(root.path())?;
+    let segments = relative
+        .split('/')
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        .collect::<Vec<_>>();
+    match segments.as_slice() {
+        [only] if only.is_empty() => Some(Vec::new()),
+        _ => Some(segments),
+    }
+}
+
+/// Returns the targets of the `href` attributes in `html`, with entities like `&amp;` decoded.
+fn links(html: &str) -> Vec<String> {
+    // Lowercasing ASCII keeps byte offsets, so they can be used on `html`.
+    let lower = html.to_ascii_lowercase();
+    let bytes = html.as_bytes();
+    let skip_whitespace = |mut i: usize| {
+        while bytes.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        i
+    };
+
+    let mut links = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = lower[pos..].find("href") {
+        let start = pos + found;
+        pos = start + "href".len();
+        if start == 0 || !bytes[start - 1].is_ascii_whitespace() {
+            continue;
+        }
+        let i = skip_whitespace(pos);
+        if bytes.get(i) != Some(&b'=') {
+            continue;
+        }
+        let i = skip_whitespace(i + 1);
+        let (value, end) = match bytes.get(i) {
+            Some(&quote @ (b'"' | b'\'')) => match html[i + 1..].find(quote as char) {
+                Some(len) => (i + 1, i + 1 + len),
+                None => break,
+            },
+            Some(_) => {
+                let len = html[i..].find(|c: char| c.is_ascii_whitespace() || c == '>');
+                (i, len.map_or(html.len(), |len| i + len))
+            }
+            None => break,
+        };
+        links.push(decode_entities(&html[value..end]));
+        pos = end;
+    }
+    links
+}
+
+fn decode_entities(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directory_urls_end_with_a_slash() {
+        assert_eq!(
+            directory_url("https://example.com/pub").unwrap().as_str(),
+            "https://example.com/pub/"
+        );
+        assert_eq!(
+            directory_url("https://example.com/pub/").unwrap().as_str(),
+            "https://example.com/pub/"
+        );
+        assert!(directory_url("not a url").is_err());
+    }
+
+    #[test]
+    fn segments_below_the_root() {
+        let root = Url::parse("https://example.com/pub/").unwrap();
+        let segments = |target: &str| relative_segments(&root, &Url::parse(target).unwrap());
+        assert_eq!(
+            segments("https://example.com/pub/docs/a%20b.txt"),
+            Some(vec!["docs".to_owned(), "a b.txt".to_owned()])
+        );
+        assert_eq!(
+            segments("https://example.com/pub/docs/"),
+            Some(vec!["docs".to_owned(), String::new()])
+        );
+        assert_eq!(segments("https://example.com/pub/"), Some(Vec::new()));
+        assert_eq!(segments("https://example.com/other/a.txt"), None);
+        assert_eq!(segments("https://mirror.example.com/pub/a.txt"), None);
+    }
+
+    #[test]
+    fn finds_links() {
+        let html = r#"<a href="a.txt">a</a> <A HREF='docs/'>docs</A> <a href=b&amp;c.iso>b</a>
+            <a data-href="no">x</a> <link href = "style.css">"#;
+        assert_eq!(links(html), ["a.txt", "docs/", "b&c.iso", "style.css"]);
+    }
+}
@@ -0,0 +1,619 @@
+use crate::{
+    download::Downloader,
+    error::DownloadError,
+    filename, metrics,
+    options::DownloadOptions,
+    report::DownloadReport,
+    segments::{Segment, StreamVariant},
+};
+use roxmltree::{Document, Node};
+use std::{path::Path, str::FromStr, time::Instant};
+use url::Url;
+
+/// Most segments a representation may have, so a manifest can't make the list of segments fill
+/// the memory.
+const MAX_SEGMENTS: u64 = 1_000_000;
+
+/// The representation picked from a manifest, with what its segment URLs are made of.
+struct Representation<'a> {
+    id: &'a str,
+    bandwidth: u64,
+    /// Length of the period, in seconds.
+    duration: Option<f64>,
+}
+
+impl Downloader {
+    /// Downloads the MPEG-DASH presentation at `url` into a single file. See
+    /// [`Downloader::download_dash_with`].
+    pub async fn download_dash(
+        &self,
+        url: &str,
+        variant: StreamVariant,
+    ) -> Result<DownloadReport, DownloadError> {
+        self.download_dash_with(url, variant, &DownloadOptions::default())
+            .await
+    }
+
+    /// Like [`Downloader::download_dash`], with settings that only apply to this download.
+    ///
+    /// `variant` picks a representation of the first video adaptation set of the manifest by its
+    /// bandwidth, or of the first adaptation set if none has video. Its initialization segment
+    /// and media segments, listed with `SegmentList`, `SegmentTemplate` or a single `BaseURL`, are
+    /// fetched over `conn_count` connections and joined in order, into a file named after the
+    /// manifest unless `options` sets a filename.
+    ///
+    /// Only static presentations with a single period are supported, and encrypted ones aren't.
+    /// Other adaptation sets, like a separate audio track, aren't downloaded.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "download_dash", skip_all, fields(url = %url)))]
+    pub async fn download_dash_with(
+        &self,
+        url: &str,
+        variant: StreamVariant,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        let _running = self.shutdown.enter();
+        let result = match self.shutdown.is_shut_down() {
+            true => Err(DownloadError::Cancelled),
+            false => {
+                self.fetch_dash(url, variant, &options.started(url, self.shutdown.token()))
+                    .await
+            }
+        };
+        self.hooks.run(url, &result, started.elapsed()).await;
+        metrics::record_outcome(&result);
+        result
+    }
+
+    async fn fetch_dash(
+        &self,
+        url: &str,
+        variant: StreamVariant,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let parsed = Url::parse(url).map_err(|_| DownloadError::InvalidUrl(url.to_owned()))?;
+        let (base, xml) = self.fetch_manifest(&parsed, options).await?;
+        let (segments, extension) =
+            parse(&base, &xml, variant).map_err(|reason| DownloadError::InvalidManifest {
+                url: url.to_owned(),
+                reason,
+            })?;
+
+        let name = match &options.filename {
+            Some(name) => name.clone(),
+            None => Path::new(&filename::from_url(url))
+                .with_extension(extension)
+                .to_string_lossy()
+                .into_owned(),
+        };
+        let name = self.output_name(url, Some(&name), &Default::default(), options);
+        self.download_segments(url, &name, &segments, options).await
+    }
+}
+
+/// Parses a manifest served from `base`, returning the segments of the representation picked by
+/// `variant` and the extension of the file they make up.
+fn parse(
+    base: &Url,
+    xml: &str,
+    variant: StreamVariant,
+) -> Result<(Vec<Segment>, &'static str), String> {
+    let doc = Document::parse(xml).map_err(|e| e.to_string())?;
+    let mpd = doc.root_element();
+    if !is_element(&mpd, "MPD") {
+        return Err("root element is not <MPD>".to_owned());
+    }
+    if mpd.attribute("type") == Some("dynamic") {
+        return Err("live presentations aren't supported".to_owned());
+    }
+    let mut periods = children(mpd, "Period");
+    let period = periods.next().ok_or("no Period")?;
+    if periods.next().is_some() {
+        return Err("presentations with more than one Period aren't supported".to_owned());
+    }
+    let duration = period
+        .attribute("duration")
+        .or_else(|| mpd.attribute("mediaPresentationDuration"))
+        .map(parse_duration)
+        .transpose()?;
+
+    let sets: Vec<_> = children(period, "AdaptationSet").collect();
+    let set = sets
+        .iter()
+        .find(|set| content_type(set) == Some("video"))
+        .or(sets.first())
+        .copied()
+        .ok_or("no AdaptationSet")?;
+    if set
+        .descendants()
+        .any(|n| is_element(&n, "ContentProtection"))
+    {
+        return Err("encrypted streams aren't supported".to_owned());
+    }
+    let representations: Vec<_> = children(set, "Representation").collect();
+    if representations.is_empty() {
+        return Err("AdaptationSet without a Representation".to_owned());
+    }
+    let bandwidths: Vec<u64> = representations
+        .iter()
+        .map(|r| {
+            r.attribute("bandwidth")
+                .and_then(|b| b.parse().ok())
+                .unwrap_or(0)
+        })
+        .collect();
+    let index = variant.select(&bandwidths);
+    let node = representations[index];
+    let representation = Representation {
+        id: node.attribute("id").unwrap_or_default(),
+        bandwidth: bandwidths[index],
+        duration,
+    };
+    debug!(
+        id = representation.id,
+        bandwidth = representation.bandwidth,
+        "selected representation"
+    );
+
+    let mut url = base.clone();
+    for level in [mpd, period, set, node] {
+        if let Some(base_url) = child(level, "BaseURL").and_then(|n| n.text()) {
+            url = join(&url, base_url.trim())?;
+        }
+    }
+    // Segment information is inherited, the representation's own taking precedence.
+    let levels = [node, set, period];
+    let segments = if let Some(list) = levels.iter().find_map(|n| child(*n, "SegmentList")) {
+        segment_list(&url, list)?
+    } else {
+        let templates: Vec<_> = levels
+            .iter()
+            .filter_map(|n| child(*n, "SegmentTemplate"))
+            .collect();
+        if !templates.is_empty() {
+            segment_template(&url, &templates, &representation)?
+        } else if child(node, "BaseURL").is_some() || child(set, "BaseURL").is_some() {
+            vec![Segment { url, range: None }]
+        } else {
+            return Err(format!(
+                "Representation `{}` has no segments",
+                representation.id
+            ));
+        }
+    };
+
+    let mime_type = node.attribute("mimeType").or(set.attribute("mimeType"));
+    let extension = match mime_type {
+        Some("video/webm" | "audio/webm") => "webm",
+        Some("audio/mp4") => "m4a",
+        _ => "mp4",
+    };
+    Ok((segments, extension))
+}
+
+/// Returns whether an adaptation set holds `video`, `audio` or `text`, if it says.
+fn content_type<'a>(set: &Node<'a, '_>) -> Option<&'a str> {
+    let mime_type = set.attribute("mimeType").or_else(|| {
+        children(*set, "Representation")
+            .next()
+            .and_then(|r| r.attribute("mimeType"))
+    });
+    set.attribute("contentType")
+        .or_else(|| mime_type.and_then(|mime| mime.split('/').next()))
+}
+
+fn segment_list(base: &Url, list: Node) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    if let Some(init) = child(list, "Initialization") {
+        segments.push(segment(
+            base,
+            init.attribute("sourceURL"),
+            init.attribute("range"),
+        )?);
+    }
+    for url in children(list, "SegmentURL") {
+        segments.push(segment(
+            base,
+            url.attribute("media"),
+            url.attribute("mediaRange"),
+        )?);
+    }
+    Ok(segments)
+}
+
+/// Makes the segment at `uri`, or at `base` if there's none, optionally limited to a `range`
+/// like `500-999`.
+fn segment(base: &Url, uri: Option<&str>, range: Option<&str>) -> Result<Segment, String> {
+    let url = match uri {
+        Some(uri) => join(base, uri)?,
+        None => base.clone(),
+    };
+    let range = match range {
+        Some(range) => {
+            let invalid = || format!("invalid byte range `{}`", range);
+            let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+            let start: u64 = start.trim().parse().map_err(|_| invalid())?;
+            let end: u64 = end.trim().parse().map_err(|_| invalid())?;
+            if end < start {
+                return Err(invalid());
+            }
+            Some((start, (end - start).checked_add(1).ok_or_else(invalid)?))
+        }
+        None => None,
+    };
+    Ok(Segment { url, range })
+}
+
+/// Lists the segments of `templates`, the most specific first, whose attributes override those
+/// of the others.
+fn segment_template(
+    base: &Url,
+    templates: &[Node],
+    representation: &Representation,
+) -> Result<Vec<Segment>, String> {
+    let attribute = |name| templates.iter().find_map(|t| t.attribute(name));
+    let timescale: u64 = attribute("timescale").map_or(Ok(1), |v| number(v, "timescale"))?;
+    let start_number: u64 = attribute("startNumber").map_or(Ok(1), |v| number(v, "startNumber"))?;
+    let media = attribute("media").ok_or("SegmentTemplate without media")?;
+
+    let mut segments = Vec::new();
+    if let Some(init) = attribute("initialization") {
+        let url = join(base, &expand(init, representation, None, None)?)?;
+        segments.push(Segment { url, range: None });
+    }
+    let end = representation
+        .duration
+        .map(|duration| duration * timescale as f64);
+    let times = match templates.iter().find_map(|t| child(*t, "SegmentTimeline")) {
+        Some(timeline) => timeline_times(timeline, end)?,
+        None => {
+            let duration: u64 = number(
+                attribute("duration").ok_or("SegmentTemplate without duration or timeline")?,
+                "duration",
+            )?;
+            let end = end.ok_or("presentation without a duration")?;
+            if duration == 0 {
+                return Err("SegmentTemplate with a duration of 0".to_owned());
+            }
+            let count = (end / duration as f64).ceil();
+            if count > MAX_SEGMENTS as f64 {
+                return Err(too_many_segments());
+            }
+            (0..count as u64)
+                .map(|i| i.checked_mul(duration).ok_or_else(time_overflow))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    for (i, time) in times.into_iter().enumerate() {
+        let number = start_number
+            .checked_add(i as u64)
+            .ok_or("segment number overflows")?;
+        let url = join(
+            base,
+            &expand(media, representation, Some(number), Some(time))?,
+        )?;
+        segments.push(Segment { url, range: None });
+    }
+    Ok(segments)
+}
+
+/// Returns the start times of the segments of a `SegmentTimeline`, in the timescale of its
+/// template. `end` is where the period ends, up to which the last entry may repeat.
+fn timeline_times(timeline: Node, end: Option<f64>) -> Result<Vec<u64>, String> {
+    let entries: Vec<_> = children(timeline, "S").collect();
+    let mut times = Vec::new();
+    let mut time: u64 = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        if let Some(t) = entry.attribute("t") {
+            time = number(t, "t")?;
+        }
+        let duration: u64 = number(entry.attribute("d").ok_or("S without d")?, "d")?;
+        if duration == 0 {
+            return Err("S with a duration of 0".to_owned());
+        }
+        let repeat: i64 = entry.attribute("r").map_or(Ok(0), |r| number(r, "r"))?;
+        let repeat = match u64::try_from(repeat) {
+            Ok(repeat) => repeat,
+            // Repeats until the next entry, or the end of the period.
+            Err(_) => {
+                let until = match entries.get(i + 1).and_then(|n| n.attribute("t")) {
+                    Some(t) => number::<u64>(t, "t")? as f64,
+                    None => end.ok_or("open-ended SegmentTimeline without a duration")?,
+                };
+                let count = ((until - time as f64) / duration as f64).ceil();
+                (count as u64).saturating_sub(1)
+            }
+        };
+        if repeat >= MAX_SEGMENTS - times.len() as u64 {
+            return Err(too_many_segments());
+        }
+        for _ in 0..=repeat {
+            times.push(time);
+            time = time.checked_add(duration).ok_or_else(time_overflow)?;
+        }
+    }
+    Ok(times)
+}
+
+fn too_many_segments() -> String {
+    format!("more than {} segments", MAX_SEGMENTS)
+}
+
+fn time_overflow() -> String {
+    "segment time overflows".to_owned()
+}
+
+/// Fills in the identifiers of a template like `video_$RepresentationID$_$Number%05d$.m4s`.
+fn expand(
+    template: &str,
+    representation: &Representation,
+    number: Option<u64>,
+    time: Option<u64>,
+) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let end = after
+            .find('$')
+            .ok_or_else(|| format!("unterminated identifier in `{}`", template))?;
+        let identifier = &after[..end];
+        rest = &after[end + 1..];
+
+        let (name, format) = match identifier.split_once('%') {
+            Some((name, format)) => (name, Some(format)),
+            None => (identifier, None),
+        };
+        let value = match name {
+            "" => {
+                expanded.push('$');
+                continue;
+            }
+            "RepresentationID" => {
+                expanded.push_str(representation.id);
+                continue;
+            }
+            "Number" => number,
+            "Time" => time,
+            "Bandwidth" => Some(representation.bandwidth),
+            _ => return Err(format!("unknown identifier `${}$`", identifier)),
+        };
+        let value = value.ok_or_else(|| format!("`${}$` in the initialization template", name))?;
+        match format {
+            Some(format) => {
+                let width = format
+                    .strip_suffix('d')
+                    .and_then(|width| width.parse::<usize>().ok())
+                    .ok_or_else(|| format!("invalid format `%{}`", format))?;
+                expanded.push_str(&format!("{:0width$}", value, width = width));
+            }
+            None => expanded.push_str(&value.to_string()),
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Parses an `xs:duration` like `PT1H2M3.5S` into seconds. Months count as 30 days and years as
+/// 365.
+fn parse_duration(value: &str) -> Result<f64, String> {
+    let invalid = || format!("invalid duration `{}`", value);
+    let rest = value.trim().strip_prefix('P').ok_or_else(invalid)?;
+    let mut seconds = 0.0;
+    let mut in_time = false;
+    let mut amount = String::new();
+    for c in rest.chars() {
+        match c {
+            'T' => in_time = true,
+            '0'..='9' | '.' => amount.push(c),
+            unit => {
+                let value: f64 = amount.parse().map_err(|_| invalid())?;
+                amount.clear();
+                let unit_seconds = match (unit, in_time) {
+                    ('Y', false) => 365.0 * 86400.0,
+                    ('M', false) => 30.0 * 86400.0,
+                    ('W', false) => 7.0 * 86400.0,
+                    ('D', false) => 86400.0,
+                    ('H', true) => 3600.0,
+                    ('M', true) => 60.0,
+                    ('S', true) => 1.0,
+                    _ => return Err(invalid()),
+                };
+                seconds += value * unit_seconds;
+            }
+        }
+    }
+    if !amount.is_empty() {
+        return Err(invalid());
+    }
+    Ok(seconds)
+}
+
+fn number<T: FromStr>(value: &str, name: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid {} `{}`", name, value))
+}
+
+fn join(base: &Url, uri: &str) -> Result<Url, String> {
+    base.join(uri).map_err(|_| format!("invalid URL `{}`", uri))
+}
+
+fn is_element(node: &Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| is_element(n, name))
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children().filter(move |n| is_element(n, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Listed = (String, Option<(u64, u64)>);
+
+    fn segments(xml: &str) -> Result<Vec<Listed>, String> {
+        let base = Url::parse("https://example.com/video/manifest.mpd").unwrap();
+        let (segments, _) = parse(&base, xml, StreamVariant::Highest)?;
+        Ok(segments
+            .into_iter()
+            .map(|s| (s.url.to_string(), s.range))
+            .collect())
+    }
+
+    fn urls(xml: &str) -> Vec<String> {
+        segments(xml)
+            .unwrap()
+            .into_iter()
+            .map(|(url, _)| url)
+            .collect()
+    }
+
+    fn template(contents: &str) -> String {
+        format!(
+            r#"<MPD mediaPresentationDuration="PT10S"><Period><AdaptationSet mimeType="video/mp4">
+            <Representation id="hd" bandwidth="2000">{}</Representation>
+            </AdaptationSet></Period></MPD>"#,
+            contents
+        )
+    }
+
+    #[test]
+    fn lists_segment_lists() {
+        let xml = r#"<MPD><Period>
+            <AdaptationSet contentType="audio"><Representation id="a"><BaseURL>a.mp4</BaseURL></Representation></AdaptationSet>
+            <AdaptationSet mimeType="video/webm">
+              <Representation id="low" bandwidth="100"><BaseURL>low.webm</BaseURL></Representation>
+              <Representation id="high" bandwidth="900">
+                <BaseURL>high/</BaseURL>
+                <SegmentList>
+                  <Initialization sourceURL="init.webm" range="0-99"/>
+                  <SegmentURL media="1.webm"/>
+                  <SegmentURL mediaRange="100-199"/>
+                </SegmentList>
+              </Representation>
+            </AdaptationSet>
+            </Period></MPD>"#;
+        let base = Url::parse("https://example.com/video/manifest.mpd").unwrap();
+        let (_, extension) = parse(&base, xml, StreamVariant::Highest).unwrap();
+        assert_eq!(extension, "webm");
+        assert_eq!(
+            segments(xml).unwrap(),
+            [
+                (
+                    "https://example.com/video/high/init.webm".to_owned(),
+                    Some((0, 100))
+                ),
+                ("https://example.com/video/high/1.webm".to_owned(), None),
+                (
+                    "https://example.com/video/high/".to_owned(),
+                    Some((100, 100))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn expands_templates_with_a_duration() {
+        let xml = template(
+            r#"<SegmentTemplate timescale="10" duration="40" startNumber="0"
+                initialization="$RepresentationID$/init.mp4" media="$RepresentationID$/$Number%03d$.m4s"/>"#,
+        );
+        assert_eq!(
+            urls(&xml),
+            [
+                "https://example.com/video/hd/init.mp4",
+                "https://example.com/video/hd/000.m4s",
+                "https://example.com/video/hd/001.m4s",
+                "https://example.com/video/hd/002.m4s",
+            ]
+        );
+    }
+
+    #[test]
+    fn expands_timelines() {
+        let xml = template(
+            r#"<SegmentTemplate media="$Time$.m4s"><SegmentTimeline>
+                <S t="5" d="2" r="1"/><S d="1"/><S t="20" d="3" r="-1"/>
+            </SegmentTimeline></SegmentTemplate>"#,
+        );
+        let base = "https://example.com/video/";
+        assert_eq!(
+            urls(&xml),
+            ["5", "7", "9", "20"].map(|t| format!("{}{}.m4s", base, t))
+        );
+
+        // A negative repeat lasts until the next entry's start, or the end of the period.
+        let xml = template(
+            r#"<SegmentTemplate media="$Time$.m4s"><SegmentTimeline>
+                <S t="0" d="3" r="-1"/><S t="9" d="1" r="-1"/>
+            </SegmentTimeline></SegmentTemplate>"#,
+        );
+        assert_eq!(
+            urls(&xml),
+            ["0", "3", "6", "9"].map(|t| format!("{}{}.m4s", base, t))
+        );
+    }
+
+    #[test]
+    fn refuses_too_many_segments() {
+        let timeline = template(
+            r#"<SegmentTemplate media="$Number$.m4s"><SegmentTimeline>
+                <S d="1" r="1000000000000"/>
+            </SegmentTimeline></SegmentTemplate>"#,
+        );
+        assert_eq!(segments(&timeline).unwrap_err(), too_many_segments());
+
+        let duration = r#"<MPD mediaPresentationDuration="PT100000000H"><Period><AdaptationSet>
+            <Representation><SegmentTemplate duration="1" media="$Number$.m4s"/></Representation>
+            </AdaptationSet></Period></MPD>"#;
+        assert_eq!(segments(duration).unwrap_err(), too_many_segments());
+    }
+
+    #[test]
+    fn refuses_overflowing_times_and_ranges() {
+        let xml = template(
+            r#"<SegmentTemplate media="$Time$.m4s"><SegmentTimeline>
+                <S t="18446744073709551610" d="5" r="2"/>
+            </SegmentTimeline></SegmentTemplate>"#,
+        );
+        assert_eq!(segments(&xml).unwrap_err(), time_overflow());
+
+        let xml = template(
+            r#"<SegmentList><SegmentURL media="a" mediaRange="0-18446744073709551615"/></SegmentList>"#,
+        );
+        assert!(segments(&xml).is_err());
+    }
+
+    #[test]
+    fn refuses_malformed_manifests() {
+        assert!(segments("<MPD>").is_err());
+        assert!(segments("<Manifest/>").is_err());
+        assert!(segments(r#"<MPD type="dynamic"><Period/></MPD>"#).is_err());
+        assert!(segments("<MPD><Period/><Period/></MPD>").is_err());
+        assert!(segments(&template(
+            r#"<SegmentList><SegmentURL mediaRange="9-1"/></SegmentList>"#
+        ))
+        .is_err());
+        assert!(segments(&template(
+            r#"<SegmentTemplate media="$Foo$.m4s" duration="1"/>"#
+        ))
+        .is_err());
+        assert!(segments(&template(
+            r#"<SegmentTemplate media="a.m4s" duration="0"/>"#
+        ))
+        .is_err());
+        assert!(segments(&template(r#"<ContentProtection/><BaseURL>a</BaseURL>"#)).is_err());
+        assert!(segments(&template("")).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! Decompression of responses sent with a `Content-Encoding`, enabled with the `decompression`
+//! feature.
+//!
+//! Byte ranges of a compressed response can't be stitched together into the uncompressed file,
+//! so only whole-file requests ask for compression. Servers that compress the file are downloaded
+//! sequentially.
+
+use flate2::{Decompress, FlushDecompress, Status};
+use reqwest::header::{HeaderMap, CONTENT_ENCODING};
+use std::io::{self, Write};
+use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+/// How much the output buffer grows by while decoding a piece of the body.
+const OUTPUT_CHUNK: usize = 64 * 1024;
+
+/// The `Accept-Encoding` sent with requests for whole files.
+pub(crate) const ACCEPT_ENCODING: &str = "gzip, br, zstd, deflate";
+
+/// Returns the `Content-Encoding` of a response, unless it isn't compressed.
+pub(crate) fn content_encoding(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty() && v != "identity")
+}
+
+/// Decodes a response body piece by piece as it arrives.
+pub(crate) enum Decoder {
+    Gzip(flate2::write::MultiGzDecoder<Vec<u8>>),
+    /// HTTP's `deflate` is the zlib format.
+    Deflate {
+        decompress: Decompress,
+        finished: bool,
+    },
+    Brotli(Box<brotli_decompressor::DecompressorWriter<Vec<u8>>>),
+    Zstd {
+        decoder: zstd::stream::raw::Decoder<'static>,
+        finished: bool,
+    },
+}
+
+impl Decoder {
+    /// Creates a decoder for the `Content-Encoding` in `headers`.
+    ///
+    /// Returns `None` for uncompressed responses and for encodings that weren't asked for, which
+    /// are written as they are.
+    pub fn from_headers(headers: &HeaderMap) -> Option<io::Result<Self>> {
+        Some(Ok(match content_encoding(headers)?.as_str() {
+            "gzip" | "x-gzip" => Self::Gzip(flate2::write::MultiGzDecoder::new(Vec::new())),
+            "deflate" => Self::Deflate {
+                decompress: Decompress::new(true),
+                finished: false,
+            },
+            "br" => Self::Brotli(Box::new(brotli_decompressor::DecompressorWriter::new(
+                Vec::new(),
+                4096,
+            ))),
+            "zstd" => match zstd::stream::raw::Decoder::new() {
+                Ok(decoder) => Self::Zstd {
+                    decoder,
+                    finished: false,
+                },
+                Err(e) => return Some(Err(e)),
+            },
+            _ => return None,
+        }))
+    }
+
+    /// Decodes the next piece of the body and returns what it decompressed to.
+    pub fn decode(&mut self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(decoder) => {
+                decoder.write_all(bytes)?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Self::Deflate {
+                decompress,
+                finished,
+            } => inflate(decompress, finished, bytes),
+            Self::Brotli(decoder) => {
+                decoder.write_all(bytes)?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Self::Zstd { decoder, finished } => decode_zstd(decoder, finished, bytes),
+        }
+    }
+
+    /// Returns the rest of the decompressed body, or an error if it was cut off.
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(mut decoder) => {
+                decoder.try_finish()?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Self::Brotli(mut decoder) => {
+                decoder.close()?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            // Both decode everything they're given right away.
+            Self::Deflate { finished, .. } | Self::Zstd { finished, .. } => match finished {
+                true => Ok(Vec::new()),
+                false => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the compressed stream ended early",
+                )),
+            },
+        }
+    }
+}
+
+/// Decodes a piece of a zlib stream. Anything after the end of the stream is ignored.
+fn inflate(decompress: &mut Decompress, finished: &mut bool, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut input = bytes;
+    while !*finished {
+        output.reserve(OUTPUT_CHUNK);
+        let read = decompress.total_in();
+        let status = decompress
+            .decompress_vec(input, &mut output, FlushDecompress::None)
+            .map_err(io::Error::other)?;
+        input = &input[(decompress.total_in() - read) as usize..];
+        *finished = status == Status::StreamEnd;
+        // Output is only held back when there's no room for it.
+        if input.is_empty() && output.len() < output.capacity() {
+            break;
+        }
+    }
+    Ok(output)
+}
+
+/// Decodes a piece of a zstd stream, which may consist of several frames.
+fn decode_zstd(
+    decoder: &mut zstd::stream::raw::Decoder<'static>,
+    finished: &mut bool,
+    bytes: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut input = InBuffer::around(bytes);
+    loop {
+        output.reserve(OUTPUT_CHUNK);
+        let position = output.len();
+        let mut buffer = OutBuffer::around_pos(&mut output, position);
+        // A hint of 0 means a frame just ended.
+        let hint = decoder.run(&mut input, &mut buffer)?;
+        let full = buffer.pos() == buffer.capacity();
+        *finished = hint == 0;
+        if input.pos() == bytes.len() && !full {
+            break;
+        }
+    }
+    Ok(output)
+}
@@ -0,0 +1,95 @@
+use crate::checksum::{self, Checksum, ChecksumAlgorithm};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_ENCODING};
+
+/// Digests of the bytes of a response, as defined by RFC 9530.
+const CONTENT_DIGEST: &str = "content-digest";
+/// Digests of the whole file, as defined by RFC 9530.
+const REPR_DIGEST: &str = "repr-digest";
+/// Digests of the whole file, as defined by RFC 3230, which RFC 9530 replaces.
+const DIGEST: &str = "digest";
+
+/// Algorithms in the order they are preferred in, when a server sends several digests.
+const PREFERRED: [ChecksumAlgorithm; 2] = [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Md5];
+
+/// A digest with the algorithm it was computed with.
+type Digest = (ChecksumAlgorithm, Vec<u8>);
+
+/// Asks servers for the digests this module can check, without replacing headers that are
+/// already set.
+pub(crate) fn want_digests(headers: &mut HeaderMap) {
+    let wanted = [
+        ("want-content-digest", "sha-256=10, md5=1"),
+        ("want-repr-digest", "sha-256=10, md5=1"),
+        ("want-digest", "sha-256, md5;q=0.1"),
+    ];
+    for (name, value) in wanted {
+        headers
+            .entry(HeaderName::from_static(name))
+            .or_insert(HeaderValue::from_static(value));
+    }
+}
+
+/// Returns the `Content-Digest` of the bytes of a response with these `headers`.
+pub(crate) fn content_digest(headers: &HeaderMap) -> Option<Checksum> {
+    preferred(headers, CONTENT_DIGEST, parse_dictionary)
+}
+
+/// Returns the digest of the whole file that a response with these `headers` describes, from
+/// `Repr-Digest` or the older `Digest` header. If the response is `complete`, holding the whole
+/// file, its `Content-Digest` is used as well.
+///
+/// Digests of compressed files are ignored, since they don't match the decompressed file.
+pub(crate) fn repr_digest(headers: &HeaderMap, complete: bool) -> Option<Checksum> {
+    let encoded = headers
+        .get(CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding != "identity");
+    if encoded {
+        return None;
+    }
+    preferred(headers, REPR_DIGEST, parse_dictionary)
+        .or_else(|| preferred(headers, DIGEST, parse_legacy))
+        .or_else(|| complete.then(|| content_digest(headers)).flatten())
+}
+
+/// Picks the digest in the header `name` with the most preferred algorithm.
+fn preferred(headers: &HeaderMap, name: &str, parse: fn(&str) -> Vec<Digest>) -> Option<Checksum> {
+    let digests: Vec<_> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(parse)
+        .collect();
+    PREFERRED.into_iter().find_map(|algorithm| {
+        digests
+            .iter()
+            .find(|(a, _)| *a == algorithm)
+            .map(|(_, digest)| Checksum::new(algorithm, &checksum::to_hex(digest)))
+    })
+}
+
+/// Parses a structured field dictionary like `sha-256=:base64:, md5=:base64:`.
+fn parse_dictionary(value: &str) -> Vec<Digest> {
+    value
+        .split(',')
+        .filter_map(|member| {
+            let (name, value) = member.split_once('=')?;
+            // Parameters follow the byte sequence.
+            let value = value.trim().strip_prefix(':')?.split(':').next()?;
+            let algorithm = ChecksumAlgorithm::from_name(name.trim())?;
+            Some((algorithm, STANDARD.decode(value).ok()?))
+        })
+        .collect()
+}
+
+/// Parses an RFC 3230 digest list like `SHA-256=base64, MD5=base64`.
+fn parse_legacy(value: &str) -> Vec<Digest> {
+    value
+        .split(',')
+        .filter_map(|member| {
+            let (name, value) = member.split_once('=')?;
+            let algorithm = ChecksumAlgorithm::from_name(name.trim())?;
+            Some((algorithm, STANDARD.decode(value.trim()).ok()?))
+        })
+        .collect()
+}
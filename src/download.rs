@@ -1,173 +1,2234 @@
-use crate::error::DownloadError;
-use futures::{stream::FuturesUnordered, StreamExt};
+use crate::{
+    adaptive::{self, Congestion, ConnectionScaler},
+    batch::BatchTracker,
+    builder::DownloaderBuilder,
+    checksum::{ChecksumSource, Hasher, OrderedHasher},
+    chunk::{self, ChunkJob, ChunkOutput},
+    digest,
+    error::{DownloadError, IoResultExt},
+    event::{DownloadEvent, EventStream},
+    filename,
+    hooks::Hooks,
+    hosts::{HostLimiter, HostPermit},
+    journal,
+    list::{UrlList, UrlListEntry},
+    local::{self, LocalFile},
+    metalink::{Metalink, MetalinkFile},
+    metrics,
+    mirrors::Mirrors,
+    options::{
+        Allocation, CancelPolicy, DownloadOptions, DuplicatePolicy, Durability, OverwritePolicy,
+    },
+    pinning::CertificatePins,
+    policy::{Blocked, UrlPolicy},
+    probe::Probe,
+    progress::{BatchProgressReporter, ChunkProgress, Progress, ProgressReporter, SpeedMeter},
+    report::DownloadReport,
+    reservation::{Reservation, Reservations},
+    resume::{self, ChunkState, ResumeState},
+    retry::{self, RetryPolicy},
+    shutdown::Shutdown,
+    staging::Staging,
+    stall::{MinSpeed, StallDetector},
+    storage::{self, SharedFile},
+    template,
+    throttle::Throttle,
+    trace,
+};
+use futures::{
+    stream::{self, FuturesUnordered},
+    Stream, StreamExt,
+};
+use reqwest::{
+    header::{HeaderMap, CONTENT_TYPE},
+    StatusCode,
+};
 use std::{
-    io::SeekFrom,
+    collections::{HashMap, VecDeque},
+    future::Future,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::{
     fs,
-    io::{AsyncSeekExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
 };
 
+/// How often the bytes of a parallel download that couldn't be hashed while they were written are
+/// read back, if the file has a checksum.
+const READ_BACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Chunks are only split for work stealing if both halves are at least this large.
+const MIN_SPLIT_SIZE: u64 = 1024 * 1024;
+
+/// Largest piece of a file that is buffered per connection when streaming to a writer.
+const MAX_STREAM_PIECE_SIZE: u64 = 4 * 1024 * 1024;
+
+pub(crate) const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How often the resume state of a running parallel download is saved, so little is lost in a crash.
+pub(crate) const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct Downloader {
-    client: reqwest::Client,
-    output_dir: PathBuf,
-    conn_count: usize,
+    pub(crate) client: reqwest::Client,
+    /// Headers sent with every request to the hosts a download sends its own headers to.
+    pub(crate) headers: HeaderMap,
+    pub(crate) output_dir: PathBuf,
+    pub(crate) conn_count: usize,
+    pub(crate) chunk_size: Option<u64>,
+    pub(crate) chunk_count_bounds: (usize, usize),
+    pub(crate) parallel_threshold: Option<u64>,
+    pub(crate) work_stealing: bool,
+    pub(crate) adaptive_connections: bool,
+    pub(crate) max_concurrent_files: usize,
+    #[cfg(any(feature = "ftp", feature = "sftp"))]
+    pub(crate) network: crate::net::Network,
+    #[cfg(feature = "ftp")]
+    pub(crate) tls: crate::tls::TlsConfig,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) min_speed: Option<MinSpeed>,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) throttle: Throttle,
+    pub(crate) hosts: Arc<HostLimiter>,
+    pub(crate) resume: bool,
+    pub(crate) chunk_journal: bool,
+    pub(crate) allocation: Allocation,
+    pub(crate) read_buffer_size: usize,
+    pub(crate) write_buffer_size: usize,
+    pub(crate) verify_digests: bool,
+    pub(crate) discover_checksums: bool,
+    pub(crate) cancel_policy: CancelPolicy,
+    pub(crate) durability: Durability,
+    pub(crate) overwrite_policy: OverwritePolicy,
+    pub(crate) manifest: Option<crate::manifest::Manifest>,
+    pub(crate) max_file_size: Option<u64>,
+    pub(crate) url_policy: Option<Arc<UrlPolicy>>,
+    pub(crate) reservations: Reservations,
+    pub(crate) allowed_content_types: Option<Vec<String>>,
+    pub(crate) infer_extensions: bool,
+    pub(crate) duplicate_policy: DuplicatePolicy,
+    pub(crate) output_template: Option<String>,
+    pub(crate) host_directories: bool,
+    pub(crate) staging: Option<Staging>,
+    pub(crate) progress: Option<Arc<dyn ProgressReporter>>,
+    pub(crate) progress_interval: Duration,
+    pub(crate) batch_progress: Option<Arc<dyn BatchProgressReporter>>,
+    pub(crate) batch_report: Option<PathBuf>,
+    pub(crate) hooks: Hooks,
+    #[cfg(feature = "extract")]
+    pub(crate) extract: crate::extract::ExtractConfig,
+    #[cfg(feature = "sftp")]
+    pub(crate) sftp: crate::sftp::SftpConfig,
+    #[cfg(feature = "s3")]
+    pub(crate) s3: crate::s3::S3Config,
+    #[cfg(feature = "gcs")]
+    pub(crate) gcs: crate::gcs::GcsConfig,
+    #[cfg(feature = "azure")]
+    pub(crate) azure: crate::azure::AzureConfig,
+    #[cfg(feature = "ipfs")]
+    pub(crate) ipfs: crate::ipfs::IpfsConfig,
+    #[cfg(feature = "lfs")]
+    pub(crate) hf: crate::lfs::HfConfig,
+    #[cfg(feature = "oci")]
+    pub(crate) oci: crate::oci::OciConfig,
+    #[cfg(feature = "http3")]
+    pub(crate) http3: Option<Arc<crate::http3::Http3>>,
+    pub(crate) pins: Option<Arc<CertificatePins>>,
+    #[cfg(feature = "decompression")]
+    pub(crate) decompress: bool,
+    pub(crate) shutdown: Shutdown,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub(crate) uring: Option<Arc<crate::uring::Uring>>,
+    #[cfg(feature = "mmap")]
+    pub(crate) memory_map: bool,
 }
 
 impl Downloader {
     /// Creates a new Downloader
     pub fn new(output_dir: &str, conn_count: usize) -> Self {
-        let conn_count = if conn_count > 0 { conn_count } else { 1 };
-        Self {
-            client: reqwest::Client::new(),
-            output_dir: PathBuf::from(output_dir),
-            conn_count,
-        }
+        Self::builder(output_dir, conn_count)
+            .build()
+            .expect("failed to initialize the HTTP client")
+    }
+
+    /// Like [`Downloader::new`], but creates `output_dir` first and returns an error if files
+    /// can't be created in it, or if the HTTP client can't be initialized.
+    pub fn try_new(output_dir: &str, conn_count: usize) -> Result<Self, DownloadError> {
+        Self::builder(output_dir, conn_count)
+            .create_output_dir(true)
+            .build()
+    }
+
+    /// Returns a [`DownloaderBuilder`] for configuring the HTTP client and timeouts.
+    pub fn builder(output_dir: &str, conn_count: usize) -> DownloaderBuilder {
+        DownloaderBuilder::new(output_dir, conn_count)
     }
 
     /// Downloads the file at the given `url` with the best possible strategy.
     pub async fn download(&self, url: &str) -> Result<PathBuf, DownloadError> {
-        let response = self.client.head(url).send().await?;
-        let headers = response.headers();
-        let content_length: u64 = headers
-            .get(reqwest::header::CONTENT_LENGTH)
-            .and_then(|v| v.to_str().ok())
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(0);
+        self.download_with(url, &DownloadOptions::default()).await
+    }
 
-        let accept_ranges = headers
-            .get(reqwest::header::ACCEPT_RANGES)
-            .and_then(|v| v.to_str().ok())
-            // .and_then(|v| Some(v.trim() != "none"))
-            .is_some();
+    /// Like [`Downloader::download`], with settings that only apply to this download.
+    pub async fn download_with(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<PathBuf, DownloadError> {
+        Ok(self.download_with_report(url, options).await?.path)
+    }
+
+    /// Like [`Downloader::download_with`], but returns details about the download instead of just the path.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "download", skip_all, fields(url = %url)))]
+    pub async fn download_with_report(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        let _running = self.shutdown.enter();
+        let result = match self.shutdown.is_shut_down() {
+            true => Err(DownloadError::Cancelled),
+            false => {
+                self.download_url(url, &options.started(url, self.shutdown.token()))
+                    .await
+            }
+        };
+        #[cfg(feature = "extract")]
+        let result = match result {
+            Ok(report) => self.extract_download(report, options).await,
+            Err(e) => Err(e),
+        };
+        self.hooks.run(url, &result, started.elapsed()).await;
+        metrics::record_outcome(&result);
+        result
+    }
+
+    /// Stops all downloads for good, for example when the process receives `SIGTERM`, and waits
+    /// until they have returned.
+    ///
+    /// Running downloads stop requesting data and fail with [`DownloadError::Cancelled`], as do
+    /// downloads waiting for a connection and any started later. If resuming is enabled, partial
+    /// files are synced to disk and kept with their resume state, whatever the cancel policy.
+    pub async fn shutdown(&self) {
+        self.shutdown.run().await;
+    }
+
+    async fn download_url(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        self.check_policy(url)?;
+        if let Some(local) = local::open(url).await? {
+            return self.download_local(url, local, options).await;
+        }
+        #[cfg(feature = "ftp")]
+        if crate::ftp::is_ftp_url(url) {
+            return self.download_ftp(url, options).await;
+        }
+        #[cfg(feature = "sftp")]
+        if crate::sftp::is_sftp_url(url) {
+            return self.download_sftp(url, options).await;
+        }
+        #[cfg(feature = "ipfs")]
+        if crate::ipfs::is_ipfs_url(url) {
+            return self.download_ipfs(url, options).await;
+        }
+        #[cfg(feature = "lfs")]
+        if crate::lfs::is_hf_url(url) {
+            let (file_url, options) = self.resolve_hf_url(url, options).await?;
+            let mut report = self.download_http(&file_url, &options).await?;
+            report.url = url.to_owned();
+            return Ok(report);
+        }
+        #[cfg(feature = "oci")]
+        if crate::oci::is_oci_url(url) {
+            let (blob_url, options) = self.resolve_oci_url(url, options).await?;
+            let mut report = self.download_http(&blob_url, &options).await?;
+            report.url = url.to_owned();
+            return Ok(report);
+        }
+        #[cfg(any(feature = "s3", feature = "azure"))]
+        if let Some((object_url, options)) = self.resolve_storage_url(url, options)? {
+            let mut report = self.download_http(&object_url, &options).await?;
+            report.url = url.to_owned();
+            return Ok(report);
+        }
+
+        self.download_http(url, options).await
+    }
+
+    /// Like [`Downloader::download_with_report`], but reports what happens during the download as a
+    /// stream of events.
+    ///
+    /// The stream ends with [`DownloadEvent::Completed`] or [`DownloadEvent::Failed`]. The download
+    /// only runs while the stream is polled, and dropping the stream aborts it.
+    pub fn download_events<'a>(
+        &'a self,
+        url: &'a str,
+        options: &DownloadOptions,
+    ) -> impl Stream<Item = DownloadEvent> + Unpin + 'a {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let options = DownloadOptions {
+            events: Some(sender),
+            ..options.clone()
+        };
+        EventStream::new(
+            async move { self.download_with_report(url, &options).await },
+            receiver,
+        )
+    }
+
+    /// Downloads an `http://` or `https://` URL, or one of its mirrors, unless it's unchanged since
+    /// the last download.
+    pub(crate) async fn download_http(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        check_scheme(url)?;
+        let options = &options.scoped(url);
+        if let Some(report) = self.check_unchanged(url, options).await? {
+            return Ok(report);
+        }
+        let report = self.fetch_http(url, options).await?;
+        self.remember_validators(&report, options).await?;
+        Ok(report)
+    }
+
+    async fn fetch_http(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let mirrors = Arc::new(Mirrors::new(url, &options.mirrors));
+        for url in mirrors.urls() {
+            check_scheme(url)?;
+            self.check_policy(url)?;
+        }
+        let discovered = self.discover_checksum(url, options).await;
+        let options = discovered.as_ref().unwrap_or(options);
+
+        let with_filename = |mut probe: Probe| {
+            if let Some(filename) = &options.filename {
+                probe.filename = Some(filename.clone());
+            }
+            probe
+        };
 
-        println!(
-            "accept_ranges: {}, content_length: {}",
-            accept_ranges, content_length
+        let mut probe = match self.parallel_threshold {
+            Some(threshold) => {
+                // The response to a request for the whole file describes it as well as a probe.
+                let started = Instant::now();
+                let (response, permit) = self.get_from_mirrors(&mirrors, options).await?;
+                let probe = with_filename(Probe::from_head(&response));
+                let small = probe.content_length.is_some_and(|len| len < threshold);
+                if small || (probe.is_conclusive() && !probe.supports_ranges()) {
+                    debug!(content_length = ?probe.content_length, "downloading sequentially");
+                    let url = mirrors.primary();
+                    self.check_size(url, probe.content_length, options)?;
+                    self.check_content_type(url, &probe.headers, options)?;
+                    let name =
+                        self.output_name(url, probe.filename.as_deref(), &probe.headers, options);
+                    if let Some(report) = self.check_existing(url, &name, &probe, options).await? {
+                        return Ok(report);
+                    }
+                    return self
+                        .save_response(url, &probe, response, options, started)
+                        .await;
+                }
+                drop((response, permit));
+                match probe.is_conclusive() {
+                    true => probe,
+                    false => with_filename(self.probe_mirrors(&mirrors, options).await?),
+                }
+            }
+            None => with_filename(self.probe_mirrors(&mirrors, options).await?),
+        };
+
+        debug!(
+            accept_ranges = ?probe.accept_ranges,
+            content_length = ?probe.content_length,
+            "probed remote file"
         );
+        self.check_size(url, probe.content_length, options)?;
+        self.check_content_type(url, &probe.headers, options)?;
 
-        let output_path = if content_length > 0 && accept_ranges {
-            self.parallel(url, content_length).await?
+        if probe.supports_ranges() {
+            let result = match self.parallel_with(&mirrors, &probe, options).await {
+                Err(e) if e.is_remote_changed() => {
+                    info!("remote file changed during the download, starting over");
+                    probe = Probe {
+                        filename: probe.filename,
+                        ..self.probe_mirrors(&mirrors, options).await?
+                    };
+                    self.check_size(url, probe.content_length, options)?;
+                    self.parallel_with(&mirrors, &probe, options).await
+                }
+                result => result,
+            };
+            match result {
+                Err(e) if e.is_range_ignored() => {
+                    info!("server ignored the requested byte ranges, downloading sequentially");
+                    self.sequential_with(&mirrors, &probe, options).await
+                }
+                result => result,
+            }
         } else {
-            self.sequential(url).await?
+            debug!("server doesn't support range requests, downloading sequentially");
+            self.sequential_with(&mirrors, &probe, options).await
+        }
+    }
+
+    /// Copies a `file://` URL or decodes a `data:` URL into the output directory.
+    async fn download_local(
+        &self,
+        url: &str,
+        mut local: LocalFile,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        let filename = options.filename.as_deref().or(local.filename.as_deref());
+        let name = self.output_name(url, filename, &local.headers, options);
+        let probe = Probe {
+            content_length: Some(local.len),
+            headers: local.headers.clone(),
+            ..Probe::default()
+        };
+        if let Some(report) = self.check_existing(url, &name, &probe, options).await? {
+            return Ok(report);
+        }
+        self.check_size(url, Some(local.len), options)?;
+
+        let reservation = self.reserve_output_path(&name, options);
+        let output_path = reservation.path.clone();
+        let report = DownloadReport {
+            path: output_path.clone(),
+            url: url.to_owned(),
+            final_url: url.to_owned(),
+            status: None,
+            headers: local.headers.clone(),
+            size: local.len,
+            bytes_downloaded: 0,
+            elapsed: Duration::ZERO,
+            retries: 0,
+            checksum: None,
+            checksum_source: None,
+            extracted_to: None,
+            chunks: Vec::new(),
+        };
+        if let Some(source) = &local.path {
+            // Copying a file onto itself would truncate it.
+            if fs::canonicalize(source).await.ok() == fs::canonicalize(&output_path).await.ok() {
+                return Ok(report);
+            }
+        }
+
+        let partial = self.partial_path(&output_path);
+        create_parent_dir(&partial).await?;
+        let existing = fs::metadata(&partial).await.map_or(0, |m| m.len());
+        storage::check_space(&partial, local.len.saturating_sub(existing))?;
+        let mut file = fs::File::create(&partial).await.with_path(&partial)?;
+        options.emit(|| DownloadEvent::Started {
+            path: output_path.clone(),
+            total: Some(local.len),
+        });
+
+        let mut hasher = options.checksum.as_ref().map(|c| Hasher::new(c.algorithm));
+        let result = self
+            .copy_local(
+                url,
+                &mut local,
+                &mut file,
+                Some(&partial),
+                options,
+                &mut hasher,
+            )
+            .await;
+        drop(file);
+
+        let result = result.and_then(|copied| match (&options.checksum, hasher) {
+            (Some(checksum), Some(hasher)) => checksum.verify(hasher).map(|()| copied),
+            _ => Ok(copied),
+        });
+        let copied = match result {
+            Ok(copied) => copied,
+            Err(e) => {
+                let keep = matches!(e, DownloadError::Cancelled) && !self.discards_cancelled(&e);
+                if !keep {
+                    remove_partial(&partial).await.with_path(&partial)?;
+                }
+                return Err(e);
+            }
+        };
+        self.make_durable(&partial).await?;
+        self.move_into_place(&partial, &output_path).await?;
+
+        Ok(DownloadReport {
+            size: copied,
+            bytes_downloaded: copied,
+            elapsed: started.elapsed(),
+            checksum: options.checksum.clone(),
+            checksum_source: options.checksum_source.clone(),
+            ..report
+        })
+    }
+
+    /// Copies the contents of `local` into `writer`, reporting progress like a sequential download.
+    async fn copy_local<W>(
+        &self,
+        url: &str,
+        local: &mut LocalFile,
+        writer: &mut W,
+        path: Option<&Path>,
+        options: &DownloadOptions,
+        hasher: &mut Option<Hasher>,
+    ) -> Result<u64, DownloadError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buffer = vec![0; self.read_buffer_size];
+        let mut meter = SpeedMeter::new(0);
+        let mut last_report = Instant::now();
+        let mut copied = 0;
+
+        loop {
+            let read = options
+                .or_cancelled(async {
+                    options.unpaused().await;
+                    local.reader.read(&mut buffer).await
+                })
+                .await?
+                .map_err(|e| write_error(e, local.path.as_deref()))?;
+            if read == 0 {
+                break;
+            }
+
+            write_to(writer, &buffer[..read], path).await?;
+            copied += read as u64;
+            if let Some(hasher) = hasher {
+                hasher.update(&buffer[..read]);
+            }
+
+            if last_report.elapsed() >= self.progress_interval {
+                self.report_sequential_progress(url, options, copied, Some(local.len), &mut meter);
+                last_report = Instant::now();
+            }
+        }
+        writer.flush().await.map_err(|e| write_error(e, path))?;
+
+        self.report_sequential_progress(url, options, copied, Some(local.len), &mut meter);
+        Ok(copied)
+    }
+
+    /// Probes the mirrors in order until one of them responds.
+    pub(crate) async fn probe_mirrors(
+        &self,
+        mirrors: &Mirrors,
+        options: &DownloadOptions,
+    ) -> Result<Probe, DownloadError> {
+        let mut last_error = None;
+
+        for url in mirrors.urls() {
+            match self.probe_remote(url, options).await {
+                Ok(probe) => return Ok(probe),
+                Err(e) if e.is_remote() => {
+                    warn!(mirror = url, error = %e, "failed to probe mirror");
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(last_error.expect("there is at least one mirror"))
+    }
+
+    /// Sends the request built by `request` for `url`, over HTTP/3 if the server supports it, and
+    /// checks that the response came from a server with a pinned key.
+    pub(crate) async fn send_request(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+        request: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, DownloadError> {
+        self.check_policy(url)?;
+        let request =
+            |client: &reqwest::Client| options.shared_headers(url, &self.headers, request(client));
+        #[cfg(feature = "http3")]
+        let response = match &self.http3 {
+            Some(http3) => http3.send(&self.client, url, options, request).await?,
+            None => options.send(url, || request(&self.client)).await?,
         };
+        #[cfg(not(feature = "http3"))]
+        let response = options.send(url, || request(&self.client)).await?;
+        if let Some(pins) = &self.pins {
+            pins.check(&response)?;
+        }
+        Ok(response)
+    }
 
-        Ok(output_path)
+    /// Checks that the [`UrlPolicy`] allows requests to `url`, if there is one.
+    pub(crate) fn check_policy(&self, url: &str) -> Result<(), DownloadError> {
+        match &self.url_policy {
+            Some(policy) => policy
+                .check(url)
+                .map_err(|Blocked { url, reason }| DownloadError::Blocked { url, reason }),
+            None => Ok(()),
+        }
+    }
+
+    /// Asks for a compressed response if decompression is enabled. Only used for requests of
+    /// whole files.
+    fn accept_encoding(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        #[cfg(feature = "decompression")]
+        if self.decompress {
+            return request.header(
+                reqwest::header::ACCEPT_ENCODING,
+                crate::decompress::ACCEPT_ENCODING,
+            );
+        }
+        request
     }
 
-    pub async fn download_multiple(
-        &'static self,
-        urls: &[String],
-    ) -> Result<Vec<PathBuf>, DownloadError> {
-        let chunked = urls.chunks(self.conn_count);
+    /// Finds out the size of the file and whether the server supports range requests.
+    ///
+    /// Asks with a `HEAD` request first. If that fails or is inconclusive, requests the first byte of the file instead.
+    async fn probe_remote(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<Probe, DownloadError> {
+        let permit = options.or_cancelled(self.hosts.acquire(url)).await?;
+        let head = options
+            .or_cancelled(self.send_request(url, options, |client| {
+                self.accept_encoding(client.head(url))
+            }))
+            .await?;
+        drop(permit);
 
-        for batch in chunked {
-            let mut futures: FuturesUnordered<_> = batch
+        let head = match head {
+            Ok(response) => {
+                let probe = Probe::from_head(&response);
+                if probe.is_conclusive() {
+                    return Ok(probe);
+                }
+                Some(probe)
+            }
+            Err(_) => None,
+        };
+        debug!("HEAD request was inconclusive, requesting the first byte");
+
+        let request =
+            |client: &reqwest::Client| client.get(url).header(reqwest::header::RANGE, "bytes=0-0");
+        let _permit = options.or_cancelled(self.hosts.acquire(url)).await?;
+        let response = match options
+            .or_cancelled(self.send_request(url, options, request))
+            .await?
+        {
+            Ok(response) => response,
+            Err(e) => return head.ok_or(e),
+        };
+
+        let mut probe = Probe::from_range_probe(&response);
+        if let Some(head) = head {
+            probe.filename = probe.filename.or(head.filename);
+            probe.content_length = probe.content_length.or(head.content_length);
+            probe.validator = probe.validator.or(head.validator);
+            #[cfg(feature = "decompression")]
+            {
+                probe.content_encoding = head.content_encoding;
+            }
+        }
+
+        Ok(probe)
+    }
+
+    /// Downloads all `urls`, running up to `max_concurrent_files` downloads at once.
+    ///
+    /// The results are in the same order as `urls`. A failed download does not stop the others.
+    /// URLs that are listed more than once are downloaded once, and their other occurrences
+    /// handled according to the duplicate policy.
+    pub async fn download_multiple(&self, urls: &[String]) -> Vec<Result<PathBuf, DownloadError>> {
+        let batch = BatchTracker::new(urls.iter().map(String::as_str));
+        // The index of the first occurrence of every URL.
+        let mut first = HashMap::new();
+        let originals: Vec<usize> = urls
+            .iter()
+            .enumerate()
+            .map(|(index, url)| *first.entry(url.as_str()).or_insert(index))
+            .collect();
+
+        let downloads = async {
+            let mut results: Vec<_> = urls.iter().map(|_| None).collect();
+            let unique = urls
                 .iter()
-                .map(|url| {
-                    let url = url.to_string();
-                    tokio::spawn(async move { self.sequential(&url).await })
+                .enumerate()
+                .filter(|(index, _)| originals[*index] == *index);
+            let mut downloads = stream::iter(unique)
+                .map(|(index, url)| {
+                    let batch = &batch;
+                    async move {
+                        let result = self
+                            .download_in_batch(batch, index, url, &DownloadOptions::default())
+                            .await;
+                        batch.finish(index, &result);
+                        (index, result)
+                    }
                 })
-                .collect();
+                .buffered(self.max_concurrent_files);
+            while let Some((index, result)) = downloads.next().await {
+                results[index] = Some(result);
+            }
+
+            for (index, url) in urls.iter().enumerate() {
+                let original = originals[index];
+                if original != index {
+                    let first = results[original].as_ref();
+                    let result = self
+                        .duplicate(url, first.expect("first occurrences are downloaded"))
+                        .await;
+                    batch.finish(index, &result);
+                    results[index] = Some(result);
+                }
+            }
+            results
+                .into_iter()
+                .map(|result| {
+                    result
+                        .expect("every URL has a result")
+                        .map(|report| report.path)
+                })
+                .collect()
+        };
+        self.report_batch(&batch, downloads).await
+    }
+
+    /// Returns the report for another occurrence of `url` in a batch, whose first occurrence was
+    /// downloaded with `original`.
+    async fn duplicate(
+        &self,
+        url: &str,
+        original: &Result<DownloadReport, DownloadError>,
+    ) -> Result<DownloadReport, DownloadError> {
+        let original = match original {
+            Ok(report) => report,
+            Err(e) => {
+                return Err(DownloadError::DuplicateFailed {
+                    url: url.to_owned(),
+                    reason: e.to_string(),
+                })
+            }
+        };
+        let mut report = DownloadReport {
+            bytes_downloaded: 0,
+            elapsed: Duration::ZERO,
+            retries: 0,
+            chunks: Vec::new(),
+            ..original.clone()
+        };
+        if self.duplicate_policy == DuplicatePolicy::SamePath {
+            return Ok(report);
         }
 
-        Ok(Vec::new())
+        let name = original
+            .path
+            .strip_prefix(&self.output_dir)
+            .unwrap_or(&original.path);
+        let reservation = self.reservations.reserve(
+            self.output_path_candidates(&name.to_string_lossy()),
+            |path| !path.exists(),
+        );
+        let path = reservation.path.clone();
+        let linked = self.duplicate_policy == DuplicatePolicy::Hardlink
+            && fs::hard_link(&original.path, &path).await.is_ok();
+        if !linked {
+            fs::copy(&original.path, &path).await.with_path(&path)?;
+        }
+        debug!(path = %path.display(), "saved duplicate download");
+        report.path = path;
+        Ok(report)
     }
 
-    /// Assumes that the host supports [Range requests](https://developer.mozilla.org/en-US/docs/Web/HTTP/Range_requests) and tries to download the file at the given `url` in parallel.
-    pub async fn parallel(&self, url: &str, content_length: u64) -> Result<PathBuf, DownloadError> {
-        let chunk_size = content_length / self.conn_count as u64;
-        let output_path = self.get_output_path(url);
-
-        let mut futures: FuturesUnordered<_> = (0..self.conn_count)
-            .map(|i| {
-                let start = i as u64 * chunk_size;
-                let end = if i == self.conn_count - 1 {
-                    content_length - 1
-                } else {
-                    start + chunk_size - 1
-                };
+    /// Downloads one file of a batch, keeping track of its progress if the batch is reported.
+    pub(crate) async fn download_in_batch(
+        &self,
+        batch: &BatchTracker,
+        index: usize,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        if self.batch_progress.is_none() && self.batch_report.is_none() {
+            return self.download_with_report(url, options).await;
+        }
+        batch.start(index);
+        let mut events = self.download_events(url, options);
+        while let Some(event) = events.next().await {
+            match event {
+                DownloadEvent::Completed(report) => return Ok(report),
+                DownloadEvent::Failed(e) => return Err(e),
+                event => batch.update(index, event),
+            }
+        }
+        unreachable!("the events of a download end with its outcome")
+    }
 
-                let client = self.client.clone();
-                let range = format!("bytes={}-{}", start, end);
-                let url = url.to_string();
-                let output_path = output_path.clone();
-
-                tokio::spawn(async move {
-                    let mut file = fs::OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .open(&output_path)
-                        .await?;
-
-                    let mut stream = client
-                        .get(&url)
-                        .header(reqwest::header::RANGE, range)
-                        .send()
-                        .await?
-                        .bytes_stream();
-
-                    file.seek(SeekFrom::Start(start)).await?;
-
-                    while let Some(chunk) = stream.next().await {
-                        file.write_all(&chunk?).await?;
+    /// Runs the downloads of a batch, reporting their combined progress every progress interval,
+    /// and writes the batch report once they are done.
+    pub(crate) async fn report_batch<T>(
+        &self,
+        batch: &BatchTracker,
+        downloads: impl Future<Output = T>,
+    ) -> T {
+        let output = match &self.batch_progress {
+            Some(reporter) => {
+                tokio::pin!(downloads);
+                let mut ticker = tokio::time::interval(self.progress_interval);
+                let output = loop {
+                    tokio::select! {
+                        output = &mut downloads => break output,
+                        _ = ticker.tick() => reporter.report(&batch.snapshot()),
                     }
+                };
+                reporter.report(&batch.snapshot());
+                output
+            }
+            None => downloads.await,
+        };
+        if let Some(path) = &self.batch_report {
+            let report = batch.report();
+            let contents = match path.extension().and_then(|extension| extension.to_str()) {
+                Some(extension) if extension.eq_ignore_ascii_case("csv") => report.to_csv(),
+                _ => report.to_json(),
+            };
+            if let Err(_e) = fs::write(path, contents).await {
+                warn!(path = %path.display(), error = %_e, "failed to write the batch report");
+            }
+        }
+        output
+    }
 
-                    Ok::<(), DownloadError>(())
+    /// Downloads every file listed in the Metalink document at `url`.
+    ///
+    /// Each file is fetched from all of its mirrors and verified against the strongest published hash.
+    pub async fn download_metalink(
+        &self,
+        url: &str,
+    ) -> Result<Vec<Result<DownloadReport, DownloadError>>, DownloadError> {
+        let _permit = self.hosts.acquire(url).await;
+        let response = self
+            .send_request(url, &DownloadOptions::default(), |client| client.get(url))
+            .await?;
+        let metalink = Metalink::parse(&response.text().await?)?;
+        Ok(self.download_metalink_files(&metalink).await)
+    }
+
+    /// Downloads the files of an already parsed Metalink document.
+    pub async fn download_metalink_files(
+        &self,
+        metalink: &Metalink,
+    ) -> Vec<Result<DownloadReport, DownloadError>> {
+        let batch = BatchTracker::new(
+            metalink
+                .files
+                .iter()
+                .map(|file| file.urls.first().map_or(file.name.as_str(), String::as_str)),
+        );
+        let downloads = stream::iter(metalink.files.iter().enumerate())
+            .map(|(index, file)| {
+                let batch = &batch;
+                async move {
+                    let result = self.download_metalink_file(batch, index, file).await;
+                    batch.finish(index, &result);
+                    result
+                }
+            })
+            .buffered(self.max_concurrent_files)
+            .collect();
+        self.report_batch(&batch, downloads).await
+    }
+
+    async fn download_metalink_file(
+        &self,
+        batch: &BatchTracker,
+        index: usize,
+        file: &MetalinkFile,
+    ) -> Result<DownloadReport, DownloadError> {
+        let Some((url, mirrors)) = file.urls.split_first() else {
+            return Err(DownloadError::InvalidMetalink(format!(
+                "no HTTP mirrors for {}",
+                file.name
+            )));
+        };
+
+        let mut options = DownloadOptions::new()
+            .filename(&file.name)
+            .mirrors(mirrors.iter().cloned());
+        if let Some(checksum) = &file.checksum {
+            options = options.checksum(checksum.clone());
+        }
+
+        let report = self.download_in_batch(batch, index, url, &options).await?;
+        match file.size {
+            Some(expected) if expected != report.size => {
+                Err(DownloadError::ContentLengthMismatch {
+                    expected,
+                    actual: report.size,
                 })
+            }
+            _ => Ok(report),
+        }
+    }
+
+    /// Downloads every file listed in a [`UrlList`] read from `reader`, like the standard input.
+    ///
+    /// Fails only if `reader` can't be read. The results are in the order the files are listed,
+    /// with [`DownloadError::InvalidUrlList`] for those whose lines are invalid.
+    pub async fn download_from_list(
+        &self,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<Vec<Result<DownloadReport, DownloadError>>, DownloadError> {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).await?;
+        Ok(self.download_url_list(UrlList::parse(&contents)).await)
+    }
+
+    /// Downloads every file listed in the [`UrlList`] at `path`.
+    pub async fn download_from_list_file(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<Result<DownloadReport, DownloadError>>, DownloadError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).await.with_path(path)?;
+        Ok(self.download_url_list(UrlList::parse(&contents)).await)
+    }
+
+    /// Downloads the files of an already parsed [`UrlList`], running up to `max_concurrent_files`
+    /// downloads at once.
+    pub async fn download_url_list(
+        &self,
+        list: UrlList,
+    ) -> Vec<Result<DownloadReport, DownloadError>> {
+        let batch = BatchTracker::new(list.entries.iter().map(|entry| match entry {
+            Ok(entry) => entry.urls.first().map_or("", String::as_str),
+            Err(_) => "",
+        }));
+        let downloads = stream::iter(list.entries.into_iter().enumerate())
+            .map(|(index, entry)| {
+                let batch = &batch;
+                async move {
+                    let result = match entry {
+                        Ok(entry) => self.download_list_entry(batch, index, &entry).await,
+                        Err(e) => Err(e),
+                    };
+                    batch.finish(index, &result);
+                    result
+                }
             })
+            .buffered(self.max_concurrent_files)
+            .collect();
+        self.report_batch(&batch, downloads).await
+    }
+
+    async fn download_list_entry(
+        &self,
+        batch: &BatchTracker,
+        index: usize,
+        entry: &UrlListEntry,
+    ) -> Result<DownloadReport, DownloadError> {
+        let (url, mirrors) = entry
+            .urls
+            .split_first()
+            .expect("entries of URL lists have a URL");
+        let mut options = DownloadOptions::new().mirrors(mirrors.iter().cloned());
+        if let Some(filename) = &entry.filename {
+            options = options.filename(filename);
+        }
+        if let Some(checksum) = &entry.checksum {
+            options = options.checksum(checksum.clone());
+        }
+        self.download_in_batch(batch, index, url, &options).await
+    }
+
+    /// Assumes that the host supports [Range requests](https://developer.mozilla.org/en-US/docs/Web/HTTP/Range_requests) and tries to download the file at the given `url` in parallel.
+    ///
+    /// If resuming is enabled, an unfinished download of the same file is continued instead of started over.
+    pub async fn parallel(&self, url: &str, content_length: u64) -> Result<PathBuf, DownloadError> {
+        let probe = Probe::with_ranges(content_length);
+        let mirrors = Arc::new(Mirrors::new(url, &[]));
+        let report = self
+            .parallel_with(&mirrors, &probe, &DownloadOptions::default())
+            .await?;
+        Ok(report.path)
+    }
+
+    async fn parallel_with(
+        &self,
+        mirrors: &Arc<Mirrors>,
+        probe: &Probe,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        let url = mirrors.primary();
+        let content_length = probe.content_length.unwrap_or_default();
+        let name = self.output_name(url, probe.filename.as_deref(), &probe.headers, options);
+        let validator = probe.validator.as_deref();
+        let with_digest = self.with_digest(options, &probe.headers, false);
+        let options = with_digest.as_ref().unwrap_or(options);
+        let resumable = self
+            .find_resumable(url, content_length, validator, &name)
+            .await;
+        let mut reservation = None;
+        let (output_path, state) = match resumable {
+            Some(resumable) => resumable,
+            None => {
+                if let Some(report) = self.check_existing(url, &name, probe, options).await? {
+                    return Ok(report);
+                }
+                let reserved = reservation.insert(self.reserve_output_path(&name, options));
+                (
+                    reserved.path.clone(),
+                    ResumeState::new(
+                        url,
+                        content_length,
+                        validator,
+                        self.chunk_count(content_length),
+                    ),
+                )
+            }
+        };
+        let state = Arc::new(state);
+        let partial = self.partial_path(&output_path);
+
+        create_parent_dir(&partial).await?;
+        storage::check_space(&partial, content_length.saturating_sub(state.written()))?;
+        let file = storage::preallocate(&partial, content_length, self.allocation)
+            .await
+            .with_path(&partial)?;
+        self.verify_regions(&state, &file, &partial)?;
+        let output = self.chunk_output(file.clone(), &partial);
+        let hasher = options
+            .checksum
+            .as_ref()
+            .map(|c| Arc::new(OrderedHasher::new(c.algorithm)));
+
+        if self.resume {
+            state.save(&partial).await?;
+        }
+        options.emit(|| DownloadEvent::Started {
+            path: output_path.clone(),
+            total: Some(content_length),
+        });
+
+        let congestion = Arc::new(Congestion::default());
+        let spawn_chunk = |chunk| {
+            let job = ChunkJob {
+                client: self.client.clone(),
+                headers: self.headers.clone(),
+                mirrors: mirrors.clone(),
+                output: output.clone(),
+                chunk,
+                options: options.clone(),
+                read_timeout: self.read_timeout,
+                min_speed: self.min_speed,
+                retry: self.retry.clone(),
+                throttle: self.throttle.clone(),
+                hosts: self.hosts.clone(),
+                congestion: congestion.clone(),
+                validator: probe.validator.clone(),
+                sync: self.durability == Durability::FsyncPerChunk,
+                write_buffer_size: self.write_buffer_size,
+                hasher: hasher.clone(),
+                verify_digests: self.verify_digests,
+                #[cfg(feature = "http3")]
+                http3: self.http3.clone(),
+                pins: self.pins.clone(),
+            };
+            tokio::spawn(trace::in_current_span(job.run()))
+        };
+
+        let mut pending: VecDeque<_> = state
+            .chunks()
+            .into_iter()
+            .filter(|chunk| !chunk.is_complete())
+            .collect();
+        let initially_written = state.written();
+        let mut scaler = self
+            .adaptive_connections
+            .then(|| ConnectionScaler::new(self.conn_count, initially_written));
+        // Lowered for good when the server turns away concurrent range requests.
+        let mut max_connections = self.conn_count;
+        let conn_limit = |scaler: &Option<ConnectionScaler>, max_connections: usize| {
+            scaler
+                .as_ref()
+                .map_or(self.conn_count, |s| s.limit())
+                .min(max_connections)
+        };
+
+        let mut futures: FuturesUnordered<_> = pending
+            .drain(..conn_limit(&scaler, max_connections).min(pending.len()))
+            .map(spawn_chunk)
             .collect();
 
-        while let Some(result) = futures.next().await {
-            let _ = result?;
+        let mut meter = SpeedMeter::new(initially_written);
+        let mut chunk_reports = Vec::new();
+        let mut ticker = tokio::time::interval(self.progress_interval);
+        let mut scaler_ticker = tokio::time::interval(adaptive::SAMPLE_INTERVAL);
+        scaler_ticker.reset();
+        let mut save_ticker = tokio::time::interval(RESUME_SAVE_INTERVAL);
+        save_ticker.reset();
+        let mut read_back_ticker = tokio::time::interval(READ_BACK_INTERVAL);
+        read_back_ticker.reset();
+
+        loop {
+            let result = tokio::select! {
+                result = futures.next() => match result {
+                    Some(result) => result.map_err(DownloadError::from).and_then(|r| r),
+                    None => break,
+                },
+                _ = ticker.tick(), if self.reports_progress(options) => {
+                    self.report_progress(url, options, &state, &mut meter);
+                    continue;
+                }
+                _ = scaler_ticker.tick(), if scaler.is_some() => {
+                    if let Some(scaler) = &mut scaler {
+                        scaler.sample(state.written(), &congestion);
+                    }
+                    let limit = conn_limit(&scaler, max_connections);
+                    self.add_connections(&state, &mut pending, &mut futures, limit, spawn_chunk);
+                    continue;
+                }
+                _ = read_back_ticker.tick(), if hasher.is_some() => {
+                    if let Some(hasher) = &hasher {
+                        let state = state.clone();
+                        hasher.read_back_in_background(&file, move || state.written_prefix());
+                    }
+                    continue;
+                }
+                _ = save_ticker.tick(), if self.resume => match self.save_state(&state, &partial).await {
+                    Ok(()) => continue,
+                    Err(e) => Err(e),
+                },
+                error = options.interrupted() => Err(error),
+            };
+
+            if let Some(chunk) = result
+                .as_ref()
+                .err()
+                .filter(|_| max_connections > 1)
+                .and_then(|e| rejected_chunk(e, &state))
+            {
+                // Fewer, longer requests take over the ranges the server refused to serve at once,
+                // down to a single connection that fetches them one after another.
+                max_connections = futures.len().div_ceil(2).max(1);
+                warn!(
+                    connections = max_connections,
+                    "server rejected concurrent range requests, using fewer connections"
+                );
+                pending.push_front(chunk);
+                let limit = conn_limit(&scaler, max_connections);
+                self.add_connections(&state, &mut pending, &mut futures, limit, spawn_chunk);
+                continue;
+            }
+
+            if let Err(e) = result {
+                warn!(error = %e, "parallel download failed");
+                for task in futures.iter() {
+                    task.abort();
+                }
+                while futures.next().await.is_some() {}
+                if self.discards_cancelled(&e) || e.is_remote_changed() || e.is_range_ignored() {
+                    remove_partial(&partial).await.with_path(&partial)?;
+                    ResumeState::remove(&partial).await?;
+                } else if self.resume {
+                    self.save_state(&state, &partial).await?;
+                }
+                return Err(e);
+            }
+            chunk_reports.extend(result.ok());
+
+            // Keep the connection that just finished busy.
+            self.add_connections(
+                &state,
+                &mut pending,
+                &mut futures,
+                conn_limit(&scaler, max_connections),
+                spawn_chunk,
+            );
+
+            if self.resume {
+                self.save_state(&state, &partial).await?;
+            }
         }
 
-        Ok(PathBuf::from(&output_path))
+        self.report_progress(url, options, &state, &mut meter);
+
+        let written = state.written();
+        if written != content_length {
+            if self.resume {
+                self.save_state(&state, &partial).await?;
+            }
+            return Err(DownloadError::ContentLengthMismatch {
+                expected: content_length,
+                actual: written,
+            });
+        }
+
+        self.make_durable(&partial).await?;
+        if self.resume {
+            ResumeState::remove(&partial).await?;
+        }
+
+        if let (Some(checksum), Some(hasher)) = (&options.checksum, &hasher) {
+            let verified = match hasher.finish(&file, content_length).await {
+                Ok(hasher) => checksum.verify(hasher),
+                Err(e) => Err(e).with_path(&partial),
+            };
+            if let Err(e) = verified {
+                warn!(error = %e, "removing file that failed verification");
+                remove_partial(&partial).await.with_path(&partial)?;
+                return Err(e);
+            }
+        }
+        drop(output);
+        drop(file);
+        self.move_into_place(&partial, &output_path).await?;
+
+        chunk_reports.sort_by_key(|c| c.start);
+
+        Ok(DownloadReport {
+            path: output_path,
+            url: url.to_owned(),
+            final_url: probe.final_url.clone().unwrap_or_else(|| url.to_owned()),
+            status: probe.status,
+            headers: probe.headers.clone(),
+            size: content_length,
+            bytes_downloaded: state.written() - initially_written,
+            elapsed: started.elapsed(),
+            retries: chunk_reports.iter().map(|c| c.retries).sum(),
+            checksum: options.checksum.clone(),
+            checksum_source: options.checksum_source.clone(),
+            extracted_to: None,
+            chunks: chunk_reports,
+        })
+    }
+
+    /// Checks whether the partial file of a download that failed with `error` is removed because
+    /// the download was cancelled. Downloads stopped by a shutdown are kept to be resumed.
+    pub(crate) fn discards_cancelled(&self, error: &DownloadError) -> bool {
+        matches!(error, DownloadError::Cancelled)
+            && self.cancel_policy == CancelPolicy::RemovePartial
+            && !(self.resume && self.shutdown.is_shut_down())
+    }
+
+    /// Saves the resume state of a running or interrupted download. It's synced to disk along with
+    /// the partial file if every chunk is or the downloader is shutting down.
+    pub(crate) async fn save_state(
+        &self,
+        state: &ResumeState,
+        output_path: &Path,
+    ) -> Result<(), DownloadError> {
+        if self.chunk_journal {
+            journal::commit(state, output_path).await?;
+        }
+        match self.durability == Durability::FsyncPerChunk || self.shutdown.is_shut_down() {
+            true => state.save_synced(output_path).await,
+            false => state.save(output_path).await,
+        }
+    }
+
+    /// Fetches the regions of a resumed sparse download again that aren't on disk, though `state`
+    /// says they were written.
+    pub(crate) fn verify_regions(
+        &self,
+        state: &ResumeState,
+        file: &SharedFile,
+        path: &Path,
+    ) -> Result<(), DownloadError> {
+        if self.allocation != Allocation::Sparse || state.written() == 0 {
+            return Ok(());
+        }
+        let lost = state.verify_regions(file).with_path(path)?;
+        if lost > 0 {
+            warn!(bytes = lost, "partial file lost data, fetching it again");
+        }
+        Ok(())
+    }
+
+    /// Forces a completed download to disk as far as the durability policy asks for.
+    pub(crate) async fn make_durable(&self, path: &Path) -> Result<(), DownloadError> {
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::FlushOnComplete => storage::sync_data(path).await.with_path(path),
+            Durability::FsyncOnComplete | Durability::FsyncPerChunk => {
+                storage::sync(path).await.with_path(path)?;
+                storage::sync_parent_dir(path).await.with_path(path)
+            }
+        }
+    }
+
+    /// Picks how the chunks of a parallel download are written to `file` at `output_path`.
+    fn chunk_output(&self, file: SharedFile, output_path: &Path) -> ChunkOutput {
+        let path = output_path.to_owned();
+        #[cfg(feature = "mmap")]
+        if self.memory_map {
+            match crate::mmap::MappedFile::new(file.handle()) {
+                Ok(map) => {
+                    return ChunkOutput::Mapped {
+                        map: Arc::new(map),
+                        path,
+                    }
+                }
+                Err(_e) => {
+                    warn!(error = %_e, "can't map the output file, writing it as usual");
+                }
+            }
+        }
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(ring) = &self.uring {
+            return ChunkOutput::Uring {
+                file,
+                path,
+                ring: ring.clone(),
+            };
+        }
+        ChunkOutput::File { file, path }
+    }
+
+    /// Returns how many chunks a parallel download of `content_length` bytes is split into.
+    pub(crate) fn chunk_count(&self, content_length: u64) -> usize {
+        let Some(chunk_size) = self.chunk_size else {
+            return self.conn_count;
+        };
+        let (min, max) = self.chunk_count_bounds;
+        let count = content_length.div_ceil(chunk_size);
+        usize::try_from(count).unwrap_or(usize::MAX).clamp(min, max)
+    }
+
+    /// Starts connections for pending chunks, or chunks split off running ones, until `limit` are running.
+    fn add_connections<T>(
+        &self,
+        state: &ResumeState,
+        pending: &mut VecDeque<Arc<ChunkState>>,
+        running: &mut FuturesUnordered<T>,
+        limit: usize,
+        spawn_chunk: impl Fn(Arc<ChunkState>) -> T,
+    ) {
+        while running.len() < limit {
+            let chunk = match pending.pop_front() {
+                Some(chunk) => chunk,
+                None if self.work_stealing => match state.split_largest(MIN_SPLIT_SIZE) {
+                    Some(chunk) => {
+                        debug!(start = chunk.start, end = chunk.end(), "split chunk");
+                        chunk
+                    }
+                    None => break,
+                },
+                None => break,
+            };
+            running.push(spawn_chunk(chunk));
+        }
+    }
+
+    /// Checks whether anyone receives the progress of a download with `options`.
+    fn reports_progress(&self, options: &DownloadOptions) -> bool {
+        self.progress.is_some() || options.events.is_some()
+    }
+
+    /// Hands the progress made by `progress` to the reporter and the event stream of the download.
+    fn publish_progress(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+        progress: impl FnOnce() -> Progress,
+    ) {
+        if !self.reports_progress(options) {
+            return;
+        }
+        let progress = progress();
+        if let Some(reporter) = &self.progress {
+            reporter.report(url, &progress);
+        }
+        options.emit(|| DownloadEvent::ChunkProgress(progress));
+    }
+
+    pub(crate) fn report_progress(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+        state: &ResumeState,
+        meter: &mut SpeedMeter,
+    ) {
+        self.publish_progress(url, options, || {
+            let chunks = state
+                .chunks()
+                .iter()
+                .map(|c| ChunkProgress {
+                    downloaded: c.written(),
+                    total: Some(c.len()),
+                })
+                .collect();
+            meter.sample(chunks, Some(state.content_length))
+        });
+    }
+
+    /// Looks for a partial download of `url` left behind by an earlier run.
+    ///
+    /// If the remote file changed since then, the partial file is reused for a fresh download.
+    pub(crate) async fn find_resumable(
+        &self,
+        url: &str,
+        content_length: u64,
+        validator: Option<&str>,
+        name: &str,
+    ) -> Option<(PathBuf, ResumeState)> {
+        if !self.resume {
+            return None;
+        }
+
+        for path in self.output_path_candidates(name) {
+            let partial = self.partial_path(&path);
+            if !path.exists() && !partial.exists() {
+                break;
+            }
+            if let Some(state) = ResumeState::load(&partial).await {
+                if state.matches(url, content_length, validator) {
+                    if self.chunk_journal {
+                        journal::apply(&state, &partial).await;
+                    }
+                    info!(
+                        path = %path.display(),
+                        written = state.written(),
+                        "resuming download"
+                    );
+                    return Some((path, state));
+                }
+                if state.url == url {
+                    info!(path = %path.display(), "remote file changed, starting over");
+                    let chunk_count = self.chunk_count(content_length);
+                    let state = ResumeState::new(url, content_length, validator, chunk_count);
+                    return Some((path, state));
+                }
+            }
+        }
+
+        None
     }
 
     /// Downloads the file at the given `url` serially.
     pub async fn sequential(&self, url: &str) -> Result<PathBuf, DownloadError> {
-        let mut stream = self.client.get(url).send().await?.bytes_stream();
-        let output_path = self.get_output_path(url);
-        let mut file = fs::File::create(&output_path).await?;
+        let report = self
+            .sequential_with(
+                &Mirrors::new(url, &[]),
+                &Probe::default(),
+                &DownloadOptions::default(),
+            )
+            .await?;
+        Ok(report.path)
+    }
+
+    async fn sequential_with(
+        &self,
+        mirrors: &Mirrors,
+        probe: &Probe,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        let url = mirrors.primary();
+        let name = self.output_name(url, probe.filename.as_deref(), &probe.headers, options);
+        if let Some(report) = self.check_existing(url, &name, probe, options).await? {
+            return Ok(report);
+        }
+
+        let (response, _permit) = self.get_from_mirrors(mirrors, options).await?;
+        self.save_response(url, probe, response, options, started)
+            .await
+    }
+
+    /// Returns `options` with the digest the server sent for the whole file as the checksum, if
+    /// digests are verified and the download has no checksum yet.
+    fn with_digest(
+        &self,
+        options: &DownloadOptions,
+        headers: &HeaderMap,
+        complete: bool,
+    ) -> Option<DownloadOptions> {
+        if !self.verify_digests || options.checksum.is_some() {
+            return None;
+        }
+        let checksum = digest::repr_digest(headers, complete)?;
+        debug!(
+            algorithm = checksum.algorithm.name(),
+            "verifying the digest sent by the server"
+        );
+        Some(
+            options
+                .clone()
+                .checksum_from(checksum, ChecksumSource::DigestHeader),
+        )
+    }
+
+    /// Saves the body of `response`, the whole file at `url`, in the output directory.
+    async fn save_response(
+        &self,
+        url: &str,
+        probe: &Probe,
+        response: reqwest::Response,
+        options: &DownloadOptions,
+        started: Instant,
+    ) -> Result<DownloadReport, DownloadError> {
+        let final_url = response.url().to_string();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let filename = probe
+            .filename
+            .clone()
+            .or_else(|| filename::from_headers(response.headers()));
+        let name = self.output_name(url, filename.as_deref(), response.headers(), options);
+        let reservation = self.reserve_output_path(&name, options);
+        let output_path = reservation.path.clone();
+        let partial = self.partial_path(&output_path);
+        create_parent_dir(&partial).await?;
+        if let Some(len) = response.content_length() {
+            // An existing file is truncated, so its space is available too.
+            let existing = fs::metadata(&partial).await.map_or(0, |m| m.len());
+            storage::check_space(&partial, len.saturating_sub(existing))?;
+        }
+        let mut file = fs::File::create(&partial).await.with_path(&partial)?;
+        options.emit(|| DownloadEvent::Started {
+            path: output_path.clone(),
+            total: response.content_length(),
+        });
+
+        let with_digest = self.with_digest(options, &headers, status == StatusCode::OK);
+        let options = with_digest.as_ref().unwrap_or(options);
+        let mut hasher = options.checksum.as_ref().map(|c| Hasher::new(c.algorithm));
+        let result = self
+            .copy_body(
+                url,
+                response,
+                &mut file,
+                Some(&partial),
+                options,
+                &mut hasher,
+            )
+            .await;
+
+        let downloaded = match result {
+            Ok(downloaded) => downloaded,
+            Err(e) => {
+                drop(file);
+                // Nothing resumes from the partial file, so it's only kept if asked to.
+                let keep = matches!(e, DownloadError::Cancelled) && !self.discards_cancelled(&e);
+                if !keep {
+                    remove_partial(&partial).await.with_path(&partial)?;
+                }
+                return Err(e);
+            }
+        };
+        drop(file);
+        self.make_durable(&partial).await?;
+
+        if let (Some(checksum), Some(hasher)) = (&options.checksum, hasher) {
+            if let Err(e) = checksum.verify(hasher) {
+                warn!(error = %e, "removing file that failed verification");
+                remove_partial(&partial).await.with_path(&partial)?;
+                return Err(e);
+            }
+        }
+        self.move_into_place(&partial, &output_path).await?;
+
+        Ok(DownloadReport {
+            path: output_path,
+            url: url.to_owned(),
+            final_url,
+            status: Some(status),
+            headers,
+            size: downloaded,
+            bytes_downloaded: downloaded,
+            elapsed: started.elapsed(),
+            retries: 0,
+            checksum: options.checksum.clone(),
+            checksum_source: options.checksum_source.clone(),
+            extracted_to: None,
+            chunks: Vec::new(),
+        })
+    }
+
+    /// Requests the whole file, falling back to the next mirror if a request fails.
+    ///
+    /// Once all mirrors have failed, they are tried again according to the retry policy. The
+    /// response comes with the permit for its connection.
+    async fn get_from_mirrors(
+        &self,
+        mirrors: &Mirrors,
+        options: &DownloadOptions,
+    ) -> Result<(reqwest::Response, HostPermit), DownloadError> {
+        let mut attempt = 1;
+        let mut retry_after_waited = Duration::ZERO;
+
+        loop {
+            let mut last_error = None;
+            for mirror in mirrors.urls() {
+                let permit = options.or_cancelled(self.hosts.acquire(mirror)).await?;
+                match options
+                    .or_cancelled(self.send_request(mirror, options, |client| {
+                        self.accept_encoding(client.get(mirror))
+                    }))
+                    .await?
+                {
+                    Ok(response) => return Ok((response, permit)),
+                    Err(e) if e.is_remote() => {
+                        warn!(mirror, error = %e, "request to mirror failed");
+                        last_error = Some(e);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let error = last_error.expect("there is at least one mirror");
+            match self
+                .retry
+                .retry_delay(attempt, &error, &mut retry_after_waited)
+            {
+                Some(delay) => {
+                    metrics::record_retry();
+                    options.or_cancelled(tokio::time::sleep(delay)).await?;
+                    attempt += 1;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+
+    /// Streams the body of `response` into `writer` and returns the number of bytes written.
+    ///
+    /// Write errors are reported for `path` if the writer is a file.
+    async fn copy_body<W>(
+        &self,
+        url: &str,
+        response: reqwest::Response,
+        writer: &mut W,
+        path: Option<&Path>,
+        options: &DownloadOptions,
+        hasher: &mut Option<Hasher>,
+    ) -> Result<u64, DownloadError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let total = response.content_length();
+        self.check_size(url, total, options)?;
+        #[cfg(feature = "decompression")]
+        let mut decoder = match self.decompress {
+            true => crate::decompress::Decoder::from_headers(response.headers())
+                .transpose()
+                .map_err(DownloadError::Decompression)?,
+            false => None,
+        };
+        let mut stream = response.bytes_stream();
+        let mut meter = SpeedMeter::new(0);
+        let mut last_report = Instant::now();
+        // Counts the bytes received, which differs from those written for compressed responses.
+        let mut downloaded = 0;
+        let mut written = 0;
+        let throttle = self.throttle.connection();
+        let mut stall = StallDetector::new(self.min_speed);
+
+        while let Some(chunk) = options
+            .or_cancelled(async {
+                if options.unpaused().await {
+                    stall.reset();
+                }
+                next_chunk(&mut stream, self.read_timeout, &mut stall).await
+            })
+            .await??
+        {
+            options.or_cancelled(throttle.acquire(chunk.len())).await?;
+            downloaded += chunk.len() as u64;
+            #[cfg(feature = "decompression")]
+            let chunk = match &mut decoder {
+                Some(decoder) => decoder
+                    .decode(&chunk)
+                    .map_err(DownloadError::Decompression)?
+                    .into(),
+                None => chunk,
+            };
+            self.check_size(url, Some(written + chunk.len() as u64), options)?;
+            write_hashed(writer, &chunk, path, hasher).await?;
+            written += chunk.len() as u64;
+
+            if last_report.elapsed() >= self.progress_interval {
+                self.report_sequential_progress(url, options, downloaded, total, &mut meter);
+                last_report = Instant::now();
+            }
+        }
+        #[cfg(feature = "decompression")]
+        if let Some(decoder) = decoder {
+            let rest = decoder.finish().map_err(DownloadError::Decompression)?;
+            self.check_size(url, Some(written + rest.len() as u64), options)?;
+            write_hashed(writer, &rest, path, hasher).await?;
+            written += rest.len() as u64;
+        }
+        writer.flush().await.map_err(|e| write_error(e, path))?;
+
+        self.report_sequential_progress(url, options, downloaded, total, &mut meter);
+        match total {
+            Some(expected) if expected != downloaded => Err(DownloadError::ContentLengthMismatch {
+                expected,
+                actual: downloaded,
+            }),
+            _ => Ok(written),
+        }
+    }
+
+    /// Downloads the file at `url` into `writer` instead of a file in the output directory.
+    ///
+    /// Returns the number of bytes written. See [`Downloader::download_to_with`].
+    pub async fn download_to<W>(&self, url: &str, writer: &mut W) -> Result<u64, DownloadError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.download_to_with(url, writer, &DownloadOptions::default())
+            .await
+    }
+
+    /// Like [`Downloader::download_to`], with settings that only apply to this download.
+    ///
+    /// If the server supports range requests, consecutive pieces of the file are fetched over
+    /// `conn_count` connections and written in order, so `writer` doesn't need to be seekable.
+    /// Because the data has already been written when the checksum is verified, a mismatch only
+    /// fails the download; removing what was written is up to the caller.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "download_to", skip_all, fields(url = %url)))]
+    pub async fn download_to_with<W>(
+        &self,
+        url: &str,
+        writer: &mut W,
+        options: &DownloadOptions,
+    ) -> Result<u64, DownloadError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let _running = self.shutdown.enter();
+        if self.shutdown.is_shut_down() {
+            return Err(DownloadError::Cancelled);
+        }
+        let options = &options.started(url, self.shutdown.token());
+        self.check_policy(url)?;
+        if let Some(mut local) = local::open(url).await? {
+            self.check_size(url, Some(local.len), options)?;
+            let mut hasher = options.checksum.as_ref().map(|c| Hasher::new(c.algorithm));
+            let written = self
+                .copy_local(url, &mut local, writer, None, options, &mut hasher)
+                .await?;
+            if let (Some(checksum), Some(hasher)) = (&options.checksum, hasher) {
+                checksum.verify(hasher)?;
+            }
+            return Ok(written);
+        }
+        #[cfg(any(feature = "s3", feature = "azure"))]
+        if let Some((object_url, options)) = self.resolve_storage_url(url, options)? {
+            return self.stream_http(&object_url, writer, &options).await;
+        }
+
+        self.stream_http(url, writer, options).await
+    }
+
+    /// Streams an `http://` or `https://` URL, or one of its mirrors, into `writer` and verifies
+    /// the checksum.
+    async fn stream_http<W>(
+        &self,
+        url: &str,
+        writer: &mut W,
+        options: &DownloadOptions,
+    ) -> Result<u64, DownloadError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mirrors = Arc::new(Mirrors::new(url, &options.mirrors));
+        for url in mirrors.urls() {
+            check_scheme(url)?;
+            self.check_policy(url)?;
+        }
+
+        let discovered = self.discover_checksum(url, options).await;
+        let options = discovered.as_ref().unwrap_or(options);
+        let probe = self.probe_mirrors(&mirrors, options).await?;
+        self.check_size(url, probe.content_length, options)?;
+        self.check_content_type(url, &probe.headers, options)?;
+        let with_digest = self.with_digest(options, &probe.headers, false);
+        let options = with_digest.as_ref().unwrap_or(options);
+        let mut hasher = options.checksum.as_ref().map(|c| Hasher::new(c.algorithm));
+        let streamed = match probe.supports_ranges() {
+            true => {
+                self.stream_parallel(&mirrors, &probe, writer, options, &mut hasher)
+                    .await?
+            }
+            false => None,
+        };
+        let written = match streamed {
+            Some(written) => written,
+            None => {
+                let (response, _permit) = self.get_from_mirrors(&mirrors, options).await?;
+                self.copy_body(url, response, writer, None, options, &mut hasher)
+                    .await?
+            }
+        };
 
-        while let Some(chunk) = stream.next().await {
-            file.write_all(&chunk?).await?;
+        if let (Some(checksum), Some(hasher)) = (&options.checksum, hasher) {
+            checksum.verify(hasher)?;
         }
 
-        Ok(PathBuf::from(&output_path))
+        Ok(written)
+    }
+
+    /// Downloads the file at `url` into memory.
+    ///
+    /// Servers that support range requests are still downloaded over `conn_count` connections.
+    pub async fn download_to_vec(&self, url: &str) -> Result<Vec<u8>, DownloadError> {
+        let mut buffer = Vec::new();
+        self.download_to(url, &mut buffer).await?;
+        Ok(buffer)
     }
 
-    fn get_output_path(&self, url: &str) -> String {
-        let filename = url::Url::parse(url)
-            .ok()
-            .and_then(|u| {
-                u.path_segments()
-                    .map(|segments| segments.last().unwrap_or_else(|| "unnamed").to_owned())
+    /// Fetches the file in consecutive pieces over `conn_count` connections and writes them to `writer` in order.
+    ///
+    /// Returns `None` without writing anything if the server ignores the range of the first piece.
+    async fn stream_parallel<W>(
+        &self,
+        mirrors: &Arc<Mirrors>,
+        probe: &Probe,
+        writer: &mut W,
+        options: &DownloadOptions,
+        hasher: &mut Option<Hasher>,
+    ) -> Result<Option<u64>, DownloadError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let url = mirrors.primary();
+        let content_length = probe.content_length.unwrap_or_default();
+        let piece_size = content_length
+            .div_ceil(self.chunk_count(content_length) as u64)
+            .clamp(1, MAX_STREAM_PIECE_SIZE);
+        let congestion = Arc::new(Congestion::default());
+
+        let mut pieces = stream::iter((0..content_length).step_by(piece_size as usize))
+            .map(|start| {
+                let end = (start + piece_size).min(content_length) - 1;
+                let buffer = Arc::new(Mutex::new(Vec::new()));
+                let job = ChunkJob {
+                    client: self.client.clone(),
+                    headers: self.headers.clone(),
+                    mirrors: mirrors.clone(),
+                    output: ChunkOutput::Memory(buffer.clone()),
+                    chunk: Arc::new(ChunkState::new(start, end, 0)),
+                    options: options.clone(),
+                    read_timeout: self.read_timeout,
+                    min_speed: self.min_speed,
+                    retry: self.retry.clone(),
+                    throttle: self.throttle.clone(),
+                    hosts: self.hosts.clone(),
+                    congestion: congestion.clone(),
+                    validator: probe.validator.clone(),
+                    sync: false,
+                    write_buffer_size: self.write_buffer_size,
+                    hasher: None,
+                    verify_digests: self.verify_digests,
+                    #[cfg(feature = "http3")]
+                    http3: self.http3.clone(),
+                    pins: self.pins.clone(),
+                };
+                async move {
+                    job.run().await?;
+                    Ok::<_, DownloadError>(std::mem::take(&mut *buffer.lock().unwrap()))
+                }
             })
-            .unwrap_or_else(|| "unnamed".to_owned());
+            .buffered(self.conn_count);
+
+        let mut meter = SpeedMeter::new(0);
+        let mut last_report = Instant::now();
+        let mut downloaded = 0;
+
+        while let Some(piece) = options.or_cancelled(pieces.next()).await? {
+            let piece = match piece {
+                Ok(piece) => piece,
+                Err(e) if e.is_range_ignored() && downloaded == 0 => {
+                    debug!("server ignored the requested byte ranges, downloading sequentially");
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            };
+            writer.write_all(&piece).await?;
+            downloaded += piece.len() as u64;
+            if let Some(hasher) = hasher {
+                hasher.update(&piece);
+            }
+
+            if last_report.elapsed() >= self.progress_interval {
+                self.report_sequential_progress(
+                    url,
+                    options,
+                    downloaded,
+                    Some(content_length),
+                    &mut meter,
+                );
+                last_report = Instant::now();
+            }
+        }
+        writer.flush().await?;
+
+        self.report_sequential_progress(url, options, downloaded, Some(content_length), &mut meter);
+        Ok(Some(downloaded))
+    }
+
+    pub(crate) fn report_sequential_progress(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+        downloaded: u64,
+        total: Option<u64>,
+        meter: &mut SpeedMeter,
+    ) {
+        self.publish_progress(url, options, || {
+            meter.sample(vec![ChunkProgress { downloaded, total }], total)
+        });
+    }
+
+    /// Picks the path a file called `name` would be saved to. Existing files are only replaced if
+    /// the overwrite policy allows it.
+    pub(crate) fn get_output_path(&self, name: &str, options: &DownloadOptions) -> PathBuf {
+        let mut candidates = self.output_path_candidates(name);
+        if self.overwrite_policy(options) != OverwritePolicy::Rename {
+            return candidates.next().expect("candidate paths are unbounded");
+        }
+
+        candidates
+            .find(|path| self.is_free(path) && !self.reservations.is_reserved(path))
+            .expect("candidate paths are unbounded")
+    }
+
+    /// Picks the path a file called `name` is saved to, like [`Downloader::get_output_path`], and
+    /// reserves it until the returned reservation is dropped, so concurrent downloads of files
+    /// with the same name are saved to different paths.
+    pub(crate) fn reserve_output_path(&self, name: &str, options: &DownloadOptions) -> Reservation {
+        let mut candidates = self.output_path_candidates(name);
+        if self.overwrite_policy(options) != OverwritePolicy::Rename {
+            let path = candidates.next().expect("candidate paths are unbounded");
+            return Reservation::unreserved(path);
+        }
+        self.reservations
+            .reserve(candidates, |path| self.is_free(path))
+    }
+
+    pub(crate) fn overwrite_policy(&self, options: &DownloadOptions) -> OverwritePolicy {
+        options.overwrite.unwrap_or(self.overwrite_policy)
+    }
+
+    /// Fails with [`DownloadError::FileTooLarge`] if a file of `size` bytes is larger than the
+    /// limit of the download.
+    pub(crate) fn check_size(
+        &self,
+        url: &str,
+        size: Option<u64>,
+        options: &DownloadOptions,
+    ) -> Result<(), DownloadError> {
+        match (options.max_file_size.or(self.max_file_size), size) {
+            (Some(limit), Some(size)) if size > limit => Err(DownloadError::FileTooLarge {
+                url: url.to_owned(),
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with [`DownloadError::UnexpectedContentType`] if the `Content-Type` in `headers`
+    /// isn't one of the types the download allows.
+    pub(crate) fn check_content_type(
+        &self,
+        url: &str,
+        headers: &HeaderMap,
+        options: &DownloadOptions,
+    ) -> Result<(), DownloadError> {
+        let allowed = options.allowed_content_types.as_ref();
+        let Some(allowed) = allowed.or(self.allowed_content_types.as_ref()) else {
+            return Ok(());
+        };
+        let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+        match content_type {
+            Some(content_type)
+                if allowed
+                    .iter()
+                    .any(|pattern| filename::content_type_matches(pattern, content_type)) =>
+            {
+                Ok(())
+            }
+            _ => Err(DownloadError::UnexpectedContentType {
+                url: url.to_owned(),
+                content_type: content_type.map(str::to_owned),
+            }),
+        }
+    }
+
+    /// Applies the overwrite policy if the file `url` would be saved to as `name` already exists.
+    ///
+    /// Returns a report for the existing file if the download should be skipped.
+    pub(crate) async fn check_existing(
+        &self,
+        url: &str,
+        name: &str,
+        probe: &Probe,
+        options: &DownloadOptions,
+    ) -> Result<Option<DownloadReport>, DownloadError> {
+        let policy = self.overwrite_policy(options);
+        if policy == OverwritePolicy::Rename {
+            return Ok(None);
+        }
+
+        let path = self
+            .output_path_candidates(name)
+            .next()
+            .expect("candidate paths are unbounded");
+        let Ok(metadata) = fs::metadata(&path).await else {
+            return Ok(None);
+        };
+
+        let mut verified = false;
+        let skip = match policy {
+            OverwritePolicy::Rename
+            | OverwritePolicy::Overwrite
+            | OverwritePolicy::SkipIfUnchanged => false,
+            OverwritePolicy::Skip => true,
+            OverwritePolicy::Error => return Err(DownloadError::FileExists { path }),
+            OverwritePolicy::SkipIfSameSizeOrHash => match &options.checksum {
+                Some(checksum) => {
+                    verified = checksum.verify_file(&path).await.is_ok();
+                    verified
+                }
+                None => probe.content_length == Some(metadata.len()),
+            },
+        };
+        if !skip {
+            return Ok(None);
+        }
+
+        info!(path = %path.display(), "file already exists, skipping download");
+        Ok(Some(DownloadReport {
+            path,
+            url: url.to_owned(),
+            final_url: probe.final_url.clone().unwrap_or_else(|| url.to_owned()),
+            status: probe.status,
+            headers: probe.headers.clone(),
+            size: metadata.len(),
+            bytes_downloaded: 0,
+            elapsed: Duration::ZERO,
+            retries: 0,
+            checksum: options.checksum.clone().filter(|_| verified),
+            checksum_source: options.checksum_source.clone().filter(|_| verified),
+            extracted_to: None,
+            chunks: Vec::new(),
+        }))
+    }
+
+    /// Returns the path of a download of `url` relative to the output directory.
+    ///
+    /// `filename` is the name suggested by the server, which is preferred over the one in the URL.
+    /// It's placed according to the output template, if there is one, in the directory of
+    /// `options`.
+    pub(crate) fn output_name(
+        &self,
+        url: &str,
+        filename: Option<&str>,
+        headers: &HeaderMap,
+        options: &DownloadOptions,
+    ) -> String {
+        let filename = match filename {
+            Some(filename) => filename.to_owned(),
+            None => self.infer_extension(url, filename::from_url(url), headers),
+        };
+
+        let name = match &self.output_template {
+            Some(template) => template::render(template, url, &filename, headers),
+            None if self.host_directories => {
+                template::render(template::HOST_DIRECTORIES, url, &filename, headers)
+            }
+            None => filename,
+        };
+        match &options.directory {
+            Some(directory) => format!("{}/{}", directory, name),
+            None => name,
+        }
+    }
 
-        let p = Path::new(&filename);
+    /// Appends the extension of the `Content-Type` in `headers` to a `name` from the URL that
+    /// doesn't have one, if extensions are inferred.
+    fn infer_extension(&self, url: &str, name: String, headers: &HeaderMap) -> String {
+        if !self.infer_extensions || filename::has_extension(url, &name) {
+            return name;
+        }
+        let extension = headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(filename::extension_for_content_type);
+        match extension {
+            // Says nothing about the format.
+            Some("bin") | None => name,
+            Some(extension) => format!("{}.{}", name, extension),
+        }
+    }
+
+    /// Checks whether nothing was saved or is being saved to `path`.
+    fn is_free(&self, path: &Path) -> bool {
+        let partial = self.partial_path(path);
+        !path.exists() && !partial.exists() && !resume::sidecar_path(&partial).exists()
+    }
+
+    /// Yields the paths a file called `name` may be written to, in order of preference.
+    fn output_path_candidates(&self, name: &str) -> impl Iterator<Item = PathBuf> + '_ {
+        let path = self.output_dir.join(name);
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+
+        let p = Path::new(name);
         let file_stem = p
             .file_stem()
             .map(|v| v.to_string_lossy().to_string())
             .unwrap_or("unnamed".to_owned());
         let ext = p
             .extension()
-            .map(|v| v.to_string_lossy().to_string())
-            .unwrap_or("".to_owned());
+            .map(|v| format!(".{}", v.to_string_lossy()))
+            .unwrap_or_default();
 
-        let mut output_path = self.output_dir.join(&filename);
-        let mut i = 1;
+        std::iter::once(path)
+            .chain((1..).map(move |i| dir.join(format!("{} ({}){}", &file_stem, i, ext))))
+    }
+}
 
-        while output_path.exists() {
-            output_path = self
-                .output_dir
-                .join(format!("{} ({}).{}", &file_stem, i, ext));
-            i += 1;
+/// Fails for URLs that can't be downloaded over HTTP.
+pub(crate) fn check_scheme(url: &str) -> Result<(), DownloadError> {
+    match url::Url::parse(url) {
+        Ok(parsed) if !matches!(parsed.scheme(), "http" | "https") => {
+            Err(DownloadError::UnsupportedScheme(parsed.scheme().to_owned()))
         }
+        _ => Ok(()),
+    }
+}
+
+/// Returns the chunk that failed with `error` if the server turned its range request away.
+fn rejected_chunk(error: &DownloadError, state: &ResumeState) -> Option<Arc<ChunkState>> {
+    match error {
+        DownloadError::Chunk { start, source, .. } if chunk::is_congestion(source) => state
+            .chunks()
+            .into_iter()
+            .find(|chunk| chunk.start == *start),
+        _ => None,
+    }
+}
+
+/// Sends `request` to `url`, treating error statuses as failures.
+pub(crate) async fn send(
+    request: reqwest::RequestBuilder,
+    url: &str,
+) -> Result<reqwest::Response, DownloadError> {
+    let response = trace::propagate(request)
+        .send()
+        .await
+        .map_err(|e| DownloadError::request(url, e))?;
+
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(retry::parse_retry_after);
+        return Err(DownloadError::Status {
+            url: url.to_owned(),
+            status,
+            retry_after,
+        });
+    }
+
+    Ok(response)
+}
+
+/// Writes `bytes` to `writer`, reporting errors for `path` if the writer is a file.
+async fn write_to<W>(writer: &mut W, bytes: &[u8], path: Option<&Path>) -> Result<(), DownloadError>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer
+        .write_all(bytes)
+        .await
+        .map_err(|e| write_error(e, path))
+}
+
+/// Writes `bytes` of the downloaded file and adds them to the checksum.
+pub(crate) async fn write_hashed<W>(
+    writer: &mut W,
+    bytes: &[u8],
+    path: Option<&Path>,
+    hasher: &mut Option<Hasher>,
+) -> Result<(), DownloadError>
+where
+    W: AsyncWrite + Unpin,
+{
+    write_to(writer, bytes, path).await?;
+    if let Some(hasher) = hasher {
+        hasher.update(bytes);
+    }
+    Ok(())
+}
+
+fn write_error(error: std::io::Error, path: Option<&Path>) -> DownloadError {
+    match path {
+        Some(path) => DownloadError::File {
+            path: path.to_owned(),
+            source: error,
+        },
+        None => DownloadError::FileWriteError(error),
+    }
+}
+
+/// Creates the directory `path` is in, which may come from the output template.
+pub(crate) async fn create_parent_dir(path: &Path) -> Result<(), DownloadError> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent).await.with_path(parent),
+        None => Ok(()),
+    }
+}
+
+/// Deletes a partially downloaded file, if it exists.
+pub(crate) async fn remove_partial(path: &Path) -> std::io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Reads the next chunk of a response body, failing if it takes longer than `read_timeout` or the
+/// body has been slower than the minimum speed of `stall`.
+pub(crate) async fn next_chunk<S>(
+    stream: &mut S,
+    read_timeout: Option<Duration>,
+    stall: &mut StallDetector,
+) -> Result<Option<bytes::Bytes>, DownloadError>
+where
+    S: Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Unpin,
+{
+    let chunk = stall
+        .watch(async {
+            match read_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, stream.next())
+                    .await
+                    .map_err(|_| DownloadError::TimeoutError(timeout)),
+                None => Ok(stream.next().await),
+            }
+        })
+        .await?
+        .transpose()?;
 
-        output_path.to_string_lossy().to_string()
+    if let Some(bytes) = &chunk {
+        stall.record(bytes.len())?;
     }
+    Ok(chunk)
 }
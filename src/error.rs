@@ -1,3 +1,9 @@
+use crate::{pinning, policy, redirect};
+use reqwest::StatusCode;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -10,4 +16,371 @@ pub enum DownloadError {
 
     #[error(transparent)]
     FileWriteError(#[from] std::io::Error),
+
+    #[cfg(feature = "ftp")]
+    #[error(transparent)]
+    Ftp(#[from] suppaftp::FtpError),
+
+    #[cfg(feature = "sftp")]
+    #[error(transparent)]
+    Ssh(#[from] russh::Error),
+
+    #[cfg(feature = "sftp")]
+    #[error(transparent)]
+    Sftp(#[from] russh_sftp::client::error::Error),
+
+    #[cfg(feature = "sftp")]
+    #[error("could not log in to {host}")]
+    LoginFailed { host: String },
+
+    #[cfg(feature = "azure")]
+    #[error("the access key of storage account {account} is not valid base64")]
+    InvalidAccountKey { account: String },
+
+    #[cfg(feature = "decompression")]
+    #[error("failed to decompress the response: {0}")]
+    Decompression(std::io::Error),
+
+    #[cfg(feature = "crawl")]
+    #[error(transparent)]
+    Glob(#[from] globset::Error),
+
+    #[cfg(feature = "webdav")]
+    #[error("invalid WebDAV response from {url}: {reason}")]
+    InvalidWebDav { url: String, reason: String },
+
+    #[cfg(feature = "torrent")]
+    #[error("invalid torrent: {0}")]
+    InvalidTorrent(String),
+
+    #[cfg(feature = "ipfs")]
+    #[error("invalid IPFS content {cid}: {reason}")]
+    InvalidIpfs { cid: String, reason: String },
+
+    #[cfg(feature = "lfs")]
+    #[error("git LFS object {oid} is unavailable: {reason}")]
+    LfsObject { oid: String, reason: String },
+
+    #[cfg(feature = "oci")]
+    #[error("OCI registry {registry}: {reason}")]
+    Registry { registry: String, reason: String },
+
+    #[cfg(feature = "zsync")]
+    #[error("invalid zsync control file: {0}")]
+    InvalidZsync(String),
+
+    #[cfg(feature = "extract")]
+    #[error("failed to extract or decompress {}: {source}", path.display())]
+    Extract {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("no data received for {0:?}")]
+    TimeoutError(Duration),
+
+    #[error("transfer was slower than {bytes_per_sec} bytes/s for {time:?}")]
+    TooSlow { bytes_per_sec: u64, time: Duration },
+
+    #[error("download was cancelled")]
+    Cancelled,
+
+    #[error("download didn't complete before its deadline")]
+    DeadlineExceeded,
+
+    #[error("checksum mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+
+    #[error("{url} responded with {status}")]
+    Status {
+        url: String,
+        status: StatusCode,
+        /// The delay the server asked for in a `Retry-After` header.
+        retry_after: Option<Duration>,
+    },
+
+    #[error("failed to download bytes {start}-{end} of {url}: {source}")]
+    Chunk {
+        url: String,
+        start: u64,
+        end: u64,
+        source: Box<DownloadError>,
+    },
+
+    #[error("{}: {source}", path.display())]
+    File {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("can't use the output directory {}: {source}", path.display())]
+    OutputDir {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("unsupported URL scheme `{0}`")]
+    UnsupportedScheme(String),
+
+    #[error("invalid URL: {0}")]
+    InvalidUrl(String),
+
+    #[error("too many redirects while requesting {url}")]
+    TooManyRedirects { url: String },
+
+    #[error("{url} redirected to {location}, which is on another origin")]
+    CrossOriginRedirect { url: String, location: String },
+
+    #[error("refused request to {url}: {reason}")]
+    Blocked { url: String, reason: String },
+
+    #[error("expected {expected} bytes, got {actual}")]
+    ContentLengthMismatch { expected: u64, actual: u64 },
+
+    #[error("{url} is larger than the limit of {limit} bytes")]
+    FileTooLarge { url: String, limit: u64 },
+
+    #[error("{url} has the content type {}, which isn't allowed", .content_type.as_deref().unwrap_or("none"))]
+    UnexpectedContentType {
+        url: String,
+        content_type: Option<String>,
+    },
+
+    #[error("network interface {0} doesn't exist or has no usable address")]
+    UnknownInterface(String),
+
+    #[error("invalid certificate pin `{0}`, expected the base64 encoded SHA-256 of a public key")]
+    InvalidPin(String),
+
+    #[error("{url} didn't present a certificate with a pinned key")]
+    CertificateNotPinned { url: String },
+
+    #[error("invalid TLS certificate or key: {0}")]
+    InvalidTls(String),
+
+    #[error("invalid metalink: {0}")]
+    InvalidMetalink(String),
+
+    #[error("invalid streaming manifest {url}: {reason}")]
+    InvalidManifest { url: String, reason: String },
+
+    #[error("invalid URL list, line {line}: {reason}")]
+    InvalidUrlList { line: usize, reason: String },
+
+    #[error(
+        "not enough space for {}: {required} bytes needed, {available} available",
+        path.display()
+    )]
+    InsufficientDiskSpace {
+        path: PathBuf,
+        required: u64,
+        available: u64,
+    },
+
+    #[error("{url} is listed more than once and failed to download: {reason}")]
+    DuplicateFailed { url: String, reason: String },
+
+    #[error("{} already exists", path.display())]
+    FileExists { path: PathBuf },
+
+    #[error("{url} changed while it was being downloaded")]
+    RemoteChanged { url: String },
+
+    #[error("{url} didn't respond with the requested byte range")]
+    RangeIgnored { url: String },
+
+    #[error(
+        "{url} sent a response that doesn't match its digest: expected {expected}, got {actual}"
+    )]
+    DigestMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl DownloadError {
+    /// Checks whether the operation that failed with this error may succeed if attempted again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::RequestError(e) => !e.is_builder() && !e.is_redirect() && e.status().is_none(),
+            Self::Status { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Self::TimeoutError(_)
+            | Self::TooSlow { .. }
+            | Self::ContentLengthMismatch { .. }
+            | Self::DigestMismatch { .. } => true,
+            #[cfg(feature = "ftp")]
+            Self::Ftp(e) => match e {
+                suppaftp::FtpError::ConnectionError(_) => true,
+                // 4xx replies are transient failures, 5xx ones permanent.
+                suppaftp::FtpError::UnexpectedResponse(response) => {
+                    (400..500).contains(&(response.status as u32))
+                }
+                _ => false,
+            },
+            #[cfg(feature = "sftp")]
+            Self::Ssh(e) => matches!(
+                e,
+                russh::Error::IO(_)
+                    | russh::Error::Disconnect
+                    | russh::Error::HUP
+                    | russh::Error::ConnectionTimeout
+                    | russh::Error::KeepaliveTimeout
+                    | russh::Error::InactivityTimeout
+                    | russh::Error::SendError
+                    | russh::Error::RecvError
+            ),
+            #[cfg(feature = "sftp")]
+            Self::Sftp(e) => matches!(
+                e,
+                russh_sftp::client::error::Error::IO(_) | russh_sftp::client::error::Error::Timeout
+            ),
+            Self::Chunk { source, .. } => source.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Checks whether the error was caused by the server, so another mirror may do better.
+    pub(crate) fn is_remote(&self) -> bool {
+        match self {
+            Self::RequestError(_)
+            | Self::Status { .. }
+            | Self::TimeoutError(_)
+            | Self::TooSlow { .. }
+            | Self::TooManyRedirects { .. }
+            | Self::CrossOriginRedirect { .. }
+            | Self::Blocked { .. }
+            | Self::ContentLengthMismatch { .. }
+            | Self::CertificateNotPinned { .. }
+            | Self::RangeIgnored { .. }
+            | Self::DigestMismatch { .. } => true,
+            #[cfg(feature = "ftp")]
+            Self::Ftp(_) => true,
+            #[cfg(feature = "sftp")]
+            Self::Ssh(_) | Self::Sftp(_) => true,
+            Self::Chunk { source, .. } => source.is_remote(),
+            _ => false,
+        }
+    }
+
+    /// Checks whether the download failed because the remote file was replaced.
+    pub(crate) fn is_remote_changed(&self) -> bool {
+        match self {
+            Self::RemoteChanged { .. } => true,
+            Self::Chunk { source, .. } => source.is_remote_changed(),
+            _ => false,
+        }
+    }
+
+    /// Checks whether the download failed because the server doesn't actually serve byte ranges.
+    pub(crate) fn is_range_ignored(&self) -> bool {
+        match self {
+            Self::RangeIgnored { .. } => true,
+            Self::Chunk { source, .. } => source.is_range_ignored(),
+            _ => false,
+        }
+    }
+
+    /// Returns the HTTP status the server responded with, if that's what caused the error.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::RequestError(e) => e.status(),
+            Self::Status { status, .. } => Some(*status),
+            Self::Chunk { source, .. } => source.status(),
+            _ => None,
+        }
+    }
+
+    /// Returns how long the server asked the client to wait before trying again.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Status { retry_after, .. } => *retry_after,
+            Self::Chunk { source, .. } => source.retry_after(),
+            _ => None,
+        }
+    }
+
+    /// Wraps an error from sending a request to `url`.
+    pub(crate) fn request(url: &str, error: reqwest::Error) -> Self {
+        if let Some(blocked) = blocked_by_policy(&error) {
+            return Self::Blocked {
+                url: blocked.url.clone(),
+                reason: blocked.reason.clone(),
+            };
+        }
+        if refused_by_pins(&error) {
+            return Self::CertificateNotPinned {
+                url: url.to_owned(),
+            };
+        }
+        let refused = std::error::Error::source(&error)
+            .and_then(|source| source.downcast_ref::<redirect::Refused>());
+        if let Some(refused) = refused {
+            Self::CrossOriginRedirect {
+                url: url.to_owned(),
+                location: refused.location.clone(),
+            }
+        } else if error.is_redirect() {
+            Self::TooManyRedirects {
+                url: url.to_owned(),
+            }
+        } else {
+            Self::RequestError(error)
+        }
+    }
+}
+
+/// Finds the [`UrlPolicy`](crate::UrlPolicy) refusing a redirect or a resolved host among the
+/// causes of `error`, which include the I/O error of the resolver.
+fn blocked_by_policy(error: &reqwest::Error) -> Option<&policy::Blocked> {
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        if let Some(blocked) = error.downcast_ref::<policy::Blocked>() {
+            return Some(blocked);
+        }
+        let inner = error
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref());
+        if let Some(blocked) = inner.and_then(|e| e.downcast_ref::<policy::Blocked>()) {
+            return Some(blocked);
+        }
+        source = error.source();
+    }
+    None
+}
+
+/// Checks whether the TLS handshake that `error` comes from was refused because the server's
+/// certificate has no pinned key. The TLS error is wrapped in I/O errors of the connection.
+fn refused_by_pins(error: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        if pinning::is_not_pinned(error) {
+            return true;
+        }
+        // The source of an I/O error is that of the error it wraps, skipping it.
+        source = match error
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+        {
+            Some(inner) => Some(inner),
+            None => error.source(),
+        };
+    }
+    false
+}
+
+/// Attaches the affected path to I/O errors.
+pub(crate) trait IoResultExt<T> {
+    fn with_path(self, path: &Path) -> Result<T, DownloadError>;
+}
+
+impl<T> IoResultExt<T> for std::io::Result<T> {
+    fn with_path(self, path: &Path) -> Result<T, DownloadError> {
+        self.map_err(|source| DownloadError::File {
+            path: path.to_owned(),
+            source,
+        })
+    }
 }
@@ -0,0 +1,82 @@
+use crate::{error::DownloadError, progress::Progress, report::DownloadReport};
+use futures::Stream;
+use std::{
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+
+/// Something that happened during a download, see [`Downloader::download_events`](crate::Downloader::download_events).
+// Only one `Completed` event is sent per download, boxing its report isn't worth the API change.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum DownloadEvent {
+    /// The file is being written to `path`. `total` is its size, if it is known.
+    Started { path: PathBuf, total: Option<u64> },
+    /// A snapshot of the download and each of its connections, sent every progress interval.
+    ChunkProgress(Progress),
+    /// The byte range from `start` to `end` failed and is attempted again, for the `attempt`th time.
+    ChunkRetried {
+        start: u64,
+        end: u64,
+        attempt: u32,
+        error: String,
+    },
+    /// The connection for the byte range from `start` to `end` was given up on because it stayed
+    /// below the minimum speed or didn't receive anything within the read timeout.
+    Stalled { start: u64, end: u64 },
+    /// The download finished. Always the last event.
+    Completed(DownloadReport),
+    /// The download failed. Always the last event.
+    Failed(DownloadError),
+}
+
+/// Runs a download, yielding the events it sends before its outcome.
+pub(crate) struct EventStream<F> {
+    download: Option<Pin<Box<F>>>,
+    events: mpsc::UnboundedReceiver<DownloadEvent>,
+    outcome: Option<DownloadEvent>,
+}
+
+impl<F> EventStream<F>
+where
+    F: Future<Output = Result<DownloadReport, DownloadError>>,
+{
+    pub fn new(download: F, events: mpsc::UnboundedReceiver<DownloadEvent>) -> Self {
+        Self {
+            download: Some(Box::pin(download)),
+            events,
+            outcome: None,
+        }
+    }
+}
+
+impl<F> Stream for EventStream<F>
+where
+    F: Future<Output = Result<DownloadReport, DownloadError>>,
+{
+    type Item = DownloadEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<DownloadEvent>> {
+        let this = &mut *self;
+        if let Some(download) = &mut this.download {
+            if let Poll::Ready(result) = download.as_mut().poll(cx) {
+                this.download = None;
+                this.outcome = Some(match result {
+                    Ok(report) => DownloadEvent::Completed(report),
+                    Err(e) => DownloadEvent::Failed(e),
+                });
+            }
+        }
+        if let Poll::Ready(Some(event)) = this.events.poll_recv(cx) {
+            return Poll::Ready(Some(event));
+        }
+        // Everything the download sent has been yielded once it is done.
+        match this.download {
+            Some(_) => Poll::Pending,
+            None => Poll::Ready(this.outcome.take()),
+        }
+    }
+}
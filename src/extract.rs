@@ -0,0 +1,363 @@
+//! Extraction of downloaded archives and decompression of compressed files, enabled with the
+//! `extract` feature.
+//!
+//! Entries are only ever written inside the target directory: paths that would leave it, like
+//! `../../.bashrc`, are skipped and absolute ones are taken as relative to it. Links are skipped
+//! too, since they could point anywhere.
+
+use crate::{
+    download::{create_parent_dir, Downloader},
+    error::DownloadError,
+    options::DownloadOptions,
+    probe::Probe,
+    report::DownloadReport,
+};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+/// What happens to downloaded archives and compressed files.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExtractConfig {
+    /// Archives are only extracted if this is set.
+    pub dir: Option<PathBuf>,
+    /// Removes archives once they have been extracted.
+    pub remove_archive: bool,
+    /// Decompresses files that are compressed on their own, like `data.json.gz`.
+    pub decompress: bool,
+    /// Keeps compressed files once they have been decompressed.
+    pub keep_compressed: bool,
+}
+
+/// The archive formats that can be extracted, told apart by their extension.
+#[derive(Debug, Clone, Copy)]
+enum Archive {
+    Zip,
+    Tar,
+    TarGz,
+    TarZst,
+}
+
+impl Archive {
+    fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        let extensions = [
+            (".zip", Self::Zip),
+            (".tar", Self::Tar),
+            (".tar.gz", Self::TarGz),
+            (".tgz", Self::TarGz),
+            (".tar.zst", Self::TarZst),
+            (".tzst", Self::TarZst),
+        ];
+        extensions
+            .into_iter()
+            .find(|(extension, _)| name.ends_with(extension))
+            .map(|(_, archive)| archive)
+    }
+}
+
+/// The formats single files can be compressed with, told apart by their extension.
+#[derive(Debug, Clone, Copy)]
+enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    /// Returns how the file at `path` is compressed and the path it's decompressed to, without
+    /// the extension.
+    fn from_path(path: &Path) -> Option<(Self, PathBuf)> {
+        let compression = match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "gz" => Self::Gzip,
+            "zst" => Self::Zstd,
+            "xz" => Self::Xz,
+            _ => return None,
+        };
+        let stem = path.file_stem().filter(|stem| !stem.is_empty())?;
+        Some((compression, path.with_file_name(stem)))
+    }
+}
+
+impl Downloader {
+    /// Extracts the file of `report` if it's an archive, or decompresses it if it's a compressed
+    /// file, as far as that's enabled.
+    pub(crate) async fn extract_download(
+        &self,
+        report: DownloadReport,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let budget = Budget::new(options.max_file_size.or(self.max_file_size));
+        if let Some(archive) = Archive::from_path(&report.path) {
+            return match &self.extract.dir {
+                Some(dir) => self.extract_archive(report, archive, dir, budget).await,
+                None => Ok(report),
+            };
+        }
+        match Compression::from_path(&report.path) {
+            Some((compression, output)) if self.extract.decompress => {
+                self.decompress_file(report, compression, output, budget, options)
+                    .await
+            }
+            _ => Ok(report),
+        }
+    }
+
+    async fn extract_archive(
+        &self,
+        mut report: DownloadReport,
+        archive: Archive,
+        dir: &Path,
+        mut budget: Budget,
+    ) -> Result<DownloadReport, DownloadError> {
+        debug!(path = %report.path.display(), dir = %dir.display(), "extracting archive");
+        let path = report.path.clone();
+        let target = dir.to_path_buf();
+        tokio::task::spawn_blocking(move || extract(archive, &path, &target, &mut budget))
+            .await?
+            .map_err(|source| extract_error(&report, source))?;
+
+        if self.extract.remove_archive {
+            remove(&report.path).await?;
+        }
+        report.extracted_to = Some(dir.to_path_buf());
+        Ok(report)
+    }
+
+    async fn decompress_file(
+        &self,
+        mut report: DownloadReport,
+        compression: Compression,
+        output: PathBuf,
+        mut budget: Budget,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        // The decompressed file is saved like a download of its own, following the overwrite
+        // policy, and only appears once it's complete.
+        let name = output.strip_prefix(&self.output_dir).unwrap_or(&output);
+        let name = name.to_string_lossy();
+        let existing = self
+            .check_existing(&report.url, &name, &Probe::default(), options)
+            .await?;
+        if existing.is_some() {
+            info!(path = %report.path.display(), "decompressed file already exists, keeping the compressed one");
+            return Ok(report);
+        }
+        let reservation = self.reserve_output_path(&name, options);
+        let output = reservation.path.clone();
+        let mut partial = self.partial_path(&output);
+        if partial == output {
+            let mut path = output.clone().into_os_string();
+            path.push(".part");
+            partial = PathBuf::from(path);
+        }
+        create_parent_dir(&partial).await?;
+
+        debug!(path = %report.path.display(), output = %output.display(), "decompressing file");
+        let path = report.path.clone();
+        let target = partial.clone();
+        let size = tokio::task::spawn_blocking(move || {
+            let decompressed = decompress(compression, &path, &target, &mut budget);
+            if decompressed.is_err() {
+                let _ = fs::remove_file(&target);
+            }
+            decompressed
+        })
+        .await?
+        .map_err(|source| extract_error(&report, source))?;
+        self.make_durable(&partial).await?;
+        self.move_into_place(&partial, &output).await?;
+
+        if !self.extract.keep_compressed {
+            remove(&report.path).await?;
+        }
+        report.path = output;
+        report.size = size;
+        Ok(report)
+    }
+}
+
+/// Turns an error extracting or decompressing the file of `report` into the error the download
+/// fails with.
+fn extract_error(report: &DownloadReport, source: io::Error) -> DownloadError {
+    match source.get_ref().and_then(|e| e.downcast_ref::<TooLarge>()) {
+        Some(&TooLarge(limit)) => DownloadError::FileTooLarge {
+            url: report.url.clone(),
+            limit,
+        },
+        None => DownloadError::Extract {
+            path: report.path.clone(),
+            source,
+        },
+    }
+}
+
+/// Counts the bytes written out of an archive or compressed file against the file size limit,
+/// since they can be many times the size of the download.
+struct Budget {
+    limit: Option<u64>,
+    written: u64,
+}
+
+impl Budget {
+    fn new(limit: Option<u64>) -> Self {
+        Self { limit, written: 0 }
+    }
+
+    /// Counts `size` more bytes, failing with [`TooLarge`] once there are more than the limit.
+    fn charge(&mut self, size: u64) -> io::Result<()> {
+        self.written = self.written.saturating_add(size);
+        match self.limit {
+            Some(limit) if self.written > limit => Err(io::Error::other(TooLarge(limit))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Copies `reader` to `writer`, reading at most one byte more than the limit allows.
+    fn copy(&mut self, reader: impl Read, writer: &mut impl io::Write) -> io::Result<u64> {
+        let remaining = match self.limit {
+            Some(limit) => limit.saturating_sub(self.written).saturating_add(1),
+            None => u64::MAX,
+        };
+        let copied = io::copy(&mut reader.take(remaining), writer)?;
+        self.charge(copied)?;
+        Ok(copied)
+    }
+}
+
+/// The error extraction fails with once more bytes than the limit were written.
+#[derive(Debug)]
+struct TooLarge(u64);
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "more than {} bytes were extracted", self.0)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+async fn remove(path: &Path) -> Result<(), DownloadError> {
+    tokio::fs::remove_file(path)
+        .await
+        .map_err(|source| DownloadError::Extract {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
+/// Decompresses the file at `path` into `output`, replacing it, and returns the decompressed
+/// size.
+fn decompress(
+    compression: Compression,
+    path: &Path,
+    output: &Path,
+    budget: &mut Budget,
+) -> io::Result<u64> {
+    let file = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = match compression {
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(file)?),
+        Compression::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(file)),
+    };
+    let mut output = io::BufWriter::new(File::create(output)?);
+    let size = budget.copy(reader, &mut output)?;
+    io::Write::flush(&mut output)?;
+    Ok(size)
+}
+
+fn extract(archive: Archive, path: &Path, dir: &Path, budget: &mut Budget) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let file = BufReader::new(File::open(path)?);
+    match archive {
+        Archive::Zip => extract_zip(file, dir, budget),
+        Archive::Tar => extract_tar(file, dir, budget),
+        Archive::TarGz => extract_tar(flate2::bufread::MultiGzDecoder::new(file), dir, budget),
+        Archive::TarZst => extract_tar(zstd::Decoder::with_buffer(file)?, dir, budget),
+    }
+}
+
+fn extract_tar(reader: impl Read, dir: &Path, budget: &mut Budget) -> io::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        if !(kind.is_file() || kind.is_dir()) {
+            debug!(entry = %String::from_utf8_lossy(&entry.path_bytes()), "skipping archive entry that isn't a file");
+            continue;
+        }
+        // An entry never holds more bytes than its header says.
+        budget.charge(entry.size())?;
+        // Refuses paths that leave `dir`.
+        if !entry.unpack_in(dir)? {
+            warn!(entry = %String::from_utf8_lossy(&entry.path_bytes()), "skipping archive entry outside of the target directory");
+        }
+    }
+    Ok(())
+}
+
+fn extract_zip(reader: impl Read + io::Seek, dir: &Path, budget: &mut Budget) -> io::Result<()> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if entry.is_symlink() {
+            continue;
+        }
+        let Some(name) = entry.enclosed_name() else {
+            warn!("skipping archive entry outside of the target directory");
+            continue;
+        };
+        let path = dir.join(name);
+        if entry.is_dir() {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if let Err(e) = budget.copy(&mut entry, &mut File::create(&path)?) {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_copies_up_to_the_limit() {
+        let mut budget = Budget::new(Some(8));
+        let mut output = Vec::new();
+        assert_eq!(budget.copy(&b"12345"[..], &mut output).unwrap(), 5);
+        assert_eq!(budget.copy(&b"678"[..], &mut output).unwrap(), 3);
+        assert_eq!(output, b"12345678");
+    }
+
+    #[test]
+    fn budget_fails_past_the_limit() {
+        let mut budget = Budget::new(Some(8));
+        let mut output = Vec::new();
+        budget.copy(&b"12345"[..], &mut output).unwrap();
+        let error = budget.copy(&b"6789"[..], &mut output).unwrap_err();
+        assert!(matches!(
+            error.get_ref().and_then(|e| e.downcast_ref::<TooLarge>()),
+            Some(TooLarge(8))
+        ));
+        // Reads stop one byte past the limit.
+        assert_eq!(output.len(), 9);
+        assert!(budget.charge(0).is_err());
+    }
+
+    #[test]
+    fn budget_without_limit() {
+        let mut budget = Budget::new(None);
+        budget.charge(u64::MAX).unwrap();
+        assert_eq!(budget.copy(&b"123"[..], &mut Vec::new()).unwrap(), 3);
+    }
+}
@@ -0,0 +1,396 @@
+use percent_encoding::percent_decode_str;
+use reqwest::header::{HeaderMap, CONTENT_DISPOSITION};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// Longest file name most file systems allow, in bytes.
+const MAX_NAME_LEN: usize = 255;
+
+/// Extensions longer than this aren't kept when a long name is shortened.
+const MAX_EXTENSION_LEN: usize = 16;
+
+/// Characters Windows doesn't allow in file names.
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Query parameters that name the file, like in `download?file=report.pdf`.
+const NAME_PARAMETERS: &[&str] = &["filename", "file", "name"];
+
+/// Extensions of scripts that serve files named in their query, like `download.php?file=a.zip`.
+const SCRIPT_EXTENSIONS: &[&str] = &["php", "asp", "aspx", "jsp", "cgi", "pl"];
+
+/// Returns the name of the file at `url`.
+///
+/// That's the percent-decoded last path segment, unless a `filename`, `file` or `name` query
+/// parameter names a file with an extension and the segment has none or is a script. URLs
+/// without a path, like `https://example.com/`, are named after the parameter or their host, and
+/// those without a host after a hash of the URL.
+pub(crate) fn from_url(url: &str) -> String {
+    let Ok(parsed) = url::Url::parse(url) else {
+        return url_hash(url);
+    };
+    let segment = parsed
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        .and_then(|name| base_name(&name));
+    let parameter = parsed.query_pairs().find_map(|(key, value)| {
+        NAME_PARAMETERS
+            .contains(&key.to_ascii_lowercase().as_str())
+            .then(|| base_name(&value))
+            .flatten()
+    });
+
+    let extension = |name: &str| {
+        Path::new(name)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+    };
+    let named_by_parameter = match (&segment, &parameter) {
+        (None, Some(_)) => true,
+        (Some(segment), Some(parameter)) => {
+            extension(parameter).is_some()
+                && extension(segment).is_none_or(|e| SCRIPT_EXTENSIONS.contains(&e.as_str()))
+        }
+        _ => false,
+    };
+    let name = if named_by_parameter {
+        parameter
+    } else {
+        segment
+    };
+    name.or_else(|| parsed.host_str().and_then(base_name))
+        .unwrap_or_else(|| url_hash(url))
+}
+
+/// Checks whether `name`, a name [from the URL](from_url) `url`, has an extension. Names after
+/// the host don't, even if it has dots.
+pub(crate) fn has_extension(url: &str, name: &str) -> bool {
+    let host = url::Url::parse(url).ok();
+    let host = host.as_ref().and_then(|url| url.host_str());
+    Path::new(name).extension().is_some() && host.and_then(base_name).as_deref() != Some(name)
+}
+
+/// Returns 8 hex digits of the SHA-256 of `url`.
+pub(crate) fn url_hash(url: &str) -> String {
+    let digest = Sha256::digest(url.as_bytes());
+    digest[..4].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns the filename suggested by the `Content-Disposition` header, if any.
+pub(crate) fn from_headers(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(CONTENT_DISPOSITION)?.to_str().ok()?;
+    from_content_disposition(value)
+}
+
+/// Extracts the filename from a `Content-Disposition` header value.
+///
+/// The RFC 5987 encoded `filename*` parameter takes precedence over `filename`.
+/// Directory components are stripped so the server can't write outside the output directory.
+pub(crate) fn from_content_disposition(value: &str) -> Option<String> {
+    let mut filename = None;
+    let mut extended = None;
+
+    for (key, value) in parameters(value) {
+        match key.to_ascii_lowercase().as_str() {
+            "filename" => filename = Some(value),
+            "filename*" => extended = decode_extended(&value),
+            _ => {}
+        }
+    }
+
+    base_name(&extended.or(filename)?)
+}
+
+/// Strips directory components from a name chosen by the server, so it can't escape the output
+/// directory, and [sanitizes](sanitize) the rest.
+pub(crate) fn base_name(name: &str) -> Option<String> {
+    let name = sanitize(name.rsplit(['/', '\\']).next().unwrap_or_default());
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Makes `name` a valid file name on all common platforms.
+///
+/// Replaces control and reserved characters with `_`, trims surrounding spaces and trailing dots,
+/// prefixes Windows device names like `CON` and `nul.txt` with `_` and shortens names longer than
+/// 255 bytes, keeping the extension. Returns an empty string if nothing is left.
+pub(crate) fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || RESERVED_CHARS.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    let mut name = name.trim().trim_end_matches(['.', ' ']).to_owned();
+
+    let device = name.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|r| r.eq_ignore_ascii_case(device))
+    {
+        name.insert(0, '_');
+    }
+
+    if name.len() > MAX_NAME_LEN {
+        let extension = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() && ext.len() < MAX_EXTENSION_LEN => {
+                format!(".{}", ext)
+            }
+            _ => String::new(),
+        };
+        let mut stem_len = MAX_NAME_LEN - extension.len();
+        while !name.is_char_boundary(stem_len) {
+            stem_len -= 1;
+        }
+        name.truncate(stem_len);
+        name.push_str(&extension);
+    }
+
+    name
+}
+
+/// Splits the parameters of a header value like `attachment; filename="a;b.txt"` into key/value pairs.
+fn parameters(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+
+    // Skip the disposition type.
+    for c in chars.by_ref() {
+        if c == ';' {
+            break;
+        }
+    }
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ';').is_some() {}
+
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ';').collect();
+        }
+
+        params.push((key.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    params
+}
+
+/// Decodes an RFC 5987 value like `UTF-8''na%C3%AFve.txt`.
+fn decode_extended(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?;
+    let decoded = percent_decode_str(encoded);
+
+    if charset.eq_ignore_ascii_case("utf-8") {
+        decoded.decode_utf8().ok().map(|s| s.into_owned())
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        Some(decoded.map(char::from).collect())
+    } else {
+        None
+    }
+}
+
+/// Returns the usual file extension for a `Content-Type` header value.
+pub(crate) fn extension_for_content_type(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    let extension = match mime.as_str() {
+        "text/html" => "html",
+        "text/plain" => "txt",
+        "text/css" => "css",
+        "text/csv" => "csv",
+        "text/xml" | "application/xml" => "xml",
+        "text/javascript" | "application/javascript" => "js",
+        "application/json" => "json",
+        "application/pdf" => "pdf",
+        "application/zip" => "zip",
+        "application/gzip" | "application/x-gzip" => "gz",
+        "application/x-tar" => "tar",
+        "application/x-bzip2" => "bz2",
+        "application/x-xz" => "xz",
+        "application/zstd" => "zst",
+        "application/x-7z-compressed" => "7z",
+        "application/wasm" => "wasm",
+        "application/octet-stream" => "bin",
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "audio/mpeg" => "mp3",
+        "audio/ogg" => "ogg",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        _ => return None,
+    };
+    Some(extension)
+}
+
+/// Checks whether the media type of a `Content-Type` header value matches `pattern`, like
+/// `application/pdf`, or `image/*` for every image type.
+pub(crate) fn content_type_matches(pattern: &str, content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    match pattern.strip_suffix("/*") {
+        Some(kind) => mime
+            .split_once('/')
+            .is_some_and(|(mime_kind, _)| mime_kind.eq_ignore_ascii_case(kind)),
+        None => mime.eq_ignore_ascii_case(pattern.trim()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_directories() {
+        assert_eq!(base_name("../../.bashrc").as_deref(), Some(".bashrc"));
+        assert_eq!(base_name("..\\..\\boot.ini").as_deref(), Some("boot.ini"));
+        assert_eq!(base_name("/etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(base_name("docs/"), None);
+        assert_eq!(base_name(".."), None);
+        assert_eq!(base_name("."), None);
+        assert_eq!(base_name(""), None);
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(sanitize("a<b>c:d\"e|f?g*h"), "a_b_c_d_e_f_g_h");
+        assert_eq!(sanitize("tab\there\n"), "tab_here_");
+        assert_eq!(sanitize("  report.pdf. . "), "report.pdf");
+        assert_eq!(sanitize("CON"), "_CON");
+        assert_eq!(sanitize("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize("console.txt"), "console.txt");
+        assert_eq!(sanitize("..."), "");
+    }
+
+    #[test]
+    fn shortens_long_names() {
+        let name = sanitize(&format!("{}.tar.gz", "a".repeat(300)));
+        assert_eq!(name.len(), MAX_NAME_LEN);
+        assert!(name.ends_with("a.gz"));
+
+        // Multi-byte characters aren't split.
+        let name = sanitize(&"é".repeat(200));
+        assert!(name.len() <= MAX_NAME_LEN);
+        assert_eq!(name.chars().count(), MAX_NAME_LEN / 2);
+
+        // Overly long extensions are dropped.
+        let name = sanitize(&format!("a.{}", "b".repeat(300)));
+        assert_eq!(name.len(), MAX_NAME_LEN);
+    }
+
+    #[test]
+    fn names_from_urls() {
+        assert_eq!(
+            from_url("https://example.com/files/report.pdf"),
+            "report.pdf"
+        );
+        assert_eq!(from_url("https://example.com/a%20b.txt?x=1"), "a b.txt");
+        assert_eq!(from_url("https://example.com/%2e%2e"), "example.com");
+        assert_eq!(
+            from_url("https://example.com/download.php?file=a.zip"),
+            "a.zip"
+        );
+        assert_eq!(
+            from_url("https://example.com/get?name=..%2F..%2Fb.zip"),
+            "b.zip"
+        );
+        assert_eq!(
+            from_url("https://example.com/report.pdf?file=a.zip"),
+            "report.pdf"
+        );
+        assert_eq!(from_url("https://example.com/"), "example.com");
+        assert_eq!(
+            from_url("https://example.com/?file=report.pdf"),
+            "report.pdf"
+        );
+        assert_eq!(from_url("not a url"), url_hash("not a url"));
+        assert_eq!(url_hash("not a url").len(), 8);
+    }
+
+    #[test]
+    fn extensions_of_url_names() {
+        assert!(has_extension("https://example.com/a.txt", "a.txt"));
+        assert!(!has_extension("https://example.com/a", "a"));
+        assert!(!has_extension("https://example.com/", "example.com"));
+    }
+
+    #[test]
+    fn names_from_content_disposition() {
+        let name = |value| from_content_disposition(value);
+        assert_eq!(name("attachment; filename=a.txt").as_deref(), Some("a.txt"));
+        assert_eq!(
+            name(r#"attachment; filename="a;b \"c\".txt""#).as_deref(),
+            Some("a;b _c_.txt")
+        );
+        assert_eq!(
+            name("attachment; filename=fallback.txt; filename*=UTF-8''na%C3%AFve.txt").as_deref(),
+            Some("naïve.txt")
+        );
+        assert_eq!(
+            name("attachment; filename*=iso-8859-1'en'caf%E9.txt").as_deref(),
+            Some("café.txt")
+        );
+        assert_eq!(
+            name(r#"attachment; filename="../../etc/passwd""#).as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(
+            name("attachment; filename*=UTF-8''..%2F..%2F").as_deref(),
+            None
+        );
+        assert_eq!(name("inline").as_deref(), None);
+    }
+
+    #[test]
+    fn content_types() {
+        assert_eq!(extension_for_content_type("application/pdf"), Some("pdf"));
+        assert_eq!(
+            extension_for_content_type("Text/HTML; charset=utf-8"),
+            Some("html")
+        );
+        assert_eq!(extension_for_content_type("application/x-unknown"), None);
+
+        assert!(content_type_matches(
+            "application/pdf",
+            "application/pdf; q=1"
+        ));
+        assert!(content_type_matches("image/*", "IMAGE/png"));
+        assert!(!content_type_matches("image/*", "imagex/png"));
+        assert!(!content_type_matches("application/pdf", "application/pdfx"));
+    }
+}
@@ -0,0 +1,379 @@
+//! Downloads over FTP and FTPS, enabled with the `ftp` feature.
+
+use crate::{
+    download::{create_parent_dir, remove_partial, Downloader, RESUME_SAVE_INTERVAL},
+    error::{DownloadError, IoResultExt},
+    event::DownloadEvent,
+    metrics,
+    net::{self, Network},
+    options::{DownloadOptions, Durability},
+    probe::Probe,
+    progress::SpeedMeter,
+    report::DownloadReport,
+    resume::{ChunkState, ResumeState},
+    stall::StallDetector,
+    storage,
+    tls::TlsConfig,
+};
+use percent_encoding::percent_decode_str;
+use reqwest::header::HeaderMap;
+use std::{
+    io::SeekFrom,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use suppaftp::{
+    tokio::{AsyncNativeTlsConnector, AsyncNativeTlsFtpStream},
+    types::FileType,
+    FtpError,
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+/// Checks whether `url` is an `ftp://` or `ftps://` URL.
+pub(crate) fn is_ftp_url(url: &str) -> bool {
+    url::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "ftp" | "ftps"))
+}
+
+/// The server a file is on, how to log in and the path of the file.
+struct Location {
+    host: String,
+    port: u16,
+    secure: bool,
+    username: String,
+    password: String,
+    /// Relative to the directory the server starts the session in, like `ftp://` URLs are.
+    path: String,
+}
+
+impl Location {
+    fn parse(url: &str) -> Result<Self, DownloadError> {
+        let invalid = || DownloadError::InvalidUrl(url.to_owned());
+        let parsed = url::Url::parse(url).map_err(|_| invalid())?;
+        let decode = |value: &str| percent_decode_str(value).decode_utf8_lossy().into_owned();
+
+        let path = decode(parsed.path().strip_prefix('/').unwrap_or(parsed.path()));
+        if path.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            host: parsed.host_str().ok_or_else(invalid)?.to_owned(),
+            port: parsed.port().unwrap_or(21),
+            secure: parsed.scheme() == "ftps",
+            username: match parsed.username() {
+                "" => "anonymous".to_owned(),
+                username => decode(username),
+            },
+            password: parsed
+                .password()
+                .map_or_else(|| "anonymous@".to_owned(), decode),
+            path,
+        })
+    }
+
+    /// Opens a control connection, logged in and switched to binary transfers.
+    async fn connect(
+        &self,
+        network: &Network,
+        tls: &TlsConfig,
+    ) -> Result<AsyncNativeTlsFtpStream, DownloadError> {
+        let stream = network
+            .connect(&self.host, self.port)
+            .await
+            .map_err(FtpError::ConnectionError)?;
+        let local = network.local_address();
+        let receive_buffer_size = network.receive_buffer_size;
+        let mut ftp = AsyncNativeTlsFtpStream::connect_with_stream(stream)
+            .await?
+            .passive_stream_builder(move |addr| {
+                Box::pin(async move {
+                    net::connect_from(local, addr, receive_buffer_size)
+                        .await
+                        .map_err(FtpError::ConnectionError)
+                })
+            });
+        if self.secure {
+            let connector = AsyncNativeTlsConnector::from(tls.ftp_connector()?);
+            ftp = ftp.into_secure(connector, &self.host).await?;
+        }
+        ftp.login(&self.username, &self.password).await?;
+        ftp.transfer_type(FileType::Binary).await?;
+        Ok(ftp)
+    }
+}
+
+impl Downloader {
+    /// Downloads an `ftp://` or `ftps://` URL.
+    ///
+    /// The size of the file is asked for with `SIZE`. Transfers that fail continue at the last
+    /// written byte with `REST`, both when they are retried and, with resuming enabled, in later runs.
+    pub(crate) async fn download_ftp(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        let location = Location::parse(url)?;
+
+        let (content_length, validator) = {
+            let _permit = options.or_cancelled(self.hosts.acquire(url)).await?;
+            let mut ftp = options
+                .or_cancelled(location.connect(&self.network, &self.tls))
+                .await??;
+            let size = ftp.size(&location.path).await.ok().map(|size| size as u64);
+            let modified = ftp.mdtm(&location.path).await.ok().map(|t| t.to_string());
+            let _ = ftp.quit().await;
+            (size.filter(|&size| size > 0), modified)
+        };
+        debug!(content_length = ?content_length, "probed FTP file");
+        self.check_size(url, content_length, options)?;
+
+        let name = self.output_name(url, options.filename.as_deref(), &HeaderMap::new(), options);
+        let resumable = match content_length {
+            Some(len) => {
+                self.find_resumable(url, len, validator.as_deref(), &name)
+                    .await
+            }
+            None => None,
+        };
+        let mut reservation = None;
+        let (output_path, state) = match resumable {
+            Some((path, state)) => (path, Some(state)),
+            None => {
+                let probe = Probe {
+                    content_length,
+                    ..Probe::default()
+                };
+                if let Some(report) = self.check_existing(url, &name, &probe, options).await? {
+                    return Ok(report);
+                }
+                let state =
+                    content_length.map(|len| ResumeState::new(url, len, validator.as_deref(), 1));
+                let reserved = reservation.insert(self.reserve_output_path(&name, options));
+                (reserved.path.clone(), state)
+            }
+        };
+
+        let partial = self.partial_path(&output_path);
+
+        // Without a known size the whole file is one chunk that ends with the transfer.
+        let chunks = match &state {
+            Some(state) => state.chunks(),
+            None => vec![Arc::new(ChunkState::new(0, u64::MAX - 1, 0))],
+        };
+        let initially_written: u64 = chunks.iter().map(|c| c.written()).sum();
+
+        create_parent_dir(&partial).await?;
+        if let Some(len) = content_length {
+            storage::check_space(&partial, len - initially_written)?;
+        }
+        if initially_written == 0 {
+            fs::File::create(&partial).await.with_path(&partial)?;
+        }
+        if let (true, Some(state)) = (self.resume, &state) {
+            state.save(&partial).await?;
+        }
+        options.emit(|| DownloadEvent::Started {
+            path: output_path.clone(),
+            total: content_length,
+        });
+
+        let mut meter = SpeedMeter::new(initially_written);
+        let mut last_report = Instant::now();
+        let report_progress = |meter: &mut SpeedMeter| match &state {
+            Some(state) => self.report_progress(url, options, state, meter),
+            None => self.report_sequential_progress(url, options, chunks[0].written(), None, meter),
+        };
+
+        let mut attempt = 1;
+        let mut retry_after_waited = Duration::ZERO;
+        let result = loop {
+            let result = async {
+                for chunk in chunks.iter().filter(|chunk| !chunk.is_complete()) {
+                    self.fetch_ftp_chunk(
+                        &location,
+                        &partial,
+                        chunk,
+                        state.as_ref(),
+                        options,
+                        &mut || {
+                            if last_report.elapsed() >= self.progress_interval {
+                                report_progress(&mut meter);
+                                last_report = Instant::now();
+                            }
+                        },
+                    )
+                    .await?;
+                }
+                Ok::<(), DownloadError>(())
+            }
+            .await;
+
+            match result {
+                Ok(()) => break Ok(()),
+                Err(e) => match self.retry.retry_delay(attempt, &e, &mut retry_after_waited) {
+                    Some(delay) => {
+                        warn!(attempt, error = %e, "FTP transfer failed, retrying");
+                        metrics::record_retry();
+                        if let Err(e) = options.or_cancelled(tokio::time::sleep(delay)).await {
+                            break Err(e);
+                        }
+                        attempt += 1;
+                    }
+                    None => break Err(e),
+                },
+            }
+        };
+        report_progress(&mut meter);
+
+        if let Err(e) = result {
+            // Nothing is worth keeping if the server refused to send the file at all.
+            let empty = chunks.iter().all(|c| c.written() == 0);
+            if self.discards_cancelled(&e) || empty {
+                remove_partial(&partial).await.with_path(&partial)?;
+                ResumeState::remove(&partial).await?;
+            } else if let (true, Some(state)) = (self.resume, &state) {
+                self.save_state(state, &partial).await?;
+            }
+            return Err(e);
+        }
+        self.make_durable(&partial).await?;
+        if self.resume {
+            ResumeState::remove(&partial).await?;
+        }
+
+        if let Some(checksum) = &options.checksum {
+            if let Err(e) = checksum.verify_file(&partial).await {
+                warn!(error = %e, "removing file that failed verification");
+                remove_partial(&partial).await.with_path(&partial)?;
+                return Err(e);
+            }
+        }
+        self.move_into_place(&partial, &output_path).await?;
+
+        let size = chunks.iter().map(|c| c.written()).sum();
+        Ok(DownloadReport {
+            path: output_path,
+            url: url.to_owned(),
+            final_url: url.to_owned(),
+            status: None,
+            headers: HeaderMap::new(),
+            size,
+            bytes_downloaded: size - initially_written,
+            elapsed: started.elapsed(),
+            retries: attempt - 1,
+            checksum: options.checksum.clone(),
+            checksum_source: options.checksum_source.clone(),
+            extracted_to: None,
+            chunks: Vec::new(),
+        })
+    }
+
+    /// Retrieves the rest of `chunk` on a new connection, starting with `REST` if part of the file
+    /// has already been written.
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch_ftp_chunk(
+        &self,
+        location: &Location,
+        output_path: &Path,
+        chunk: &ChunkState,
+        state: Option<&ResumeState>,
+        options: &DownloadOptions,
+        on_progress: &mut (dyn FnMut() + Send),
+    ) -> Result<(), DownloadError> {
+        chunk.reset_claims();
+        let start = chunk.start + chunk.written();
+
+        let _permit = options
+            .or_cancelled(
+                self.hosts
+                    .acquire(&format!("ftp://{}:{}", location.host, location.port)),
+            )
+            .await?;
+        let mut ftp = options
+            .or_cancelled(location.connect(&self.network, &self.tls))
+            .await??;
+        if start > 0 {
+            let offset =
+                usize::try_from(start).map_err(|_| DownloadError::ContentLengthMismatch {
+                    expected: chunk.len(),
+                    actual: chunk.written(),
+                })?;
+            ftp.resume_transfer(offset).await?;
+        }
+        let mut stream = ftp.retr_as_stream(&location.path).await?;
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(output_path)
+            .await
+            .with_path(output_path)?;
+        file.seek(SeekFrom::Start(start))
+            .await
+            .with_path(output_path)?;
+
+        let throttle = self.throttle.connection();
+        let mut stall = StallDetector::new(self.min_speed);
+        let mut buffer = vec![0; self.read_buffer_size];
+        let mut last_save = Instant::now();
+        let mut at_end = false;
+
+        while !chunk.is_complete() {
+            if options.or_cancelled(options.unpaused()).await? {
+                stall.reset();
+            }
+            let read = async {
+                let read = match self.read_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, stream.read(&mut buffer))
+                        .await
+                        .map_err(|_| DownloadError::TimeoutError(timeout))?,
+                    None => stream.read(&mut buffer).await,
+                };
+                read.map_err(|e| DownloadError::from(FtpError::ConnectionError(e)))
+            };
+            let read = options.or_cancelled(stall.watch(read)).await??;
+            if read == 0 {
+                at_end = true;
+                break;
+            }
+            stall.record(read)?;
+            options.or_cancelled(throttle.acquire(read)).await?;
+
+            // The server sends the rest of the file; the end of the chunk is where this connection stops.
+            let claimed = chunk.claim(read as u64) as usize;
+            file.write_all(&buffer[..claimed])
+                .await
+                .with_path(output_path)?;
+            chunk.add_written(claimed as u64);
+            on_progress();
+
+            if let (true, Some(state)) = (self.resume, state) {
+                if last_save.elapsed() >= RESUME_SAVE_INTERVAL {
+                    file.flush().await.with_path(output_path)?;
+                    self.save_state(state, output_path).await?;
+                    last_save = Instant::now();
+                }
+            }
+        }
+        file.flush().await.with_path(output_path)?;
+        if self.durability == Durability::FsyncPerChunk {
+            file.sync_data().await.with_path(output_path)?;
+        }
+
+        if at_end {
+            // The reply tells whether the server sent the whole file.
+            stream.finish().await?;
+            if state.is_some() && !chunk.is_complete() {
+                return Err(DownloadError::ContentLengthMismatch {
+                    expected: chunk.len(),
+                    actual: chunk.written(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+//! Downloads of `gs://bucket/object` URLs from Google Cloud Storage, enabled with the `gcs` feature.
+//!
+//! Objects are downloaded from the XML API, which serves ranges like any other HTTP server.
+//! Requests are authenticated with an OAuth access token, or signed like S3 requests with an HMAC
+//! key.
+
+use crate::{
+    error::DownloadError,
+    options::DownloadOptions,
+    s3::{AwsCredentials, SigV4Signer},
+    signing::{self, host_header, RequestSigner},
+};
+use reqwest::{header::AUTHORIZATION, RequestBuilder};
+use std::sync::Arc;
+
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+
+/// The region HMAC-signed requests are scoped to, which Cloud Storage accepts for every bucket.
+const SIGNING_REGION: &str = "auto";
+
+/// Where objects are downloaded from and how requests are authenticated.
+#[derive(Clone, Default)]
+pub(crate) struct GcsConfig {
+    pub auth: Option<GcsAuth>,
+    /// An emulator like `http://localhost:4443`. Defaults to `STORAGE_EMULATOR_HOST`.
+    pub endpoint: Option<String>,
+}
+
+#[derive(Clone)]
+pub(crate) enum GcsAuth {
+    /// The access ID and secret of an HMAC key.
+    Hmac(AwsCredentials),
+    /// An OAuth 2.0 access token, like the one printed by `gcloud auth print-access-token`.
+    Token(String),
+}
+
+/// Checks whether `url` is a `gs://` URL or points to the Cloud Storage XML API.
+pub(crate) fn is_gcs_url(url: &str) -> bool {
+    url::Url::parse(url).is_ok_and(|url| {
+        url.scheme() == "gs"
+            || (url.scheme() == "https" && url.host_str() == Some("storage.googleapis.com"))
+    })
+}
+
+impl GcsConfig {
+    /// Returns the HTTP URL of the object `url` points to, with `gs://` mirrors translated the
+    /// same way, and options that authenticate the requests to them.
+    ///
+    /// Without credentials the requests aren't authenticated, which works for public objects.
+    pub fn resolve(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<(String, DownloadOptions), DownloadError> {
+        let endpoint = self
+            .endpoint
+            .clone()
+            .or_else(|| std::env::var("STORAGE_EMULATOR_HOST").ok())
+            .unwrap_or_else(|| DEFAULT_ENDPOINT.to_owned());
+
+        let object_url = |url: &str| {
+            let invalid = || DownloadError::InvalidUrl(url.to_owned());
+            let parsed = url::Url::parse(url).map_err(|_| invalid())?;
+            if parsed.scheme() != "gs" {
+                return Ok(url.to_owned());
+            }
+            let bucket = parsed.host_str().ok_or_else(invalid)?;
+            if parsed.path().len() <= 1 {
+                return Err(invalid());
+            }
+            let query = parsed
+                .query()
+                .map(|q| format!("?{}", q))
+                .unwrap_or_default();
+            Ok(format!(
+                "{}/{}{}{}",
+                endpoint.trim_end_matches('/'),
+                bucket,
+                parsed.path(),
+                query
+            ))
+        };
+
+        let resolved = object_url(url)?;
+        let mut options = options.clone();
+        // Only the hosts of objects are sent credentials, not other mirrors.
+        let mut hosts: Vec<String> = url::Url::parse(&resolved)
+            .ok()
+            .and_then(|url| host_header(&url))
+            .into_iter()
+            .collect();
+        options.mirrors = options
+            .mirrors
+            .iter()
+            .map(|mirror| match is_gcs_url(mirror) {
+                true => {
+                    let resolved = object_url(mirror)?;
+                    hosts.extend(
+                        url::Url::parse(&resolved)
+                            .ok()
+                            .and_then(|u| host_header(&u)),
+                    );
+                    Ok(resolved)
+                }
+                false => Ok(mirror.clone()),
+            })
+            .collect::<Result<_, DownloadError>>()?;
+        hosts.sort();
+        hosts.dedup();
+
+        if let Some(auth) = &self.auth {
+            let signer: Arc<dyn RequestSigner> = match auth {
+                GcsAuth::Hmac(credentials) => Arc::new(SigV4Signer::new(
+                    credentials.clone(),
+                    SIGNING_REGION.to_owned(),
+                    hosts,
+                )),
+                GcsAuth::Token(token) => Arc::new(BearerSigner {
+                    token: token.clone(),
+                    hosts,
+                }),
+            };
+            options.auth = Arc::new(options.auth.with_signer(signer));
+        }
+
+        Ok((resolved, options))
+    }
+}
+
+/// Sends an OAuth access token to the hosts of objects.
+struct BearerSigner {
+    token: String,
+    hosts: Vec<String>,
+}
+
+impl RequestSigner for BearerSigner {
+    fn sign(&self, request: RequestBuilder) -> RequestBuilder {
+        let to_host = signing::inspect(&request)
+            .and_then(|built| host_header(built.url()))
+            .is_some_and(|host| self.hosts.contains(&host));
+        match to_host {
+            true => request.header(AUTHORIZATION, format!("Bearer {}", self.token)),
+            false => request,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(auth: GcsAuth) -> GcsConfig {
+        GcsConfig {
+            auth: Some(auth),
+            endpoint: Some(DEFAULT_ENDPOINT.to_owned()),
+        }
+    }
+
+    #[test]
+    fn signs_hmac_requests_like_s3() {
+        let credentials = AwsCredentials {
+            access_key_id: "GOOG1EXAMPLE".to_owned(),
+            secret_access_key: "bGoa+V7g/yqDXvKRqq+JTFn4uQZbPiQJo4pf9RzJ".to_owned(),
+            session_token: None,
+        };
+        let signer = SigV4Signer::new(
+            credentials,
+            SIGNING_REGION.to_owned(),
+            vec!["storage.googleapis.com".to_owned()],
+        );
+        let request =
+            reqwest::Client::new().get("https://storage.googleapis.com/bucket/dir/object.bin");
+        // 2015-08-30T12:36:00Z.
+        let signed = signer.sign_at(request, 1_440_938_160).build().unwrap();
+        assert_eq!(
+            signed.headers()[AUTHORIZATION],
+            "AWS4-HMAC-SHA256 Credential=GOOG1EXAMPLE/20150830/auto/s3/aws4_request, \
+             SignedHeaders=host;x-amz-content-sha256;x-amz-date, \
+             Signature=4f62f16c7e57877936dfe79ce223d0d80253d3cd8e0f50f52497d16356778d8f"
+        );
+    }
+
+    #[test]
+    fn sends_tokens_to_the_object_hosts() {
+        let options = DownloadOptions {
+            mirrors: vec![
+                "gs://other/object.bin".to_owned(),
+                "https://mirror.example.com/object.bin".to_owned(),
+            ],
+            ..Default::default()
+        };
+        let (resolved, options) = config(GcsAuth::Token("ya29.token".to_owned()))
+            .resolve("gs://bucket/dir/object.bin?generation=1", &options)
+            .unwrap();
+        assert_eq!(
+            resolved,
+            "https://storage.googleapis.com/bucket/dir/object.bin?generation=1"
+        );
+        assert_eq!(
+            options.mirrors,
+            [
+                "https://storage.googleapis.com/other/object.bin",
+                "https://mirror.example.com/object.bin",
+            ]
+        );
+
+        let client = reqwest::Client::new();
+        let authorization = |url: &str| {
+            let (request, _) = options.auth.authorize(client.get(url), true);
+            request
+                .build()
+                .unwrap()
+                .headers()
+                .get(AUTHORIZATION)
+                .cloned()
+        };
+        assert_eq!(authorization(&resolved).unwrap(), "Bearer ya29.token");
+        assert_eq!(
+            authorization(&options.mirrors[0]).unwrap(),
+            "Bearer ya29.token"
+        );
+        assert!(authorization(&options.mirrors[1]).is_none());
+    }
+
+    #[test]
+    fn refuses_urls_without_an_object() {
+        let config = config(GcsAuth::Token("ya29.token".to_owned()));
+        assert!(config
+            .resolve("gs://bucket", &DownloadOptions::default())
+            .is_err());
+        assert!(config
+            .resolve("gs://bucket/", &DownloadOptions::default())
+            .is_err());
+    }
+}
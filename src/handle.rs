@@ -0,0 +1,57 @@
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+/// Controls a running download from the outside.
+///
+/// Attach it to a download with [`DownloadOptions::handle`](crate::DownloadOptions::handle).
+#[derive(Debug, Clone)]
+pub struct DownloadHandle {
+    cancel_token: CancellationToken,
+    paused: watch::Sender<bool>,
+}
+
+impl DownloadHandle {
+    pub fn new() -> Self {
+        Self {
+            cancel_token: CancellationToken::new(),
+            paused: watch::Sender::new(false),
+        }
+    }
+
+    /// Suspends reading from all connections. Open connections are kept.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    /// Continues a paused download.
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Aborts the download.
+    pub fn cancel(&self) {
+        self.cancel_token.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel_token.is_cancelled()
+    }
+
+    pub(crate) fn cancel_token(&self) -> CancellationToken {
+        self.cancel_token.clone()
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.paused.subscribe()
+    }
+}
+
+impl Default for DownloadHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,431 @@
+use crate::{
+    download::Downloader,
+    error::DownloadError,
+    filename, metrics,
+    options::DownloadOptions,
+    report::DownloadReport,
+    segments::{Segment, StreamVariant},
+};
+use std::{path::Path, time::Instant};
+use url::Url;
+
+/// An HLS playlist, which either lists the variants of a stream or the segments of one.
+enum Playlist {
+    Multivariant(Vec<Variant>),
+    Media {
+        /// The initialization section, followed by the segments.
+        segments: Vec<Segment>,
+        fragmented: bool,
+    },
+}
+
+struct Variant {
+    bandwidth: u64,
+    url: Url,
+}
+
+impl Downloader {
+    /// Downloads the HLS stream at `url` into a single file. See [`Downloader::download_hls_with`].
+    pub async fn download_hls(
+        &self,
+        url: &str,
+        variant: StreamVariant,
+    ) -> Result<DownloadReport, DownloadError> {
+        self.download_hls_with(url, variant, &DownloadOptions::default())
+            .await
+    }
+
+    /// Like [`Downloader::download_hls`], with settings that only apply to this download.
+    ///
+    /// If `url` is a multivariant playlist, `variant` picks the stream that is downloaded by its
+    /// bandwidth. The segments of its media playlist are fetched over `conn_count` connections
+    /// and joined in order, into a `.ts` file, or an `.mp4` file for fragmented MP4 streams, named
+    /// after the playlist unless `options` sets a filename.
+    ///
+    /// Alternative renditions, like audio tracks in other languages, aren't downloaded, and
+    /// encrypted streams aren't supported. Live playlists are downloaded as far as they go when
+    /// they're fetched.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "download_hls", skip_all, fields(url = %url)))]
+    pub async fn download_hls_with(
+        &self,
+        url: &str,
+        variant: StreamVariant,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        let _running = self.shutdown.enter();
+        let result = match self.shutdown.is_shut_down() {
+            true => Err(DownloadError::Cancelled),
+            false => {
+                self.fetch_hls(url, variant, &options.started(url, self.shutdown.token()))
+                    .await
+            }
+        };
+        self.hooks.run(url, &result, started.elapsed()).await;
+        metrics::record_outcome(&result);
+        result
+    }
+
+    async fn fetch_hls(
+        &self,
+        url: &str,
+        variant: StreamVariant,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let parsed = Url::parse(url).map_err(|_| DownloadError::InvalidUrl(url.to_owned()))?;
+        let playlist = self.fetch_playlist(&parsed, options).await?;
+        let (media_url, segments, fragmented) = match playlist {
+            Playlist::Media {
+                segments,
+                fragmented,
+            } => (parsed, segments, fragmented),
+            Playlist::Multivariant(variants) => {
+                let bandwidths: Vec<_> = variants.iter().map(|v| v.bandwidth).collect();
+                let chosen = &variants[variant.select(&bandwidths)];
+                debug!(bandwidth = chosen.bandwidth, url = %chosen.url, "selected variant");
+                match self.fetch_playlist(&chosen.url, options).await? {
+                    Playlist::Media {
+                        segments,
+                        fragmented,
+                    } => (chosen.url.clone(), segments, fragmented),
+                    Playlist::Multivariant(_) => {
+                        return Err(invalid(
+                            &chosen.url,
+                            "variant is another multivariant playlist".to_owned(),
+                        ))
+                    }
+                }
+            }
+        };
+
+        let extension = if fragmented { "mp4" } else { "ts" };
+        let name = match &options.filename {
+            Some(name) => name.clone(),
+            None => Path::new(&filename::from_url(media_url.as_str()))
+                .with_extension(extension)
+                .to_string_lossy()
+                .into_owned(),
+        };
+        let name = self.output_name(url, Some(&name), &Default::default(), options);
+        self.download_segments(url, &name, &segments, options).await
+    }
+
+    async fn fetch_playlist(
+        &self,
+        url: &Url,
+        options: &DownloadOptions,
+    ) -> Result<Playlist, DownloadError> {
+        let (base, text) = self.fetch_manifest(url, options).await?;
+        parse(&base, &text).map_err(|reason| invalid(url, reason))
+    }
+}
+
+fn invalid(url: &Url, reason: String) -> DownloadError {
+    DownloadError::InvalidManifest {
+        url: url.to_string(),
+        reason,
+    }
+}
+
+/// Parses a playlist served from `base`, which its URIs are relative to.
+fn parse(base: &Url, text: &str) -> Result<Playlist, String> {
+    let mut lines = text
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        return Err("doesn't start with #EXTM3U".to_owned());
+    }
+
+    let mut variants = Vec::new();
+    let mut segments = Vec::new();
+    let mut init: Option<Segment> = None;
+    let mut bandwidth = None;
+    let mut byte_range = None;
+    // Where the previous sub-range ended, which is where one without an offset starts.
+    let mut previous_end: Option<(Url, u64)> = None;
+    for line in lines {
+        if let Some(tag) = line.strip_prefix('#') {
+            let (name, value) = tag.split_once(':').unwrap_or((tag, ""));
+            match name {
+                "EXT-X-STREAM-INF" => {
+                    let attributes = attributes(value);
+                    let value =
+                        attribute(&attributes, "BANDWIDTH").ok_or("variant without a BANDWIDTH")?;
+                    bandwidth = Some(
+                        value
+                            .parse::<u64>()
+                            .map_err(|_| format!("invalid BANDWIDTH `{}`", value))?,
+                    );
+                }
+                "EXT-X-BYTERANGE" => byte_range = Some(parse_byte_range(value)?),
+                "EXT-X-MAP" => {
+                    let attributes = attributes(value);
+                    let uri = attribute(&attributes, "URI").ok_or("EXT-X-MAP without a URI")?;
+                    let url = join(base, uri)?;
+                    let range = match attribute(&attributes, "BYTERANGE") {
+                        Some(range) => match parse_byte_range(range)? {
+                            (len, Some(start)) => Some((start, len)),
+                            (_, None) => {
+                                return Err("EXT-X-MAP BYTERANGE without an offset".to_owned())
+                            }
+                        },
+                        None => None,
+                    };
+                    match &init {
+                        Some(init) if init.url != url || init.range != range => {
+                            return Err(
+                                "changing initialization sections aren't supported".to_owned()
+                            )
+                        }
+                        Some(_) => {}
+                        None => init = Some(Segment { url, range }),
+                    }
+                }
+                "EXT-X-KEY" => {
+                    let attributes = attributes(value);
+                    match attribute(&attributes, "METHOD") {
+                        Some("NONE") => {}
+                        method => {
+                            return Err(format!(
+                                "encrypted segments ({}) aren't supported",
+                                method.unwrap_or("unknown method")
+                            ))
+                        }
+                    }
+                }
+                // Comments and tags that don't change what's downloaded, like `EXTINF`.
+                _ => {}
+            }
+            continue;
+        }
+
+        let url = join(base, line)?;
+        if let Some(bandwidth) = bandwidth.take() {
+            variants.push(Variant { bandwidth, url });
+            continue;
+        }
+        let range = match byte_range.take() {
+            Some((len, offset)) => {
+                let start = match (offset, &previous_end) {
+                    (Some(start), _) => start,
+                    (None, Some((previous, end))) if *previous == url => *end,
+                    (None, _) => return Err(format!("byte range of `{}` without an offset", line)),
+                };
+                let end = start
+                    .checked_add(len)
+                    .ok_or_else(|| format!("byte range of `{}` overflows", line))?;
+                previous_end = Some((url.clone(), end));
+                Some((start, len))
+            }
+            None => None,
+        };
+        segments.push(Segment { url, range });
+    }
+
+    if !variants.is_empty() {
+        return Ok(Playlist::Multivariant(variants));
+    }
+    if segments.is_empty() {
+        return Err("lists no variants or segments".to_owned());
+    }
+    let fragmented = init.is_some();
+    Ok(Playlist::Media {
+        segments: init.into_iter().chain(segments).collect(),
+        fragmented,
+    })
+}
+
+fn join(base: &Url, uri: &str) -> Result<Url, String> {
+    base.join(uri).map_err(|_| format!("invalid URI `{}`", uri))
+}
+
+/// Parses a byte range like `1024@2048` into its length and offset, refusing ranges that end
+/// past `u64::MAX`.
+fn parse_byte_range(value: &str) -> Result<(u64, Option<u64>), String> {
+    let invalid = || format!("invalid byte range `{}`", value);
+    let (len, offset) = match value.split_once('@') {
+        Some((len, offset)) => (len, Some(offset.parse().map_err(|_| invalid())?)),
+        None => (value, None),
+    };
+    let len: u64 = len.parse().map_err(|_| invalid())?;
+    if offset.is_some_and(|offset: u64| offset.checked_add(len).is_none()) {
+        return Err(invalid());
+    }
+    Ok((len, offset))
+}
+
+/// Splits an attribute list like `BANDWIDTH=1280000,CODECS="avc1.4d401f,mp4a.40.2"` into names
+/// and values, without the quotes around strings.
+fn attributes(list: &str) -> Vec<(&str, &str)> {
+    let mut attributes = Vec::new();
+    let mut rest = list;
+    while let Some((name, after)) = rest.split_once('=') {
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((value, next)) => (value, next),
+                None => (quoted, ""),
+            },
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        attributes.push((name.trim(), value));
+        rest = next.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+    }
+    attributes
+}
+
+fn attribute<'a>(attributes: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| *value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("https://example.com/live/index.m3u8").unwrap()
+    }
+
+    type Listed = (String, Option<(u64, u64)>);
+
+    /// Parses a media playlist into its segment URLs and ranges, and whether it's fragmented.
+    fn media(text: &str) -> (Vec<Listed>, bool) {
+        match parse(&base(), text).unwrap() {
+            Playlist::Media {
+                segments,
+                fragmented,
+            } => (
+                segments
+                    .into_iter()
+                    .map(|s| (s.url.to_string(), s.range))
+                    .collect(),
+                fragmented,
+            ),
+            Playlist::Multivariant(_) => panic!("not a media playlist"),
+        }
+    }
+
+    #[test]
+    fn parses_multivariant_playlists() {
+        let text = "#EXTM3U\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=1280000,CODECS=\"avc1.4d401f,mp4a.40.2\"\n\
+            low/index.m3u8\n\
+            #EXT-X-STREAM-INF:RESOLUTION=1920x1080,BANDWIDTH=5000000\n\
+            https://cdn.example.com/high.m3u8\n";
+        let Ok(Playlist::Multivariant(variants)) = parse(&base(), text) else {
+            panic!("not a multivariant playlist");
+        };
+        let variants: Vec<_> = variants
+            .iter()
+            .map(|v| (v.bandwidth, v.url.as_str()))
+            .collect();
+        assert_eq!(
+            variants,
+            [
+                (1_280_000, "https://example.com/live/low/index.m3u8"),
+                (5_000_000, "https://cdn.example.com/high.m3u8"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_media_playlists() {
+        let text = "\u{feff}#EXTM3U\n\
+            #EXT-X-TARGETDURATION:10\n\
+            # a comment\n\
+            #EXTINF:9.0,\n\
+            seg0.ts\n\
+            \n\
+            #EXTINF:9.0,\n\
+            /other/seg1.ts\n\
+            #EXT-X-ENDLIST\n";
+        let (segments, fragmented) = media(text);
+        assert!(!fragmented);
+        assert_eq!(
+            segments,
+            [
+                ("https://example.com/live/seg0.ts".to_owned(), None),
+                ("https://example.com/other/seg1.ts".to_owned(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        let text = "#EXTM3U\n\
+            #EXT-X-MAP:URI=\"main.mp4\",BYTERANGE=\"720@0\"\n\
+            #EXT-X-BYTERANGE:1000@720\n\
+            main.mp4\n\
+            #EXT-X-BYTERANGE:500\n\
+            main.mp4\n\
+            #EXT-X-MAP:URI=\"main.mp4\",BYTERANGE=\"720@0\"\n\
+            #EXT-X-BYTERANGE:300\n\
+            main.mp4\n";
+        let (segments, fragmented) = media(text);
+        assert!(fragmented);
+        let url = "https://example.com/live/main.mp4".to_owned();
+        assert_eq!(
+            segments,
+            [
+                (url.clone(), Some((0, 720))),
+                (url.clone(), Some((720, 1000))),
+                (url.clone(), Some((1720, 500))),
+                (url, Some((2220, 300))),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_invalid_byte_ranges() {
+        assert_eq!(parse_byte_range("1024@2048"), Ok((1024, Some(2048))));
+        assert_eq!(parse_byte_range("1024"), Ok((1024, None)));
+        assert!(parse_byte_range("1@18446744073709551615").is_err());
+        assert!(parse_byte_range("x@0").is_err());
+        assert!(parse_byte_range("1@").is_err());
+
+        let reject = |text: &str| assert!(parse(&base(), text).is_err(), "{}", text);
+        reject("#EXTM3U\n#EXT-X-BYTERANGE:100\na.ts\n");
+        reject("#EXTM3U\n#EXT-X-BYTERANGE:1@0\na.ts\n#EXT-X-BYTERANGE:1\nb.ts\n");
+        reject(
+            "#EXTM3U\n#EXT-X-BYTERANGE:10@18446744073709551605\na.ts\n\
+             #EXT-X-BYTERANGE:1\na.ts\n",
+        );
+        reject("#EXTM3U\n#EXT-X-MAP:URI=\"i.mp4\",BYTERANGE=\"720\"\na.mp4\n");
+        reject(
+            "#EXTM3U\n#EXT-X-MAP:URI=\"i.mp4\"\na.mp4\n\
+             #EXT-X-MAP:URI=\"j.mp4\"\nb.mp4\n",
+        );
+    }
+
+    #[test]
+    fn refuses_encrypted_and_malformed_playlists() {
+        let reject = |text: &str| assert!(parse(&base(), text).is_err(), "{}", text);
+        reject("#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"key\"\na.ts\n");
+        reject("#EXTM3U\n#EXT-X-KEY:URI=\"key\"\na.ts\n");
+        reject("a.ts\n");
+        reject("#EXTM3U\n#EXT-X-ENDLIST\n");
+        reject("#EXTM3U\n#EXT-X-STREAM-INF:CODECS=\"avc1\"\nlow.m3u8\n");
+        reject("#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=fast\nlow.m3u8\n");
+
+        let (segments, _) = media("#EXTM3U\n#EXT-X-KEY:METHOD=NONE\na.ts\n");
+        assert_eq!(segments.len(), 1);
+    }
+
+    #[test]
+    fn splits_attribute_lists() {
+        assert_eq!(
+            attributes("BANDWIDTH=1280000, CODECS=\"avc1.4d401f,mp4a.40.2\",NAME=\"a=b\""),
+            [
+                ("BANDWIDTH", "1280000"),
+                ("CODECS", "avc1.4d401f,mp4a.40.2"),
+                ("NAME", "a=b"),
+            ]
+        );
+        assert_eq!(attributes("URI=\"unterminated"), [("URI", "unterminated")]);
+        assert!(attributes("").is_empty());
+    }
+}
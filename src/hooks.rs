@@ -0,0 +1,74 @@
+use crate::{error::DownloadError, report::DownloadReport};
+use futures::future::BoxFuture;
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
+
+/// Runs once a download has finished, for example to move the file, notify someone, or start
+/// processing it.
+///
+/// Hooks run before the download returns, in the order they were added. Implemented for async
+/// closures taking the report of a successful download.
+pub trait DownloadHook: Send + Sync {
+    /// Called after the file has been downloaded and verified.
+    fn completed(&self, report: &DownloadReport) -> BoxFuture<'static, ()>;
+
+    /// Called after the download of `url` failed, `elapsed` after it started. Does nothing by
+    /// default.
+    fn failed(
+        &self,
+        url: &str,
+        error: &DownloadError,
+        elapsed: Duration,
+    ) -> BoxFuture<'static, ()> {
+        let _ = (url, error, elapsed);
+        Box::pin(async {})
+    }
+}
+
+impl<F, Fut> DownloadHook for F
+where
+    F: Fn(DownloadReport) -> Fut + Send + Sync,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn completed(&self, report: &DownloadReport) -> BoxFuture<'static, ()> {
+        Box::pin(self(report.clone()))
+    }
+}
+
+/// The hooks of a downloader, and how many downloads may run them at once.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    hooks: Vec<Arc<dyn DownloadHook>>,
+    limit: Option<Arc<Semaphore>>,
+}
+
+impl Hooks {
+    pub fn new(hooks: Vec<Arc<dyn DownloadHook>>, concurrency: Option<usize>) -> Self {
+        Self {
+            hooks,
+            limit: concurrency.map(|limit| Arc::new(Semaphore::new(limit.max(1)))),
+        }
+    }
+
+    /// Runs every hook for the outcome of the download of `url`, which took `elapsed`.
+    pub async fn run(
+        &self,
+        url: &str,
+        result: &Result<DownloadReport, DownloadError>,
+        elapsed: Duration,
+    ) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let _permit = match &self.limit {
+            Some(limit) => limit.acquire().await.ok(),
+            None => None,
+        };
+        for hook in &self.hooks {
+            match result {
+                Ok(report) => hook.completed(report).await,
+                Err(e) => hook.failed(url, e, elapsed).await,
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Allows a connection to a host while it's held.
+pub(crate) type HostPermit = Option<OwnedSemaphorePermit>;
+
+/// Limits the connections to every host, shared by all downloads of a [`Downloader`](crate::Downloader).
+pub(crate) struct HostLimiter {
+    max_connections: Option<usize>,
+    request_delay: Option<Duration>,
+    hosts: Mutex<HashMap<String, Host>>,
+}
+
+struct Host {
+    connections: Option<Arc<Semaphore>>,
+    next_request: Instant,
+}
+
+impl HostLimiter {
+    pub fn new(max_connections: Option<usize>, request_delay: Option<Duration>) -> Self {
+        Self {
+            max_connections: max_connections.map(|max| max.max(1)),
+            request_delay,
+            hosts: Mutex::default(),
+        }
+    }
+
+    /// Waits until a request may be sent to the host of `url`.
+    ///
+    /// The returned permit should be held until the response has been read.
+    pub async fn acquire(&self, url: &str) -> HostPermit {
+        if self.max_connections.is_none() && self.request_delay.is_none() {
+            return None;
+        }
+
+        let key = host_key(url);
+        let connections = self.host(&key, |host| host.connections.clone());
+        let permit = match connections {
+            Some(connections) => Some(
+                connections
+                    .acquire_owned()
+                    .await
+                    .expect("the semaphore is never closed"),
+            ),
+            None => None,
+        };
+
+        if let Some(delay) = self.request_delay {
+            let wait = self.host(&key, |host| {
+                let now = Instant::now();
+                let start = host.next_request.max(now);
+                host.next_request = start + delay;
+                start - now
+            });
+            tokio::time::sleep(wait).await;
+        }
+
+        permit
+    }
+
+    fn host<T>(&self, key: &str, f: impl FnOnce(&mut Host) -> T) -> T {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = hosts.entry(key.to_owned()).or_insert_with(|| Host {
+            connections: self
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            next_request: Instant::now(),
+        });
+        f(host)
+    }
+}
+
+/// Identifies the server of `url` by its host and port.
+fn host_key(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(url) => format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        ),
+        Err(_) => url.to_owned(),
+    }
+}
@@ -0,0 +1,148 @@
+//! HTTP/3 (QUIC) requests, enabled with the `http3` feature.
+//!
+//! Downloads start over TCP. Servers that advertise HTTP/3 on the same port with an `Alt-Svc`
+//! header are then requested over QUIC, until that fails and they're requested over TCP again.
+
+use crate::{error::DownloadError, options::DownloadOptions};
+use reqwest::{
+    header::{HeaderMap, ALT_SVC},
+    RequestBuilder, Response, Version,
+};
+use std::{collections::HashMap, sync::Mutex};
+
+/// How a host that advertised HTTP/3 is requested.
+#[derive(Clone, Copy, PartialEq)]
+enum Support {
+    /// Over QUIC, once a connection has been established.
+    Advertised,
+    /// Over the established QUIC connection.
+    Connected,
+    /// Over TCP, since QUIC didn't get through.
+    Failed,
+}
+
+/// The HTTP/3 client and the hosts it's used for, shared by all downloads of a
+/// [`Downloader`](crate::Downloader).
+pub(crate) struct Http3 {
+    client: reqwest::Client,
+    hosts: Mutex<HashMap<String, Support>>,
+    /// Held while connecting to a host, since reqwest fails other requests to it in the meantime.
+    connecting: tokio::sync::Mutex<()>,
+}
+
+impl Http3 {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self {
+            client,
+            hosts: Mutex::default(),
+            connecting: tokio::sync::Mutex::default(),
+        }
+    }
+
+    /// Sends the request built by `request` over HTTP/3 if the host of `url` supports it, and with
+    /// the `tcp` client otherwise or if that fails.
+    pub async fn send(
+        &self,
+        tcp: &reqwest::Client,
+        url: &str,
+        options: &DownloadOptions,
+        request: impl Fn(&reqwest::Client) -> RequestBuilder,
+    ) -> Result<Response, DownloadError> {
+        let mut support = self.support(url);
+        let _connecting = match support {
+            Some(Support::Advertised) => {
+                let connecting = self.connecting.lock().await;
+                // The connection may have been established or failed in the meantime.
+                support = self.support(url);
+                Some(connecting)
+            }
+            _ => None,
+        };
+        if matches!(support, Some(Support::Advertised | Support::Connected)) {
+            let request = || request(&self.client).version(Version::HTTP_3);
+            match options.send(url, request).await {
+                // Errors without a response mean QUIC didn't get through.
+                Err(DownloadError::RequestError(_e)) => {
+                    debug!(url, error = %_e, "HTTP/3 request failed");
+                    self.set_support(url, Support::Failed);
+                }
+                result => {
+                    self.set_support(url, Support::Connected);
+                    return result;
+                }
+            }
+        }
+
+        let result = options.send(url, || request(tcp)).await;
+        if let Ok(response) = &result {
+            self.learn(url, response.headers());
+        }
+        result
+    }
+
+    fn support(&self, url: &str) -> Option<Support> {
+        let key = host_key(url)?;
+        self.hosts.lock().unwrap().get(&key).copied()
+    }
+
+    fn set_support(&self, url: &str, support: Support) {
+        let Some(key) = host_key(url) else {
+            return;
+        };
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(current) = hosts.get_mut(&key) {
+            if support == Support::Failed && *current != Support::Failed {
+                warn!(url, "HTTP/3 request failed, falling back to TCP");
+            }
+            // Hosts that failed aren't tried again.
+            if *current != Support::Failed {
+                *current = support;
+            }
+        }
+    }
+
+    /// Remembers whether the response to a request for `url` advertised HTTP/3 on the same port.
+    fn learn(&self, url: &str, headers: &HeaderMap) {
+        let Some(key) = host_key(url) else {
+            return;
+        };
+        let Some(alt_svc) = headers.get(ALT_SVC).and_then(|v| v.to_str().ok()) else {
+            return;
+        };
+        let mut hosts = self.hosts.lock().unwrap();
+        if alt_svc.trim() == "clear" {
+            hosts.remove(&key);
+        } else if offers_h3(alt_svc, &key) {
+            hosts.entry(key).or_insert(Support::Advertised);
+        }
+    }
+}
+
+/// Checks whether an `Alt-Svc` header offers HTTP/3 for the host and port in `key`.
+///
+/// The client connects to the host and port of the URL, so alternatives elsewhere are ignored.
+fn offers_h3(alt_svc: &str, key: &str) -> bool {
+    let (host, port) = key.rsplit_once(':').unwrap_or((key, ""));
+    alt_svc.split(',').any(|service| {
+        let service = service.split(';').next().unwrap_or_default().trim();
+        let Some((protocol, authority)) = service.split_once('=') else {
+            return false;
+        };
+        let authority = authority.trim_matches('"');
+        let (alt_host, alt_port) = authority.rsplit_once(':').unwrap_or((authority, ""));
+        protocol == "h3" && (alt_host.is_empty() || alt_host == host) && alt_port == port
+    })
+}
+
+/// Identifies the server of an `https://` URL by its host and port.
+fn host_key(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    if url.scheme() != "https" {
+        return None;
+    }
+    Some(format!(
+        "{}:{}",
+        url.host_str()?,
+        url.port_or_known_default()?
+    ))
+}
@@ -0,0 +1,809 @@
+//! Downloads of `ipfs://` URLs through HTTP gateways, enabled with the `ipfs` feature.
+//!
+//! Content is fetched block by block from the gateways in their trustless `?format=raw` form,
+//! and every block is checked against the hash in its CID before it's used, so the gateways don't
+//! have to be trusted. Files are put back together from their UnixFS DAG.
+
+use crate::{
+    checksum,
+    download::{self, Downloader},
+    error::{DownloadError, IoResultExt},
+    event::DownloadEvent,
+    filename, metrics,
+    mirrors::Mirrors,
+    options::DownloadOptions,
+    probe::Probe,
+    progress::SpeedMeter,
+    report::DownloadReport,
+    segments::Segment,
+};
+use futures::{future, stream::FuturesUnordered, StreamExt};
+use percent_encoding::percent_decode_str;
+use reqwest::header::HeaderMap;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::SeekFrom,
+    path::Path,
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+use url::Url;
+
+/// Public gateways that serve blocks in their trustless form.
+const DEFAULT_GATEWAYS: &[&str] = &[
+    "https://ipfs.io",
+    "https://dweb.link",
+    "https://trustless-gateway.link",
+];
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BASE32_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Multicodec codes of the block encodings.
+const RAW: u64 = 0x55;
+const DAG_PB: u64 = 0x70;
+
+/// Multicodec codes of the hash functions.
+const IDENTITY: u64 = 0x00;
+const SHA2_256: u64 = 0x12;
+const BLAKE3: u64 = 0x1e;
+
+/// The gateways `ipfs://` URLs are downloaded from.
+#[derive(Clone)]
+pub(crate) struct IpfsConfig {
+    pub gateways: Vec<String>,
+}
+
+impl Default for IpfsConfig {
+    fn default() -> Self {
+        Self {
+            gateways: DEFAULT_GATEWAYS.iter().map(|&g| g.to_owned()).collect(),
+        }
+    }
+}
+
+/// Checks whether `url` is an `ipfs://` URL.
+pub(crate) fn is_ipfs_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| url.scheme() == "ipfs")
+}
+
+/// A content identifier: how a block is encoded and the multihash of the block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cid {
+    version: u64,
+    codec: u64,
+    hash: u64,
+    digest: Vec<u8>,
+}
+
+impl Cid {
+    /// Parses a CID in its text form, like `bafkrei...` or `Qm...`.
+    fn parse(text: &str) -> Result<Self, String> {
+        if text.len() == 46 && text.starts_with("Qm") {
+            return Self::from_bytes(&base58_decode(text).ok_or("invalid base58 CID")?);
+        }
+        let (base, rest) = text.split_at(text.chars().next().map_or(0, char::len_utf8));
+        let bytes = match base {
+            "b" => base32_decode(rest),
+            "B" => base32_decode(&rest.to_ascii_lowercase()),
+            "z" => base58_decode(rest),
+            _ => return Err(format!("unsupported multibase prefix `{}`", base)),
+        };
+        Self::from_bytes(&bytes.ok_or("invalid multibase encoding")?)
+    }
+
+    /// Parses a binary CID, like those of the links of `dag-pb` nodes.
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let truncated = || "truncated CID".to_owned();
+        // Version 0 CIDs are bare SHA-256 multihashes of `dag-pb` blocks.
+        if bytes.len() == 34 && bytes[..2] == [SHA2_256 as u8, 32] {
+            return Ok(Self {
+                version: 0,
+                codec: DAG_PB,
+                hash: SHA2_256,
+                digest: bytes[2..].to_vec(),
+            });
+        }
+        let mut rest = bytes;
+        let version = read_varint(&mut rest).ok_or_else(truncated)?;
+        if version != 1 {
+            return Err(format!("unsupported CID version {}", version));
+        }
+        let codec = read_varint(&mut rest).ok_or_else(truncated)?;
+        let hash = read_varint(&mut rest).ok_or_else(truncated)?;
+        let len = read_varint(&mut rest).ok_or_else(truncated)?;
+        if rest.len() as u64 != len {
+            return Err(truncated());
+        }
+        Ok(Self {
+            version,
+            codec,
+            hash,
+            digest: rest.to_vec(),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if self.version == 1 {
+            write_varint(&mut bytes, 1);
+            write_varint(&mut bytes, self.codec);
+        }
+        write_varint(&mut bytes, self.hash);
+        write_varint(&mut bytes, self.digest.len() as u64);
+        bytes.extend_from_slice(&self.digest);
+        bytes
+    }
+
+    /// Fails with the expected and actual digests if `block` doesn't hash to this CID.
+    fn verify(&self, block: &[u8]) -> Result<(), (String, String)> {
+        let actual = match self.hash {
+            IDENTITY => block.to_vec(),
+            SHA2_256 => Sha256::digest(block).to_vec(),
+            BLAKE3 => blake3::hash(block).as_bytes().to_vec(),
+            _ => unreachable!("unsupported hashes are rejected before fetching"),
+        };
+        match actual == self.digest {
+            true => Ok(()),
+            false => Err((checksum::to_hex(&self.digest), checksum::to_hex(&actual))),
+        }
+    }
+
+    fn check_supported(&self) -> Result<(), String> {
+        if !matches!(self.codec, RAW | DAG_PB) {
+            return Err(format!("unsupported codec 0x{:x}", self.codec));
+        }
+        match self.hash {
+            IDENTITY => Ok(()),
+            SHA2_256 | BLAKE3 if self.digest.len() == 32 => Ok(()),
+            SHA2_256 | BLAKE3 => Err("truncated hashes aren't supported".to_owned()),
+            hash => Err(format!("unsupported hash function 0x{:x}", hash)),
+        }
+    }
+}
+
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.version {
+            0 => f.write_str(&base58_encode(&self.to_bytes())),
+            _ => write!(f, "b{}", base32_encode(&self.to_bytes())),
+        }
+    }
+}
+
+/// What a block says about the file or directory it's part of.
+struct Node {
+    kind: NodeKind,
+    /// File contents stored in the block itself, which come before those of its children.
+    data: Vec<u8>,
+    links: Vec<Link>,
+    /// The length of the file contents of each link.
+    block_sizes: Vec<u64>,
+    file_size: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum NodeKind {
+    File,
+    Directory,
+    ShardedDirectory,
+    Other(u64),
+}
+
+struct Link {
+    cid: Cid,
+    name: String,
+}
+
+impl Node {
+    /// Decodes `block`, which `cid` was checked to identify.
+    fn decode(cid: &Cid, block: Vec<u8>) -> Result<Self, String> {
+        if cid.codec == RAW {
+            return Ok(Self {
+                kind: NodeKind::File,
+                file_size: Some(block.len() as u64),
+                data: block,
+                links: Vec::new(),
+                block_sizes: Vec::new(),
+            });
+        }
+
+        let mut links = Vec::new();
+        let mut unixfs = None;
+        for (field, value) in protobuf_fields(&block)? {
+            match (field, value) {
+                (1, Field::Bytes(data)) => unixfs = Some(data),
+                (2, Field::Bytes(link)) => {
+                    let mut cid = None;
+                    let mut name = String::new();
+                    for (field, value) in protobuf_fields(link)? {
+                        match (field, value) {
+                            (1, Field::Bytes(hash)) => cid = Some(Cid::from_bytes(hash)?),
+                            (2, Field::Bytes(n)) => name = String::from_utf8_lossy(n).into_owned(),
+                            _ => {}
+                        }
+                    }
+                    let cid = cid.ok_or("dag-pb link without a hash")?;
+                    links.push(Link { cid, name });
+                }
+                _ => {}
+            }
+        }
+
+        let mut node = Self {
+            kind: NodeKind::Other(u64::MAX),
+            data: Vec::new(),
+            links,
+            block_sizes: Vec::new(),
+            file_size: None,
+        };
+        for (field, value) in protobuf_fields(unixfs.ok_or("dag-pb node without UnixFS data")?)? {
+            match (field, value) {
+                (1, Field::Varint(kind)) => {
+                    node.kind = match kind {
+                        // Raw leaves of old DAGs are file contents too.
+                        0 | 2 => NodeKind::File,
+                        1 => NodeKind::Directory,
+                        5 => NodeKind::ShardedDirectory,
+                        kind => NodeKind::Other(kind),
+                    }
+                }
+                (2, Field::Bytes(data)) => node.data = data.to_vec(),
+                (3, Field::Varint(size)) => node.file_size = Some(size),
+                (4, Field::Varint(size)) => node.block_sizes.push(size),
+                // Block sizes can also be packed into one field.
+                (4, Field::Bytes(mut packed)) => {
+                    while !packed.is_empty() {
+                        let size = read_varint(&mut packed).ok_or("invalid packed block sizes")?;
+                        node.block_sizes.push(size);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(node)
+    }
+
+    /// The length of the file contents of this node and its descendants.
+    fn content_length(&self) -> u64 {
+        self.file_size
+            .unwrap_or_else(|| self.data.len() as u64 + self.block_sizes.iter().sum::<u64>())
+    }
+}
+
+impl Downloader {
+    /// Downloads an `ipfs://CID` or `ipfs://CID/path/in/directory` URL through the configured
+    /// gateways.
+    ///
+    /// The blocks on the way to the file are requested from all gateways at once and the first
+    /// valid response wins, since finding content is usually what takes longest. The blocks of
+    /// the file are spread over the gateways, `conn_count` at a time, and one that fails or
+    /// doesn't match its CID is fetched again from the next gateway according to the retry
+    /// policy.
+    pub(crate) async fn download_ipfs(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        let parsed = Url::parse(url).map_err(|_| DownloadError::InvalidUrl(url.to_owned()))?;
+        let text = parsed
+            .host_str()
+            .ok_or_else(|| DownloadError::InvalidUrl(url.to_owned()))?;
+        let root = Cid::parse(text).map_err(|reason| invalid(text, reason))?;
+        let path: Vec<String> = parsed
+            .path_segments()
+            .into_iter()
+            .flatten()
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+            .collect();
+        let Some((gateway, others)) = self.ipfs.gateways.split_first() else {
+            return Err(invalid(text, "no gateways configured".to_owned()));
+        };
+        let gateways = Mirrors::new(gateway, others);
+
+        let (mut cid, block, mut retries) =
+            self.fetch_block(root, &gateways, true, options).await?;
+        let mut node = Node::decode(&cid, block).map_err(|reason| invalid(&cid, reason))?;
+        for name in &path {
+            let link = match node.kind {
+                NodeKind::Directory => node.links.iter().find(|link| link.name == *name),
+                NodeKind::ShardedDirectory => {
+                    return Err(invalid(
+                        &cid,
+                        "sharded directories aren't supported".to_owned(),
+                    ))
+                }
+                _ => {
+                    return Err(invalid(
+                        &cid,
+                        format!("not a directory, can't open `{}`", name),
+                    ))
+                }
+            };
+            let link = link.ok_or_else(|| invalid(&cid, format!("no `{}` in directory", name)))?;
+            let (child, block, retried) = self
+                .fetch_block(link.cid.clone(), &gateways, true, options)
+                .await?;
+            node = Node::decode(&child, block).map_err(|reason| invalid(&child, reason))?;
+            cid = child;
+            retries += retried;
+        }
+        if node.kind != NodeKind::File {
+            return Err(invalid(&cid, "not a file".to_owned()));
+        }
+
+        let total = node.content_length();
+        self.check_size(url, Some(total), options)?;
+        let name = options
+            .filename
+            .clone()
+            .or_else(|| path.last().and_then(|name| filename::base_name(name)))
+            .unwrap_or_else(|| cid.to_string());
+        let name = self.output_name(url, Some(&name), &HeaderMap::new(), options);
+        let probe = Probe {
+            content_length: Some(total),
+            ..Default::default()
+        };
+        if let Some(report) = self.check_existing(url, &name, &probe, options).await? {
+            return Ok(report);
+        }
+
+        let reservation = self.reserve_output_path(&name, options);
+        let path = reservation.path.clone();
+        let partial = self.partial_path(&path);
+        download::create_parent_dir(&partial).await?;
+        let mut file = fs::File::create(&partial).await.with_path(&partial)?;
+        file.set_len(total).await.with_path(&partial)?;
+        options.emit(|| DownloadEvent::Started {
+            path: path.clone(),
+            total: Some(total),
+        });
+
+        let result = self
+            .write_dag(url, (cid, node), &gateways, &mut file, &partial, options)
+            .await;
+        drop(file);
+        match result {
+            Ok(retried) => retries += retried,
+            Err(e) => {
+                download::remove_partial(&partial)
+                    .await
+                    .with_path(&partial)?;
+                return Err(e);
+            }
+        }
+        self.make_durable(&partial).await?;
+        self.move_into_place(&partial, &path).await?;
+
+        Ok(DownloadReport {
+            path,
+            url: url.to_owned(),
+            final_url: url.to_owned(),
+            status: None,
+            headers: HeaderMap::new(),
+            size: total,
+            bytes_downloaded: total,
+            elapsed: started.elapsed(),
+            retries,
+            checksum: None,
+            checksum_source: None,
+            extracted_to: None,
+            chunks: Vec::new(),
+        })
+    }
+
+    /// Writes the file contents of the DAG below `root` into `file`, fetching the blocks of its
+    /// children as their offsets become known, and returns the number of retried requests.
+    async fn write_dag(
+        &self,
+        url: &str,
+        root: (Cid, Node),
+        gateways: &Mirrors,
+        file: &mut fs::File,
+        path: &Path,
+        options: &DownloadOptions,
+    ) -> Result<u32, DownloadError> {
+        let total = root.1.content_length();
+        let mut queue = VecDeque::new();
+        let mut pending = FuturesUnordered::new();
+        let mut next = Some((root, 0));
+        let mut meter = SpeedMeter::new(0);
+        let mut last_report = Instant::now();
+        let mut written = 0;
+        let mut retries = 0;
+        loop {
+            if let Some(((cid, node), offset)) = next.take() {
+                let children = place(&cid, &node, offset, total)?;
+                if !node.data.is_empty() {
+                    file.seek(SeekFrom::Start(offset)).await.with_path(path)?;
+                    file.write_all(&node.data).await.with_path(path)?;
+                    written += node.data.len() as u64;
+                }
+                queue.extend(children);
+                if last_report.elapsed() >= self.progress_interval {
+                    self.report_sequential_progress(url, options, written, Some(total), &mut meter);
+                    last_report = Instant::now();
+                }
+            }
+
+            while pending.len() < self.conn_count.max(1) {
+                let Some((cid, offset)) = queue.pop_front() else {
+                    break;
+                };
+                pending.push(async move {
+                    let fetched = self.fetch_block(cid, gateways, false, options).await;
+                    (fetched, offset)
+                });
+            }
+            let Some((fetched, offset)) = pending.next().await else {
+                break;
+            };
+            let (cid, block, retried) = fetched?;
+            retries += retried;
+            let node = Node::decode(&cid, block).map_err(|reason| invalid(&cid, reason))?;
+            if node.kind != NodeKind::File {
+                return Err(invalid(
+                    &cid,
+                    "file contains a node that isn't a file".to_owned(),
+                ));
+            }
+            next = Some(((cid, node), offset));
+        }
+        file.flush().await.with_path(path)?;
+        self.report_sequential_progress(url, options, written, Some(total), &mut meter);
+        Ok(retries)
+    }
+
+    /// Fetches and verifies the block of `cid`, from all gateways at once if `race` is set and
+    /// from one of them otherwise, moving to another gateway after a failure.
+    async fn fetch_block(
+        &self,
+        cid: Cid,
+        gateways: &Mirrors,
+        race: bool,
+        options: &DownloadOptions,
+    ) -> Result<(Cid, Vec<u8>, u32), DownloadError> {
+        cid.check_supported()
+            .map_err(|reason| invalid(&cid, reason))?;
+        // The contents of small blocks can be inlined into their CIDs.
+        if cid.hash == IDENTITY {
+            let block = cid.digest.clone();
+            return Ok((cid, block, 0));
+        }
+
+        let mut gateway = gateways.assign();
+        let mut attempt = 1;
+        let mut retry_after_waited = Duration::ZERO;
+        loop {
+            let fetched = match race {
+                true => {
+                    let fetches = (0..gateways.len())
+                        .map(|i| Box::pin(self.fetch_block_from(gateways.url(i), &cid, options)));
+                    future::select_ok(fetches).await.map(|(block, _)| block)
+                }
+                false => {
+                    self.fetch_block_from(gateways.url(gateway), &cid, options)
+                        .await
+                }
+            };
+            let error = match fetched {
+                Ok(block) => return Ok((cid, block, attempt - 1)),
+                Err(e) => e,
+            };
+            match self
+                .retry
+                .retry_delay(attempt, &error, &mut retry_after_waited)
+            {
+                Some(delay) => {
+                    warn!(%cid, gateway = gateways.url(gateway), error = %error, "block failed, retrying");
+                    metrics::record_retry();
+                    options.or_cancelled(tokio::time::sleep(delay)).await?;
+                    gateway = gateways.failover(gateway);
+                    attempt += 1;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+
+    async fn fetch_block_from(
+        &self,
+        gateway: &str,
+        cid: &Cid,
+        options: &DownloadOptions,
+    ) -> Result<Vec<u8>, DownloadError> {
+        let url = format!("{}/ipfs/{}?format=raw", gateway.trim_end_matches('/'), cid);
+        let segment = Segment {
+            url: Url::parse(&url).map_err(|_| DownloadError::InvalidUrl(url.clone()))?,
+            range: None,
+        };
+        let block = self.fetch_segment_once(&segment, options).await?;
+        match cid.verify(&block) {
+            Ok(()) => Ok(block),
+            Err((expected, actual)) => Err(DownloadError::DigestMismatch {
+                url,
+                expected,
+                actual,
+            }),
+        }
+    }
+}
+
+fn invalid(cid: impl ToString, reason: String) -> DownloadError {
+    DownloadError::InvalidIpfs {
+        cid: cid.to_string(),
+        reason,
+    }
+}
+
+/// Returns the CIDs and offsets of the children of `node`, which starts at `offset` of a file of
+/// `total` bytes, failing if any of its contents would end up outside the file.
+fn place(
+    cid: &Cid,
+    node: &Node,
+    offset: u64,
+    total: u64,
+) -> Result<Vec<(Cid, u64)>, DownloadError> {
+    if !node.links.is_empty() && node.links.len() != node.block_sizes.len() {
+        return Err(invalid(
+            cid,
+            "file node without the sizes of its blocks".to_owned(),
+        ));
+    }
+    let outside = || invalid(cid, "node is larger than the file".to_owned());
+    let mut end = offset
+        .checked_add(node.data.len() as u64)
+        .filter(|&end| end <= total)
+        .ok_or_else(outside)?;
+    let mut children = Vec::with_capacity(node.links.len());
+    for (link, &size) in node.links.iter().zip(&node.block_sizes) {
+        children.push((link.cid.clone(), end));
+        end = end
+            .checked_add(size)
+            .filter(|&end| end <= total)
+            .ok_or_else(outside)?;
+    }
+    Ok(children)
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Splits a protobuf message into its field numbers and values, skipping fixed-size fields.
+fn protobuf_fields(mut message: &[u8]) -> Result<Vec<(u64, Field<'_>)>, String> {
+    let invalid = || "invalid protobuf message".to_owned();
+    let mut fields = Vec::new();
+    while !message.is_empty() {
+        let key = read_varint(&mut message).ok_or_else(invalid)?;
+        let value = match key & 7 {
+            0 => Field::Varint(read_varint(&mut message).ok_or_else(invalid)?),
+            1 | 5 => {
+                let len = if key & 7 == 1 { 8 } else { 4 };
+                message = message.get(len..).ok_or_else(invalid)?;
+                continue;
+            }
+            2 => {
+                let len = read_varint(&mut message).ok_or_else(invalid)?;
+                let len = usize::try_from(len).map_err(|_| invalid())?;
+                let bytes = message.get(..len).ok_or_else(invalid)?;
+                message = &message[len..];
+                Field::Bytes(bytes)
+            }
+            _ => return Err(invalid()),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+/// Reads an unsigned LEB128 varint from the start of `bytes` and advances past it.
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f).checked_shl(7 * i as u32)?;
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn base58_decode(text: &str) -> Option<Vec<u8>> {
+    let zeros = text.bytes().take_while(|&c| c == b'1').count();
+    // Little-endian digits of the number, in base 256.
+    let mut number: Vec<u8> = Vec::new();
+    for c in text.bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|&a| a == c)? as u32;
+        for digit in number.iter_mut() {
+            carry += u32::from(*digit) * 58;
+            *digit = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            number.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let mut bytes = vec![0; zeros];
+    bytes.extend(number.iter().rev());
+    Some(bytes)
+}
+
+fn base58_encode(bytes: &[u8]) -> String {
+    let zeros = bytes.iter().take_while(|&&b| b == 0).count();
+    // Little-endian digits of the number, in base 58.
+    let mut number: Vec<u8> = Vec::new();
+    for &byte in bytes {
+        let mut carry = u32::from(byte);
+        for digit in number.iter_mut() {
+            carry += u32::from(*digit) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            number.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    std::iter::repeat_n('1', zeros)
+        .chain(
+            number
+                .iter()
+                .rev()
+                .map(|&d| BASE58_ALPHABET[d as usize] as char),
+        )
+        .collect()
+}
+
+/// Decodes lowercase base32 without padding, as used by multibase.
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.bytes() {
+        buffer = (buffer << 5) | BASE32_ALPHABET.iter().position(|&a| a == c)? as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_cid(data: &[u8]) -> Cid {
+        Cid {
+            version: 1,
+            codec: RAW,
+            hash: SHA2_256,
+            digest: Sha256::digest(data).to_vec(),
+        }
+    }
+
+    /// Encodes a protobuf length-delimited field.
+    fn bytes_field(bytes: &mut Vec<u8>, field: u64, value: &[u8]) {
+        write_varint(bytes, field << 3 | 2);
+        write_varint(bytes, value.len() as u64);
+        bytes.extend_from_slice(value);
+    }
+
+    fn varint_field(bytes: &mut Vec<u8>, field: u64, value: u64) {
+        write_varint(bytes, field << 3);
+        write_varint(bytes, value);
+    }
+
+    #[test]
+    fn parses_cids() {
+        let v0 = Cid::parse("QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn").unwrap();
+        assert_eq!((v0.version, v0.codec, v0.hash), (0, DAG_PB, SHA2_256));
+        assert_eq!(v0.digest.len(), 32);
+        assert_eq!(
+            v0.to_string(),
+            "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn"
+        );
+
+        let v1 = raw_cid(b"hello");
+        let text = v1.to_string();
+        assert!(text.starts_with("bafkrei"));
+        assert_eq!(Cid::parse(&text).unwrap(), v1);
+        assert_eq!(Cid::parse(&text.to_ascii_uppercase()).unwrap(), v1);
+        assert!(v1.verify(b"hello").is_ok());
+        assert!(v1.verify(b"hullo").is_err());
+    }
+
+    #[test]
+    fn refuses_invalid_cids() {
+        assert!(Cid::parse("").is_err());
+        assert!(Cid::parse("mAXASIA").is_err());
+        assert!(Cid::parse("b0000").is_err());
+        let text = raw_cid(b"hello").to_string();
+        assert!(Cid::parse(&text[..text.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn decodes_raw_blocks() {
+        let node = Node::decode(&raw_cid(b"hello"), b"hello".to_vec()).unwrap();
+        assert_eq!(node.kind, NodeKind::File);
+        assert_eq!(node.data, b"hello");
+        assert_eq!(node.content_length(), 5);
+    }
+
+    #[test]
+    fn decodes_dag_pb_files() {
+        let mut unixfs = Vec::new();
+        varint_field(&mut unixfs, 1, 2);
+        bytes_field(&mut unixfs, 2, b"ab");
+        varint_field(&mut unixfs, 4, 4);
+        varint_field(&mut unixfs, 4, 6);
+        let mut link = Vec::new();
+        bytes_field(&mut link, 1, &raw_cid(b"data").to_bytes());
+        bytes_field(&mut link, 2, b"part");
+        let mut block = Vec::new();
+        bytes_field(&mut block, 2, &link);
+        bytes_field(&mut block, 2, &link);
+        bytes_field(&mut block, 1, &unixfs);
+
+        let cid = Cid {
+            codec: DAG_PB,
+            ..raw_cid(&block)
+        };
+        let node = Node::decode(&cid, block).unwrap();
+        assert_eq!(node.kind, NodeKind::File);
+        assert_eq!(node.data, b"ab");
+        assert_eq!(node.block_sizes, [4, 6]);
+        assert_eq!(node.content_length(), 12);
+        assert_eq!(node.links.len(), 2);
+        assert_eq!(node.links[0].cid, raw_cid(b"data"));
+        assert_eq!(node.links[0].name, "part");
+    }
+
+    #[test]
+    fn refuses_invalid_dag_pb_blocks() {
+        let cid = Cid {
+            codec: DAG_PB,
+            ..raw_cid(b"")
+        };
+        assert!(Node::decode(&cid, Vec::new()).is_err());
+        assert!(Node::decode(&cid, vec![0x0a, 0x05, 0x08]).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{
+    error::{DownloadError, IoResultExt},
+    resume::{sidecar_path, ResumeState},
+    storage,
+};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Extension the journal adds to the path of the resume sidecar.
+const JOURNAL_EXTENSION: &str = "journal";
+
+/// The bytes at the start of a chunk that were on disk when the journal was committed.
+struct Flushed {
+    start: u64,
+    end: u64,
+    written: u64,
+}
+
+/// Records which bytes of every chunk of `state` are on disk in the journal of `output_path`.
+///
+/// The written bytes are counted before the partial file is synced, so the journal never claims
+/// bytes that were still in the operating system's cache. It's replaced by renaming a synced
+/// copy over it, so a crash leaves either the old or the new journal behind.
+pub(crate) async fn commit(state: &ResumeState, output_path: &Path) -> Result<(), DownloadError> {
+    let mut contents = String::new();
+    for chunk in state.chunks() {
+        contents.push_str(&format!(
+            "flushed {} {} {}\n",
+            chunk.start,
+            chunk.end(),
+            chunk.written()
+        ));
+    }
+    storage::sync_data(output_path)
+        .await
+        .with_path(output_path)?;
+
+    let path = journal_path(output_path);
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    fs::write(&temp, contents).await.with_path(&temp)?;
+    storage::sync(&temp).await.with_path(&temp)?;
+    fs::rename(&temp, &path).await.with_path(&path)?;
+    storage::sync_parent_dir(&path).await.with_path(&path)
+}
+
+/// Rewinds the chunks of `state`, loaded from the sidecar of `output_path`, to the bytes its
+/// journal says are on disk. Chunks the journal doesn't know weren't flushed at all.
+pub(crate) async fn apply(state: &ResumeState, output_path: &Path) {
+    let flushed = match fs::read_to_string(journal_path(output_path)).await {
+        Ok(contents) => parse(&contents).unwrap_or_else(|| {
+            warn!(path = %output_path.display(), "ignoring invalid chunk journal");
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    };
+    for chunk in state.chunks() {
+        let written = flushed
+            .iter()
+            .find(|f| f.start == chunk.start && f.end >= chunk.end())
+            .map_or(0, |f| f.written);
+        if written < chunk.written() {
+            chunk.rewind(written);
+        }
+    }
+}
+
+/// Removes the journal of `output_path`, if there is one.
+pub(crate) async fn remove(output_path: &Path) -> Result<(), DownloadError> {
+    let path = journal_path(output_path);
+    match fs::remove_file(&path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).with_path(&path),
+        _ => Ok(()),
+    }
+}
+
+fn parse(contents: &str) -> Option<Vec<Flushed>> {
+    contents
+        .lines()
+        .map(|line| {
+            let mut fields = line.strip_prefix("flushed ")?.split(' ');
+            let mut field = || fields.next()?.parse::<u64>().ok();
+            Some(Flushed {
+                start: field()?,
+                end: field()?,
+                written: field()?,
+            })
+        })
+        .collect()
+}
+
+fn journal_path(output_path: &Path) -> PathBuf {
+    let mut path = sidecar_path(output_path).into_os_string();
+    path.push(".");
+    path.push(JOURNAL_EXTENSION);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_flushed_chunks() {
+        let flushed = parse("flushed 0 99 50\nflushed 100 199 100\n").unwrap();
+        let flushed: Vec<_> = flushed
+            .iter()
+            .map(|f| (f.start, f.end, f.written))
+            .collect();
+        assert_eq!(flushed, [(0, 99, 50), (100, 199, 100)]);
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_malformed_journals() {
+        assert!(parse("flushed 0 99 50\nflushed 100 199\n").is_none());
+        assert!(parse("flushed 0 99 x\n").is_none());
+        assert!(parse("chunk 0 99 50\n").is_none());
+    }
+}
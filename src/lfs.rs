@@ -0,0 +1,449 @@
+//! Downloads of git LFS objects through the batch API, and of `hf://` URLs of the Hugging Face
+//! Hub, enabled with the `lfs` feature.
+//!
+//! The batch API tells where an object is stored, and the object is then downloaded like any
+//! other file and verified against the SHA-256 hash its pointer names.
+
+use crate::{
+    auth::Auth, checksum::Checksum, download::Downloader, error::DownloadError, filename, metrics,
+    options::DownloadOptions, report::DownloadReport,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE};
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Instant};
+
+const DEFAULT_HF_ENDPOINT: &str = "https://huggingface.co";
+
+const LFS_MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+
+/// Pointer files are smaller than this, according to the git LFS specification.
+const MAX_POINTER_SIZE: u64 = 1024;
+
+/// Characters that are percent-encoded in the paths of files on the Hub.
+const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'?')
+    .add(b'<')
+    .add(b'>');
+
+/// Where `hf://` URLs are downloaded from and how requests are authenticated.
+#[derive(Clone, Default)]
+pub(crate) struct HfConfig {
+    /// An access token. Defaults to `HF_TOKEN`.
+    pub token: Option<String>,
+    /// Defaults to `HF_ENDPOINT`, or the public Hub.
+    pub endpoint: Option<String>,
+}
+
+/// The pointer git stores in place of a file tracked with git LFS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LfsPointer {
+    /// The hex encoded SHA-256 hash of the file.
+    pub oid: String,
+    pub size: u64,
+}
+
+impl LfsPointer {
+    /// Parses the contents of a pointer file, like
+    ///
+    /// ```text
+    /// version https://git-lfs.github.com/spec/v1
+    /// oid sha256:4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393
+    /// size 12345
+    /// ```
+    ///
+    /// Returns `None` if `text` isn't one.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut lines = text.lines();
+        let version = lines.next()?.strip_prefix("version ")?;
+        if !version.starts_with("https://git-lfs.github.com/spec/") {
+            return None;
+        }
+        let mut oid = None;
+        let mut size = None;
+        for line in lines {
+            match line.split_once(' ') {
+                Some(("oid", value)) => oid = value.strip_prefix("sha256:"),
+                Some(("size", value)) => size = value.parse().ok(),
+                _ => {}
+            }
+        }
+        let oid =
+            oid.filter(|oid| oid.len() == 64 && oid.bytes().all(|b| b.is_ascii_hexdigit()))?;
+        Some(Self {
+            oid: oid.to_ascii_lowercase(),
+            size: size?,
+        })
+    }
+}
+
+/// Checks whether `url` is an `hf://` URL.
+pub(crate) fn is_hf_url(url: &str) -> bool {
+    url.starts_with("hf://")
+}
+
+/// A file in a repository on the Hub, from a URL like
+/// `hf://datasets/owner/name@revision/path/to/file`.
+struct HubFile {
+    /// Like `owner/name` for models, or `datasets/owner/name`.
+    repo: String,
+    revision: String,
+    path: String,
+}
+
+impl HubFile {
+    fn parse(url: &str) -> Result<Self, DownloadError> {
+        let invalid = || DownloadError::InvalidUrl(url.to_owned());
+        let rest = url.strip_prefix("hf://").ok_or_else(invalid)?;
+        let (kind, rest) = match rest.split_once('/') {
+            Some((kind @ ("datasets" | "spaces"), rest)) => (Some(kind), rest),
+            _ => (None, rest),
+        };
+        let mut parts = rest.splitn(3, '/');
+        let (Some(owner), Some(name), Some(path)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let (name, revision) = match name.split_once('@') {
+            Some((name, revision)) => (name, percent_decode_str(revision).decode_utf8_lossy()),
+            None => (name, "main".into()),
+        };
+        if owner.is_empty() || name.is_empty() || revision.is_empty() || path.is_empty() {
+            return Err(invalid());
+        }
+        let repo = match kind {
+            Some(kind) => format!("{}/{}/{}", kind, owner, name),
+            None => format!("{}/{}", owner, name),
+        };
+        Ok(Self {
+            repo,
+            revision: revision.into_owned(),
+            path: percent_decode_str(path).decode_utf8_lossy().into_owned(),
+        })
+    }
+
+    /// The URL of the file at `endpoint`, under `/raw/` for what git stores, which is the pointer
+    /// of LFS files, or `/resolve/` for the file itself.
+    fn url(&self, endpoint: &str, kind: &str) -> String {
+        format!(
+            "{}/{}/{}/{}/{}",
+            endpoint,
+            self.repo,
+            kind,
+            utf8_percent_encode(&self.revision, PATH)
+                .to_string()
+                .replace('/', "%2F"),
+            utf8_percent_encode(&self.path, PATH)
+        )
+    }
+}
+
+impl Downloader {
+    /// Downloads the git LFS object `pointer` of the repository at `repo`, like
+    /// `https://github.com/owner/name.git`, and verifies it against the hash in the pointer.
+    ///
+    /// The object is looked up with the batch API at `{repo}.git/info/lfs/objects/batch`, which
+    /// is sent the credentials of `options`. The object itself is only sent the headers the batch
+    /// API returns with its URL, and is saved under the name in that URL unless `options` sets a
+    /// filename.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "download_lfs", skip_all, fields(repo = %repo, oid = %pointer.oid)))]
+    pub async fn download_lfs(
+        &self,
+        repo: &str,
+        pointer: &LfsPointer,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        let _running = self.shutdown.enter();
+        let result = match self.shutdown.is_shut_down() {
+            true => Err(DownloadError::Cancelled),
+            false => {
+                let options = options.started(repo, self.shutdown.token());
+                match self.resolve_lfs(repo, pointer, &options).await {
+                    Ok((url, options)) => self.download_http(&url, &options).await,
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        self.hooks.run(repo, &result, started.elapsed()).await;
+        metrics::record_outcome(&result);
+        result
+    }
+
+    /// Translates an `hf://` URL to the URL the file is downloaded from, and returns options
+    /// that authenticate the requests to the Hub and verify LFS files against their hashes.
+    ///
+    /// Files that aren't stored with LFS are downloaded from the Hub itself.
+    pub(crate) async fn resolve_hf_url(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<(String, DownloadOptions), DownloadError> {
+        let file = HubFile::parse(url)?;
+        let endpoint = self
+            .hf
+            .endpoint
+            .clone()
+            .or_else(|| std::env::var("HF_ENDPOINT").ok())
+            .unwrap_or_else(|| DEFAULT_HF_ENDPOINT.to_owned());
+        let endpoint = endpoint.trim_end_matches('/');
+        let token = self
+            .hf
+            .token
+            .clone()
+            .or_else(|| std::env::var("HF_TOKEN").ok());
+
+        let mut options = options.clone();
+        if options.filename.is_none() {
+            options.filename = filename::base_name(&file.path);
+        }
+        if let Some(token) = token.filter(|_| options.auth.credentials().is_none()) {
+            options = options.bearer_token(&token);
+        }
+
+        let raw = file.url(endpoint, "raw");
+        let pointer = {
+            let _permit = options.or_cancelled(self.hosts.acquire(&raw)).await?;
+            let fetch = async {
+                let response = self
+                    .send_request(&raw, &options, |client| client.get(&raw))
+                    .await?;
+                match response.content_length() {
+                    Some(len) if len >= MAX_POINTER_SIZE => Ok::<_, DownloadError>(None),
+                    _ => Ok(LfsPointer::parse(&response.text().await?)),
+                }
+            };
+            options.or_cancelled(fetch).await??
+        };
+        match pointer {
+            Some(pointer) => {
+                let repo = format!("{}/{}", endpoint, file.repo);
+                self.resolve_lfs(&repo, &pointer, &options).await
+            }
+            None => Ok((file.url(endpoint, "resolve"), options)),
+        }
+    }
+
+    /// Asks the batch API of `repo` where the object of `pointer` is, and returns its URL with
+    /// options that send the headers the API asks for and verify the object.
+    async fn resolve_lfs(
+        &self,
+        repo: &str,
+        pointer: &LfsPointer,
+        options: &DownloadOptions,
+    ) -> Result<(String, DownloadOptions), DownloadError> {
+        let repo = repo.trim_end_matches('/');
+        let batch = match repo.ends_with(".git") {
+            true => format!("{}/info/lfs/objects/batch", repo),
+            false => format!("{}.git/info/lfs/objects/batch", repo),
+        };
+        let body = json!({
+            "operation": "download",
+            "transfers": ["basic"],
+            "objects": [{ "oid": pointer.oid, "size": pointer.size }],
+            "hash_algo": "sha256",
+        })
+        .to_string();
+
+        let _permit = options.or_cancelled(self.hosts.acquire(&batch)).await?;
+        let fetch = async {
+            let response = self
+                .send_request(&batch, options, |client| {
+                    client
+                        .post(&batch)
+                        .header(ACCEPT, LFS_MEDIA_TYPE)
+                        .header(CONTENT_TYPE, LFS_MEDIA_TYPE)
+                        .body(body.clone())
+                })
+                .await?;
+            Ok::<_, DownloadError>(response.text().await?)
+        };
+        let text = options.or_cancelled(fetch).await??;
+        let (url, headers) =
+            parse_batch(&text, &pointer.oid).map_err(|reason| DownloadError::LfsObject {
+                oid: pointer.oid.clone(),
+                reason,
+            })?;
+        debug!(oid = %pointer.oid, url = %url, "found LFS object");
+
+        let mut options = DownloadOptions {
+            headers,
+            auth: Arc::new(Auth::default()),
+            origin: None,
+            ..options.clone()
+        };
+        if options.checksum.is_none() {
+            options = options.checksum(Checksum::sha256(&pointer.oid));
+        }
+        Ok((url, options))
+    }
+}
+
+/// Finds the download URL of the object `oid`, and the headers to send to it, in the response of
+/// the batch API.
+fn parse_batch(text: &str, oid: &str) -> Result<(String, HeaderMap), String> {
+    let response: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let object = response["objects"]
+        .as_array()
+        .and_then(|objects| objects.iter().find(|object| object["oid"] == oid))
+        .ok_or("not in the response of the batch API")?;
+    if let Some(error) = object.get("error") {
+        return Err(error["message"]
+            .as_str()
+            .unwrap_or("the batch API returned an error")
+            .to_owned());
+    }
+
+    let download = &object["actions"]["download"];
+    let url = download["href"]
+        .as_str()
+        .ok_or("the batch API returned no download URL")?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in download["header"].as_object().into_iter().flatten() {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid header name `{}`", name))?;
+        let value = value
+            .as_str()
+            .and_then(|value| HeaderValue::from_str(value).ok())
+            .ok_or_else(|| format!("invalid value of header `{}`", name))?;
+        headers.insert(name, value);
+    }
+    Ok((url.to_owned(), headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OID: &str = "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393";
+
+    #[test]
+    fn parses_pointers() {
+        let pointer = LfsPointer::parse(&format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 12345\n",
+            OID.to_ascii_uppercase()
+        ));
+        assert_eq!(
+            pointer,
+            Some(LfsPointer {
+                oid: OID.to_owned(),
+                size: 12345
+            })
+        );
+
+        // Unknown keys, which later versions may add, are skipped.
+        let pointer = LfsPointer::parse(&format!(
+            "version https://git-lfs.github.com/spec/v1\next-0-foo sha256:00\noid sha256:{OID}\nsize 0"
+        ));
+        assert_eq!(pointer.map(|pointer| pointer.size), Some(0));
+    }
+
+    #[test]
+    fn refuses_what_isnt_a_pointer() {
+        let valid = |version: &str, oid: &str, size: &str| {
+            LfsPointer::parse(&format!("version {version}\noid {oid}\nsize {size}\n")).is_some()
+        };
+        let version = "https://git-lfs.github.com/spec/v1";
+        let oid = format!("sha256:{OID}");
+        assert!(valid(version, &oid, "1"));
+
+        assert!(!valid("https://example.com/spec/v1", &oid, "1"));
+        assert!(!valid(version, &format!("sha1:{OID}"), "1"));
+        assert!(!valid(version, &format!("sha256:{}", &OID[1..]), "1"));
+        assert!(!valid(version, &format!("sha256:{}g", &OID[1..]), "1"));
+        assert!(!valid(version, &oid, "-1"));
+        assert!(!valid(version, &oid, "large"));
+        assert!(LfsPointer::parse(&format!("version {version}\noid {oid}\n")).is_none());
+        assert!(LfsPointer::parse(&format!("oid {oid}\nversion {version}\nsize 1\n")).is_none());
+        assert!(LfsPointer::parse("").is_none());
+        assert!(LfsPointer::parse("\u{7f}ELF binary contents").is_none());
+    }
+
+    #[test]
+    fn parses_hub_urls() {
+        let file = HubFile::parse("hf://owner/model/config.json").unwrap();
+        assert_eq!(
+            (
+                file.repo.as_str(),
+                file.revision.as_str(),
+                file.path.as_str()
+            ),
+            ("owner/model", "main", "config.json")
+        );
+        assert_eq!(
+            file.url(DEFAULT_HF_ENDPOINT, "resolve"),
+            "https://huggingface.co/owner/model/resolve/main/config.json"
+        );
+
+        let file = HubFile::parse("hf://datasets/owner/data@refs%2Fpr%2F1/dir/a%20b.csv").unwrap();
+        assert_eq!(
+            (
+                file.repo.as_str(),
+                file.revision.as_str(),
+                file.path.as_str()
+            ),
+            ("datasets/owner/data", "refs/pr/1", "dir/a b.csv")
+        );
+        assert_eq!(
+            file.url("http://hub.local", "raw"),
+            "http://hub.local/datasets/owner/data/raw/refs%2Fpr%2F1/dir/a%20b.csv"
+        );
+
+        for url in [
+            "hf://owner/model",
+            "hf://owner//file",
+            "hf://owner/model@/file",
+            "hf://datasets/owner/data",
+            "https://huggingface.co/owner/model/file",
+        ] {
+            assert!(
+                matches!(HubFile::parse(url), Err(DownloadError::InvalidUrl(_))),
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_batch_responses() {
+        let response = json!({
+            "objects": [
+                {"oid": "other", "actions": {"download": {"href": "https://wrong.example/"}}},
+                {"oid": OID, "actions": {"download": {
+                    "href": "https://storage.example/object",
+                    "header": {"Authorization": "RemoteAuth token"}
+                }}}
+            ]
+        });
+        let (url, headers) = parse_batch(&response.to_string(), OID).unwrap();
+        assert_eq!(url, "https://storage.example/object");
+        assert_eq!(headers["authorization"], "RemoteAuth token");
+
+        let error = json!({"objects": [{"oid": OID, "error": {"code": 404, "message": "Object does not exist"}}]});
+        assert_eq!(
+            parse_batch(&error.to_string(), OID).unwrap_err(),
+            "Object does not exist"
+        );
+        let missing = json!({"objects": [{"oid": "other"}]});
+        assert_eq!(
+            parse_batch(&missing.to_string(), OID).unwrap_err(),
+            "not in the response of the batch API"
+        );
+        let no_url = json!({"objects": [{"oid": OID, "actions": {}}]});
+        assert_eq!(
+            parse_batch(&no_url.to_string(), OID).unwrap_err(),
+            "the batch API returned no download URL"
+        );
+        let bad_header = json!({"objects": [{"oid": OID, "actions": {"download": {
+            "href": "https://storage.example/object", "header": {"X-Bad": "a\nb"}
+        }}}]});
+        assert_eq!(
+            parse_batch(&bad_header.to_string(), OID).unwrap_err(),
+            "invalid value of header `x-bad`"
+        );
+        assert!(parse_batch("not json", OID).is_err());
+    }
+}
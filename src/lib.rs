@@ -1,7 +1,126 @@
+#[macro_use]
+mod trace;
+
+mod adaptive;
+mod auth;
+#[cfg(feature = "azure")]
+mod azure;
+#[cfg(feature = "indicatif")]
+mod bars;
+mod batch;
+mod builder;
+mod checksum;
+mod chunk;
+mod conditional;
+#[cfg(feature = "crawl")]
+mod crawl;
+mod dash;
+#[cfg(feature = "decompression")]
+mod decompress;
+mod digest;
 mod download;
 mod error;
+mod event;
+#[cfg(feature = "extract")]
+mod extract;
+mod filename;
+#[cfg(feature = "ftp")]
+mod ftp;
+#[cfg(feature = "gcs")]
+mod gcs;
+mod handle;
+mod hls;
+mod hooks;
+mod hosts;
+#[cfg(feature = "http3")]
+mod http3;
+#[cfg(feature = "ipfs")]
+mod ipfs;
+mod journal;
+#[cfg(feature = "lfs")]
+mod lfs;
+mod list;
+mod local;
+mod manager;
+mod manifest;
+mod metalink;
+mod metrics;
+mod mirrors;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod net;
+#[cfg(feature = "oci")]
+mod oci;
+mod options;
+mod pinning;
+mod policy;
+mod probe;
+mod progress;
+mod redirect;
+mod report;
+mod reservation;
+mod resume;
+mod retry;
+#[cfg(feature = "s3")]
+mod s3;
+mod segments;
+#[cfg(feature = "sftp")]
+mod sftp;
+mod shutdown;
+mod sidecar;
+#[cfg(any(feature = "s3", feature = "azure"))]
+mod signing;
+mod staging;
+mod stall;
+mod storage;
+mod template;
+mod throttle;
+mod tls;
+#[cfg(feature = "torrent")]
+mod torrent;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+#[cfg(feature = "webdav")]
+mod webdav;
+mod webhook;
+#[cfg(feature = "zsync")]
+mod zsync;
 
+pub use auth::{Credentials, CredentialsProvider};
+#[cfg(feature = "indicatif")]
+pub use bars::ProgressBars;
+pub use builder::DownloaderBuilder;
+pub use checksum::{Checksum, ChecksumAlgorithm, ChecksumSource};
+pub use conditional::{FileChange, PlannedDownload};
+#[cfg(feature = "crawl")]
+pub use crawl::CrawlOptions;
 pub use download::Downloader;
+pub use error::DownloadError;
+pub use event::DownloadEvent;
+pub use handle::DownloadHandle;
+pub use hooks::DownloadHook;
+#[cfg(feature = "lfs")]
+pub use lfs::LfsPointer;
+pub use list::{UrlList, UrlListEntry};
+pub use manager::{DownloadManager, JobId, JobInfo, JobStatus};
+pub use metalink::{Metalink, MetalinkFile};
+pub use net::{IpVersion, Resolver};
+pub use options::{
+    Allocation, CancelPolicy, DownloadOptions, DuplicatePolicy, Durability, OverwritePolicy,
+};
+pub use policy::UrlPolicy;
+pub use probe::{BatchEstimate, RemoteFile};
+pub use progress::{
+    BatchProgress, BatchProgressReporter, ChunkProgress, FileProgress, Progress, ProgressReporter,
+};
+pub use redirect::CrossOriginRedirects;
+pub use report::{BatchFileReport, BatchReport, ChunkReport, DownloadReport};
+pub use retry::RetryPolicy;
+pub use segments::StreamVariant;
+pub use tokio_util::sync::CancellationToken;
+#[cfg(feature = "torrent")]
+pub use torrent::{Torrent, TorrentFile};
+pub use webhook::Webhook;
 
 // #[cfg(test)]
 // mod tests {
@@ -0,0 +1,267 @@
+use crate::{
+    checksum::{Checksum, ChecksumAlgorithm},
+    error::DownloadError,
+    filename,
+};
+
+/// A list of URLs to download, in the format of [aria2](https://aria2.github.io/manual/en/html/aria2c.html#input-file)
+/// input files.
+///
+/// Every line that doesn't start with whitespace holds the URL of a file, optionally followed by
+/// mirrors of it separated by tabs or spaces. The lines below it that do start with whitespace
+/// set options of the file:
+///
+/// ```text
+/// https://example.com/file.iso https://mirror.example.com/file.iso
+///   out=debian.iso
+///   checksum=sha-256=9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+/// ```
+///
+/// The options can also follow the URL on its line, like `https://example.com/file.iso
+/// out=debian.iso`. `out` sets the name the file is saved under and `checksum` the checksum it's
+/// verified against, with an algorithm of `sha-256`, `md5` or `blake3`. Empty lines and lines
+/// starting with `#` are skipped.
+#[derive(Debug, Default)]
+pub struct UrlList {
+    /// The files in the order they are listed, or the error that makes the lines of one invalid.
+    pub entries: Vec<Result<UrlListEntry, DownloadError>>,
+}
+
+/// A file listed in a [`UrlList`].
+#[derive(Debug, Clone, Default)]
+pub struct UrlListEntry {
+    /// The URL of the file, followed by its mirrors.
+    pub urls: Vec<String>,
+    /// Name to save the file under, instead of the one from the server.
+    pub filename: Option<String>,
+    pub checksum: Option<Checksum>,
+}
+
+impl UrlList {
+    /// Parses a list of URLs.
+    ///
+    /// Invalid lines don't fail the whole list: the file they belong to is listed as
+    /// [`DownloadError::InvalidUrlList`] with the number of the line, and the others are kept.
+    pub fn parse(contents: &str) -> Self {
+        let mut entries: Vec<Result<UrlListEntry, DownloadError>> = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let number = i + 1;
+            let invalid = |reason: String| DownloadError::InvalidUrlList {
+                line: number,
+                reason,
+            };
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with(char::is_whitespace) {
+                match entries.last_mut() {
+                    Some(Ok(entry)) => {
+                        if let Err(reason) = apply_option(entry, trimmed) {
+                            *entries.last_mut().unwrap() = Err(invalid(reason));
+                        }
+                    }
+                    // The file is already invalid.
+                    Some(Err(_)) => {}
+                    None => entries.push(Err(invalid(format!(
+                        "option `{}` doesn't follow a URL",
+                        trimmed
+                    )))),
+                }
+                continue;
+            }
+
+            let mut entry = UrlListEntry::default();
+            let parsed = trimmed.split_whitespace().try_for_each(|token| {
+                if is_option(token) {
+                    apply_option(&mut entry, token)
+                } else if url::Url::parse(token).is_err() {
+                    Err(format!("invalid URL `{}`", token))
+                } else {
+                    entry.urls.push(token.to_owned());
+                    Ok(())
+                }
+            });
+            let parsed = parsed.and_then(|_| {
+                if entry.urls.is_empty() {
+                    Err(format!("no URL before `{}`", trimmed))
+                } else {
+                    Ok(entry)
+                }
+            });
+            entries.push(parsed.map_err(invalid));
+        }
+        Self { entries }
+    }
+}
+
+/// Checks whether `token` is an option like `out=file.iso` rather than a URL, which has a `:`
+/// after its scheme before any `=` in its query.
+fn is_option(token: &str) -> bool {
+    token.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    })
+}
+
+fn apply_option(entry: &mut UrlListEntry, option: &str) -> Result<(), String> {
+    let Some((name, value)) = option.split_once('=') else {
+        return Err(format!(
+            "expected an option like `out=<name>`, got `{}`",
+            option
+        ));
+    };
+    match name.trim() {
+        "out" => {
+            let value = value.trim();
+            match filename::base_name(value) {
+                Some(name) if name == value => entry.filename = Some(name),
+                _ => return Err(format!("`{}` is not a valid file name", value)),
+            }
+        }
+        "checksum" => {
+            let (algorithm, digest) = value.trim().split_once('=').ok_or_else(|| {
+                format!("expected `checksum=<algorithm>=<digest>`, got `{}`", option)
+            })?;
+            let algorithm = ChecksumAlgorithm::from_name(algorithm)
+                .ok_or_else(|| format!("unsupported checksum algorithm `{}`", algorithm))?;
+            if !algorithm.is_hex_digest(digest) {
+                return Err(format!(
+                    "`{}` is not a hex encoded {} digest",
+                    digest,
+                    algorithm.name()
+                ));
+            }
+            entry.checksum = Some(Checksum::new(algorithm, digest));
+        }
+        name => return Err(format!("unsupported option `{}`", name)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    /// The line and reason of every invalid file of `list`.
+    fn invalid(list: &UrlList) -> Vec<(usize, String)> {
+        list.entries
+            .iter()
+            .filter_map(|entry| match entry {
+                Err(DownloadError::InvalidUrlList { line, reason }) => {
+                    Some((*line, reason.clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_files_with_mirrors_and_options() {
+        let contents = format!(
+            "# a comment\n\
+             https://example.com/file.iso\thttps://mirror.example/file.iso\n\
+             \x20 out=debian.iso\n\
+             \tchecksum=sha-256={DIGEST}\n\
+             \n\
+             https://example.com/other out=other.bin checksum=md5=D41D8CD98F00B204E9800998ECF8427E\n\
+             https://example.com/plain?a=b\n"
+        );
+        let list = UrlList::parse(&contents);
+        let entries: Vec<_> = list.entries.into_iter().map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 3);
+
+        assert_eq!(
+            entries[0].urls,
+            [
+                "https://example.com/file.iso",
+                "https://mirror.example/file.iso"
+            ]
+        );
+        assert_eq!(entries[0].filename.as_deref(), Some("debian.iso"));
+        let checksum = entries[0].checksum.as_ref().unwrap();
+        assert_eq!(
+            (checksum.algorithm.name(), checksum.expected.as_str()),
+            ("sha256", DIGEST)
+        );
+
+        assert_eq!(entries[1].urls, ["https://example.com/other"]);
+        assert_eq!(entries[1].filename.as_deref(), Some("other.bin"));
+        let checksum = entries[1].checksum.as_ref().unwrap();
+        assert_eq!(checksum.algorithm.name(), "md5");
+        assert_eq!(checksum.expected, "d41d8cd98f00b204e9800998ecf8427e");
+
+        assert_eq!(entries[2].urls, ["https://example.com/plain?a=b"]);
+        assert_eq!(entries[2].filename, None);
+        assert!(entries[2].checksum.is_none());
+    }
+
+    #[test]
+    fn refuses_out_names_that_leave_the_directory() {
+        let list = UrlList::parse(
+            "https://example.com/a\n  out=../etc/passwd\n\
+             https://example.com/b out=dir/file\n\
+             https://example.com/c out=..\n\
+             https://example.com/d out=\n",
+        );
+        assert_eq!(
+            invalid(&list),
+            [
+                (2, "`../etc/passwd` is not a valid file name".to_owned()),
+                (3, "`dir/file` is not a valid file name".to_owned()),
+                (4, "`..` is not a valid file name".to_owned()),
+                (5, "`` is not a valid file name".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_options_only_fail_their_file() {
+        let list = UrlList::parse(
+            "  out=orphan.iso\n\
+             https://example.com/a\n  size=10\n  out=ignored.iso\n\
+             https://example.com/b\n  out=b.iso\n",
+        );
+        assert_eq!(
+            invalid(&list),
+            [
+                (1, "option `out=orphan.iso` doesn't follow a URL".to_owned()),
+                (3, "unsupported option `size`".to_owned()),
+            ]
+        );
+        let entry = list.entries[2].as_ref().unwrap();
+        assert_eq!(entry.urls, ["https://example.com/b"]);
+        assert_eq!(entry.filename.as_deref(), Some("b.iso"));
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let list = UrlList::parse(
+            "not-a-url\n\
+             out=only-options.iso\n\
+             https://example.com/a\n  justtext\n\
+             https://example.com/b checksum=sha-256\n\
+             https://example.com/c checksum=sha-1=abc\n\
+             https://example.com/d checksum=md5=xyz\n",
+        );
+        assert_eq!(
+            invalid(&list),
+            [
+                (1, "invalid URL `not-a-url`".to_owned()),
+                (2, "no URL before `out=only-options.iso`".to_owned()),
+                (
+                    4,
+                    "expected an option like `out=<name>`, got `justtext`".to_owned()
+                ),
+                (
+                    5,
+                    "expected `checksum=<algorithm>=<digest>`, got `checksum=sha-256`".to_owned()
+                ),
+                (6, "unsupported checksum algorithm `sha-1`".to_owned()),
+                (7, "`xyz` is not a hex encoded md5 digest".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use crate::{
+    error::{DownloadError, IoResultExt},
+    filename,
+};
+use data_url::DataUrl;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use std::{io::Cursor, path::PathBuf};
+use tokio::{fs, io::AsyncRead};
+
+/// The contents of a `file://` or `data:` URL, which are read without any network requests.
+pub(crate) struct LocalFile {
+    pub reader: Box<dyn AsyncRead + Send + Unpin>,
+    pub len: u64,
+    /// The file a `file://` URL points to.
+    pub path: Option<PathBuf>,
+    /// The name to save the file under, if it can't be taken from the URL.
+    pub filename: Option<String>,
+    /// The `Content-Type` of a `data:` URL, so templates and reports see it like a server's.
+    pub headers: HeaderMap,
+}
+
+/// Opens `url` if it is a `file://` or `data:` URL.
+///
+/// Returns `None` for all other URLs.
+pub(crate) async fn open(url: &str) -> Result<Option<LocalFile>, DownloadError> {
+    let Ok(parsed) = url::Url::parse(url) else {
+        return Ok(None);
+    };
+    let invalid = || DownloadError::InvalidUrl(url.to_owned());
+
+    match parsed.scheme() {
+        "file" => {
+            let path = parsed.to_file_path().map_err(|()| invalid())?;
+            let file = fs::File::open(&path).await.with_path(&path)?;
+            let len = file.metadata().await.with_path(&path)?.len();
+            Ok(Some(LocalFile {
+                reader: Box::new(file),
+                len,
+                path: Some(path),
+                filename: None,
+                headers: HeaderMap::new(),
+            }))
+        }
+        "data" => {
+            let data_url = DataUrl::process(url).map_err(|_| invalid())?;
+            let (body, _) = data_url.decode_to_vec().map_err(|_| invalid())?;
+
+            let content_type = data_url.mime_type().to_string();
+            let filename = match filename::extension_for_content_type(&content_type) {
+                Some(extension) => format!("data.{}", extension),
+                None => "data".to_owned(),
+            };
+            let mut headers = HeaderMap::new();
+            if let Ok(value) = HeaderValue::from_str(&content_type) {
+                headers.insert(CONTENT_TYPE, value);
+            }
+
+            Ok(Some(LocalFile {
+                len: body.len() as u64,
+                reader: Box::new(Cursor::new(body)),
+                path: None,
+                filename: Some(filename),
+                headers,
+            }))
+        }
+        _ => Ok(None),
+    }
+}
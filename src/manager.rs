@@ -0,0 +1,601 @@
+use crate::{
+    checksum::{Checksum, ChecksumAlgorithm},
+    download::Downloader,
+    error::{DownloadError, IoResultExt},
+    handle::DownloadHandle,
+    options::DownloadOptions,
+    report::DownloadReport,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+
+/// Name of the file a persistent [`DownloadManager`] keeps its queue in.
+const QUEUE_FILE: &str = "queue.simult";
+
+/// Identifies a job of a [`DownloadManager`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct JobId(u64);
+
+/// Where a job of a [`DownloadManager`] is in its life cycle.
+#[derive(Debug, Clone)]
+pub enum JobStatus {
+    /// Waiting for a free download slot.
+    Queued,
+    Running,
+    Completed(Arc<DownloadReport>),
+    Failed(Arc<DownloadError>),
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Checks whether the job is done, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Completed(_) | Self::Failed(_) | Self::Cancelled)
+    }
+}
+
+/// A snapshot of a job of a [`DownloadManager`].
+#[derive(Debug, Clone)]
+pub struct JobInfo {
+    pub id: JobId,
+    pub url: String,
+    pub priority: i32,
+    pub status: JobStatus,
+}
+
+/// A queue of downloads that runs up to a fixed number of them at once.
+///
+/// Queued jobs with a higher priority start first; jobs of the same priority start in the order
+/// they were added. Jobs are run on the current tokio runtime.
+///
+/// A manager created with [`DownloadManager::restore`] saves its queue to disk, so unfinished
+/// jobs survive a crash or restart.
+#[derive(Clone)]
+pub struct DownloadManager {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    downloader: Downloader,
+    state: Mutex<State>,
+    /// The file the queue is saved to, if it is persistent.
+    queue_file: Option<PathBuf>,
+    /// The version of the queue last written to the queue file.
+    saved: Arc<Mutex<u64>>,
+}
+
+struct State {
+    jobs: BTreeMap<JobId, Job>,
+    next_id: u64,
+    running: usize,
+    max_concurrent: usize,
+    /// Counts the saves of the queue, so an older snapshot never overwrites a newer one.
+    version: u64,
+}
+
+struct Job {
+    url: String,
+    options: DownloadOptions,
+    priority: i32,
+    handle: DownloadHandle,
+    status: watch::Sender<JobStatus>,
+}
+
+impl DownloadManager {
+    /// Creates a manager that runs up to `max_concurrent_files` of the `downloader`'s downloads at once.
+    pub fn new(downloader: Downloader) -> Self {
+        Self::with_queue_file(downloader, None)
+    }
+
+    /// Creates a manager that saves its queue in `dir` and restarts the unfinished jobs saved there
+    /// by an earlier process.
+    ///
+    /// Resuming is enabled on the `downloader`, so restarted jobs continue where they stopped. The
+    /// file name, mirrors and checksum of each job are saved; other options, like headers and
+    /// credentials, are not.
+    pub fn restore(
+        mut downloader: Downloader,
+        dir: impl AsRef<Path>,
+    ) -> Result<Self, DownloadError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).with_path(dir)?;
+        let queue_file = dir.join(QUEUE_FILE);
+        let saved = match fs::read_to_string(&queue_file) {
+            Ok(contents) => parse_queue(&contents),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_path(&queue_file),
+        };
+
+        downloader.resume = true;
+        let manager = Self::with_queue_file(downloader, Some(queue_file));
+        {
+            let mut state = manager.inner.state.lock().unwrap();
+            state.next_id = saved.keys().last().map_or(0, |id| id.0 + 1);
+            state.jobs = saved;
+        }
+        manager.inner.schedule();
+        Ok(manager)
+    }
+
+    fn with_queue_file(downloader: Downloader, queue_file: Option<PathBuf>) -> Self {
+        let max_concurrent = downloader.max_concurrent_files;
+        Self {
+            inner: Arc::new(Inner {
+                downloader,
+                state: Mutex::new(State {
+                    jobs: BTreeMap::new(),
+                    next_id: 0,
+                    running: 0,
+                    max_concurrent,
+                    version: 0,
+                }),
+                queue_file,
+                saved: Arc::default(),
+            }),
+        }
+    }
+
+    /// Queues a download of `url`.
+    pub fn add(&self, url: &str, priority: i32) -> JobId {
+        self.add_with(url, DownloadOptions::new(), priority)
+    }
+
+    /// Queues a download of `url` with `options`.
+    ///
+    /// The manager replaces the cancellation token and pause handle of `options` with its own, see
+    /// [`DownloadManager::handle`].
+    pub fn add_with(&self, url: &str, options: DownloadOptions, priority: i32) -> JobId {
+        let id = {
+            let mut state = self.inner.state.lock().unwrap();
+            let id = JobId(state.next_id);
+            state.next_id += 1;
+            let job = Job::new(url.to_owned(), options, priority);
+            // A manager that shut down doesn't start anything anymore.
+            if self.inner.downloader.shutdown.is_shut_down() {
+                job.status.send_replace(JobStatus::Cancelled);
+            }
+            state.jobs.insert(id, job);
+            self.inner.save(&mut state);
+            id
+        };
+        self.inner.schedule();
+        id
+    }
+
+    /// Changes the priority of a job. Only affects jobs that haven't started yet.
+    ///
+    /// Returns `false` if there is no such job.
+    pub fn set_priority(&self, id: JobId, priority: i32) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        match state.jobs.get_mut(&id) {
+            Some(job) => {
+                job.priority = priority;
+                self.inner.save(&mut state);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Changes how many downloads run at once. Running downloads are not interrupted if it is lowered.
+    pub fn set_max_concurrent(&self, max_concurrent: usize) {
+        self.inner.state.lock().unwrap().max_concurrent = max_concurrent.max(1);
+        self.inner.schedule();
+    }
+
+    /// Cancels a job, whether it is queued or running.
+    ///
+    /// Returns `false` if there is no such job or it already finished.
+    pub fn cancel(&self, id: JobId) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        let Some(job) = state.jobs.get(&id) else {
+            return false;
+        };
+        let status = job.status.borrow().clone();
+        match status {
+            JobStatus::Queued => {
+                job.status.send_replace(JobStatus::Cancelled);
+                self.inner.save(&mut state);
+                true
+            }
+            JobStatus::Running => {
+                job.handle.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the handle that pauses, resumes and cancels a job.
+    pub fn handle(&self, id: JobId) -> Option<DownloadHandle> {
+        let state = self.inner.state.lock().unwrap();
+        state.jobs.get(&id).map(|job| job.handle.clone())
+    }
+
+    pub fn status(&self, id: JobId) -> Option<JobStatus> {
+        let state = self.inner.state.lock().unwrap();
+        state.jobs.get(&id).map(|job| job.status.borrow().clone())
+    }
+
+    /// Lists all jobs in the order they were added.
+    pub fn jobs(&self) -> Vec<JobInfo> {
+        let state = self.inner.state.lock().unwrap();
+        state
+            .jobs
+            .iter()
+            .map(|(&id, job)| JobInfo {
+                id,
+                url: job.url.clone(),
+                priority: job.priority,
+                status: job.status.borrow().clone(),
+            })
+            .collect()
+    }
+
+    /// Forgets all finished jobs.
+    pub fn clear_finished(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state
+            .jobs
+            .retain(|_, job| !job.status.borrow().is_finished());
+    }
+
+    /// Waits until a job is finished and returns its final status.
+    ///
+    /// Returns `None` if there is no such job.
+    pub async fn wait(&self, id: JobId) -> Option<JobStatus> {
+        let mut status = {
+            let state = self.inner.state.lock().unwrap();
+            state.jobs.get(&id)?.status.subscribe()
+        };
+        let finished = status.wait_for(JobStatus::is_finished).await;
+        finished.ok().map(|status| status.clone())
+    }
+
+    /// Stops the manager for good, for example when the process receives `SIGTERM`, and waits
+    /// until all jobs are finished.
+    ///
+    /// No more jobs are started. Running jobs are stopped by [`Downloader::shutdown`], and they end
+    /// up [`JobStatus::Cancelled`] like the queued ones. A persistent queue still lists them all, so
+    /// [`DownloadManager::restore`] restarts them.
+    pub async fn shutdown(&self) {
+        self.inner.downloader.shutdown().await;
+        {
+            let state = self.inner.state.lock().unwrap();
+            for job in state.jobs.values() {
+                job.status.send_if_modified(|status| {
+                    let queued = matches!(status, JobStatus::Queued);
+                    if queued {
+                        *status = JobStatus::Cancelled;
+                    }
+                    queued
+                });
+            }
+        }
+        self.wait_all().await;
+    }
+
+    /// Waits until all jobs that are queued or running are finished.
+    pub async fn wait_all(&self) {
+        let ids: Vec<JobId> = self
+            .inner
+            .state
+            .lock()
+            .unwrap()
+            .jobs
+            .keys()
+            .copied()
+            .collect();
+        for id in ids {
+            self.wait(id).await;
+        }
+    }
+}
+
+impl Inner {
+    /// Starts queued jobs until all download slots are taken.
+    fn schedule(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        while state.running < state.max_concurrent && !self.downloader.shutdown.is_shut_down() {
+            let next = state
+                .jobs
+                .iter()
+                .filter(|(_, job)| matches!(*job.status.borrow(), JobStatus::Queued))
+                .max_by_key(|(id, job)| (job.priority, std::cmp::Reverse(**id)))
+                .map(|(&id, _)| id);
+            let Some(id) = next else {
+                break;
+            };
+
+            state.running += 1;
+            let job = &state.jobs[&id];
+            job.status.send_replace(JobStatus::Running);
+            let url = job.url.clone();
+            let options = job.options.clone().handle(&job.handle);
+            let inner = self.clone();
+            tokio::spawn(async move {
+                let result = inner.downloader.download_with_report(&url, &options).await;
+                inner.finish(id, result);
+            });
+        }
+    }
+
+    fn finish(self: &Arc<Self>, id: JobId, result: Result<DownloadReport, DownloadError>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.running -= 1;
+            if let Some(job) = state.jobs.get(&id) {
+                let status = match result {
+                    Ok(report) => JobStatus::Completed(Arc::new(report)),
+                    Err(_)
+                        if job.handle.is_cancelled() || self.downloader.shutdown.is_shut_down() =>
+                    {
+                        JobStatus::Cancelled
+                    }
+                    Err(e) => JobStatus::Failed(Arc::new(e)),
+                };
+                job.status.send_replace(status);
+            }
+            self.save(&mut state);
+        }
+        self.schedule();
+    }
+
+    /// Writes the unfinished jobs to the queue file, if the queue is persistent.
+    ///
+    /// Running jobs are saved as well, so they are restarted if the process dies. After a shutdown
+    /// the queue is left as it was.
+    ///
+    /// Only the snapshot is taken under the state lock; the file is written on the blocking pool.
+    fn save(&self, state: &mut State) {
+        let Some(queue_file) = self.queue_file.clone() else {
+            return;
+        };
+        if self.downloader.shutdown.is_shut_down() {
+            return;
+        }
+
+        state.version += 1;
+        let version = state.version;
+        let contents = serialize_queue(&state.jobs);
+        let saved = self.saved.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut saved = saved.lock().unwrap();
+            if *saved > version {
+                return;
+            }
+            *saved = version;
+
+            // Write a new file and rename it, so a crash never leaves a truncated queue behind.
+            let mut temp = queue_file.as_os_str().to_owned();
+            temp.push(".tmp");
+            let temp = PathBuf::from(temp);
+            let result = fs::write(&temp, contents).and_then(|()| fs::rename(&temp, &queue_file));
+            if let Err(_e) = result {
+                warn!(error = %_e, path = %queue_file.display(), "can't save download queue");
+            }
+        });
+    }
+}
+
+impl Job {
+    fn new(url: String, options: DownloadOptions, priority: i32) -> Self {
+        Self {
+            url,
+            options,
+            priority,
+            handle: DownloadHandle::new(),
+            status: watch::Sender::new(JobStatus::Queued),
+        }
+    }
+}
+
+/// Writes the unfinished jobs in the format of a queue file, one field per line.
+///
+/// Line breaks and backslashes in values are escaped, so a value always stays on its own line.
+fn serialize_queue(jobs: &BTreeMap<JobId, Job>) -> String {
+    let mut out = String::new();
+    for (id, job) in jobs {
+        if job.status.borrow().is_finished() {
+            continue;
+        }
+        let _ = writeln!(out, "job {} {} {}", id.0, job.priority, escape(&job.url));
+        if let Some(filename) = &job.options.filename {
+            let _ = writeln!(out, "filename {}", escape(filename));
+        }
+        for mirror in &job.options.mirrors {
+            let _ = writeln!(out, "mirror {}", escape(mirror));
+        }
+        if let Some(checksum) = &job.options.checksum {
+            let _ = writeln!(
+                out,
+                "checksum {} {}",
+                checksum.algorithm.name(),
+                checksum.expected
+            );
+        }
+    }
+    out
+}
+
+/// Reads the jobs of a queue file. Lines that can't be parsed are skipped.
+fn parse_queue(contents: &str) -> BTreeMap<JobId, Job> {
+    let mut jobs = BTreeMap::new();
+    let mut current = None;
+
+    for line in contents.lines() {
+        let Some((key, value)) = line.split_once(' ') else {
+            continue;
+        };
+        if key == "job" {
+            let mut fields = value.splitn(3, ' ');
+            let id = fields.next().and_then(|v| v.parse().ok());
+            let priority = fields.next().and_then(|v| v.parse().ok());
+            current = match (id, priority, fields.next()) {
+                (Some(id), Some(priority), Some(url)) => {
+                    let job = Job::new(unescape(url), DownloadOptions::new(), priority);
+                    jobs.insert(JobId(id), job);
+                    Some(JobId(id))
+                }
+                _ => None,
+            };
+            continue;
+        }
+
+        let Some(job) = current.and_then(|id| jobs.get_mut(&id)) else {
+            continue;
+        };
+        let options = std::mem::take(&mut job.options);
+        job.options = match key {
+            "filename" => options.filename(&unescape(value)),
+            "mirror" => options.mirrors([unescape(value)]),
+            "checksum" => match value
+                .split_once(' ')
+                .and_then(|(name, hex)| Some((ChecksumAlgorithm::from_name(name)?, hex)))
+            {
+                Some((algorithm, hex)) => options.checksum(Checksum::new(algorithm, hex)),
+                None => options,
+            },
+            _ => options,
+        };
+    }
+    jobs
+}
+
+/// Escapes backslashes and line breaks in a value of the queue file.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Reverses [`escape`].
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(jobs: impl IntoIterator<Item = (u64, Job)>) -> BTreeMap<JobId, Job> {
+        jobs.into_iter().map(|(id, job)| (JobId(id), job)).collect()
+    }
+
+    #[test]
+    fn queue_round_trips() {
+        let options = DownloadOptions::new()
+            .filename("file.iso")
+            .mirrors(["https://mirror.example/file.iso"])
+            .checksum(Checksum::sha256(&"ab".repeat(32)));
+        let jobs = queue([
+            (
+                3,
+                Job::new("https://example.com/file.iso".into(), options, 5),
+            ),
+            (
+                7,
+                Job::new(
+                    "https://example.com/other".into(),
+                    DownloadOptions::new(),
+                    -1,
+                ),
+            ),
+        ]);
+
+        let parsed = parse_queue(&serialize_queue(&jobs));
+        assert_eq!(
+            parsed.keys().copied().collect::<Vec<_>>(),
+            [JobId(3), JobId(7)]
+        );
+        let job = &parsed[&JobId(3)];
+        assert_eq!(job.url, "https://example.com/file.iso");
+        assert_eq!(job.priority, 5);
+        assert_eq!(job.options.filename.as_deref(), Some("file.iso"));
+        assert_eq!(job.options.mirrors, ["https://mirror.example/file.iso"]);
+        let checksum = job.options.checksum.as_ref().unwrap();
+        assert_eq!(checksum.algorithm.name(), "sha256");
+        assert_eq!(checksum.expected, "ab".repeat(32));
+        let job = &parsed[&JobId(7)];
+        assert_eq!(
+            (job.url.as_str(), job.priority),
+            ("https://example.com/other", -1)
+        );
+        assert_eq!(job.options.filename, None);
+    }
+
+    #[test]
+    fn line_breaks_in_values_dont_add_jobs() {
+        let options = DownloadOptions::new()
+            .filename("a\njob 9 0 https://evil.example/\\x")
+            .mirrors(["https://mirror.example/a\r\nmirror https://evil.example/"]);
+        let jobs = queue([(1, Job::new("https://example.com/a\\b".into(), options, 0))]);
+
+        let serialized = serialize_queue(&jobs);
+        assert_eq!(serialized.lines().count(), 3);
+        let parsed = parse_queue(&serialized);
+        assert_eq!(parsed.len(), 1);
+        let job = &parsed[&JobId(1)];
+        assert_eq!(job.url, "https://example.com/a\\b");
+        assert_eq!(
+            job.options.filename.as_deref(),
+            Some("a\njob 9 0 https://evil.example/\\x")
+        );
+        assert_eq!(
+            job.options.mirrors,
+            ["https://mirror.example/a\r\nmirror https://evil.example/"]
+        );
+    }
+
+    #[test]
+    fn finished_jobs_are_not_saved() {
+        let done = Job::new("https://example.com/done".into(), DownloadOptions::new(), 0);
+        done.status.send_replace(JobStatus::Cancelled);
+        let jobs = queue([
+            (0, done),
+            (
+                1,
+                Job::new(
+                    "https://example.com/queued".into(),
+                    DownloadOptions::new(),
+                    0,
+                ),
+            ),
+        ]);
+
+        let parsed = parse_queue(&serialize_queue(&jobs));
+        assert_eq!(parsed.keys().copied().collect::<Vec<_>>(), [JobId(1)]);
+    }
+
+    #[test]
+    fn skips_lines_it_cant_parse() {
+        let contents = "filename orphan\njob x 0 https://example.com/bad\nmirror lost\n\
+                        job 2 1 https://example.com/good\nunknown field\nchecksum nope ab\n";
+        let parsed = parse_queue(contents);
+        assert_eq!(parsed.keys().copied().collect::<Vec<_>>(), [JobId(2)]);
+        let job = &parsed[&JobId(2)];
+        assert!(job.options.mirrors.is_empty());
+        assert!(job.options.checksum.is_none());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{
+    error::{DownloadError, IoResultExt},
+    report::DownloadReport,
+};
+use reqwest::header::{ETAG, LAST_MODIFIED};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+};
+use tokio::{fs, sync::Mutex};
+
+/// Name of the manifest in the output directory.
+pub(crate) const MANIFEST_NAME: &str = ".simult-manifest.json";
+
+/// What the server said about a file when it was last downloaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ManifestEntry {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub size: u64,
+}
+
+impl ManifestEntry {
+    pub fn from_report(report: &DownloadReport) -> Self {
+        let header = |name| {
+            report
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            size: report.size,
+        }
+    }
+}
+
+/// The validators of every file downloaded into an output directory, kept in a single JSON file
+/// in it, like
+///
+/// ```json
+/// {"files": {"isos/debian.iso": {"etag": "\"5f3a\"", "last_modified": null, "size": 123}}}
+/// ```
+pub(crate) struct Manifest {
+    /// Directory the paths of the files are relative to.
+    dir: PathBuf,
+    /// The entries by path, read from the manifest when they're first needed.
+    entries: Mutex<Option<BTreeMap<String, ManifestEntry>>>,
+}
+
+impl Manifest {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            entries: Mutex::new(None),
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(MANIFEST_NAME)
+    }
+
+    /// Returns the entry of the file at `path`.
+    pub async fn get(&self, path: &Path) -> Option<ManifestEntry> {
+        let mut entries = self.entries.lock().await;
+        let entries = self.loaded(&mut entries).await;
+        entries.get(&self.key(path)).cloned()
+    }
+
+    /// Replaces the entry of the file at `path` and writes the manifest.
+    pub async fn insert(&self, path: &Path, entry: ManifestEntry) -> Result<(), DownloadError> {
+        let mut entries = self.entries.lock().await;
+        let entries = self.loaded(&mut entries).await;
+        if entries.get(&self.key(path)) == Some(&entry) {
+            return Ok(());
+        }
+        entries.insert(self.key(path), entry);
+        self.save(entries).await
+    }
+
+    async fn loaded<'a>(
+        &self,
+        entries: &'a mut Option<BTreeMap<String, ManifestEntry>>,
+    ) -> &'a mut BTreeMap<String, ManifestEntry> {
+        if entries.is_none() {
+            let path = self.path();
+            *entries = Some(match fs::read_to_string(&path).await {
+                Ok(contents) => parse(&contents).unwrap_or_else(|| {
+                    warn!(path = %path.display(), "ignoring invalid manifest");
+                    BTreeMap::new()
+                }),
+                Err(_) => BTreeMap::new(),
+            });
+        }
+        entries.as_mut().expect("entries were loaded")
+    }
+
+    /// Writes a new manifest and renames it over the old one, so a crash never leaves a truncated
+    /// manifest behind.
+    async fn save(&self, entries: &BTreeMap<String, ManifestEntry>) -> Result<(), DownloadError> {
+        let files: serde_json::Map<_, _> = entries
+            .iter()
+            .map(|(path, entry)| {
+                let entry = json!({
+                    "etag": entry.etag,
+                    "last_modified": entry.last_modified,
+                    "size": entry.size,
+                });
+                (path.clone(), entry)
+            })
+            .collect();
+        let contents =
+            serde_json::to_string_pretty(&json!({ "files": files })).expect("manifests serialize");
+
+        let path = self.path();
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        fs::create_dir_all(&self.dir).await.with_path(&self.dir)?;
+        fs::write(&temp, contents).await.with_path(&temp)?;
+        fs::rename(&temp, &path).await.with_path(&path)
+    }
+
+    /// The path of the file at `path` relative to the output directory, with `/` between its
+    /// components on every platform.
+    fn key(&self, path: &Path) -> String {
+        let relative = path.strip_prefix(&self.dir).unwrap_or(path);
+        let components: Vec<_> = relative
+            .components()
+            .filter(|component| !matches!(component, Component::CurDir))
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+        components.join("/")
+    }
+}
+
+fn parse(contents: &str) -> Option<BTreeMap<String, ManifestEntry>> {
+    let manifest: Value = serde_json::from_str(contents).ok()?;
+    manifest["files"]
+        .as_object()?
+        .iter()
+        .map(|(path, entry)| {
+            let text = |name: &str| entry[name].as_str().map(str::to_owned);
+            let entry = ManifestEntry {
+                etag: text("etag"),
+                last_modified: text("last_modified"),
+                size: entry["size"].as_u64()?,
+            };
+            Some((path.clone(), entry))
+        })
+        .collect()
+}
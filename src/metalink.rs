@@ -0,0 +1,223 @@
+use crate::{
+    checksum::{Checksum, ChecksumAlgorithm},
+    error::DownloadError,
+    filename,
+};
+use roxmltree::{Document, Node};
+
+/// The files described by a [Metalink](https://www.rfc-editor.org/rfc/rfc5854) v3 or v4 document.
+#[derive(Debug, Clone, Default)]
+pub struct Metalink {
+    pub files: Vec<MetalinkFile>,
+}
+
+/// A file listed in a Metalink document.
+#[derive(Debug, Clone)]
+pub struct MetalinkFile {
+    /// Name of the file, without directory components.
+    pub name: String,
+    pub size: Option<u64>,
+    /// The strongest published hash simult can verify.
+    pub checksum: Option<Checksum>,
+    /// HTTP(S) mirrors, most preferred first.
+    pub urls: Vec<String>,
+}
+
+impl Metalink {
+    /// Parses a Metalink document. Both the v4 (`.meta4`) and the v3 (`.metalink`) formats are supported.
+    pub fn parse(xml: &str) -> Result<Self, DownloadError> {
+        let doc =
+            Document::parse(xml).map_err(|e| DownloadError::InvalidMetalink(e.to_string()))?;
+        let root = doc.root_element();
+        if root.tag_name().name() != "metalink" {
+            return Err(DownloadError::InvalidMetalink(
+                "root element is not <metalink>".to_owned(),
+            ));
+        }
+
+        let files = root
+            .descendants()
+            .filter(|n| is_element(n, "file"))
+            .filter_map(parse_file)
+            .collect();
+
+        Ok(Self { files })
+    }
+}
+
+fn parse_file(file: Node) -> Option<MetalinkFile> {
+    let name = filename::base_name(file.attribute("name")?)?;
+
+    let size = file
+        .descendants()
+        .find(|n| is_element(n, "size"))
+        .and_then(|n| n.text())
+        .and_then(|v| v.trim().parse().ok());
+
+    let checksum = file
+        .descendants()
+        .filter(|n| is_element(n, "hash") && !is_element(&n.parent().unwrap_or(*n), "pieces"))
+        .filter_map(|n| {
+            let algorithm = ChecksumAlgorithm::from_name(n.attribute("type")?)?;
+            Some(Checksum::new(algorithm, n.text()?))
+        })
+        .max_by_key(|c| hash_strength(c.algorithm));
+
+    let mut urls: Vec<(i64, String)> = file
+        .descendants()
+        .filter(|n| is_element(n, "url"))
+        .filter_map(|n| {
+            let url = n.text()?.trim();
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                return None;
+            }
+            Some((url_rank(&n), url.to_owned()))
+        })
+        .collect();
+    urls.sort_by_key(|(rank, _)| *rank);
+
+    Some(MetalinkFile {
+        name,
+        size,
+        checksum,
+        urls: urls.into_iter().map(|(_, url)| url).collect(),
+    })
+}
+
+fn is_element(node: &Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name
+}
+
+/// Orders mirrors so the most preferred comes first.
+///
+/// v4 uses `priority`, where lower is better. v3 uses `preference`, where higher is better.
+fn url_rank(url: &Node) -> i64 {
+    if let Some(priority) = url
+        .attribute("priority")
+        .and_then(|v| v.parse::<i64>().ok())
+    {
+        priority
+    } else if let Some(preference) = url
+        .attribute("preference")
+        .and_then(|v| v.parse::<i64>().ok())
+    {
+        -preference
+    } else {
+        i64::MAX
+    }
+}
+
+fn hash_strength(algorithm: ChecksumAlgorithm) -> u8 {
+    match algorithm {
+        ChecksumAlgorithm::Md5 => 0,
+        ChecksumAlgorithm::Sha256 => 1,
+        ChecksumAlgorithm::Blake3 => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_v4_documents() {
+        let metalink = Metalink::parse(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <metalink xmlns="urn:ietf:params:xml:ns:metalink">
+              <file name="example.iso">
+                <size>14471447</size>
+                <hash type="md5">0123456789ABCDEF0123456789ABCDEF</hash>
+                <hash type="sha-256">f0ad929cd259957e160ea442eb80986b5f01c1b6ea8b4b2c6be7d4d6c6a0d0c0</hash>
+                <pieces length="262144" type="sha-256">
+                  <hash>aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa</hash>
+                </pieces>
+                <url priority="2">https://mirror.example.org/example.iso</url>
+                <url priority="1">https://example.com/example.iso</url>
+                <url>http://fallback.example.net/example.iso</url>
+                <url priority="1">ftp://example.com/example.iso</url>
+              </file>
+            </metalink>"#,
+        )
+        .unwrap();
+        let [file] = metalink.files.as_slice() else {
+            panic!("expected one file, got {:?}", metalink.files);
+        };
+        assert_eq!(file.name, "example.iso");
+        assert_eq!(file.size, Some(14_471_447));
+        assert_eq!(
+            file.checksum,
+            Some(Checksum::sha256(
+                "f0ad929cd259957e160ea442eb80986b5f01c1b6ea8b4b2c6be7d4d6c6a0d0c0"
+            ))
+        );
+        assert_eq!(
+            file.urls,
+            [
+                "https://example.com/example.iso",
+                "https://mirror.example.org/example.iso",
+                "http://fallback.example.net/example.iso",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_v3_documents() {
+        let metalink = Metalink::parse(
+            r#"<metalink version="3.0" xmlns="http://www.metalinker.org/">
+              <files>
+                <file name="a.txt">
+                  <verification><hash type="md5">0123456789abcdef0123456789abcdef</hash></verification>
+                  <resources>
+                    <url type="http" preference="10">https://low.example.com/a.txt</url>
+                    <url type="http" preference="100">https://high.example.com/a.txt</url>
+                  </resources>
+                </file>
+                <file name="b.txt"></file>
+              </files>
+            </metalink>"#,
+        )
+        .unwrap();
+        assert_eq!(metalink.files.len(), 2);
+        let file = &metalink.files[0];
+        assert_eq!(file.size, None);
+        assert_eq!(
+            file.checksum,
+            Some(Checksum::md5("0123456789abcdef0123456789abcdef"))
+        );
+        assert_eq!(
+            file.urls,
+            [
+                "https://high.example.com/a.txt",
+                "https://low.example.com/a.txt"
+            ]
+        );
+        assert_eq!(metalink.files[1].checksum, None);
+        assert!(metalink.files[1].urls.is_empty());
+    }
+
+    #[test]
+    fn strips_directories_from_names() {
+        let metalink = Metalink::parse(
+            r#"<metalink>
+              <file name="../../.bashrc"><url>https://example.com/a</url></file>
+              <file name="dir/"><url>https://example.com/b</url></file>
+              <file><url>https://example.com/c</url></file>
+            </metalink>"#,
+        )
+        .unwrap();
+        let names: Vec<_> = metalink.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, [".bashrc"]);
+    }
+
+    #[test]
+    fn refuses_other_documents() {
+        assert!(matches!(
+            Metalink::parse("<html></html>"),
+            Err(DownloadError::InvalidMetalink(_))
+        ));
+        assert!(matches!(
+            Metalink::parse("<metalink>"),
+            Err(DownloadError::InvalidMetalink(_))
+        ));
+    }
+}
@@ -0,0 +1,90 @@
+//! Download metrics that are recorded through the `metrics` facade when the `metrics` feature is
+//! enabled, and not at all otherwise.
+
+#[cfg(feature = "metrics")]
+use ::metrics::{counter, gauge, histogram};
+
+#[cfg(feature = "metrics")]
+const BYTES_DOWNLOADED: &str = "zusammen_bytes_downloaded_total";
+#[cfg(feature = "metrics")]
+const ACTIVE_CONNECTIONS: &str = "zusammen_active_connections";
+#[cfg(feature = "metrics")]
+const RETRIES: &str = "zusammen_retries_total";
+#[cfg(feature = "metrics")]
+const DOWNLOADS_COMPLETED: &str = "zusammen_downloads_completed_total";
+#[cfg(feature = "metrics")]
+const DOWNLOADS_FAILED: &str = "zusammen_downloads_failed_total";
+#[cfg(feature = "metrics")]
+const THROUGHPUT: &str = "zusammen_download_throughput_bytes_per_second";
+
+/// Describes the metrics to the installed recorder.
+pub(crate) fn describe() {
+    #[cfg(feature = "metrics")]
+    {
+        use ::metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+
+        describe_counter!(
+            BYTES_DOWNLOADED,
+            Unit::Bytes,
+            "Bytes received from servers."
+        );
+        describe_gauge!(
+            ACTIVE_CONNECTIONS,
+            Unit::Count,
+            "Connections that are receiving a file."
+        );
+        describe_counter!(RETRIES, Unit::Count, "Failed requests that were retried.");
+        describe_counter!(DOWNLOADS_COMPLETED, Unit::Count, "Downloads that finished.");
+        describe_counter!(
+            DOWNLOADS_FAILED,
+            Unit::Count,
+            "Downloads that failed, not counting cancelled ones."
+        );
+        describe_histogram!(
+            THROUGHPUT,
+            "Average speed of finished downloads, in bytes per second."
+        );
+    }
+}
+
+pub(crate) fn record_bytes(_bytes: usize) {
+    #[cfg(feature = "metrics")]
+    counter!(BYTES_DOWNLOADED).increment(_bytes as u64);
+}
+
+pub(crate) fn record_retry() {
+    #[cfg(feature = "metrics")]
+    counter!(RETRIES).increment(1);
+}
+
+pub(crate) fn record_outcome(
+    _result: &Result<crate::report::DownloadReport, crate::error::DownloadError>,
+) {
+    #[cfg(feature = "metrics")]
+    match _result {
+        Ok(report) => {
+            counter!(DOWNLOADS_COMPLETED).increment(1);
+            histogram!(THROUGHPUT).record(report.average_speed());
+        }
+        Err(crate::error::DownloadError::Cancelled) => {}
+        Err(_) => counter!(DOWNLOADS_FAILED).increment(1),
+    }
+}
+
+/// Counts a connection as active for as long as it is alive.
+pub(crate) struct ActiveConnection(());
+
+impl ActiveConnection {
+    pub fn open() -> Self {
+        #[cfg(feature = "metrics")]
+        gauge!(ACTIVE_CONNECTIONS).increment(1.0);
+        Self(())
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        gauge!(ACTIVE_CONNECTIONS).decrement(1.0);
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// URLs serving the same file, with bookkeeping of which ones failed.
+pub(crate) struct Mirrors {
+    urls: Vec<String>,
+    failures: Vec<AtomicU32>,
+    next: AtomicUsize,
+}
+
+impl Mirrors {
+    pub fn new(url: &str, mirrors: &[String]) -> Self {
+        let urls: Vec<String> = std::iter::once(url.to_owned())
+            .chain(mirrors.iter().filter(|m| *m != url).cloned())
+            .collect();
+        let failures = urls.iter().map(|_| AtomicU32::new(0)).collect();
+
+        Self {
+            urls,
+            failures,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.urls.len()
+    }
+
+    /// The URL the caller asked for. Used to name the file and identify resumable downloads.
+    pub fn primary(&self) -> &str {
+        &self.urls[0]
+    }
+
+    pub fn url(&self, mirror: usize) -> &str {
+        &self.urls[mirror]
+    }
+
+    pub fn urls(&self) -> &[String] {
+        &self.urls
+    }
+
+    /// Picks a mirror for a new connection, spreading connections evenly over the healthiest mirrors.
+    pub fn assign(&self) -> usize {
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+        self.healthiest(offset, None)
+    }
+
+    /// Records that `mirror` failed and returns the mirror to try instead.
+    pub fn failover(&self, mirror: usize) -> usize {
+        self.failures[mirror].fetch_add(1, Ordering::Relaxed);
+        self.healthiest(mirror + 1, Some(mirror))
+    }
+
+    /// Returns the mirror with the fewest failures, preferring the ones right after `offset` on ties.
+    fn healthiest(&self, offset: usize, exclude: Option<usize>) -> usize {
+        (0..self.len())
+            .map(|i| (offset + i) % self.len())
+            .filter(|&i| self.len() == 1 || Some(i) != exclude)
+            .min_by_key(|&i| self.failures[i].load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+}
@@ -0,0 +1,52 @@
+//! Chunks copied straight into a memory map of the output file, enabled with the `mmap` feature.
+//!
+//! This skips a system call per write, which matters once the disk and network are fast enough
+//! that writing is what holds a download up.
+
+use memmap2::MmapRaw;
+use std::{fs::File, io, sync::Arc};
+
+/// A writable map of the whole output file, shared by all chunks of a download.
+pub(crate) struct MappedFile {
+    map: MmapRaw,
+}
+
+impl MappedFile {
+    /// Maps `file`, which must already have its final, non-zero size and be open for reading
+    /// and writing.
+    pub fn new(file: &File) -> io::Result<Self> {
+        Ok(Self {
+            map: MmapRaw::map_raw(file)?,
+        })
+    }
+
+    /// Copies `bytes` into the file at `offset`.
+    pub fn write_at(&self, bytes: &[u8], offset: u64) -> io::Result<()> {
+        let in_bounds = offset
+            .checked_add(bytes.len() as u64)
+            .is_some_and(|end| end <= self.map.len() as u64);
+        if !in_bounds {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write past the end of the mapped file",
+            ));
+        }
+        // SAFETY: the range is inside the map, and chunks claim disjoint ranges of the file, so no
+        // one else accesses these bytes during the copy.
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                self.map.as_mut_ptr().add(offset as usize),
+                bytes.len(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Forces `len` bytes from `offset` on to disk.
+    pub async fn sync_range(self: &Arc<Self>, offset: u64, len: u64) -> io::Result<()> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.map.flush_range(offset as usize, len as usize))
+            .await?
+    }
+}
@@ -0,0 +1,241 @@
+use crate::policy::UrlPolicy;
+use futures::future::BoxFuture;
+use hyper::client::connect::dns::Name;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+#[cfg(any(feature = "ftp", feature = "sftp"))]
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Which IP versions connections are made over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpVersion {
+    /// Connects to the addresses of a host in the order DNS returns them. HTTP connections start a
+    /// second attempt over the other version if the first one doesn't connect within 300ms (Happy
+    /// Eyeballs).
+    #[default]
+    Any,
+    /// Only connects over IPv4.
+    V4,
+    /// Only connects over IPv6.
+    V6,
+}
+
+impl IpVersion {
+    /// Returns the unspecified address of the version, which HTTP clients bind to so they only
+    /// connect to addresses of the same version.
+    pub(crate) fn local_address(self) -> Option<IpAddr> {
+        match self {
+            Self::Any => None,
+            Self::V4 => Some(Ipv4Addr::UNSPECIFIED.into()),
+            Self::V6 => Some(Ipv6Addr::UNSPECIFIED.into()),
+        }
+    }
+}
+
+/// Resolves host names to IP addresses in place of the system resolver.
+///
+/// Implemented for async closures taking the host name.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str) -> BoxFuture<'static, io::Result<Vec<IpAddr>>>;
+}
+
+impl<F, Fut> Resolver for F
+where
+    F: Fn(String) -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<Vec<IpAddr>>> + Send + 'static,
+{
+    fn resolve(&self, host: &str) -> BoxFuture<'static, io::Result<Vec<IpAddr>>> {
+        Box::pin(self(host.to_owned()))
+    }
+}
+
+/// How connections are made, by the HTTP client and without it.
+#[derive(Clone, Default)]
+pub(crate) struct Network {
+    pub ip_version: IpVersion,
+    /// The address connections are made from, which takes precedence over `ip_version`.
+    pub local_address: Option<IpAddr>,
+    /// Addresses that hosts are connected to instead of resolving them, keyed by lowercase host.
+    pub overrides: HashMap<String, Vec<IpAddr>>,
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// Decides which hosts may be connected to, and to which of their addresses.
+    pub policy: Option<Arc<UrlPolicy>>,
+    /// The socket receive buffer size of connections that aren't made by the HTTP client, instead
+    /// of the one the system picks.
+    pub receive_buffer_size: Option<u32>,
+}
+
+impl Network {
+    /// Returns the address connections are bound to, if they're bound to one.
+    pub fn local_address(&self) -> Option<IpAddr> {
+        self.local_address.or(self.ip_version.local_address())
+    }
+
+    /// Checks whether hosts are resolved any differently than by the system resolver.
+    pub fn is_custom(&self) -> bool {
+        !self.overrides.is_empty()
+            || self.resolver.is_some()
+            || self
+                .policy
+                .as_ref()
+                .is_some_and(|policy| policy.checks_hosts())
+    }
+
+    async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let addrs = self.resolve(host, port).await?;
+        match &self.policy {
+            Some(policy) => policy.check_resolved(host, addrs),
+            None => Ok(addrs),
+        }
+    }
+
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        if let Some(ips) = self.overrides.get(&host.to_ascii_lowercase()) {
+            return Ok(ips.iter().map(|&ip| SocketAddr::new(ip, port)).collect());
+        }
+        match &self.resolver {
+            Some(resolver) => Ok(resolver
+                .resolve(host)
+                .await?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect()),
+            None => Ok(tokio::net::lookup_host((host, port)).await?.collect()),
+        }
+    }
+
+    /// Connects to `host` from the local address, for connections that aren't made by the HTTP
+    /// client. Its addresses are tried one after the other.
+    #[cfg(any(feature = "ftp", feature = "sftp"))]
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let local = self.local_address();
+        // Addresses of the other IP version can't be reached from the local address.
+        let addrs = self
+            .lookup(host, port)
+            .await?
+            .into_iter()
+            .filter(|addr| local.is_none_or(|local| local.is_ipv4() == addr.is_ipv4()));
+
+        let mut error = None;
+        for addr in addrs {
+            match connect_from(local, addr, self.receive_buffer_size).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error.unwrap_or_else(|| {
+            let version = match local {
+                Some(IpAddr::V4(_)) => "IPv4",
+                Some(IpAddr::V6(_)) => "IPv6",
+                None => "IP",
+            };
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no {} address", host, version),
+            )
+        }))
+    }
+}
+
+/// Opens a connection to `addr` from the `local` address, or any address if there is none, with
+/// a receive buffer of `receive_buffer_size` bytes if that's set.
+#[cfg(any(feature = "ftp", feature = "sftp"))]
+pub(crate) async fn connect_from(
+    local: Option<IpAddr>,
+    addr: SocketAddr,
+    receive_buffer_size: Option<u32>,
+) -> io::Result<TcpStream> {
+    if local.is_none() && receive_buffer_size.is_none() {
+        return TcpStream::connect(addr).await;
+    }
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(size) = receive_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(local) = local {
+        socket.bind(SocketAddr::new(local, 0))?;
+    }
+    socket.connect(addr).await
+}
+
+/// Returns an address of the network interface `name`, preferring the IP version `version` asks
+/// for and IPv4 otherwise. Link-local IPv6 addresses are skipped, since they can't be bound to
+/// without knowing the interface.
+#[cfg(target_os = "linux")]
+pub(crate) fn interface_address(name: &str, version: IpVersion) -> Option<IpAddr> {
+    use std::ffi::CStr;
+
+    let mut list = std::ptr::null_mut();
+    // SAFETY: `list` is only read if `getifaddrs` succeeded, and freed below.
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return None;
+    }
+    let mut addresses = Vec::new();
+    let mut entry = list;
+    while !entry.is_null() {
+        // SAFETY: `entry` is an element of the list returned by `getifaddrs`, which is still alive.
+        let ifaddr = unsafe { &*entry };
+        entry = ifaddr.ifa_next;
+        // SAFETY: `ifa_name` is a C string, and `ifa_addr` points to the socket address structure
+        // of its family.
+        unsafe {
+            if ifaddr.ifa_addr.is_null()
+                || CStr::from_ptr(ifaddr.ifa_name).to_bytes() != name.as_bytes()
+            {
+                continue;
+            }
+            match i32::from((*ifaddr.ifa_addr).sa_family) {
+                libc::AF_INET => {
+                    let addr = &*ifaddr.ifa_addr.cast::<libc::sockaddr_in>();
+                    addresses.push(IpAddr::from(addr.sin_addr.s_addr.to_ne_bytes()));
+                }
+                libc::AF_INET6 => {
+                    let addr = &*ifaddr.ifa_addr.cast::<libc::sockaddr_in6>();
+                    addresses.push(IpAddr::from(addr.sin6_addr.s6_addr));
+                }
+                _ => {}
+            }
+        }
+    }
+    // SAFETY: `list` was returned by `getifaddrs` and isn't used anymore.
+    unsafe { libc::freeifaddrs(list) };
+
+    let usable = |ip: &IpAddr| match ip {
+        IpAddr::V4(_) => version != IpVersion::V6,
+        IpAddr::V6(ip) => version != IpVersion::V4 && ip.segments()[0] & 0xffc0 != 0xfe80,
+    };
+    let mut addresses = addresses.into_iter().filter(usable);
+    let first = addresses.next()?;
+    match (version, first) {
+        (IpVersion::Any, IpAddr::V6(_)) => Some(addresses.find(IpAddr::is_ipv4).unwrap_or(first)),
+        _ => Some(first),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn interface_address(_name: &str, _version: IpVersion) -> Option<IpAddr> {
+    None
+}
+
+/// Lets the HTTP client resolve hosts the same way. It connects to the port of the URL and only
+/// to addresses of the configured IP version on its own.
+impl reqwest::dns::Resolve for Network {
+    fn resolve(&self, name: Name) -> reqwest::dns::Resolving {
+        let dns = self.clone();
+        Box::pin(async move {
+            let addrs = dns.lookup(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
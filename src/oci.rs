@@ -0,0 +1,347 @@
+//! Downloads of blobs from OCI registries, like the layers of container images, enabled with
+//! the `oci` feature.
+//!
+//! URLs like `oci://ghcr.io/owner/image@sha256:<hex>` are downloaded from
+//! `/v2/<name>/blobs/<digest>` of the registry, with a token from its token service if it asks
+//! for one, and verified against the digest.
+
+use crate::{
+    auth::Credentials,
+    checksum::{Checksum, ChecksumAlgorithm},
+    download::{self, Downloader},
+    error::DownloadError,
+    options::DownloadOptions,
+    pinning::CertificatePins,
+    trace,
+};
+use reqwest::{header::WWW_AUTHENTICATE, StatusCode};
+use serde_json::Value;
+use url::Url;
+
+/// The registry `docker.io` names refer to.
+const DOCKER_HUB: &str = "registry-1.docker.io";
+
+/// Which registries are spoken to over plain HTTP.
+#[derive(Clone, Default)]
+pub(crate) struct OciConfig {
+    pub insecure_registries: Vec<String>,
+}
+
+/// Checks whether `url` is an `oci://` URL.
+pub(crate) fn is_oci_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| url.scheme() == "oci")
+}
+
+/// A blob in a repository of a registry.
+struct Blob {
+    /// The host and port of the registry's API.
+    registry: String,
+    /// Like `library/ubuntu` or `owner/image`.
+    repository: String,
+    algorithm: ChecksumAlgorithm,
+    /// The hex encoded digest.
+    digest: String,
+}
+
+impl Blob {
+    fn parse(url: &str) -> Result<Self, DownloadError> {
+        let invalid = || DownloadError::InvalidUrl(url.to_owned());
+        let parsed = Url::parse(url).map_err(|_| invalid())?;
+        let host = parsed.host_str().ok_or_else(invalid)?;
+        let (repository, digest) = parsed
+            .path()
+            .trim_start_matches('/')
+            .split_once('@')
+            .ok_or_else(invalid)?;
+        let (algorithm, digest) = digest.split_once(':').ok_or_else(invalid)?;
+        if repository.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut registry = match parsed.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        };
+        let mut repository = repository.to_owned();
+        if matches!(host, "docker.io" | "index.docker.io") {
+            registry = DOCKER_HUB.to_owned();
+            // Official images are short for `library/<name>`.
+            if !repository.contains('/') {
+                repository = format!("library/{}", repository);
+            }
+        }
+        let unsupported = || DownloadError::Registry {
+            registry: registry.clone(),
+            reason: format!("unsupported digest `{}:{}`", algorithm, digest),
+        };
+        let algorithm = match algorithm {
+            "sha256" => ChecksumAlgorithm::Sha256,
+            _ => return Err(unsupported()),
+        };
+        if !algorithm.is_hex_digest(digest) {
+            return Err(unsupported());
+        }
+        Ok(Self {
+            registry,
+            repository,
+            algorithm,
+            digest: digest.to_ascii_lowercase(),
+        })
+    }
+}
+
+/// What a registry asks for in its `WWW-Authenticate` challenge, if it's a token.
+#[derive(Clone)]
+struct TokenService {
+    realm: String,
+    service: Option<String>,
+}
+
+impl TokenService {
+    /// Parses a challenge like `Bearer realm="https://auth.docker.io/token",service="registry.docker.io"`.
+    fn parse(challenge: &str) -> Option<Self> {
+        let (scheme, params) = challenge.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        let mut realm = None;
+        let mut service = None;
+        let mut rest = params;
+        while let Some((name, after)) = rest.split_once('=') {
+            let (value, next) = match after.trim_start().strip_prefix('"') {
+                Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+                None => after.split_once(',').unwrap_or((after, "")),
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "realm" => realm = Some(value.to_owned()),
+                "service" => service = Some(value.to_owned()),
+                _ => {}
+            }
+            rest = next.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        }
+        Some(Self {
+            realm: realm?,
+            service,
+        })
+    }
+
+    /// Asks for a token that may pull from `repository`, with `credentials` if there are any.
+    async fn fetch(
+        &self,
+        client: &reqwest::Client,
+        pins: Option<&CertificatePins>,
+        repository: &str,
+        credentials: Option<&Credentials>,
+    ) -> Result<String, DownloadError> {
+        let scope = format!("repository:{}:pull", repository);
+        let mut query = vec![("scope", scope.as_str())];
+        if let Some(service) = &self.service {
+            query.push(("service", service));
+        }
+        let mut request = client.get(&self.realm).query(&query);
+        if let Some(Credentials::Basic { username, password }) = credentials {
+            request = request.basic_auth(username, password.as_ref());
+        }
+        let response = download::send(request, &self.realm).await?;
+        if let Some(pins) = pins {
+            pins.check(&response)?;
+        }
+
+        let invalid = |reason: String| DownloadError::Registry {
+            registry: self.realm.clone(),
+            reason,
+        };
+        let body: Value = serde_json::from_str(&response.text().await?)
+            .map_err(|e| invalid(format!("invalid token response: {}", e)))?;
+        body["token"]
+            .as_str()
+            .or_else(|| body["access_token"].as_str())
+            .map(str::to_owned)
+            .ok_or_else(|| invalid("token response without a token".to_owned()))
+    }
+}
+
+impl Downloader {
+    /// Translates an `oci://` URL to the URL of its blob, and returns options that authenticate
+    /// the requests to it and verify the blob against its digest.
+    ///
+    /// Registries that hand out tokens are pinged at `/v2/` first to find their token service.
+    /// Basic credentials of `options` are traded for a token, and a new token is fetched when
+    /// one expires during the download.
+    pub(crate) async fn resolve_oci_url(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<(String, DownloadOptions), DownloadError> {
+        let blob = Blob::parse(url)?;
+        let scheme = match self.oci.insecure_registries.contains(&blob.registry) {
+            true => "http",
+            false => "https",
+        };
+        let api = format!("{}://{}/v2/", scheme, blob.registry);
+        let blob_url = format!(
+            "{}{}/blobs/{}:{}",
+            api,
+            blob.repository,
+            blob.algorithm.name(),
+            blob.digest
+        );
+
+        let mut options = options.clone();
+        if options.filename.is_none() {
+            options.filename = Some(blob.digest.clone());
+        }
+        if options.checksum.is_none() {
+            options = options.checksum(Checksum::new(blob.algorithm, &blob.digest));
+        }
+
+        let Some(service) = self.token_service(&api, &options).await? else {
+            return Ok((blob_url, options));
+        };
+        self.check_policy(&service.realm)?;
+        debug!(registry = %blob.registry, realm = %service.realm, "fetching registry token");
+        let credentials = options.auth.credentials();
+        let fetch = service.fetch(
+            &self.client,
+            self.pins.as_deref(),
+            &blob.repository,
+            credentials.as_ref(),
+        );
+        let token = options.or_cancelled(fetch).await??;
+
+        let client = self.client.clone();
+        let pins = self.pins.clone();
+        let repository = blob.repository;
+        let refresh = move |_url: String| {
+            let (client, pins, service) = (client.clone(), pins.clone(), service.clone());
+            let (repository, credentials) = (repository.clone(), credentials.clone());
+            async move {
+                let fetch =
+                    service.fetch(&client, pins.as_deref(), &repository, credentials.as_ref());
+                fetch.await.ok().map(Credentials::Bearer)
+            }
+        };
+        let options = options.bearer_token(&token).on_unauthorized(refresh);
+        Ok((blob_url, options))
+    }
+
+    /// Pings the API of a registry at `api`, returning its token service if it asks for a token.
+    async fn token_service(
+        &self,
+        api: &str,
+        options: &DownloadOptions,
+    ) -> Result<Option<TokenService>, DownloadError> {
+        self.check_policy(api)?;
+        let _permit = options.or_cancelled(self.hosts.acquire(api)).await?;
+        let ping = trace::propagate(self.client.get(api)).send();
+        let response = options
+            .or_cancelled(ping)
+            .await?
+            .map_err(|e| DownloadError::request(api, e))?;
+        if let Some(pins) = &self.pins {
+            pins.check(&response)?;
+        }
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(None);
+        }
+        Ok(response
+            .headers()
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|challenge| challenge.to_str().ok())
+            .find_map(TokenService::parse))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn blob(url: &str) -> (String, String, String) {
+        let blob = Blob::parse(url).unwrap();
+        assert_eq!(blob.algorithm.name(), "sha256");
+        (blob.registry, blob.repository, blob.digest)
+    }
+
+    #[test]
+    fn parses_blob_references() {
+        assert_eq!(
+            blob(&format!("oci://ghcr.io/owner/image@sha256:{DIGEST}")),
+            ("ghcr.io".into(), "owner/image".into(), DIGEST.into())
+        );
+        assert_eq!(
+            blob(&format!(
+                "oci://localhost:5000/a/b/c@sha256:{}",
+                DIGEST.to_ascii_uppercase()
+            )),
+            ("localhost:5000".into(), "a/b/c".into(), DIGEST.into())
+        );
+    }
+
+    #[test]
+    fn resolves_docker_hub_names() {
+        assert_eq!(
+            blob(&format!("oci://docker.io/ubuntu@sha256:{DIGEST}")),
+            (DOCKER_HUB.into(), "library/ubuntu".into(), DIGEST.into())
+        );
+        assert_eq!(
+            blob(&format!(
+                "oci://index.docker.io/owner/image@sha256:{DIGEST}"
+            )),
+            (DOCKER_HUB.into(), "owner/image".into(), DIGEST.into())
+        );
+    }
+
+    #[test]
+    fn refuses_invalid_references() {
+        for url in [
+            "oci://ghcr.io/owner/image".to_owned(),
+            "oci://ghcr.io/owner/image@sha256".to_owned(),
+            format!("oci://ghcr.io/@sha256:{DIGEST}"),
+            format!("oci:owner/image@sha256:{DIGEST}"),
+        ] {
+            assert!(
+                matches!(Blob::parse(&url), Err(DownloadError::InvalidUrl(_))),
+                "{url}"
+            );
+        }
+        for url in [
+            format!("oci://ghcr.io/owner/image@sha512:{DIGEST}"),
+            "oci://ghcr.io/owner/image@sha256:abc".to_owned(),
+        ] {
+            assert!(
+                matches!(
+                    Blob::parse(&url),
+                    Err(DownloadError::Registry { registry, .. }) if registry == "ghcr.io"
+                ),
+                "{url}"
+            );
+        }
+        assert!(is_oci_url(&format!(
+            "oci://ghcr.io/owner/image@sha256:{DIGEST}"
+        )));
+        assert!(!is_oci_url(
+            "https://ghcr.io/v2/owner/image/blobs/sha256:abc"
+        ));
+    }
+
+    #[test]
+    fn parses_token_challenges() {
+        let service = TokenService::parse(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/ubuntu:pull""#,
+        )
+        .unwrap();
+        assert_eq!(service.realm, "https://auth.docker.io/token");
+        assert_eq!(service.service.as_deref(), Some("registry.docker.io"));
+
+        let service = TokenService::parse("bearer realm=https://ghcr.io/token").unwrap();
+        assert_eq!(service.realm, "https://ghcr.io/token");
+        assert_eq!(service.service, None);
+
+        assert!(TokenService::parse(r#"Basic realm="registry""#).is_none());
+        assert!(TokenService::parse(r#"Bearer service="registry""#).is_none());
+        assert!(TokenService::parse("Bearer").is_none());
+    }
+}
@@ -0,0 +1,497 @@
+use crate::{
+    auth::{Auth, Credentials, CredentialsProvider},
+    checksum::{Checksum, ChecksumSource},
+    download,
+    error::DownloadError,
+    event::DownloadEvent,
+    filename,
+    handle::DownloadHandle,
+};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, COOKIE},
+    RequestBuilder, Response, StatusCode,
+};
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+
+/// What happens to a partially downloaded file when its download is cancelled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CancelPolicy {
+    /// Deletes the partial file and its resume state.
+    #[default]
+    RemovePartial,
+    /// Leaves the partial file on disk so it can be resumed later.
+    KeepPartial,
+}
+
+/// What happens when the file a download would be saved to already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Saves the download as `name (1).ext`, `name (2).ext` and so on. Downloads running at the
+    /// same time never pick the same name.
+    #[default]
+    Rename,
+    /// Replaces the existing file.
+    Overwrite,
+    /// Keeps the existing file and doesn't download anything.
+    Skip,
+    /// Fails with [`DownloadError::FileExists`].
+    Error,
+    /// Keeps the existing file if it matches the checksum of the download or, without a checksum,
+    /// the size reported by the server. Replaces it otherwise.
+    SkipIfSameSizeOrHash,
+    /// Keeps the existing file if the server responds with `304 Not Modified` to a conditional
+    /// request, with an `If-Modified-Since` of its modification time and an `If-None-Match` of
+    /// the `ETag` it was downloaded with. Replaces it otherwise, for periodic re-syncs of a
+    /// mirror.
+    ///
+    /// Downloads set the modification time of files to their `Last-Modified` date and keep
+    /// their `ETag` in `<file>.etag`. Only works for HTTP(S) URLs saved under the name from the
+    /// URL or [`DownloadOptions::filename`], other files are always replaced.
+    SkipIfUnchanged,
+}
+
+/// What happens when the same URL is listed more than once in
+/// [`Downloader::download_multiple`](crate::Downloader::download_multiple). The URL is only
+/// downloaded once either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Returns the path of the one download for every occurrence.
+    #[default]
+    SamePath,
+    /// Hard links the download to `name (1).ext`, `name (2).ext` and so on for every further
+    /// occurrence, or copies it where that isn't possible.
+    Hardlink,
+    /// Copies the download to `name (1).ext`, `name (2).ext` and so on for every further
+    /// occurrence.
+    Copy,
+}
+
+/// How the output file of a parallel download is allocated before its chunks are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Allocation {
+    /// Sizes the file and leaves it to the file system to allocate blocks as they're written.
+    #[default]
+    Resize,
+    /// Reserves disk blocks for the whole file up front, so a full disk is detected right away.
+    /// Only has an effect on Linux; elsewhere files are just resized.
+    Reserve,
+    /// Keeps the file sparse, so it only takes up the space of the bytes downloaded so far.
+    ///
+    /// The resume state records which regions of the file were written, and a resumed download
+    /// checks them against the data the file system holds, on Linux, fetching the regions that
+    /// were lost in a crash again.
+    Sparse,
+}
+
+/// When downloaded data is forced from the operating system's cache to the disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Never, the operating system writes it back whenever it sees fit.
+    #[default]
+    None,
+    /// Flushes the contents of a file to the disk once its download completes.
+    FlushOnComplete,
+    /// Syncs a file once its download completes, including its metadata and directory entry, so
+    /// it survives a crash from the moment the download returns.
+    FsyncOnComplete,
+    /// Like [`Durability::FsyncOnComplete`], and also flushes every chunk once it has been written.
+    /// The resume state is only saved after the data it describes, so a crash loses nothing that
+    /// the state claims to be downloaded.
+    FsyncPerChunk,
+}
+
+/// Settings that apply to a single download.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    pub(crate) cancel_token: Option<CancellationToken>,
+    pub(crate) paused: Option<watch::Receiver<bool>>,
+    pub(crate) checksum: Option<Checksum>,
+    pub(crate) checksum_source: Option<ChecksumSource>,
+    pub(crate) mirrors: Vec<String>,
+    pub(crate) filename: Option<String>,
+    /// Sanitized path of the directory the file is saved in, relative to the output directory.
+    pub(crate) directory: Option<String>,
+    pub(crate) overwrite: Option<OverwritePolicy>,
+    pub(crate) max_file_size: Option<u64>,
+    pub(crate) allowed_content_types: Option<Vec<String>>,
+    pub(crate) headers: HeaderMap,
+    pub(crate) auth: Arc<Auth>,
+    /// Hosts the credentials and headers are sent to besides the origin of the download.
+    pub(crate) credential_hosts: Vec<String>,
+    /// The origin of the URL being downloaded, once it's known, which the credentials and headers
+    /// are limited to.
+    pub(crate) origin: Option<url::Origin>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) deadline: Option<Instant>,
+    /// Cancelled when the [`Downloader`](crate::Downloader) shuts down.
+    pub(crate) shutdown: Option<CancellationToken>,
+    /// Receives the events of [`Downloader::download_events`](crate::Downloader::download_events).
+    pub(crate) events: Option<mpsc::UnboundedSender<DownloadEvent>>,
+}
+
+impl DownloadOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Aborts the download when `token` is cancelled.
+    pub fn cancel_token(mut self, token: CancellationToken) -> Self {
+        self.cancel_token = Some(token);
+        self
+    }
+
+    /// Fails the download with [`DownloadError::DeadlineExceeded`] if it hasn't completed within
+    /// `timeout` of starting, however many requests and retries it took.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Fails the download with [`DownloadError::DeadlineExceeded`] if it hasn't completed by
+    /// `deadline`, for example to finish a batch of downloads in time.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Lets `handle` pause, resume and cancel the download.
+    pub fn handle(mut self, handle: &DownloadHandle) -> Self {
+        self.cancel_token = Some(handle.cancel_token());
+        self.paused = Some(handle.subscribe());
+        self
+    }
+
+    /// Verifies the downloaded file against `checksum`.
+    ///
+    /// Files that don't match are deleted and the download fails with [`DownloadError::ChecksumMismatch`].
+    pub fn checksum(self, checksum: Checksum) -> Self {
+        self.checksum_from(checksum, ChecksumSource::Options)
+    }
+
+    /// Verifies the downloaded file against `checksum`, which was found at `source`.
+    pub(crate) fn checksum_from(mut self, checksum: Checksum, source: ChecksumSource) -> Self {
+        self.checksum = Some(checksum);
+        self.checksum_source = Some(source);
+        self
+    }
+
+    /// Adds URLs that serve the same file. Parallel downloads fetch chunks from all of them and
+    /// move chunks off mirrors that fail.
+    pub fn mirrors<I, S>(mut self, urls: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.mirrors.extend(urls.into_iter().map(Into::into));
+        self
+    }
+
+    /// Saves the file under `filename` instead of the name suggested by the server or the URL.
+    pub fn filename(mut self, filename: &str) -> Self {
+        self.filename = Some(filename.to_owned());
+        self
+    }
+
+    /// Saves the file in `directory`, like `docs/2024`, relative to the output directory. Components
+    /// like `..` that would leave the output directory are dropped.
+    pub fn directory(mut self, directory: &str) -> Self {
+        let components: Vec<_> = directory
+            .split(['/', '\\'])
+            .map(filename::sanitize)
+            .filter(|component| !component.is_empty())
+            .collect();
+        self.directory = (!components.is_empty()).then(|| components.join("/"));
+        self
+    }
+
+    /// Decides what happens if the file already exists, instead of the [`Downloader`](crate::Downloader)'s policy.
+    pub fn overwrite_policy(mut self, policy: OverwritePolicy) -> Self {
+        self.overwrite = Some(policy);
+        self
+    }
+
+    /// Fails the download if the file is larger than `limit` bytes, instead of the
+    /// [`Downloader`](crate::Downloader)'s limit. See
+    /// [`DownloaderBuilder::max_file_size`](crate::DownloaderBuilder::max_file_size).
+    pub fn max_file_size(mut self, limit: u64) -> Self {
+        self.max_file_size = Some(limit);
+        self
+    }
+
+    /// Only downloads the file if its `Content-Type` matches one of `types`, instead of the
+    /// [`Downloader`](crate::Downloader)'s types. See
+    /// [`DownloaderBuilder::allowed_content_types`](crate::DownloaderBuilder::allowed_content_types).
+    pub fn allowed_content_types<I, S>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allowed_content_types = Some(types.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Sends `name: value` with the requests of the download, including the probe. Like
+    /// [credentials](Self::credentials), headers only go to the origin of the download URL and
+    /// the [hosts](Self::send_credentials_to) listed for them.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Sends all of `headers` with the requests of the download, see [`header`](Self::header).
+    pub fn headers(mut self, headers: HeaderMap) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    /// Sends `cookies`, like `session=abc; theme=dark`, with the requests of the download, see
+    /// [`header`](Self::header).
+    pub fn cookies(self, cookies: HeaderValue) -> Self {
+        self.header(COOKIE, cookies)
+    }
+
+    /// Authenticates with basic auth.
+    pub fn basic_auth(self, username: &str, password: Option<&str>) -> Self {
+        self.credentials(Credentials::basic(username, password))
+    }
+
+    /// Authenticates with a bearer token, like an OAuth access token.
+    pub fn bearer_token(self, token: &str) -> Self {
+        self.credentials(Credentials::bearer(token))
+    }
+
+    /// Sends `credentials` in the `Authorization` header of the requests to the origin of the
+    /// download URL, and to the [hosts](Self::send_credentials_to) listed for them. Mirrors,
+    /// servers that files listed in a metalink or playlist are fetched from and the targets of
+    /// redirects to other origins don't get them.
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.auth = Arc::new(Auth::new(Some(credentials), self.auth.provider()));
+        self
+    }
+
+    /// Asks `provider` for new credentials when the server responds with `401 Unauthorized`, for
+    /// example because a token expired during a long download, and repeats the request with them.
+    pub fn on_unauthorized(mut self, provider: impl CredentialsProvider + 'static) -> Self {
+        self.auth = Arc::new(Auth::new(self.auth.credentials(), Some(Arc::new(provider))));
+        self
+    }
+
+    /// Sends the credentials, headers and cookies of the download to `hosts` as well, like the
+    /// hosts of its mirrors.
+    pub fn send_credentials_to<I, S>(mut self, hosts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.credential_hosts
+            .extend(hosts.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sends the request made by `request` with the headers and credentials of this download.
+    ///
+    /// If the server rejects the credentials, they are refreshed and the request is sent once more.
+    pub(crate) async fn send(
+        &self,
+        url: &str,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, DownloadError> {
+        let credentials = self.sends_credentials_to(url);
+        let mut refreshed = false;
+        loop {
+            let request = match credentials {
+                true => request().headers(self.headers.clone()),
+                false => request(),
+            };
+            let (authorized, generation) = self.auth.authorize(request, credentials);
+            match download::send(authorized, url).await {
+                Err(e)
+                    if e.status() == Some(StatusCode::UNAUTHORIZED)
+                        && credentials
+                        && !refreshed
+                        && self.auth.refresh(url, generation).await =>
+                {
+                    refreshed = true;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Checks whether requests to `url` get the credentials and headers: those to the origin of
+    /// the download and to the listed hosts, or all of them until the origin is known.
+    fn sends_credentials_to(&self, url: &str) -> bool {
+        let Some(origin) = &self.origin else {
+            return true;
+        };
+        let Ok(url) = url::Url::parse(url) else {
+            return false;
+        };
+        url.origin() == *origin
+            || url.host_str().is_some_and(|host| {
+                self.credential_hosts
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(host))
+            })
+    }
+
+    /// Adds the downloader's `headers` to `request`, with the same scope as the headers of this
+    /// download.
+    pub(crate) fn shared_headers(
+        &self,
+        url: &str,
+        headers: &HeaderMap,
+        request: RequestBuilder,
+    ) -> RequestBuilder {
+        match !headers.is_empty() && self.sends_credentials_to(url) {
+            true => request.headers(headers.clone()),
+            false => request,
+        }
+    }
+
+    /// Returns the options with the credentials limited to the origin of `url`, if it's an HTTP
+    /// URL and they aren't limited yet.
+    pub(crate) fn scoped(&self, url: &str) -> Self {
+        let origin = self.origin.clone().or_else(|| {
+            url::Url::parse(url)
+                .ok()
+                .filter(|url| matches!(url.scheme(), "http" | "https"))
+                .map(|url| url.origin())
+        });
+        Self {
+            origin,
+            ..self.clone()
+        }
+    }
+
+    /// Sends the event made by `event`, if anyone is listening.
+    pub(crate) fn emit(&self, event: impl FnOnce() -> DownloadEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event());
+        }
+    }
+
+    /// Resolves once the download isn't paused.
+    pub(crate) async fn unpaused(&self) -> bool {
+        match &self.paused {
+            Some(paused) if *paused.borrow() => {
+                let mut paused = paused.clone();
+                let _ = paused.wait_for(|paused| !paused).await;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the options of a download of `url` that starts now, with the timeout counting from
+    /// now on, that is cancelled by `shutdown` as well.
+    pub(crate) fn started(&self, url: &str, shutdown: &CancellationToken) -> Self {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        Self {
+            timeout: None,
+            deadline: self.deadline.into_iter().chain(deadline).min(),
+            shutdown: Some(shutdown.clone()),
+            ..self.scoped(url)
+        }
+    }
+
+    /// Resolves once the download has been cancelled or missed its deadline, never if neither can
+    /// happen.
+    pub(crate) async fn interrupted(&self) -> DownloadError {
+        let deadline = async {
+            match self.deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = cancelled(&self.cancel_token) => DownloadError::Cancelled,
+            _ = cancelled(&self.shutdown) => DownloadError::Cancelled,
+            _ = deadline => DownloadError::DeadlineExceeded,
+        }
+    }
+
+    /// Runs `future` to completion unless the download is cancelled or misses its deadline first.
+    pub(crate) async fn or_cancelled<F: Future>(
+        &self,
+        future: F,
+    ) -> Result<F::Output, DownloadError> {
+        tokio::select! {
+            output = future => Ok(output),
+            error = self.interrupted() => Err(error),
+        }
+    }
+}
+
+/// Resolves once `token` is cancelled, or never without one.
+async fn cancelled(token: &Option<CancellationToken>) {
+    match token {
+        Some(token) => token.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_are_sent_everywhere_until_the_origin_is_known() {
+        let options = DownloadOptions::new().bearer_token("token");
+        assert!(options.sends_credentials_to("https://mirror.example.org/file"));
+    }
+
+    #[test]
+    fn credentials_are_limited_to_the_origin() {
+        let options = DownloadOptions::new()
+            .bearer_token("token")
+            .scoped("https://example.com/file");
+        assert!(options.sends_credentials_to("https://example.com/other"));
+        assert!(options.sends_credentials_to("https://example.com:443/other"));
+        assert!(!options.sends_credentials_to("http://example.com/file"));
+        assert!(!options.sends_credentials_to("https://example.com:8443/file"));
+        assert!(!options.sends_credentials_to("https://cdn.example.com/file"));
+        assert!(!options.sends_credentials_to("not a url"));
+    }
+
+    #[test]
+    fn credentials_are_sent_to_listed_hosts() {
+        let options = DownloadOptions::new()
+            .bearer_token("token")
+            .send_credentials_to(["Mirror.example.org"])
+            .scoped("https://example.com/file");
+        assert!(options.sends_credentials_to("https://mirror.example.org/file"));
+        assert!(!options.sends_credentials_to("https://other.example.org/file"));
+    }
+
+    #[test]
+    fn the_first_origin_is_kept() {
+        let options = DownloadOptions::new()
+            .scoped("https://example.com/files.meta4")
+            .scoped("https://mirror.example.org/file");
+        assert!(!options.sends_credentials_to("https://mirror.example.org/file"));
+        let options = DownloadOptions::new().scoped("oci://registry.example.com/image");
+        assert!(options.origin.is_none());
+    }
+
+    #[test]
+    fn shared_headers_are_limited_like_the_downloads_own() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("secret"));
+        let options = DownloadOptions::new().scoped("https://example.com/file");
+        let client = reqwest::Client::new();
+        let sent = |url: &str| {
+            let request = options.shared_headers(url, &headers, client.get(url));
+            let request = request.build().unwrap();
+            request.headers().get("x-api-key").cloned()
+        };
+        assert_eq!(sent("https://example.com/other").unwrap(), "secret");
+        assert!(sent("https://mirror.example.org/file").is_none());
+    }
+}
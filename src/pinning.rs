@@ -0,0 +1,216 @@
+use crate::error::DownloadError;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{tls::TlsInfo, Response};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, CertificateError, ServerName,
+};
+use sha2::{Digest, Sha256};
+use std::{fmt, sync::Arc, time::SystemTime};
+
+/// The SHA-256 hashes of the public keys that servers have to present.
+pub(crate) struct CertificatePins(Vec<[u8; 32]>);
+
+impl CertificatePins {
+    /// Parses pins in the `sha256//<base64>` format of curl's `--pinnedpubkey`, or just the base64
+    /// encoded hash.
+    pub fn parse(pins: &[String]) -> Result<Self, DownloadError> {
+        pins.iter()
+            .map(|pin| {
+                let encoded = pin.trim().trim_start_matches("sha256//");
+                STANDARD
+                    .decode(encoded)
+                    .ok()
+                    .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+                    .ok_or_else(|| DownloadError::InvalidPin(pin.clone()))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// Fails unless `response` came over TLS from a server whose certificate has a pinned key.
+    ///
+    /// Connections of the [`Downloader`](crate::Downloader)'s own client are already refused
+    /// during the handshake, so this catches plain HTTP responses and those of a client it was
+    /// given.
+    pub fn check(&self, response: &Response) -> Result<(), DownloadError> {
+        let pinned = response
+            .extensions()
+            .get::<TlsInfo>()
+            .and_then(TlsInfo::peer_certificate)
+            .is_some_and(|certificate| self.is_pinned(certificate));
+        match pinned {
+            true => Ok(()),
+            false => Err(DownloadError::CertificateNotPinned {
+                url: response.url().to_string(),
+            }),
+        }
+    }
+
+    /// Checks whether the DER encoded `certificate` has a pinned key.
+    fn is_pinned(&self, certificate: &[u8]) -> bool {
+        subject_public_key_info(certificate)
+            .is_some_and(|key| self.0.contains(&Sha256::digest(key).into()))
+    }
+}
+
+/// Verifies the certificates of servers during the TLS handshake, refusing those without a pinned
+/// key before a request is sent to them.
+pub(crate) struct PinVerifier {
+    /// Verifies the chain and the host name, unless invalid certificates are accepted.
+    pub chain: Option<WebPkiVerifier>,
+    pub pins: Arc<CertificatePins>,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(chain) = &self.chain {
+            chain.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+        match self.pins.is_pinned(&end_entity.0) {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                Arc::new(NotPinned),
+            ))),
+        }
+    }
+}
+
+/// The error handshakes with servers whose certificate has no pinned key fail with.
+#[derive(Debug)]
+pub(crate) struct NotPinned;
+
+impl fmt::Display for NotPinned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the certificate doesn't have a pinned key")
+    }
+}
+
+impl std::error::Error for NotPinned {}
+
+/// Checks whether `error` is a handshake refused by a [`PinVerifier`].
+pub(crate) fn is_not_pinned(error: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(
+        error.downcast_ref::<rustls::Error>(),
+        Some(rustls::Error::InvalidCertificate(CertificateError::Other(other)))
+            if other.is::<NotPinned>()
+    )
+}
+
+/// Returns the DER encoded `SubjectPublicKeyInfo` of a DER encoded X.509 certificate.
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    let (certificate, _) = split_element(certificate)?;
+    let (tbs_certificate, _) = split_element(contents(certificate)?)?;
+    let mut fields = contents(tbs_certificate)?;
+    // The version is optional and explicitly tagged with [0].
+    if fields.first() == Some(&0xa0) {
+        fields = split_element(fields)?.1;
+    }
+    // The serial number, signature algorithm, issuer, validity and subject come first.
+    for _ in 0..5 {
+        fields = split_element(fields)?.1;
+    }
+    Some(split_element(fields)?.0)
+}
+
+/// Splits the first DER element off `der`, returning it with its header and what follows it.
+fn split_element(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (header_len, contents_len) = header(der)?;
+    let len = header_len.checked_add(contents_len)?;
+    (len <= der.len()).then(|| der.split_at(len))
+}
+
+/// Returns the contents of a DER element, without its header.
+fn contents(element: &[u8]) -> Option<&[u8]> {
+    element.get(header(element)?.0..)
+}
+
+/// Returns the length of the tag and length bytes of a DER element and the length of its contents.
+fn header(der: &[u8]) -> Option<(usize, usize)> {
+    let &len = der.get(1)?;
+    if len < 0x80 {
+        return Some((2, usize::from(len)));
+    }
+    // Long lengths are stored in the next `len & 0x7f` bytes.
+    let count = usize::from(len & 0x7f);
+    if count == 0 || count > std::mem::size_of::<u32>() {
+        return None;
+    }
+    let contents_len = der
+        .get(2..2 + count)?
+        .iter()
+        .fold(0usize, |len, &byte| len << 8 | usize::from(byte));
+    Some((2 + count, contents_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A self-signed certificate for `example.com` with a P-256 key.
+    const CERTIFICATE: &str = "\
+        MIIBgTCCASegAwIBAgIUCzJLqHI/cYSeA4nQkyhZiivOM5MwCgYIKoZIzj0EAwIw\
+        FjEUMBIGA1UEAwwLZXhhbXBsZS5jb20wHhcNMjYxMDE0MTYyNjIxWhcNMzYxMDEx\
+        MTYyNjIxWjAWMRQwEgYDVQQDDAtleGFtcGxlLmNvbTBZMBMGByqGSM49AgEGCCqG\
+        SM49AwEHA0IABH9VsiMlDzutR8Lk3XKR6exStjJxwqwKK5t6Dszhp+9tjLYMNWHw\
+        xyiQl7fiM1hDOMn7dwtVw0HwsmcC8ZvLC5WjUzBRMB0GA1UdDgQWBBSbNHITcGnh\
+        nxoQCMomkYBf6Mn1VDAfBgNVHSMEGDAWgBSbNHITcGnhnxoQCMomkYBf6Mn1VDAP\
+        BgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIQDeoKeFPpTb3a+3bqAU\
+        CpioCTEUFKCcHegSC6zisxYlrgIgFRi1W1sJl2fOuwzhWjOVag2Oy6IqU93bmm/p\
+        FmemI7E=";
+
+    /// The pin of the key of [`CERTIFICATE`], as `openssl` computes it.
+    const PIN: &str = "sha256//kxPXQf1kAFx80yGPhiXpYGqOvpm9z7uRNO3WSNzHzUk=";
+
+    #[test]
+    fn parses_pins() {
+        let pins = CertificatePins::parse(&[PIN.to_owned()]).unwrap();
+        assert_eq!(pins.0.len(), 1);
+        assert!(CertificatePins::parse(&[
+            " kxPXQf1kAFx80yGPhiXpYGqOvpm9z7uRNO3WSNzHzUk= ".to_owned()
+        ])
+        .is_ok());
+        for invalid in ["sha256//not base64", "c2hvcnQ="] {
+            assert!(matches!(
+                CertificatePins::parse(&[invalid.to_owned()]),
+                Err(DownloadError::InvalidPin(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn checks_the_key_of_certificates() {
+        let certificate = STANDARD.decode(CERTIFICATE).unwrap();
+        let pins = CertificatePins::parse(&[PIN.to_owned()]).unwrap();
+        assert!(pins.is_pinned(&certificate));
+        let other = CertificatePins::parse(&[STANDARD.encode([0; 32])]).unwrap();
+        assert!(!other.is_pinned(&certificate));
+        assert!(!pins.is_pinned(&certificate[..100]));
+        assert!(!pins.is_pinned(&[]));
+    }
+
+    #[test]
+    fn recognizes_refused_handshakes() {
+        let refused =
+            rustls::Error::InvalidCertificate(CertificateError::Other(Arc::new(NotPinned)));
+        assert!(is_not_pinned(&refused));
+        let invalid = rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer);
+        assert!(!is_not_pinned(&invalid));
+        assert!(!is_not_pinned(&std::io::Error::other("connection reset")));
+    }
+}
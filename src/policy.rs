@@ -0,0 +1,363 @@
+use reqwest::redirect::Policy;
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use url::{Host, Url};
+
+/// Which URLs may be requested, for services that download URLs their users provide and must not
+/// be turned against hosts of their own network.
+///
+/// The policy is checked for the URL of every download, its mirrors and the URLs found while
+/// downloading it, like those of the segments of a stream, and for every redirect. Host names are
+/// checked again when they're resolved, so one that resolves to a private address is refused as
+/// well. Downloads through a proxy leave resolving to it, so only their URLs are checked.
+#[derive(Debug, Clone, Default)]
+pub struct UrlPolicy {
+    allowed_hosts: Vec<String>,
+    blocked_hosts: Vec<String>,
+    allowed_schemes: Vec<String>,
+    block_private_ips: bool,
+}
+
+impl UrlPolicy {
+    /// Allows every URL, until hosts or schemes are restricted.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only allows requests to `host` and the other allowed hosts. `*.example.com` allows every
+    /// subdomain of `example.com`.
+    pub fn allow_host(mut self, host: &str) -> Self {
+        self.allowed_hosts.push(normalize(host));
+        self
+    }
+
+    /// Refuses requests to `host`, even if it's allowed. `*.example.com` refuses every subdomain
+    /// of `example.com`.
+    pub fn block_host(mut self, host: &str) -> Self {
+        self.blocked_hosts.push(normalize(host));
+        self
+    }
+
+    /// Only allows URLs with the scheme `scheme` and the other allowed schemes. URLs that are
+    /// downloaded over HTTPS, like those of the `oci://` and `s3://` schemes, need `https` as well.
+    pub fn allow_scheme(mut self, scheme: &str) -> Self {
+        self.allowed_schemes.push(scheme.to_ascii_lowercase());
+        self
+    }
+
+    /// Refuses requests to loopback, private, link-local and other addresses that aren't
+    /// reachable on the internet, like the metadata service of a cloud provider at
+    /// `169.254.169.254`.
+    pub fn block_private_ips(mut self) -> Self {
+        self.block_private_ips = true;
+        self
+    }
+
+    /// Checks whether hosts have to be checked when they're resolved.
+    pub(crate) fn checks_hosts(&self) -> bool {
+        self.block_private_ips || !self.allowed_hosts.is_empty() || !self.blocked_hosts.is_empty()
+    }
+
+    /// Returns why `url` may not be requested, if it may not.
+    ///
+    /// URLs that don't parse are refused, and so are `file://` and `data:` URLs and others without
+    /// a host if hosts are restricted, since they'd bypass the checks of the host.
+    pub(crate) fn check(&self, url: &str) -> Result<(), Blocked> {
+        let blocked = |reason: String| Blocked {
+            url: url.to_owned(),
+            reason,
+        };
+        let Ok(parsed) = Url::parse(url) else {
+            return Err(blocked("it isn't a valid URL".to_owned()));
+        };
+        let scheme = parsed.scheme();
+        if !self.allowed_schemes.is_empty() && !self.allowed_schemes.iter().any(|s| s == scheme) {
+            return Err(blocked(format!("the scheme `{}` isn't allowed", scheme)));
+        }
+        if self.checks_hosts() && matches!(scheme, "file" | "data") {
+            return Err(blocked(format!(
+                "`{}` URLs aren't allowed while hosts are restricted",
+                scheme
+            )));
+        }
+        match parsed.host() {
+            Some(Host::Domain(host)) => self.check_name(host).map_err(blocked),
+            Some(Host::Ipv4(ip)) => self.check_ip(ip.into()).map_err(blocked),
+            Some(Host::Ipv6(ip)) => self.check_ip(ip.into()).map_err(blocked),
+            None if self.checks_hosts() => Err(blocked("it has no host".to_owned())),
+            None => Ok(()),
+        }
+    }
+
+    fn check_name(&self, host: &str) -> Result<(), String> {
+        let host = normalize(host);
+        if self
+            .blocked_hosts
+            .iter()
+            .any(|pattern| matches(pattern, &host))
+        {
+            return Err(format!("the host {} is blocked", host));
+        }
+        if !self.allowed_hosts.is_empty()
+            && !self
+                .allowed_hosts
+                .iter()
+                .any(|pattern| matches(pattern, &host))
+        {
+            return Err(format!("the host {} isn't allowed", host));
+        }
+        Ok(())
+    }
+
+    fn check_ip(&self, ip: IpAddr) -> Result<(), String> {
+        self.check_name(&ip.to_string())?;
+        if self.block_private_ips && is_private(ip) {
+            return Err(format!("{} is a private address", ip));
+        }
+        Ok(())
+    }
+
+    /// Checks the host `host` before it's resolved, and drops the private addresses it
+    /// resolved to from `addrs`, failing if there are none left.
+    pub(crate) fn check_resolved(
+        &self,
+        host: &str,
+        addrs: Vec<SocketAddr>,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let blocked = |reason| {
+            let url = host.to_owned();
+            io::Error::new(io::ErrorKind::PermissionDenied, Blocked { url, reason })
+        };
+        self.check_name(host).map_err(blocked)?;
+        if !self.block_private_ips || addrs.is_empty() {
+            return Ok(addrs);
+        }
+        let public: Vec<_> = addrs
+            .into_iter()
+            .filter(|addr| !is_private(addr.ip()))
+            .collect();
+        if public.is_empty() {
+            return Err(blocked("it only resolves to private addresses".to_owned()));
+        }
+        Ok(public)
+    }
+
+    /// Refuses the redirects this policy doesn't allow, and leaves the others to `redirects`.
+    pub(crate) fn redirects(self: Arc<Self>, redirects: Policy) -> Policy {
+        Policy::custom(move |attempt| match self.check(attempt.url().as_str()) {
+            Ok(()) => redirects.redirect(attempt),
+            Err(blocked) => attempt.error(blocked),
+        })
+    }
+}
+
+/// Lowercases a host pattern and removes the brackets around IPv6 addresses and a trailing dot.
+fn normalize(host: &str) -> String {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .trim_end_matches('.')
+        .to_ascii_lowercase()
+}
+
+/// Checks whether the normalized `host` matches `pattern`.
+fn matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+/// Checks whether `ip` isn't reachable on the internet, or shouldn't be requested from it.
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // "This network" and shared address space for carrier-grade NAT.
+                || first == 0
+                || (first == 100 && second & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(embedded) = embedded_ipv4(ip) {
+                return is_private(embedded.into());
+            }
+            let segments = ip.segments();
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local and link-local addresses.
+                || segments[0] & 0xfe00 == 0xfc00
+                || segments[0] & 0xffc0 == 0xfe80
+                // Local-use NAT64 prefix.
+                || segments[..3] == [0x64, 0xff9b, 1]
+        }
+    }
+}
+
+/// Returns the IPv4 address an IPv6 address reaches: IPv4-mapped `::ffff:a.b.c.d`,
+/// IPv4-compatible `::a.b.c.d`, NAT64 `64:ff9b::a.b.c.d` and 6to4 `2002:aabb:ccdd::`.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let from = |high: u16, low: u16| {
+        let [a, b] = high.to_be_bytes();
+        let [c, d] = low.to_be_bytes();
+        Ipv4Addr::new(a, b, c, d)
+    };
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return Some(ip);
+    }
+    match segments {
+        // `::` and `::1` are the unspecified and loopback addresses, not IPv4 ones.
+        [0, 0, 0, 0, 0, 0, 0, 0 | 1] => None,
+        [0, 0, 0, 0, 0, 0, high, low] => Some(from(high, low)),
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(from(high, low)),
+        [0x2002, high, low, ..] => Some(from(high, low)),
+        _ => None,
+    }
+}
+
+/// The error a request the [`UrlPolicy`] doesn't allow fails with.
+#[derive(Debug)]
+pub(crate) struct Blocked {
+    pub url: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "refused request to {}: {}", self.url, self.reason)
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn private(ip: &str) -> bool {
+        is_private(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn private_ipv4() {
+        for ip in [
+            "10.1.2.3",
+            "127.0.0.1",
+            "169.254.169.254",
+            "172.16.0.1",
+            "192.168.1.1",
+            "0.0.0.0",
+            "100.64.0.1",
+            "255.255.255.255",
+            "224.0.0.1",
+        ] {
+            assert!(private(ip), "{}", ip);
+        }
+        assert!(!private("93.184.216.34"));
+        assert!(!private("100.128.0.1"));
+    }
+
+    #[test]
+    fn private_ipv6() {
+        for ip in [
+            "::1",
+            "::",
+            "fc00::1",
+            "fd12::1",
+            "fe80::1",
+            "ff02::1",
+            "64:ff9b:1::1",
+        ] {
+            assert!(private(ip), "{}", ip);
+        }
+        assert!(!private("2606:4700::1111"));
+    }
+
+    #[test]
+    fn private_ipv4_in_ipv6() {
+        for ip in [
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+        ] {
+            assert!(private(ip), "{}", ip);
+        }
+        assert!(!private("::ffff:93.184.216.34"));
+        assert!(!private("64:ff9b::93.184.216.34"));
+        assert!(!private("2002:5db8:d822::1"));
+    }
+
+    #[test]
+    fn checks_hosts_and_schemes() {
+        let policy = UrlPolicy::new()
+            .allow_host("*.example.com")
+            .block_host("bad.example.com")
+            .allow_scheme("https");
+        assert!(policy.check("https://cdn.example.com/a").is_ok());
+        assert!(policy.check("https://CDN.Example.com./a").is_ok());
+        assert!(policy.check("https://example.com/a").is_err());
+        assert!(policy.check("https://bad.example.com/a").is_err());
+        assert!(policy.check("https://evilexample.com/a").is_err());
+        assert!(policy.check("http://cdn.example.com/a").is_err());
+    }
+
+    #[test]
+    fn blocks_private_addresses() {
+        let policy = UrlPolicy::new().block_private_ips();
+        assert!(policy.check("http://93.184.216.34/").is_ok());
+        assert!(policy.check("http://example.com/").is_ok());
+        for url in [
+            "http://127.0.0.1:8080/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]/",
+            "http://[::ffff:10.0.0.1]/",
+            "http://[64:ff9b::7f00:1]/",
+            "http://[2002:a9fe:a9fe::]/",
+        ] {
+            assert!(policy.check(url).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn refuses_urls_without_hosts() {
+        let policy = UrlPolicy::new().block_private_ips();
+        assert!(policy.check("file:///etc/passwd").is_err());
+        assert!(policy.check("data:text/plain,hi").is_err());
+        assert!(policy.check("not a url").is_err());
+        assert!(UrlPolicy::new().check("not a url").is_err());
+        assert!(UrlPolicy::new().check("file:///etc/passwd").is_ok());
+        assert!(UrlPolicy::new()
+            .allow_host("example.com")
+            .check("file:///etc/passwd")
+            .is_err());
+    }
+
+    #[test]
+    fn resolved_addresses() {
+        let policy = UrlPolicy::new().block_private_ips();
+        let addr = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 443);
+        let public = policy
+            .check_resolved("example.com", vec![addr("10.0.0.1"), addr("93.184.216.34")])
+            .unwrap();
+        assert_eq!(public, vec![addr("93.184.216.34")]);
+        let error = policy
+            .check_resolved("internal", vec![addr("127.0.0.1")])
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
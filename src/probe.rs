@@ -0,0 +1,310 @@
+use crate::{
+    download::{self, Downloader},
+    error::DownloadError,
+    filename,
+    mirrors::Mirrors,
+    options::DownloadOptions,
+};
+use futures::{stream, StreamExt};
+use reqwest::{
+    header::{
+        HeaderMap, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, LAST_MODIFIED,
+    },
+    Response, StatusCode,
+};
+use std::{collections::HashSet, path::PathBuf};
+
+/// What the server revealed about a file before downloading it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Probe {
+    pub content_length: Option<u64>,
+    /// `None` if the server didn't say whether it supports range requests.
+    pub accept_ranges: Option<bool>,
+    pub filename: Option<String>,
+    /// The URL of the probed response, after following redirects.
+    pub final_url: Option<String>,
+    pub status: Option<StatusCode>,
+    pub headers: HeaderMap,
+    /// A strong `ETag` or the `Last-Modified` date, for detecting that the file changed.
+    pub validator: Option<String>,
+    /// How the server compresses the file when asked to.
+    #[cfg(feature = "decompression")]
+    pub content_encoding: Option<String>,
+}
+
+impl Probe {
+    /// Creates a probe for a file of known size on a server that supports range requests.
+    pub fn with_ranges(content_length: u64) -> Self {
+        Self {
+            content_length: Some(content_length),
+            accept_ranges: Some(true),
+            ..Self::default()
+        }
+    }
+
+    /// Reads the response to a `HEAD` request.
+    pub fn from_head(response: &Response) -> Self {
+        let headers = response.headers();
+        let content_length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+
+        let accept_ranges = headers
+            .get(ACCEPT_RANGES)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| !v.trim().eq_ignore_ascii_case("none"));
+
+        Self {
+            content_length,
+            accept_ranges,
+            filename: filename::from_headers(headers),
+            final_url: Some(response.url().to_string()),
+            status: Some(response.status()),
+            headers: headers.clone(),
+            validator: validator(headers),
+            #[cfg(feature = "decompression")]
+            content_encoding: crate::decompress::content_encoding(headers),
+        }
+    }
+
+    /// Reads the response to a `GET` request for `bytes=0-0`.
+    pub fn from_range_probe(response: &Response) -> Self {
+        let headers = response.headers();
+        if response.status() == StatusCode::PARTIAL_CONTENT {
+            let total = headers
+                .get(CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_content_range)
+                .and_then(|range| range.total);
+
+            Self {
+                content_length: total,
+                accept_ranges: Some(total.is_some()),
+                filename: filename::from_headers(headers),
+                final_url: Some(response.url().to_string()),
+                status: Some(response.status()),
+                headers: headers.clone(),
+                validator: validator(headers),
+                #[cfg(feature = "decompression")]
+                content_encoding: None,
+            }
+        } else {
+            Self {
+                accept_ranges: Some(false),
+                ..Self::from_head(response)
+            }
+        }
+    }
+
+    /// Checks whether the probe is enough to pick a download strategy.
+    pub fn is_conclusive(&self) -> bool {
+        match self.accept_ranges {
+            Some(true) => self.content_length.is_some(),
+            Some(false) => true,
+            None => false,
+        }
+    }
+
+    /// Checks whether the file can be downloaded in parallel.
+    pub fn supports_ranges(&self) -> bool {
+        // Ranges would be requested uncompressed, missing out on the compression.
+        #[cfg(feature = "decompression")]
+        if self.content_encoding.is_some() {
+            return false;
+        }
+        self.accept_ranges == Some(true) && self.content_length.is_some_and(|len| len > 0)
+    }
+}
+
+/// What the server says about a file, from [`Downloader::probe`].
+#[derive(Debug, Clone)]
+pub struct RemoteFile {
+    pub url: String,
+    /// The URL after following redirects.
+    pub final_url: String,
+    /// `None` if the server didn't say.
+    pub size: Option<u64>,
+    /// Whether the file would be downloaded in parallel, because the server supports range
+    /// requests and said how big it is.
+    pub supports_ranges: bool,
+    pub content_type: Option<String>,
+    /// Where the file would be saved.
+    pub path: PathBuf,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub headers: HeaderMap,
+}
+
+/// The files of a batch and how big they are together, from [`Downloader::estimate`].
+#[derive(Debug, Default)]
+pub struct BatchEstimate {
+    /// Every URL once, in the order they were first listed, or why it couldn't be probed.
+    pub files: Vec<Result<RemoteFile, DownloadError>>,
+    /// The sum of the sizes that are known.
+    pub total_bytes: u64,
+    /// How many files have no known size, counting those that couldn't be probed.
+    pub unknown_sizes: usize,
+}
+
+impl Downloader {
+    /// Asks the server about the file at `url` without downloading it. See
+    /// [`Downloader::probe_with`].
+    pub async fn probe(&self, url: &str) -> Result<RemoteFile, DownloadError> {
+        self.probe_with(url, &DownloadOptions::default()).await
+    }
+
+    /// Asks the server about the file at `url`, or its first mirror that responds, the way a
+    /// download would before fetching it: with a `HEAD` request, and a request for the first byte
+    /// if that doesn't tell enough.
+    ///
+    /// The path is where a download with the same `options` would save the file now. Only
+    /// `http://` and `https://` URLs can be probed.
+    pub async fn probe_with(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<RemoteFile, DownloadError> {
+        let mirrors = Mirrors::new(url, &options.mirrors);
+        for url in mirrors.urls() {
+            download::check_scheme(url)?;
+        }
+        let probe = self.probe_mirrors(&mirrors, options).await?;
+
+        let filename = options.filename.as_deref().or(probe.filename.as_deref());
+        let name = self.output_name(url, filename, &probe.headers, options);
+        let header = |name| {
+            probe
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        Ok(RemoteFile {
+            url: url.to_owned(),
+            final_url: probe.final_url.clone().unwrap_or_else(|| url.to_owned()),
+            size: probe.content_length,
+            supports_ranges: probe.supports_ranges(),
+            content_type: header(CONTENT_TYPE),
+            path: self.get_output_path(&name, options),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            headers: probe.headers.clone(),
+        })
+    }
+
+    /// Probes all `urls`, running up to `max_concurrent_files` probes at once, and adds up their
+    /// sizes, for example to check that a batch fits a quota before downloading it.
+    ///
+    /// URLs that are listed more than once count once, like [`Downloader::download_multiple`]
+    /// downloads them once.
+    pub async fn estimate(&self, urls: &[String]) -> BatchEstimate {
+        let mut seen = HashSet::new();
+        let unique = urls.iter().filter(|url| seen.insert(url.as_str()));
+        let files: Vec<_> = stream::iter(unique)
+            .map(|url| self.probe(url))
+            .buffered(self.max_concurrent_files)
+            .collect()
+            .await;
+
+        let sizes = files
+            .iter()
+            .map(|file| file.as_ref().ok().and_then(|file| file.size));
+        BatchEstimate {
+            total_bytes: sizes.clone().flatten().sum(),
+            unknown_sizes: sizes.filter(Option::is_none).count(),
+            files,
+        }
+    }
+}
+
+/// Picks the value to send in `If-Range`. Weak `ETag`s can't be used there, so they are skipped.
+pub(crate) fn validator(headers: &HeaderMap) -> Option<String> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    header(ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header(LAST_MODIFIED))
+        .map(str::to_owned)
+}
+
+/// A parsed `Content-Range: bytes <start>-<end>/<total>` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ContentRange {
+    pub start: u64,
+    pub end: u64,
+    /// `None` if the server sent `*` as the complete length.
+    pub total: Option<u64>,
+}
+
+/// Parses a `Content-Range` header value, refusing ranges that end before they start or after the
+/// end of the file.
+pub(crate) fn parse_content_range(value: &str) -> Option<ContentRange> {
+    let value = value.trim().strip_prefix("bytes")?.trim_start();
+    let (range, total) = value.split_once('/')?;
+    let (start, end) = range.trim().split_once('-')?;
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+
+    let range = ContentRange {
+        start: start.trim().parse().ok()?,
+        end: end.trim().parse().ok()?,
+        total,
+    };
+    let valid = range.start <= range.end && total.is_none_or(|total| range.end < total);
+    valid.then_some(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_content_ranges() {
+        assert_eq!(
+            parse_content_range("bytes 0-0/1234"),
+            Some(ContentRange {
+                start: 0,
+                end: 0,
+                total: Some(1234)
+            })
+        );
+        assert_eq!(
+            parse_content_range(" bytes 100-199/*"),
+            Some(ContentRange {
+                start: 100,
+                end: 199,
+                total: None
+            })
+        );
+        assert_eq!(
+            parse_content_range("bytes  5 - 9 / 10"),
+            Some(ContentRange {
+                start: 5,
+                end: 9,
+                total: Some(10)
+            })
+        );
+    }
+
+    #[test]
+    fn refuses_invalid_content_ranges() {
+        for value in [
+            "",
+            "bytes",
+            "bytes */1234",
+            "items 0-1/2",
+            "bytes 0-1",
+            "bytes 0-/10",
+            "bytes -1/10",
+            "bytes a-b/10",
+            "bytes 0-1/x",
+            "bytes 5-3/10",
+            "bytes 0-10/10",
+            "bytes 0-18446744073709551616/*",
+        ] {
+            assert_eq!(parse_content_range(value), None, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+/// Weight of the most recent sample in the smoothed download speed.
+const SPEED_SMOOTHING: f64 = 0.3;
+
+/// A snapshot of a running download.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// Bytes of the file that are on disk, including ones from a resumed run.
+    pub downloaded: u64,
+    /// Size of the file, if the server reported it.
+    pub total: Option<u64>,
+    /// Progress of every connection. Sequential downloads have a single entry.
+    pub chunks: Vec<ChunkProgress>,
+    /// Current download speed in bytes per second.
+    pub speed: f64,
+    /// Estimated time until the download finishes.
+    pub eta: Option<Duration>,
+}
+
+/// Progress of a single connection.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkProgress {
+    pub downloaded: u64,
+    pub total: Option<u64>,
+}
+
+/// Receives progress updates while a download is running.
+pub trait ProgressReporter: Send + Sync {
+    fn report(&self, url: &str, progress: &Progress);
+}
+
+impl<F> ProgressReporter for F
+where
+    F: Fn(&str, &Progress) + Send + Sync,
+{
+    fn report(&self, url: &str, progress: &Progress) {
+        self(url, progress)
+    }
+}
+
+/// A snapshot of a batch of downloads, like the files of [`Downloader::download_multiple`](crate::Downloader::download_multiple).
+#[derive(Debug, Clone)]
+pub struct BatchProgress {
+    /// Bytes on disk of the files that are running or completed.
+    pub downloaded: u64,
+    /// Combined size of the files that haven't failed, once the size of all of them is known.
+    pub total: Option<u64>,
+    pub completed: usize,
+    pub failed: usize,
+    /// Files whose download hasn't started yet.
+    pub pending: usize,
+    /// Progress of every file that is being downloaded.
+    pub running: Vec<FileProgress>,
+    /// Combined download speed in bytes per second.
+    pub speed: f64,
+    /// Estimated time until all files are downloaded.
+    pub eta: Option<Duration>,
+}
+
+/// Progress of a single file of a batch.
+#[derive(Debug, Clone)]
+pub struct FileProgress {
+    pub url: String,
+    pub progress: Progress,
+}
+
+/// Receives progress updates while a batch of downloads is running.
+pub trait BatchProgressReporter: Send + Sync {
+    fn report(&self, progress: &BatchProgress);
+}
+
+impl<F> BatchProgressReporter for F
+where
+    F: Fn(&BatchProgress) + Send + Sync,
+{
+    fn report(&self, progress: &BatchProgress) {
+        self(progress)
+    }
+}
+
+/// Turns byte counts sampled over time into [`Progress`] snapshots.
+pub(crate) struct SpeedMeter {
+    last_sample: Instant,
+    last_downloaded: u64,
+    speed: f64,
+}
+
+impl SpeedMeter {
+    pub fn new(downloaded: u64) -> Self {
+        Self {
+            last_sample: Instant::now(),
+            last_downloaded: downloaded,
+            speed: 0.0,
+        }
+    }
+
+    pub fn sample(&mut self, chunks: Vec<ChunkProgress>, total: Option<u64>) -> Progress {
+        let now = Instant::now();
+        let downloaded: u64 = chunks.iter().map(|c| c.downloaded).sum();
+        let elapsed = now.duration_since(self.last_sample).as_secs_f64();
+
+        if elapsed > 0.0 {
+            let current = downloaded.saturating_sub(self.last_downloaded) as f64 / elapsed;
+            self.speed = if self.speed == 0.0 {
+                current
+            } else {
+                SPEED_SMOOTHING * current + (1.0 - SPEED_SMOOTHING) * self.speed
+            };
+            self.last_sample = now;
+            self.last_downloaded = downloaded;
+        }
+
+        let eta = match total {
+            Some(total) if self.speed > 0.0 => Some(Duration::from_secs_f64(
+                total.saturating_sub(downloaded) as f64 / self.speed,
+            )),
+            _ => None,
+        };
+
+        Progress {
+            downloaded,
+            total,
+            chunks,
+            speed: self.speed,
+            eta,
+        }
+    }
+}
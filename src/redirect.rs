@@ -0,0 +1,59 @@
+use reqwest::redirect::Policy;
+use std::fmt;
+
+/// How many redirects a request follows, unless configured otherwise.
+pub(crate) const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// What happens when a redirect leads to another origin, like from a file host to a pre-signed
+/// storage URL.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CrossOriginRedirects {
+    /// Follows the redirect without the `Authorization`, `Cookie` and `Proxy-Authorization`
+    /// headers. Redirects that only change the scheme would keep them, so they aren't followed.
+    #[default]
+    StripCredentials,
+    /// Fails the download with [`DownloadError::CrossOriginRedirect`](crate::DownloadError::CrossOriginRedirect).
+    Deny,
+}
+
+/// Follows up to `max` redirects, refusing the cross-origin ones `cross_origin` doesn't allow.
+///
+/// reqwest removes the credentials whenever the host or port changes.
+pub(crate) fn policy(max: usize, cross_origin: CrossOriginRedirects) -> Policy {
+    Policy::custom(move |attempt| {
+        let Some(previous) = attempt.previous().last() else {
+            return attempt.follow();
+        };
+        let next = attempt.url();
+        let same_host = next.host_str() == previous.host_str()
+            && next.port_or_known_default() == previous.port_or_known_default();
+        let refused = match cross_origin {
+            CrossOriginRedirects::StripCredentials => {
+                same_host && next.scheme() != previous.scheme()
+            }
+            CrossOriginRedirects::Deny => next.origin() != previous.origin(),
+        };
+        if refused {
+            let location = next.to_string();
+            attempt.error(Refused { location })
+        } else if attempt.previous().len() > max {
+            attempt.error("too many redirects")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// The error a redirect to another origin fails with, see [`policy`].
+#[derive(Debug)]
+pub(crate) struct Refused {
+    pub location: String,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "refused redirect to {}", self.location)
+    }
+}
+
+impl std::error::Error for Refused {}
@@ -0,0 +1,144 @@
+use crate::checksum::{Checksum, ChecksumSource};
+use reqwest::{header::HeaderMap, StatusCode};
+use serde_json::json;
+use std::{fmt::Write, path::PathBuf, time::Duration};
+
+/// Details about a finished download.
+#[derive(Debug, Clone)]
+pub struct DownloadReport {
+    /// Where the file was saved.
+    pub path: PathBuf,
+    /// The URL that was requested.
+    pub url: String,
+    /// The URL the file was served from, after following redirects.
+    pub final_url: String,
+    /// Status of the response that described the file.
+    pub status: Option<StatusCode>,
+    /// Headers of the response that described the file.
+    pub headers: HeaderMap,
+    /// Size of the file on disk.
+    pub size: u64,
+    /// Bytes transferred by this run. Less than `size` if the download was resumed.
+    pub bytes_downloaded: u64,
+    pub elapsed: Duration,
+    /// Number of failed attempts that were retried.
+    pub retries: u32,
+    /// The checksum the file was verified against, if it was.
+    pub checksum: Option<Checksum>,
+    /// Where [`checksum`](Self::checksum) came from.
+    pub checksum_source: Option<ChecksumSource>,
+    /// Directory the file was extracted into, if it's an archive and archives are extracted.
+    /// `path` no longer exists if the archive was removed afterwards.
+    pub extracted_to: Option<PathBuf>,
+    /// Timing of every chunk of a parallel download. Empty for sequential downloads.
+    pub chunks: Vec<ChunkReport>,
+}
+
+impl DownloadReport {
+    /// Returns the average speed in bytes per second.
+    pub fn average_speed(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes_downloaded as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Details about a single chunk of a parallel download.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkReport {
+    pub start: u64,
+    pub end: u64,
+    /// Bytes written for this chunk by this run.
+    pub bytes_downloaded: u64,
+    pub elapsed: Duration,
+    pub retries: u32,
+}
+
+/// The outcome of every file of a batch, for audit logs. See
+/// [`DownloaderBuilder::batch_report`](crate::DownloaderBuilder::batch_report).
+#[derive(Debug, Clone, Default)]
+pub struct BatchReport {
+    pub files: Vec<BatchFileReport>,
+}
+
+/// The outcome of one file of a batch.
+#[derive(Debug, Clone)]
+pub struct BatchFileReport {
+    pub url: String,
+    /// Where the file was saved, if it was.
+    pub path: Option<PathBuf>,
+    pub size: Option<u64>,
+    /// The checksum the file was verified against, if it was.
+    pub checksum: Option<Checksum>,
+    pub elapsed: Duration,
+    /// Number of times the file, or a chunk of it, was requested.
+    pub attempts: u32,
+    /// Why the download failed, if it did.
+    pub error: Option<String>,
+}
+
+impl BatchReport {
+    /// Formats the report as a JSON array of objects with the fields `url`, `path`, `size`,
+    /// `checksum`, `duration_secs`, `attempts` and `error`, where `checksum` is an object with
+    /// the `algorithm` and the `expected` digest.
+    pub fn to_json(&self) -> String {
+        let files: Vec<_> = self
+            .files
+            .iter()
+            .map(|file| {
+                json!({
+                    "url": file.url,
+                    "path": file.path.as_ref().map(|path| path.to_string_lossy()),
+                    "size": file.size,
+                    "checksum": file.checksum.as_ref().map(|checksum| json!({
+                        "algorithm": checksum.algorithm.name(),
+                        "expected": checksum.expected,
+                    })),
+                    "duration_secs": file.elapsed.as_secs_f64(),
+                    "attempts": file.attempts,
+                    "error": file.error,
+                })
+            })
+            .collect();
+        serde_json::to_string_pretty(&files).expect("reports serialize to JSON")
+    }
+
+    /// Formats the report as CSV with a header row and the columns `url`, `path`, `size`,
+    /// `checksum`, `duration_secs`, `attempts` and `error`. Checksums are written as
+    /// `<algorithm>:<digest>`, and fields without a value are left empty.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("url,path,size,checksum,duration_secs,attempts,error\n");
+        for file in &self.files {
+            let fields = [
+                file.url.clone(),
+                file.path
+                    .as_ref()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                file.size.map(|size| size.to_string()).unwrap_or_default(),
+                file.checksum
+                    .as_ref()
+                    .map(|checksum| format!("{}:{}", checksum.algorithm.name(), checksum.expected))
+                    .unwrap_or_default(),
+                format!("{:.3}", file.elapsed.as_secs_f64()),
+                file.attempts.to_string(),
+                file.error.clone().unwrap_or_default(),
+            ];
+            let row: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+            let _ = writeln!(csv, "{}", row.join(","));
+        }
+        csv
+    }
+}
+
+/// Quotes `field` if it contains a separator, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
@@ -0,0 +1,61 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// The output paths that running downloads picked, shared by all downloads of a
+/// [`Downloader`](crate::Downloader), so two downloads of files with the same name never pick the
+/// same free path before either has created its file.
+#[derive(Default)]
+pub(crate) struct Reservations {
+    paths: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Reservations {
+    /// Reserves the first of `candidates` that's `free` and not reserved by another download.
+    pub fn reserve(
+        &self,
+        mut candidates: impl Iterator<Item = PathBuf>,
+        free: impl Fn(&Path) -> bool,
+    ) -> Reservation {
+        let mut paths = self.paths.lock().unwrap();
+        let path = candidates
+            .find(|path| !paths.contains(path) && free(path))
+            .expect("candidate paths are unbounded");
+        paths.insert(path.clone());
+        Reservation {
+            paths: Some(self.paths.clone()),
+            path,
+        }
+    }
+
+    /// Checks whether a running download reserved `path`.
+    pub fn is_reserved(&self, path: &Path) -> bool {
+        let paths = self.paths.lock().unwrap();
+        paths.contains(path)
+    }
+}
+
+/// A path picked for a download, which other downloads don't pick until it's dropped.
+pub(crate) struct Reservation {
+    /// Where the path is released, or nothing if it wasn't reserved.
+    paths: Option<Arc<Mutex<HashSet<PathBuf>>>>,
+    pub path: PathBuf,
+}
+
+impl Reservation {
+    /// A path that's used without reserving it, because downloads to it replace the file anyway.
+    pub fn unreserved(path: PathBuf) -> Self {
+        Self { paths: None, path }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(paths) = &self.paths {
+            let mut paths = paths.lock().unwrap();
+            paths.remove(&self.path);
+        }
+    }
+}
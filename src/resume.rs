@@ -0,0 +1,476 @@
+use crate::{
+    error::{DownloadError, IoResultExt},
+    journal,
+    storage::{self, SharedFile},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::fs;
+
+pub(crate) const SIDECAR_EXTENSION: &str = "simult";
+
+/// Size of the regions in the bitmap of written regions.
+const REGION_SIZE: u64 = 64 * 1024;
+
+/// A byte range of the output file and how much of it has been written so far.
+///
+/// The end of the range may move while the chunk is downloading, when part of it is handed to another connection.
+pub(crate) struct ChunkState {
+    pub start: u64,
+    range: Mutex<ClaimedRange>,
+    written: AtomicU64,
+}
+
+struct ClaimedRange {
+    end: u64,
+    /// Bytes the downloading connection has committed to write. Never less than `written`.
+    claimed: u64,
+}
+
+impl ChunkState {
+    pub fn new(start: u64, end: u64, written: u64) -> Self {
+        Self {
+            start,
+            range: Mutex::new(ClaimedRange {
+                end,
+                claimed: written,
+            }),
+            written: AtomicU64::new(written),
+        }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.range.lock().unwrap().end
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end() - self.start + 1
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Acquire)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.written() >= self.len()
+    }
+
+    /// Discards claims that weren't written, before the chunk is fetched again.
+    pub fn reset_claims(&self) {
+        self.range.lock().unwrap().claimed = self.written();
+    }
+
+    /// Reserves up to `bytes` bytes for writing and returns how many may be written.
+    pub fn claim(&self, bytes: u64) -> u64 {
+        let mut range = self.range.lock().unwrap();
+        let len = range.end - self.start + 1;
+        let claimable = bytes.min(len.saturating_sub(range.claimed));
+        range.claimed += claimable;
+        claimable
+    }
+
+    /// Records that `bytes` claimed bytes have been written.
+    pub fn add_written(&self, bytes: u64) {
+        self.written.fetch_add(bytes, Ordering::AcqRel);
+    }
+
+    /// Forgets everything written after the first `written` bytes, so it's fetched again.
+    pub fn rewind(&self, written: u64) {
+        let mut range = self.range.lock().unwrap();
+        range.claimed = written;
+        self.written.store(written, Ordering::Release);
+    }
+
+    /// Bytes that haven't been claimed yet.
+    pub fn unclaimed(&self) -> u64 {
+        let range = self.range.lock().unwrap();
+        (range.end - self.start + 1).saturating_sub(range.claimed)
+    }
+
+    /// Gives away the second half of the unclaimed bytes, if that's at least `min_size` bytes.
+    ///
+    /// Returns the range that was split off.
+    pub fn split(&self, min_size: u64) -> Option<(u64, u64)> {
+        let mut range = self.range.lock().unwrap();
+        let unclaimed = (range.end - self.start + 1).saturating_sub(range.claimed);
+        let half = unclaimed / 2;
+        if half < min_size.max(1) {
+            return None;
+        }
+
+        let split_at = range.end + 1 - half;
+        let stolen = (split_at, range.end);
+        range.end = split_at - 1;
+        Some(stolen)
+    }
+}
+
+/// Progress of a parallel download, persisted next to the partial file so it can be resumed.
+pub(crate) struct ResumeState {
+    pub url: String,
+    pub content_length: u64,
+    /// The `ETag` or `Last-Modified` date of the remote file when the download started.
+    pub validator: Option<String>,
+    chunks: Mutex<Vec<Arc<ChunkState>>>,
+    /// The regions the sidecar said were written, if it was loaded from one that records them.
+    saved_regions: Option<Regions>,
+}
+
+impl ResumeState {
+    /// Splits `content_length` bytes into `chunk_count` fresh chunks.
+    pub fn new(
+        url: &str,
+        content_length: u64,
+        validator: Option<&str>,
+        chunk_count: usize,
+    ) -> Self {
+        let chunk_count = chunk_count.min(content_length.max(1) as usize);
+        let chunk_size = content_length / chunk_count as u64;
+        let chunks = (0..chunk_count)
+            .map(|i| {
+                let start = i as u64 * chunk_size;
+                let end = if i == chunk_count - 1 {
+                    content_length - 1
+                } else {
+                    start + chunk_size - 1
+                };
+                Arc::new(ChunkState::new(start, end, 0))
+            })
+            .collect();
+
+        Self {
+            url: url.to_owned(),
+            content_length,
+            validator: validator.map(str::to_owned),
+            chunks: Mutex::new(chunks),
+            saved_regions: None,
+        }
+    }
+
+    /// Returns all chunks, including ones that were split off during the download.
+    pub fn chunks(&self) -> Vec<Arc<ChunkState>> {
+        self.chunks.lock().unwrap().clone()
+    }
+
+    /// Bytes of the file that have been written.
+    pub fn written(&self) -> u64 {
+        self.chunks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| c.written())
+            .sum()
+    }
+
+    /// Bytes from the start of the file on that have all been written.
+    pub fn written_prefix(&self) -> u64 {
+        let mut chunks = self.chunks();
+        chunks.sort_by_key(|c| c.start);
+        let mut end = 0;
+        for chunk in chunks {
+            if chunk.start != end {
+                break;
+            }
+            end = chunk.start + chunk.written();
+            if !chunk.is_complete() {
+                break;
+            }
+        }
+        end
+    }
+
+    /// Splits the chunk with the most remaining bytes, so an idle connection can help with it.
+    ///
+    /// Returns the new chunk, or `None` if no chunk has at least `2 * min_size` bytes left.
+    pub fn split_largest(&self, min_size: u64) -> Option<Arc<ChunkState>> {
+        let mut chunks = self.chunks.lock().unwrap();
+        let largest = chunks.iter().max_by_key(|c| c.unclaimed())?;
+        let (start, end) = largest.split(min_size)?;
+        let chunk = Arc::new(ChunkState::new(start, end, 0));
+        chunks.push(chunk.clone());
+        Some(chunk)
+    }
+
+    /// Rewinds every chunk to the end of the data that's really in `file`, for sparse files: up
+    /// to the first region the sidecar didn't mark as written or the first hole in the file.
+    /// Returns how many written bytes were forgotten.
+    pub fn verify_regions(&self, file: &SharedFile) -> io::Result<u64> {
+        let before = self.written();
+        for chunk in self.chunks() {
+            let end = chunk.start + chunk.written();
+            let mut trusted = file.next_hole(chunk.start)?.min(end);
+            if let Some(regions) = &self.saved_regions {
+                trusted = trusted.min(regions.written_until(chunk.start));
+            }
+            if trusted < end {
+                chunk.rewind(trusted - chunk.start);
+            }
+        }
+        Ok(before - self.written())
+    }
+
+    /// Loads the state stored in the sidecar of `output_path`, if there is a valid one.
+    pub async fn load(output_path: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(sidecar_path(output_path)).await.ok()?;
+        Self::parse(&contents)
+    }
+
+    /// Writes the current state to the sidecar of `output_path`.
+    pub async fn save(&self, output_path: &Path) -> Result<(), DownloadError> {
+        let path = sidecar_path(output_path);
+        fs::write(&path, self.serialize()).await.with_path(&path)
+    }
+
+    /// Like [`ResumeState::save`], and waits until both the output file and the state are on disk.
+    pub async fn save_synced(&self, output_path: &Path) -> Result<(), DownloadError> {
+        storage::sync(output_path).await.with_path(output_path)?;
+        self.save(output_path).await?;
+        let path = sidecar_path(output_path);
+        storage::sync(&path).await.with_path(&path)
+    }
+
+    /// Removes the sidecar of `output_path` and its chunk journal once the download has completed.
+    pub async fn remove(output_path: &Path) -> Result<(), DownloadError> {
+        journal::remove(output_path).await?;
+        let path = sidecar_path(output_path);
+        match fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).with_path(&path),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks whether this state describes the same, unchanged remote file.
+    pub fn matches(&self, url: &str, content_length: u64, validator: Option<&str>) -> bool {
+        self.url == url
+            && self.content_length == content_length
+            && self.validator.as_deref() == validator
+    }
+
+    fn serialize(&self) -> String {
+        let mut out = format!("url {}\nlength {}\n", self.url, self.content_length);
+        if let Some(validator) = &self.validator {
+            out.push_str(&format!("validator {}\n", validator));
+        }
+        let chunks = self.chunks();
+        for chunk in &chunks {
+            out.push_str(&format!(
+                "chunk {} {} {}\n",
+                chunk.start,
+                chunk.end(),
+                chunk.written()
+            ));
+        }
+        let regions = Regions::from_chunks(self.content_length, &chunks);
+        out.push_str(&format!("regions {} {}\n", REGION_SIZE, regions.encode()));
+        out
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let mut url = None;
+        let mut content_length = None;
+        let mut validator = None;
+        let mut chunks = Vec::new();
+        let mut saved_regions = None;
+
+        for line in contents.lines() {
+            let (key, value) = line.split_once(' ')?;
+            match key {
+                "url" => url = Some(value.to_owned()),
+                "length" => content_length = Some(value.parse().ok()?),
+                "validator" => validator = Some(value.to_owned()),
+                "chunk" => {
+                    let mut fields = value.split(' ').map(|v| v.parse::<u64>());
+                    let start = fields.next()?.ok()?;
+                    let end = fields.next()?.ok()?;
+                    let written = fields.next()?.ok()?;
+                    if end < start || written > end - start + 1 {
+                        return None;
+                    }
+                    chunks.push(Arc::new(ChunkState::new(start, end, written)));
+                }
+                "regions" => {
+                    let (size, bitmap) = value.split_once(' ')?;
+                    saved_regions = Some(Regions::decode(size.parse().ok()?, bitmap)?);
+                }
+                _ => {}
+            }
+        }
+
+        // The chunks must cover the file exactly once, or the written bytes don't add up.
+        let content_length = content_length?;
+        chunks.sort_by_key(|c| c.start);
+        let mut next = 0;
+        for chunk in &chunks {
+            if chunk.start != next {
+                return None;
+            }
+            next = chunk.end() + 1;
+        }
+        if chunks.is_empty() || next != content_length {
+            return None;
+        }
+
+        Some(Self {
+            url: url?,
+            content_length,
+            validator,
+            chunks: Mutex::new(chunks),
+            saved_regions,
+        })
+    }
+}
+
+/// A bitmap of the regions of a file that hold written bytes.
+struct Regions {
+    size: u64,
+    bits: Vec<u8>,
+}
+
+impl Regions {
+    fn from_chunks(content_length: u64, chunks: &[Arc<ChunkState>]) -> Self {
+        let count = content_length.div_ceil(REGION_SIZE);
+        let mut bits = vec![0; count.div_ceil(8) as usize];
+        for chunk in chunks {
+            let written = chunk.written();
+            if written == 0 || count == 0 {
+                continue;
+            }
+            let first = chunk.start / REGION_SIZE;
+            let last = (chunk.start + written - 1) / REGION_SIZE;
+            for region in first..=last.min(count - 1) {
+                bits[(region / 8) as usize] |= 1 << (region % 8);
+            }
+        }
+        Self {
+            size: REGION_SIZE,
+            bits,
+        }
+    }
+
+    fn decode(size: u64, bitmap: &str) -> Option<Self> {
+        if size == 0 {
+            return None;
+        }
+        let bits = STANDARD.decode(bitmap).ok()?;
+        Some(Self { size, bits })
+    }
+
+    fn encode(&self) -> String {
+        STANDARD.encode(&self.bits)
+    }
+
+    fn contains(&self, region: u64) -> bool {
+        let byte = self.bits.get((region / 8) as usize).copied().unwrap_or(0);
+        byte & (1 << (region % 8)) != 0
+    }
+
+    /// Returns where the first region from `offset` on that isn't marked as written starts.
+    fn written_until(&self, offset: u64) -> u64 {
+        let mut region = offset / self.size;
+        while self.contains(region) {
+            region += 1;
+        }
+        (region * self.size).max(offset)
+    }
+}
+
+/// Returns the path of the sidecar file that tracks the progress of `output_path`.
+pub(crate) fn sidecar_path(output_path: &Path) -> PathBuf {
+    let mut name = output_path.as_os_str().to_owned();
+    name.push(".");
+    name.push(SIDECAR_EXTENSION);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_the_sidecar() {
+        let state = ResumeState::new("https://example.com/a", 300_000, Some("\"etag\""), 3);
+        state.chunks()[0].add_written(70_000);
+        state.chunks()[2].add_written(10);
+
+        let loaded = ResumeState::parse(&state.serialize()).unwrap();
+        assert!(loaded.matches("https://example.com/a", 300_000, Some("\"etag\"")));
+        let chunks: Vec<_> = loaded
+            .chunks()
+            .iter()
+            .map(|c| (c.start, c.end(), c.written()))
+            .collect();
+        assert_eq!(
+            chunks,
+            [
+                (0, 99_999, 70_000),
+                (100_000, 199_999, 0),
+                (200_000, 299_999, 10)
+            ]
+        );
+        let regions = loaded.saved_regions.unwrap();
+        assert_eq!(regions.written_until(0), 2 * REGION_SIZE);
+        assert_eq!(regions.written_until(150_000), 150_000);
+    }
+
+    #[test]
+    fn refuses_invalid_sidecars() {
+        assert!(ResumeState::parse("").is_none());
+        assert!(ResumeState::parse("url https://example.com/a\nlength 10\n").is_none());
+        assert!(ResumeState::parse("length 10\nchunk 0 9 0\n").is_none());
+        assert!(ResumeState::parse("url https://example.com/a\nchunk 0 9 0\n").is_none());
+        assert!(ResumeState::parse("url u\nlength 10\nchunk 5 4 0\n").is_none());
+        assert!(ResumeState::parse("url u\nlength 10\nchunk 0 x 0\n").is_none());
+        assert!(ResumeState::parse("url u\nlength 10\nchunk 0 4 6\nchunk 5 9 0\n").is_none());
+        assert!(ResumeState::parse("url u\nlength 10\nchunk 0 4 0\nchunk 5 10 0\n").is_none());
+        assert!(ResumeState::parse("url u\nlength 10\nchunk 0 9 0\nregions 0 AA==\n").is_none());
+    }
+
+    #[test]
+    fn refuses_overlapping_chunks() {
+        let overlapping = "url u\nlength 10\nchunk 0 9 10\nchunk 0 9 10\n";
+        assert!(ResumeState::parse(overlapping).is_none());
+        assert!(ResumeState::parse("url u\nlength 10\nchunk 0 5 0\nchunk 4 9 0\n").is_none());
+    }
+
+    #[test]
+    fn refuses_chunks_with_gaps() {
+        assert!(ResumeState::parse("url u\nlength 10\nchunk 0 3 0\nchunk 5 9 0\n").is_none());
+        assert!(ResumeState::parse("url u\nlength 10\nchunk 0 8 0\n").is_none());
+        assert!(ResumeState::parse("url u\nlength 10\nchunk 1 9 0\n").is_none());
+    }
+
+    #[test]
+    fn sorts_chunks_by_start() {
+        let state = ResumeState::parse("url u\nlength 10\nchunk 5 9 1\nchunk 0 4 2\n").unwrap();
+        let starts: Vec<_> = state.chunks().iter().map(|c| c.start).collect();
+        assert_eq!(starts, [0, 5]);
+        assert_eq!(state.written(), 3);
+    }
+
+    #[test]
+    fn marks_written_regions() {
+        let chunks = [
+            Arc::new(ChunkState::new(0, REGION_SIZE * 2 - 1, REGION_SIZE + 1)),
+            Arc::new(ChunkState::new(REGION_SIZE * 2, REGION_SIZE * 9, 0)),
+        ];
+        let regions = Regions::from_chunks(REGION_SIZE * 9 + 1, &chunks);
+        assert_eq!(regions.bits, [0b11, 0]);
+
+        let decoded = Regions::decode(REGION_SIZE, &regions.encode()).unwrap();
+        assert!(decoded.contains(1));
+        assert!(!decoded.contains(2));
+        assert!(!decoded.contains(100));
+        assert_eq!(decoded.written_until(5), REGION_SIZE * 2);
+        assert_eq!(
+            decoded.written_until(REGION_SIZE * 3 + 5),
+            REGION_SIZE * 3 + 5
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use crate::error::DownloadError;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// How failed requests are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between attempts.
+    pub max_backoff: Duration,
+    /// Factor the delay grows by after every attempt.
+    pub multiplier: f64,
+    /// Randomizes every delay to between 50% and 100% of its value, so connections don't retry in lockstep.
+    pub jitter: bool,
+    /// Longest total time a request waits for the delays servers ask for in `Retry-After` headers.
+    /// A request that would have to wait longer fails instead.
+    pub retry_after_budget: Duration,
+}
+
+impl RetryPolicy {
+    /// Fails on the first error.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Returns how long to wait before the attempt following `attempt`.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        let delay = delay.min(self.max_backoff.as_secs_f64());
+
+        let delay = if self.jitter {
+            delay * (0.5 + random_fraction() / 2.0)
+        } else {
+            delay
+        };
+
+        Duration::from_secs_f64(delay)
+    }
+
+    /// Checks whether another attempt should be made after `attempt` failed with `error`.
+    pub(crate) fn should_retry(&self, attempt: u32, error: &DownloadError) -> bool {
+        attempt < self.max_attempts && error.is_retryable()
+    }
+
+    /// Returns how long to wait before retrying after `attempt` failed with `error`, or `None` if it
+    /// shouldn't be retried.
+    ///
+    /// Delays requested with `Retry-After` take precedence over the backoff and are added to
+    /// `retry_after_waited`, which is checked against the budget.
+    pub(crate) fn retry_delay(
+        &self,
+        attempt: u32,
+        error: &DownloadError,
+        retry_after_waited: &mut Duration,
+    ) -> Option<Duration> {
+        if !self.should_retry(attempt, error) {
+            return None;
+        }
+
+        match error.retry_after() {
+            Some(delay) if *retry_after_waited + delay > self.retry_after_budget => None,
+            Some(delay) => {
+                *retry_after_waited += delay;
+                Some(delay)
+            }
+            None => Some(self.backoff(attempt)),
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Three attempts, starting with a 500ms delay that doubles up to 30s. Servers may delay retries
+    /// by up to a minute in total.
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: true,
+            retry_after_budget: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Parses a `Retry-After` header, given either in seconds or as an HTTP date.
+pub(crate) fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(std::time::SystemTime::now())
+            .unwrap_or_default(),
+    )
+}
+
+/// Returns a pseudo-random number in `0.0..1.0`.
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default(),
+    );
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,429 @@
+//! Downloads of `s3://bucket/key` URLs, enabled with the `s3` feature.
+//!
+//! Objects are downloaded over HTTPS like any other file, with every request signed with AWS
+//! Signature Version 4.
+
+use crate::{
+    error::DownloadError,
+    options::DownloadOptions,
+    signing::{self, hmac_sha256, host_header, RequestSigner},
+    template,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{
+    header::{HeaderValue, AUTHORIZATION},
+    RequestBuilder,
+};
+use sha2::{Digest, Sha256};
+use std::{sync::Arc, time::SystemTime};
+
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Signals that the body of the request isn't signed, which is the case for all downloads.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Characters that are percent-encoded in canonical paths and queries: all but the unreserved ones.
+const SIGV4_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Where S3 objects are downloaded from and the credentials requests are signed with.
+///
+/// Settings that aren't configured are read from the usual `AWS_*` environment variables.
+#[derive(Clone, Default)]
+pub(crate) struct S3Config {
+    pub credentials: Option<AwsCredentials>,
+    pub region: Option<String>,
+    /// An S3 compatible service like `http://localhost:9000`, addressed with path-style URLs.
+    pub endpoint: Option<String>,
+}
+
+#[derive(Clone)]
+pub(crate) struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    fn from_env() -> Option<Self> {
+        Some(Self {
+            access_key_id: std::env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+/// Checks whether `url` is an `s3://` URL.
+pub(crate) fn is_s3_url(url: &str) -> bool {
+    url::Url::parse(url).is_ok_and(|url| url.scheme() == "s3")
+}
+
+impl S3Config {
+    /// Returns the HTTP URL of the object `url` points to, with mirrors translated the same way,
+    /// and options that sign the requests to them.
+    ///
+    /// Without credentials the requests aren't signed, which works for public buckets.
+    pub fn resolve(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<(String, DownloadOptions), DownloadError> {
+        let region = self
+            .region
+            .clone()
+            .or_else(|| std::env::var("AWS_REGION").ok())
+            .or_else(|| std::env::var("AWS_DEFAULT_REGION").ok())
+            .unwrap_or_else(|| DEFAULT_REGION.to_owned());
+        let endpoint = self
+            .endpoint
+            .clone()
+            .or_else(|| std::env::var("AWS_ENDPOINT_URL").ok());
+
+        let object_url = |url: &str| {
+            let invalid = || DownloadError::InvalidUrl(url.to_owned());
+            let parsed = url::Url::parse(url).map_err(|_| invalid())?;
+            let bucket = parsed.host_str().ok_or_else(invalid)?;
+            if parsed.path().len() <= 1 {
+                return Err(invalid());
+            }
+            let query = parsed
+                .query()
+                .map(|q| format!("?{}", q))
+                .unwrap_or_default();
+
+            Ok(match &endpoint {
+                Some(endpoint) => format!(
+                    "{}/{}{}{}",
+                    endpoint.trim_end_matches('/'),
+                    bucket,
+                    parsed.path(),
+                    query
+                ),
+                // Bucket names with dots don't match the wildcard certificate of virtual hosts.
+                None if bucket.contains('.') => format!(
+                    "https://s3.{}.amazonaws.com/{}{}{}",
+                    region,
+                    bucket,
+                    parsed.path(),
+                    query
+                ),
+                None => format!(
+                    "https://{}.s3.{}.amazonaws.com{}{}",
+                    bucket,
+                    region,
+                    parsed.path(),
+                    query
+                ),
+            })
+        };
+
+        let resolved = object_url(url)?;
+        let mut options = options.clone();
+        options.mirrors = options
+            .mirrors
+            .iter()
+            .map(|mirror| match is_s3_url(mirror) {
+                true => object_url(mirror),
+                false => Ok(mirror.clone()),
+            })
+            .collect::<Result<_, _>>()?;
+
+        if let Some(credentials) = self.credentials.clone().or_else(AwsCredentials::from_env) {
+            let mut hosts: Vec<String> = std::iter::once(&resolved)
+                .chain(&options.mirrors)
+                .filter_map(|url| url::Url::parse(url).ok())
+                .filter_map(|url| host_header(&url))
+                .collect();
+            hosts.dedup();
+            let signer = SigV4Signer::new(credentials, region, hosts);
+            options.auth = Arc::new(options.auth.with_signer(Arc::new(signer)));
+        }
+
+        Ok((resolved, options))
+    }
+}
+
+/// Signs requests to the hosts of S3 objects with AWS Signature Version 4. Requests to other
+/// hosts, like HTTPS mirrors, are left alone so the credentials aren't disclosed to them.
+pub(crate) struct SigV4Signer {
+    credentials: AwsCredentials,
+    region: String,
+    hosts: Vec<String>,
+}
+
+impl SigV4Signer {
+    pub fn new(credentials: AwsCredentials, region: String, hosts: Vec<String>) -> Self {
+        Self {
+            credentials,
+            region,
+            hosts,
+        }
+    }
+}
+
+impl RequestSigner for SigV4Signer {
+    fn sign(&self, request: RequestBuilder) -> RequestBuilder {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.sign_at(request, now)
+    }
+}
+
+impl SigV4Signer {
+    /// Signs `request` as if it's sent `now` seconds after the Unix epoch.
+    pub fn sign_at(&self, request: RequestBuilder, now: u64) -> RequestBuilder {
+        let Some(built) = signing::inspect(&request) else {
+            return request;
+        };
+        let Some(host) = host_header(built.url()).filter(|host| self.hosts.contains(host)) else {
+            return request;
+        };
+
+        let (year, month, day) = template::civil_from_days((now / 86_400) as i64);
+        let date = format!("{:04}{:02}{:02}", year, month, day);
+        let time = now % 86_400;
+        let timestamp = format!(
+            "{}T{:02}{:02}{:02}Z",
+            date,
+            time / 3600,
+            time / 60 % 60,
+            time % 60
+        );
+
+        let mut headers = vec![
+            ("host", host.as_str()),
+            ("x-amz-content-sha256", UNSIGNED_PAYLOAD),
+            ("x-amz-date", timestamp.as_str()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token));
+        }
+        let canonical_request = canonical_request(
+            built.method().as_str(),
+            built.url(),
+            &headers,
+            UNSIGNED_PAYLOAD,
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let signature = signature(
+            &self.credentials.secret_access_key,
+            &timestamp,
+            &scope,
+            &canonical_request,
+        );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.credentials.access_key_id,
+            scope,
+            signed_headers(&headers),
+            signature
+        );
+
+        // `host` is sent by the client itself.
+        let mut request = request;
+        for (name, value) in &headers[1..] {
+            request = request.header(*name, *value);
+        }
+        match HeaderValue::from_str(&authorization) {
+            Ok(value) => request.header(AUTHORIZATION, value),
+            Err(_) => request,
+        }
+    }
+}
+
+/// Puts a request with `headers`, sorted by their lowercase names, into the canonical form that's
+/// signed.
+fn canonical_request(
+    method: &str,
+    url: &url::Url,
+    headers: &[(&str, &str)],
+    payload_hash: &str,
+) -> String {
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        canonical_path(url.path()),
+        canonical_query(url),
+        canonical_headers,
+        signed_headers(headers),
+        payload_hash
+    )
+}
+
+fn signed_headers(headers: &[(&str, &str)]) -> String {
+    headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Signs a canonical request made at `timestamp` with the key derived for `scope`, which is like
+/// `20150830/us-east-1/s3/aws4_request`.
+fn signature(
+    secret_access_key: &str,
+    timestamp: &str,
+    scope: &str,
+    canonical_request: &str,
+) -> String {
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope,
+        to_hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = scope.split('/').fold(
+        format!("AWS4{}", secret_access_key).into_bytes(),
+        |key, part| hmac_sha256(&key, part.as_bytes()),
+    );
+    to_hex(&hmac_sha256(&key, string_to_sign.as_bytes()))
+}
+
+fn canonical_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            let decoded = percent_decode_str(segment).collect::<Vec<_>>();
+            percent_encoding::percent_encode(&decoded, SIGV4_ENCODE).to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn canonical_query(url: &url::Url) -> String {
+    let mut pairs: Vec<String> = url
+        .query_pairs()
+        .map(|(name, value)| {
+            format!(
+                "{}={}",
+                utf8_percent_encode(&name, SIGV4_ENCODE),
+                utf8_percent_encode(&value, SIGV4_ENCODE)
+            )
+        })
+        .collect();
+    pairs.sort();
+    pairs.join("&")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+    const EMPTY_PAYLOAD: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    /// Signs a request from the AWS Signature Version 4 test suite, which all share one date,
+    /// scope and key.
+    fn suite_signature(path_and_query: &str) -> String {
+        let url =
+            url::Url::parse(&format!("https://example.amazonaws.com{}", path_and_query)).unwrap();
+        let headers = [
+            ("host", "example.amazonaws.com"),
+            ("x-amz-date", "20150830T123600Z"),
+        ];
+        let canonical_request = canonical_request("GET", &url, &headers, EMPTY_PAYLOAD);
+        signature(
+            SECRET,
+            "20150830T123600Z",
+            "20150830/us-east-1/service/aws4_request",
+            &canonical_request,
+        )
+    }
+
+    #[test]
+    fn signs_the_test_suite_requests() {
+        let vectors = [
+            (
+                "/",
+                "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+            ),
+            (
+                "/?Param1=value1",
+                "a67d582fa61cc504c4bae71f336f98b97f1ea3c7a6bfe1b6e45aec72011b9aeb",
+            ),
+            (
+                "/?Param2=value2&Param1=value1",
+                "b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500",
+            ),
+            (
+                "/?Param1=value2&Param1=Value1",
+                "eedbc4e291e521cf13422ffca22be7d2eb8146eecf653089df300a15b2382bd1",
+            ),
+            (
+                "/?Param1=value2&Param1=value1",
+                "5772eed61e12b33fae39ee5e7012498b51d56abc0abb7c60486157bd471c4694",
+            ),
+            (
+                "/example space/",
+                "652487583200325589f1fba4c7e578f72c47cb61beeca81406b39ddec1366741",
+            ),
+            (
+                "/ሴ",
+                "8318018e0b0f223aa2bbf98705b62bb787dc9c0e678f255a891fd03141be5d85",
+            ),
+            (
+                "/-._~0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz",
+                "07ef7494c76fa4850883e2b006601f940f8a34d404d0cfa977f52a65bbf5f24f",
+            ),
+        ];
+        for (path, expected) in vectors {
+            assert_eq!(suite_signature(path), expected, "{}", path);
+        }
+    }
+
+    fn signer() -> SigV4Signer {
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_owned(),
+            secret_access_key: SECRET.to_owned(),
+            session_token: Some("TOKEN".to_owned()),
+        };
+        SigV4Signer::new(
+            credentials,
+            "eu-west-1".to_owned(),
+            vec!["bucket.s3.amazonaws.com".to_owned()],
+        )
+    }
+
+    #[test]
+    fn signs_requests_to_the_object_hosts() {
+        let client = reqwest::Client::new();
+        let request = client.get("https://bucket.s3.amazonaws.com/photos/a%20b.jpg?versionId=3");
+        // 2015-08-30T12:36:00Z.
+        let signed = signer().sign_at(request, 1_440_938_160).build().unwrap();
+        let header = |name| signed.headers()[name].to_str().unwrap();
+        assert_eq!(header("x-amz-date"), "20150830T123600Z");
+        assert_eq!(header("x-amz-content-sha256"), UNSIGNED_PAYLOAD);
+        assert_eq!(header("x-amz-security-token"), "TOKEN");
+        assert_eq!(
+            header("authorization"),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/eu-west-1/s3/aws4_request, \
+             SignedHeaders=host;x-amz-content-sha256;x-amz-date;x-amz-security-token, \
+             Signature=8262e15a976de8c162d04e03292485f315f2010954793adfe7a0fbb0796070f2"
+        );
+
+        let mirror = client.get("https://mirror.example.com/photos/a.jpg");
+        let unsigned = signer().sign_at(mirror, 1_440_938_160).build().unwrap();
+        assert!(!unsigned.headers().contains_key("authorization"));
+        assert!(!unsigned.headers().contains_key("x-amz-date"));
+    }
+
+    #[test]
+    fn canonicalizes_paths_and_queries() {
+        assert_eq!(canonical_path("/a b/%41%2Fc/ሴ"), "/a%20b/A%2Fc/%E1%88%B4");
+        let url = url::Url::parse("https://h/?b=2&a=x%20y&a=1&c").unwrap();
+        assert_eq!(canonical_query(&url), "a=1&a=x%20y&b=2&c=");
+    }
+}
@@ -0,0 +1,324 @@
+use crate::{
+    checksum::Hasher,
+    download::{self, Downloader},
+    error::{DownloadError, IoResultExt},
+    event::DownloadEvent,
+    metrics,
+    options::DownloadOptions,
+    probe::Probe,
+    progress::SpeedMeter,
+    report::DownloadReport,
+    stall::StallDetector,
+};
+use futures::{stream, StreamExt};
+use reqwest::{
+    header::{HeaderMap, RANGE},
+    StatusCode,
+};
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+use tokio::{fs, io::AsyncWriteExt};
+use url::Url;
+
+/// Most bytes a segment is buffered with in memory before it's written.
+pub(crate) const MAX_SEGMENT_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Most bytes allocated for a segment before they arrive, whatever its `Content-Length` says.
+const MAX_PREALLOCATION: usize = 1024 * 1024;
+
+/// Which variant of a stream offered in several qualities is downloaded, picked by bandwidth.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StreamVariant {
+    /// The one with the highest bandwidth.
+    #[default]
+    Highest,
+    /// The one with the lowest bandwidth.
+    Lowest,
+    /// The one with the highest bandwidth of at most this many bits per second, or the lowest if
+    /// they all need more.
+    MaxBandwidth(u64),
+}
+
+impl StreamVariant {
+    /// Returns the index of the variant to download among those with `bandwidths`.
+    pub(crate) fn select(self, bandwidths: &[u64]) -> usize {
+        let indices = 0..bandwidths.len();
+        let lowest = indices.clone().min_by_key(|&i| bandwidths[i]);
+        let chosen = match self {
+            Self::Highest => indices.max_by_key(|&i| bandwidths[i]),
+            Self::Lowest => lowest,
+            Self::MaxBandwidth(max) => indices
+                .filter(|&i| bandwidths[i] <= max)
+                .max_by_key(|&i| bandwidths[i])
+                .or(lowest),
+        };
+        chosen.unwrap_or(0)
+    }
+}
+
+/// A piece of a segmented stream, like those listed by HLS playlists.
+#[derive(Debug, Clone)]
+pub(crate) struct Segment {
+    pub url: Url,
+    /// The first byte and the length of the piece, if it's only part of the file at `url`. The
+    /// manifest parsers make sure the piece ends before `u64::MAX`.
+    pub range: Option<(u64, u64)>,
+}
+
+impl Downloader {
+    /// Fetches the playlist or manifest of a stream, returning the URL it was served from, which
+    /// the URLs in it are relative to, and its contents.
+    pub(crate) async fn fetch_manifest(
+        &self,
+        url: &Url,
+        options: &DownloadOptions,
+    ) -> Result<(Url, String), DownloadError> {
+        let _permit = options
+            .or_cancelled(self.hosts.acquire(url.as_str()))
+            .await?;
+        let fetch = async {
+            let response = self
+                .send_request(url.as_str(), options, |client| client.get(url.as_str()))
+                .await?;
+            let base = response.url().clone();
+            Ok((base, response.text().await?))
+        };
+        options.or_cancelled(fetch).await?
+    }
+
+    /// Downloads the `segments` of the stream described at `url` and joins them into the file
+    /// called `name`, in order.
+    ///
+    /// Up to `conn_count` segments are fetched at once, each retried according to the retry
+    /// policy, and only the ones that haven't been written yet are kept in memory.
+    pub(crate) async fn download_segments(
+        &self,
+        url: &str,
+        name: &str,
+        segments: &[Segment],
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        if let Some(report) = self
+            .check_existing(url, name, &Probe::default(), options)
+            .await?
+        {
+            return Ok(report);
+        }
+        let reservation = self.reserve_output_path(name, options);
+        let path = reservation.path.clone();
+        let partial = self.partial_path(&path);
+        download::create_parent_dir(&partial).await?;
+        let mut file = fs::File::create(&partial).await.with_path(&partial)?;
+        options.emit(|| DownloadEvent::Started {
+            path: path.clone(),
+            total: None,
+        });
+
+        let mut hasher = options.checksum.as_ref().map(|c| Hasher::new(c.algorithm));
+        let result = self
+            .write_segments(url, segments, &mut file, &partial, options, &mut hasher)
+            .await;
+        drop(file);
+        // A partial stream can't be resumed, so it's never kept.
+        let (written, retries) = match result {
+            Ok(written) => written,
+            Err(e) => {
+                download::remove_partial(&partial)
+                    .await
+                    .with_path(&partial)?;
+                return Err(e);
+            }
+        };
+        self.make_durable(&partial).await?;
+
+        if let (Some(checksum), Some(hasher)) = (&options.checksum, hasher) {
+            if let Err(e) = checksum.verify(hasher) {
+                warn!(error = %e, "removing file that failed verification");
+                download::remove_partial(&partial)
+                    .await
+                    .with_path(&partial)?;
+                return Err(e);
+            }
+        }
+        self.move_into_place(&partial, &path).await?;
+
+        Ok(DownloadReport {
+            path,
+            url: url.to_owned(),
+            final_url: url.to_owned(),
+            status: None,
+            headers: HeaderMap::new(),
+            size: written,
+            bytes_downloaded: written,
+            elapsed: started.elapsed(),
+            retries,
+            checksum: options.checksum.clone(),
+            checksum_source: options.checksum_source.clone(),
+            extracted_to: None,
+            chunks: Vec::new(),
+        })
+    }
+
+    /// Writes the segments to `file` as they arrive, returning the number of bytes written and of
+    /// retried requests.
+    async fn write_segments(
+        &self,
+        url: &str,
+        segments: &[Segment],
+        file: &mut fs::File,
+        path: &Path,
+        options: &DownloadOptions,
+        hasher: &mut Option<Hasher>,
+    ) -> Result<(u64, u32), DownloadError> {
+        let mut fetched = stream::iter(segments)
+            .map(|segment| self.fetch_segment(segment, options))
+            .buffered(self.conn_count.max(1));
+        let mut meter = SpeedMeter::new(0);
+        let mut last_report = Instant::now();
+        let mut written = 0;
+        let mut retries = 0;
+        while let Some(result) = fetched.next().await {
+            let (bytes, retried) = result?;
+            self.check_size(url, Some(written + bytes.len() as u64), options)?;
+            download::write_hashed(file, &bytes, Some(path), hasher).await?;
+            written += bytes.len() as u64;
+            retries += retried;
+            if last_report.elapsed() >= self.progress_interval {
+                self.report_sequential_progress(url, options, written, None, &mut meter);
+                last_report = Instant::now();
+            }
+        }
+        file.flush().await.with_path(path)?;
+        self.report_sequential_progress(url, options, written, None, &mut meter);
+        Ok((written, retries))
+    }
+
+    /// Fetches a segment into memory, returning it with the number of retried requests.
+    async fn fetch_segment(
+        &self,
+        segment: &Segment,
+        options: &DownloadOptions,
+    ) -> Result<(Vec<u8>, u32), DownloadError> {
+        let mut attempt = 1;
+        let mut retry_after_waited = Duration::ZERO;
+        loop {
+            let error = match self.fetch_segment_once(segment, options).await {
+                Ok(bytes) => return Ok((bytes, attempt - 1)),
+                Err(e) => e,
+            };
+            match self
+                .retry
+                .retry_delay(attempt, &error, &mut retry_after_waited)
+            {
+                Some(delay) => {
+                    warn!(url = %segment.url, error = %error, "segment failed, retrying");
+                    metrics::record_retry();
+                    options.or_cancelled(tokio::time::sleep(delay)).await?;
+                    attempt += 1;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+
+    pub(crate) async fn fetch_segment_once(
+        &self,
+        segment: &Segment,
+        options: &DownloadOptions,
+    ) -> Result<Vec<u8>, DownloadError> {
+        let url = segment.url.as_str();
+        let _permit = options.or_cancelled(self.hosts.acquire(url)).await?;
+        let response = options
+            .or_cancelled(self.send_request(url, options, |client| {
+                let request = client.get(url);
+                match segment.range {
+                    Some((start, len)) => request.header(
+                        RANGE,
+                        format!("bytes={}-{}", start, start + (len.max(1) - 1)),
+                    ),
+                    None => request,
+                }
+            }))
+            .await??;
+        if segment.range.is_some() && response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(DownloadError::RangeIgnored {
+                url: url.to_owned(),
+            });
+        }
+
+        let expected = response.content_length();
+        self.check_size(url, expected, options)?;
+        check_segment_size(url, expected.unwrap_or(0))?;
+        let mut stream = response.bytes_stream();
+        let throttle = self.throttle.connection();
+        let mut stall = StallDetector::new(self.min_speed);
+        let capacity = expected.unwrap_or(0).min(MAX_PREALLOCATION as u64);
+        let mut bytes = Vec::with_capacity(capacity as usize);
+        while let Some(chunk) = options
+            .or_cancelled(download::next_chunk(
+                &mut stream,
+                self.read_timeout,
+                &mut stall,
+            ))
+            .await??
+        {
+            options.or_cancelled(throttle.acquire(chunk.len())).await?;
+            let len = (bytes.len() + chunk.len()) as u64;
+            self.check_size(url, Some(len), options)?;
+            check_segment_size(url, len)?;
+            bytes.extend_from_slice(&chunk);
+        }
+        match expected {
+            Some(expected) if expected != bytes.len() as u64 => {
+                Err(DownloadError::ContentLengthMismatch {
+                    expected,
+                    actual: bytes.len() as u64,
+                })
+            }
+            _ => Ok(bytes),
+        }
+    }
+}
+
+/// Fails with [`DownloadError::FileTooLarge`] if a segment of `size` bytes is too large to buffer.
+fn check_segment_size(url: &str, size: u64) -> Result<(), DownloadError> {
+    match size > MAX_SEGMENT_SIZE {
+        true => Err(DownloadError::FileTooLarge {
+            url: url.to_owned(),
+            limit: MAX_SEGMENT_SIZE,
+        }),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_size_is_bounded() {
+        check_segment_size("https://example.com/0.ts", MAX_SEGMENT_SIZE).unwrap();
+        assert!(matches!(
+            check_segment_size("https://example.com/0.ts", MAX_SEGMENT_SIZE + 1),
+            Err(DownloadError::FileTooLarge {
+                limit: MAX_SEGMENT_SIZE,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn variant_selection() {
+        let bandwidths = [800_000, 2_400_000, 1_200_000];
+        assert_eq!(StreamVariant::Highest.select(&bandwidths), 1);
+        assert_eq!(StreamVariant::Lowest.select(&bandwidths), 0);
+        assert_eq!(
+            StreamVariant::MaxBandwidth(1_500_000).select(&bandwidths),
+            2
+        );
+        assert_eq!(StreamVariant::MaxBandwidth(100).select(&bandwidths), 0);
+    }
+}
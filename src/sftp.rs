@@ -0,0 +1,531 @@
+//! Downloads over SFTP, enabled with the `sftp` feature.
+
+use crate::{
+    download::{create_parent_dir, remove_partial, Downloader, RESUME_SAVE_INTERVAL},
+    error::{DownloadError, IoResultExt},
+    event::DownloadEvent,
+    metrics,
+    net::Network,
+    options::{DownloadOptions, Durability},
+    probe::Probe,
+    progress::SpeedMeter,
+    report::DownloadReport,
+    resume::{ChunkState, ResumeState},
+    stall::StallDetector,
+    storage::{self, SharedFile},
+};
+use bytes::Bytes;
+use futures::future::try_join_all;
+use percent_encoding::percent_decode_str;
+use reqwest::header::HeaderMap;
+use russh::{
+    client::{self, Handle},
+    keys::{self, PrivateKeyWithHashAlg, PublicKeyOrCertificate},
+};
+use russh_sftp::client::{error::Error as SftpError, SftpSession};
+use std::{
+    collections::VecDeque,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+/// Keys that are tried, in this order, if no other way to log in is configured.
+const DEFAULT_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// How the [`Downloader`] logs in to SFTP servers and checks their host keys.
+#[derive(Clone, Default)]
+pub(crate) struct SftpConfig {
+    pub auth: Option<SftpAuth>,
+    /// Defaults to `~/.ssh/known_hosts`.
+    pub known_hosts: Option<PathBuf>,
+}
+
+#[derive(Clone)]
+pub(crate) enum SftpAuth {
+    Password {
+        username: String,
+        password: String,
+    },
+    PrivateKey {
+        username: String,
+        path: PathBuf,
+        passphrase: Option<String>,
+    },
+}
+
+impl SftpAuth {
+    fn username(&self) -> &str {
+        match self {
+            Self::Password { username, .. } | Self::PrivateKey { username, .. } => username,
+        }
+    }
+}
+
+/// Checks whether `url` is an `sftp://` URL.
+pub(crate) fn is_sftp_url(url: &str) -> bool {
+    url::Url::parse(url).is_ok_and(|url| url.scheme() == "sftp")
+}
+
+/// The server a file is on and how to find the file there.
+struct Location {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    /// Absolute, or relative to the home directory for URLs like `sftp://host/~/file`.
+    path: String,
+}
+
+impl Location {
+    fn parse(url: &str) -> Result<Self, DownloadError> {
+        let invalid = || DownloadError::InvalidUrl(url.to_owned());
+        let parsed = url::Url::parse(url).map_err(|_| invalid())?;
+        let decode = |value: &str| percent_decode_str(value).decode_utf8_lossy().into_owned();
+
+        let path = decode(parsed.path());
+        let path = match path.strip_prefix("/~/") {
+            Some(relative) => relative.to_owned(),
+            None => path,
+        };
+        if path.is_empty() || path.ends_with('/') {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            host: parsed.host_str().ok_or_else(invalid)?.to_owned(),
+            port: parsed.port().unwrap_or(22),
+            username: Some(parsed.username())
+                .filter(|username| !username.is_empty())
+                .map(decode),
+            password: parsed.password().map(decode),
+            path,
+        })
+    }
+}
+
+/// Accepts the server if its host key is in the known hosts file.
+struct HostKeyCheck {
+    host: String,
+    port: u16,
+    known_hosts: Option<PathBuf>,
+}
+
+impl client::Handler for HostKeyCheck {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        // Host certificates would need a trusted CA, which can't be configured.
+        let PublicKeyOrCertificate::PublicKey { key, .. } = key else {
+            return Ok(false);
+        };
+        let known = match &self.known_hosts {
+            Some(path) => keys::check_known_hosts_path(&self.host, self.port, key, path),
+            None => keys::check_known_hosts(&self.host, self.port, key),
+        };
+        match known {
+            Ok(known) => Ok(known),
+            Err(keys::Error::KeyChanged { line }) => Err(russh::Error::KeyChanged { line }),
+            Err(_e) => {
+                warn!(error = %_e, "could not read known hosts");
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// An SSH connection with an open SFTP session.
+struct Connection {
+    _ssh: Handle<HostKeyCheck>,
+    sftp: SftpSession,
+}
+
+impl Connection {
+    async fn open(
+        location: &Location,
+        config: &SftpConfig,
+        network: &Network,
+    ) -> Result<Self, DownloadError> {
+        let handler = HostKeyCheck {
+            host: location.host.clone(),
+            port: location.port,
+            known_hosts: config.known_hosts.clone(),
+        };
+        let stream = network
+            .connect(&location.host, location.port)
+            .await
+            .map_err(russh::Error::IO)?;
+        let mut ssh =
+            client::connect_stream(Arc::new(client::Config::default()), stream, handler).await?;
+
+        if !authenticate(&mut ssh, location, config).await? {
+            return Err(DownloadError::LoginFailed {
+                host: location.host.clone(),
+            });
+        }
+
+        let channel = ssh.channel_open_session().await?;
+        channel.request_subsystem(true, "sftp").await?;
+        let sftp = SftpSession::new(channel.into_stream()).await?;
+        Ok(Self { _ssh: ssh, sftp })
+    }
+}
+
+/// Logs in with the password from the URL, the configured credentials or the default keys in
+/// `~/.ssh`, in that order.
+async fn authenticate(
+    ssh: &mut Handle<HostKeyCheck>,
+    location: &Location,
+    config: &SftpConfig,
+) -> Result<bool, DownloadError> {
+    let username = location
+        .username
+        .clone()
+        .or_else(|| config.auth.as_ref().map(|auth| auth.username().to_owned()))
+        .or_else(|| std::env::var("USER").ok())
+        .ok_or_else(|| DownloadError::LoginFailed {
+            host: location.host.clone(),
+        })?;
+
+    if let Some(password) = &location.password {
+        return Ok(ssh
+            .authenticate_password(username, password)
+            .await?
+            .success());
+    }
+
+    let keys = match &config.auth {
+        Some(SftpAuth::Password { password, .. }) => {
+            return Ok(ssh
+                .authenticate_password(username, password)
+                .await?
+                .success());
+        }
+        Some(SftpAuth::PrivateKey {
+            path, passphrase, ..
+        }) => vec![keys::load_secret_key(path, passphrase.as_deref()).map_err(russh::Error::from)?],
+        None => {
+            let Some(home) = std::env::var_os("HOME") else {
+                return Ok(false);
+            };
+            let dir = Path::new(&home).join(".ssh");
+            DEFAULT_KEYS
+                .iter()
+                .filter_map(|name| keys::load_secret_key(dir.join(name), None).ok())
+                .collect()
+        }
+    };
+
+    for key in keys {
+        let hash = ssh.best_supported_rsa_hash().await?.flatten();
+        let key = PrivateKeyWithHashAlg::new(Arc::new(key), hash);
+        if ssh
+            .authenticate_publickey(username.clone(), key)
+            .await?
+            .success()
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+impl Downloader {
+    /// Downloads an `sftp://` URL.
+    ///
+    /// Files of known size are split into chunks that are read through separate file handles at
+    /// the same time, as many as the server lets the connection open.
+    pub(crate) async fn download_sftp(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        let location = Location::parse(url)?;
+        let _permit = options.or_cancelled(self.hosts.acquire(url)).await?;
+
+        let connection = options
+            .or_cancelled(Connection::open(&location, &self.sftp, &self.network))
+            .await??;
+        let metadata = connection.sftp.metadata(location.path.as_str()).await?;
+        let content_length = metadata.size.filter(|&size| size > 0);
+        let validator = metadata.mtime.map(|mtime| mtime.to_string());
+        debug!(content_length = ?content_length, "probed SFTP file");
+        self.check_size(url, content_length, options)?;
+
+        let name = self.output_name(url, options.filename.as_deref(), &HeaderMap::new(), options);
+        let resumable = match content_length {
+            Some(len) => {
+                self.find_resumable(url, len, validator.as_deref(), &name)
+                    .await
+            }
+            None => None,
+        };
+        let mut reservation = None;
+        let (output_path, state) = match resumable {
+            Some((path, state)) => (path, Some(state)),
+            None => {
+                let probe = Probe {
+                    content_length,
+                    ..Probe::default()
+                };
+                if let Some(report) = self.check_existing(url, &name, &probe, options).await? {
+                    return Ok(report);
+                }
+                let state = content_length.map(|len| {
+                    ResumeState::new(url, len, validator.as_deref(), self.chunk_count(len))
+                });
+                let reserved = reservation.insert(self.reserve_output_path(&name, options));
+                (reserved.path.clone(), state)
+            }
+        };
+
+        let partial = self.partial_path(&output_path);
+
+        // Without a known size the whole file is one chunk that ends with the file.
+        let chunks = match &state {
+            Some(state) => state.chunks(),
+            None => vec![Arc::new(ChunkState::new(0, u64::MAX - 1, 0))],
+        };
+        let initially_written: u64 = chunks.iter().map(|c| c.written()).sum();
+
+        create_parent_dir(&partial).await?;
+        let file = match content_length {
+            Some(len) => {
+                storage::check_space(&partial, len - initially_written)?;
+                let file = storage::preallocate(&partial, len, self.allocation)
+                    .await
+                    .with_path(&partial)?;
+                if let Some(state) = &state {
+                    self.verify_regions(state, &file, &partial)?;
+                }
+                file
+            }
+            None => {
+                let file = fs::File::create(&partial).await.with_path(&partial)?;
+                SharedFile::new(file.into_std().await)
+            }
+        };
+        // Verifying the regions of a sparse file may have discarded some.
+        let initially_written: u64 = chunks.iter().map(|c| c.written()).sum();
+        if let (true, Some(state)) = (self.resume, &state) {
+            state.save(&partial).await?;
+        }
+        options.emit(|| DownloadEvent::Started {
+            path: output_path.clone(),
+            total: content_length,
+        });
+
+        let mut meter = SpeedMeter::new(initially_written);
+        let report_progress = |meter: &mut SpeedMeter| match &state {
+            Some(state) => self.report_progress(url, options, state, meter),
+            None => self.report_sequential_progress(url, options, chunks[0].written(), None, meter),
+        };
+
+        let mut connection = Some(connection);
+        let mut attempt = 1;
+        let mut retry_after_waited = Duration::ZERO;
+        let result = loop {
+            let result = async {
+                let connection = match connection.take() {
+                    Some(connection) => connection,
+                    None => {
+                        options
+                            .or_cancelled(Connection::open(&location, &self.sftp, &self.network))
+                            .await??
+                    }
+                };
+                let queue = Mutex::new(
+                    chunks
+                        .iter()
+                        .filter(|chunk| !chunk.is_complete())
+                        .cloned()
+                        .collect::<VecDeque<_>>(),
+                );
+                let workers = self.conn_count.min(queue.lock().unwrap().len());
+                let transfer = try_join_all((0..workers).map(|worker| {
+                    self.sftp_worker(
+                        worker,
+                        &connection,
+                        &location,
+                        &queue,
+                        &file,
+                        &partial,
+                        options,
+                    )
+                }));
+                tokio::pin!(transfer);
+
+                let mut progress_ticker = tokio::time::interval(self.progress_interval);
+                let mut save_ticker = tokio::time::interval(RESUME_SAVE_INTERVAL);
+                loop {
+                    tokio::select! {
+                        result = &mut transfer => break result.map(drop),
+                        _ = progress_ticker.tick() => report_progress(&mut meter),
+                        _ = save_ticker.tick() => {
+                            if let (true, Some(state)) = (self.resume, &state) {
+                                self.save_state(state, &partial).await?;
+                            }
+                        }
+                    }
+                }?;
+
+                match chunks.iter().find(|chunk| !chunk.is_complete()) {
+                    Some(chunk) if state.is_some() => Err(DownloadError::ContentLengthMismatch {
+                        expected: chunk.len(),
+                        actual: chunk.written(),
+                    }),
+                    _ => Ok(()),
+                }
+            }
+            .await;
+
+            match result {
+                Ok(()) => break Ok(()),
+                Err(e) => match self.retry.retry_delay(attempt, &e, &mut retry_after_waited) {
+                    Some(delay) => {
+                        warn!(attempt, error = %e, "SFTP transfer failed, retrying");
+                        metrics::record_retry();
+                        if let Err(e) = options.or_cancelled(tokio::time::sleep(delay)).await {
+                            break Err(e);
+                        }
+                        attempt += 1;
+                    }
+                    None => break Err(e),
+                },
+            }
+        };
+        report_progress(&mut meter);
+
+        if let Err(e) = result {
+            if self.discards_cancelled(&e) || state.is_none() {
+                remove_partial(&partial).await.with_path(&partial)?;
+                ResumeState::remove(&partial).await?;
+            } else if self.resume {
+                if let Some(state) = &state {
+                    self.save_state(state, &partial).await?;
+                }
+            }
+            return Err(e);
+        }
+        self.make_durable(&partial).await?;
+        if self.resume {
+            ResumeState::remove(&partial).await?;
+        }
+
+        if let Some(checksum) = &options.checksum {
+            if let Err(e) = checksum.verify_file(&partial).await {
+                warn!(error = %e, "removing file that failed verification");
+                remove_partial(&partial).await.with_path(&partial)?;
+                return Err(e);
+            }
+        }
+        drop(file);
+        self.move_into_place(&partial, &output_path).await?;
+
+        let size = chunks.iter().map(|c| c.written()).sum();
+        Ok(DownloadReport {
+            path: output_path,
+            url: url.to_owned(),
+            final_url: url.to_owned(),
+            status: None,
+            headers: HeaderMap::new(),
+            size,
+            bytes_downloaded: size - initially_written,
+            elapsed: started.elapsed(),
+            retries: attempt - 1,
+            checksum: options.checksum.clone(),
+            checksum_source: options.checksum_source.clone(),
+            extracted_to: None,
+            chunks: Vec::new(),
+        })
+    }
+
+    /// Opens a handle to the remote file and reads chunks from `queue` through it until the queue
+    /// is empty.
+    ///
+    /// Every worker but the first gives up quietly if the server won't open another handle.
+    #[allow(clippy::too_many_arguments)]
+    async fn sftp_worker(
+        &self,
+        worker: usize,
+        connection: &Connection,
+        location: &Location,
+        queue: &Mutex<VecDeque<Arc<ChunkState>>>,
+        file: &SharedFile,
+        output_path: &Path,
+        options: &DownloadOptions,
+    ) -> Result<(), DownloadError> {
+        let mut remote = match connection.sftp.open(location.path.as_str()).await {
+            Ok(remote) => remote,
+            Err(_e) if worker > 0 => {
+                debug!(worker, error = %_e, "server refused another file handle");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let throttle = self.throttle.connection();
+        let mut stall = StallDetector::new(self.min_speed);
+        let mut buffer = vec![0; self.read_buffer_size];
+
+        loop {
+            let Some(chunk) = queue.lock().unwrap().pop_front() else {
+                return Ok(());
+            };
+            chunk.reset_claims();
+            let mut offset = chunk.start + chunk.written();
+            remote
+                .seek(SeekFrom::Start(offset))
+                .await
+                .map_err(io_error)?;
+            stall.reset();
+
+            while !chunk.is_complete() {
+                if options.or_cancelled(options.unpaused()).await? {
+                    stall.reset();
+                }
+                let len = chunk.unclaimed().min(buffer.len() as u64) as usize;
+                let read = async {
+                    let read = match self.read_timeout {
+                        Some(timeout) => {
+                            tokio::time::timeout(timeout, remote.read(&mut buffer[..len]))
+                                .await
+                                .map_err(|_| DownloadError::TimeoutError(timeout))?
+                        }
+                        None => remote.read(&mut buffer[..len]).await,
+                    };
+                    read.map_err(io_error)
+                };
+                let read = options.or_cancelled(stall.watch(read)).await??;
+                if read == 0 {
+                    break;
+                }
+                stall.record(read)?;
+                options.or_cancelled(throttle.acquire(read)).await?;
+
+                let claimed = chunk.claim(read as u64) as usize;
+                file.write_all_at(Bytes::copy_from_slice(&buffer[..claimed]), offset)
+                    .await
+                    .with_path(output_path)?;
+                chunk.add_written(claimed as u64);
+                offset += claimed as u64;
+            }
+            if self.durability == Durability::FsyncPerChunk {
+                file.sync_data().await.with_path(output_path)?;
+            }
+        }
+    }
+}
+
+fn io_error(error: std::io::Error) -> DownloadError {
+    SftpError::IO(error.to_string()).into()
+}
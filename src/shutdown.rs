@@ -0,0 +1,49 @@
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+/// Stops all downloads of a [`Downloader`](crate::Downloader), see
+/// [`Downloader::shutdown`](crate::Downloader::shutdown).
+pub(crate) struct Shutdown {
+    token: CancellationToken,
+    /// How many downloads are running.
+    running: watch::Sender<usize>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            token: CancellationToken::new(),
+            running: watch::Sender::new(0),
+        }
+    }
+
+    /// Cancelled once the downloader shuts down.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Counts a download as running until the returned guard is dropped.
+    pub fn enter(&self) -> Running<'_> {
+        self.running.send_modify(|running| *running += 1);
+        Running(&self.running)
+    }
+
+    /// Stops all downloads and waits until they are done cleaning up.
+    pub async fn run(&self) {
+        self.token.cancel();
+        let mut running = self.running.subscribe();
+        let _ = running.wait_for(|running| *running == 0).await;
+    }
+}
+
+pub(crate) struct Running<'a>(&'a watch::Sender<usize>);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.send_modify(|running| *running -= 1);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{
+    checksum::{Checksum, ChecksumAlgorithm, ChecksumSource},
+    download::Downloader,
+    filename,
+    options::DownloadOptions,
+};
+
+/// Sidecar files bigger than this aren't checksum lists, or not ones worth reading.
+const MAX_SIDECAR_SIZE: usize = 1024 * 1024;
+
+/// A place the checksum of a file may be published at.
+struct Sidecar {
+    url: String,
+    algorithm: ChecksumAlgorithm,
+    /// Whether the sidecar lists many files, like `SHA256SUMS`, rather than only this one.
+    lists_many: bool,
+}
+
+impl Downloader {
+    /// Looks for a checksum of the file at `url` next to it, in order in `<url>.sha256`,
+    /// `SHA256SUMS`, `<url>.md5` and `MD5SUMS`, and returns `options` with the first one found.
+    ///
+    /// Sidecars that are missing or can't be read are skipped, it's not an error for a file to
+    /// have none.
+    pub(crate) async fn discover_checksum(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Option<DownloadOptions> {
+        if !self.discover_checksums || options.checksum.is_some() {
+            return None;
+        }
+        let name = filename::from_url(url);
+        for sidecar in sidecars(url) {
+            let Some(contents) = self.fetch_sidecar(&sidecar.url, options).await else {
+                continue;
+            };
+            let expected = parse(&contents, &name, sidecar.algorithm, sidecar.lists_many);
+            if let Some(expected) = expected {
+                debug!(
+                    sidecar = sidecar.url,
+                    "verifying the checksum from a sidecar file"
+                );
+                let checksum = Checksum::new(sidecar.algorithm, &expected);
+                return Some(
+                    options
+                        .clone()
+                        .checksum_from(checksum, ChecksumSource::Sidecar(sidecar.url)),
+                );
+            }
+        }
+        None
+    }
+
+    async fn fetch_sidecar(&self, url: &str, options: &DownloadOptions) -> Option<String> {
+        let _permit = self.hosts.acquire(url).await;
+        let fetch = async {
+            let mut response = self
+                .send_request(url, options, |client| client.get(url))
+                .await
+                .ok()?;
+            if response
+                .content_length()
+                .is_some_and(|len| len > MAX_SIDECAR_SIZE as u64)
+            {
+                return None;
+            }
+            let mut body = Vec::new();
+            while let Some(bytes) = response.chunk().await.ok()? {
+                body.extend_from_slice(&bytes);
+                if body.len() > MAX_SIDECAR_SIZE {
+                    return None;
+                }
+            }
+            String::from_utf8(body).ok()
+        };
+        options.or_cancelled(fetch).await.ok().flatten()
+    }
+}
+
+/// Returns the sidecars that may hold the checksum of the file at `url`.
+fn sidecars(url: &str) -> Vec<Sidecar> {
+    let Ok(url) = url::Url::parse(url) else {
+        return Vec::new();
+    };
+    let path = url.path().to_owned();
+    let Some((dir, name)) = path.rsplit_once('/') else {
+        return Vec::new();
+    };
+    if name.is_empty() {
+        return Vec::new();
+    }
+
+    let with_path = |path: String| {
+        let mut url = url.clone();
+        url.set_path(&path);
+        url.set_fragment(None);
+        url.to_string()
+    };
+    [
+        (ChecksumAlgorithm::Sha256, "sha256", "SHA256SUMS"),
+        (ChecksumAlgorithm::Md5, "md5", "MD5SUMS"),
+    ]
+    .into_iter()
+    .flat_map(|(algorithm, extension, list)| {
+        [
+            Sidecar {
+                url: with_path(format!("{}.{}", path, extension)),
+                algorithm,
+                lists_many: false,
+            },
+            Sidecar {
+                url: with_path(format!("{}/{}", dir, list)),
+                algorithm,
+                lists_many: true,
+            },
+        ]
+    })
+    .collect()
+}
+
+/// Finds the hex encoded digest of the file called `name` in the `contents` of a sidecar.
+///
+/// Understands the output of `sha256sum` and `md5sum`, `<digest>  <name>` with a `*` before the
+/// name in binary mode, and the BSD style `SHA256 (<name>) = <digest>`. A sidecar of a single
+/// file may hold just the digest, or list it under another name.
+fn parse(
+    contents: &str,
+    name: &str,
+    algorithm: ChecksumAlgorithm,
+    lists_many: bool,
+) -> Option<String> {
+    let entries: Vec<_> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(parse_line)
+        .filter(|(digest, _)| algorithm.is_hex_digest(digest))
+        .collect();
+
+    let matching = entries.iter().find(|(_, listed)| {
+        listed.is_some_and(|listed| listed.strip_prefix("./").unwrap_or(listed) == name)
+    });
+    let entry = match (matching, entries.as_slice()) {
+        (Some(entry), _) => entry,
+        (None, [entry]) if !lists_many || entry.1.is_none() => entry,
+        _ => return None,
+    };
+    Some(entry.0.to_ascii_lowercase())
+}
+
+/// Splits a line of a checksum list into the digest and the name of the file, if it has one.
+fn parse_line(line: &str) -> Option<(&str, Option<&str>)> {
+    // BSD style: `SHA256 (name) = digest`.
+    if let Some((listed, digest)) = line.rsplit_once(") = ") {
+        let (_, name) = listed.split_once(" (")?;
+        return Some((digest.trim(), Some(name)));
+    }
+    match line.split_once(char::is_whitespace) {
+        Some((digest, name)) => {
+            let name = name.trim_start();
+            Some((digest, Some(name.strip_prefix('*').unwrap_or(name))))
+        }
+        None => Some((line, None)),
+    }
+}
@@ -0,0 +1,61 @@
+//! Authenticated downloads from cloud storage services, which sign every request instead of
+//! sending fixed [`Credentials`](crate::Credentials).
+
+use crate::{download::Downloader, error::DownloadError, options::DownloadOptions};
+use hmac::{Hmac, Mac};
+use reqwest::{Request, RequestBuilder};
+use sha2::Sha256;
+
+/// Adds the authentication of a storage service to requests.
+pub(crate) trait RequestSigner: Send + Sync {
+    /// Signs `request`, or returns it unchanged if it isn't sent to the service.
+    fn sign(&self, request: RequestBuilder) -> RequestBuilder;
+}
+
+impl Downloader {
+    /// Translates `s3://`, `gs://` and `az://` URLs to the HTTP URLs of their objects, and returns
+    /// options that authenticate the requests to them.
+    ///
+    /// Returns `None` for URLs that aren't stored with one of the enabled services.
+    pub(crate) fn resolve_storage_url(
+        &self,
+        url: &str,
+        options: &DownloadOptions,
+    ) -> Result<Option<(String, DownloadOptions)>, DownloadError> {
+        #[cfg(feature = "s3")]
+        if crate::s3::is_s3_url(url) {
+            return self.s3.resolve(url, options).map(Some);
+        }
+        #[cfg(feature = "gcs")]
+        if crate::gcs::is_gcs_url(url) {
+            return self.gcs.resolve(url, options).map(Some);
+        }
+        #[cfg(feature = "azure")]
+        if crate::azure::is_azure_url(url) {
+            return self.azure.resolve(url, options).map(Some);
+        }
+        Ok(None)
+    }
+}
+
+/// Builds a copy of `request` to read its method, URL and headers from.
+///
+/// Returns `None` if it can't be built, in which case sending it fails anyway.
+pub(crate) fn inspect(request: &RequestBuilder) -> Option<Request> {
+    request.try_clone()?.build().ok()
+}
+
+/// The `Host` header sent to `url`, which includes the port unless it's the default one.
+pub(crate) fn host_header(url: &url::Url) -> Option<String> {
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned(),
+    })
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
@@ -0,0 +1,99 @@
+use crate::{
+    download::{create_parent_dir, Downloader},
+    error::{DownloadError, IoResultExt},
+    options::Durability,
+    storage,
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+/// Extension of the partial files in the staging directory.
+const PARTIAL_EXTENSION: &str = "part";
+
+/// A directory that downloads are written to until they complete, like one on a faster or
+/// unwatched disk, so the output directory only ever holds complete files.
+pub(crate) struct Staging {
+    pub dir: PathBuf,
+}
+
+impl Staging {
+    /// Where the file saved to `output_path` in `output_dir` is written until it's complete, at
+    /// the same path below the staging directory with `.part` appended.
+    fn partial_path(&self, output_dir: &Path, output_path: &Path) -> PathBuf {
+        let relative = output_path
+            .strip_prefix(output_dir)
+            .ok()
+            .or_else(|| output_path.file_name().map(Path::new))
+            .unwrap_or(Path::new("unnamed"));
+        let mut path = self.dir.join(relative).into_os_string();
+        path.push(".");
+        path.push(PARTIAL_EXTENSION);
+        PathBuf::from(path)
+    }
+}
+
+impl Downloader {
+    /// Where a download to `output_path` writes until it's complete, which is `output_path`
+    /// itself unless there's a staging directory.
+    pub(crate) fn partial_path(&self, output_path: &Path) -> PathBuf {
+        match &self.staging {
+            Some(staging) => staging.partial_path(&self.output_dir, output_path),
+            None => output_path.to_owned(),
+        }
+    }
+
+    /// Moves the complete download at `partial` to `output_path`.
+    ///
+    /// If the staging directory is on another filesystem, the file is copied next to the output
+    /// path under a hidden name first and then renamed, so the output path never holds a partial
+    /// copy.
+    pub(crate) async fn move_into_place(
+        &self,
+        partial: &Path,
+        output_path: &Path,
+    ) -> Result<(), DownloadError> {
+        if partial == output_path {
+            return Ok(());
+        }
+        create_parent_dir(output_path).await?;
+        match fs::rename(partial, output_path).await {
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+            result => return self.moved(result, output_path).await,
+        }
+
+        debug!(path = %output_path.display(), "copying download out of the staging directory");
+        let name = output_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let temp = output_path.with_file_name(format!(".{}.{}", name, PARTIAL_EXTENSION));
+        let copied = match fs::copy(partial, &temp).await {
+            Ok(_) => self.make_durable(&temp).await,
+            Err(e) => Err(e).with_path(&temp),
+        };
+        if let Err(e) = copied {
+            let _ = fs::remove_file(&temp).await;
+            return Err(e);
+        }
+        self.moved(fs::rename(&temp, output_path).await, output_path)
+            .await?;
+        fs::remove_file(partial).await.with_path(partial)
+    }
+
+    /// Finishes a rename to `output_path`, forcing its directory entry to disk if the durability
+    /// policy asks for it.
+    async fn moved(&self, result: io::Result<()>, output_path: &Path) -> Result<(), DownloadError> {
+        result.with_path(output_path)?;
+        match self.durability {
+            Durability::FsyncOnComplete | Durability::FsyncPerChunk => {
+                storage::sync_parent_dir(output_path)
+                    .await
+                    .with_path(output_path)
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::error::DownloadError;
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+/// The slowest a connection may transfer for a while before it is given up on.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MinSpeed {
+    pub bytes_per_sec: u64,
+    pub time: Duration,
+}
+
+/// Measures the speed of a response body over windows of [`MinSpeed::time`] and fails once a
+/// window is slower than the minimum.
+pub(crate) struct StallDetector {
+    min_speed: Option<MinSpeed>,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl StallDetector {
+    pub fn new(min_speed: Option<MinSpeed>) -> Self {
+        Self {
+            min_speed,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Starts a new window, for example because the time since the last one was spent paused.
+    pub fn reset(&mut self) {
+        self.window_start = Instant::now();
+        self.window_bytes = 0;
+    }
+
+    /// Runs `read`, checking the speed whenever a window ends while it is still waiting for data.
+    pub async fn watch<F, T>(&mut self, read: F) -> Result<T, DownloadError>
+    where
+        F: Future<Output = Result<T, DownloadError>>,
+    {
+        tokio::pin!(read);
+        loop {
+            let Some(min_speed) = self.min_speed else {
+                return read.await;
+            };
+            let window_end = self.window_start + min_speed.time;
+            match tokio::time::timeout_at(window_end.into(), &mut read).await {
+                Ok(output) => return output,
+                Err(_) => self.check(min_speed)?,
+            }
+        }
+    }
+
+    /// Counts `bytes` that were received and checks the speed if the window is over.
+    pub fn record(&mut self, bytes: usize) -> Result<(), DownloadError> {
+        self.window_bytes += bytes as u64;
+        match self.min_speed {
+            Some(min_speed) if self.window_start.elapsed() >= min_speed.time => {
+                self.check(min_speed)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check(&mut self, min_speed: MinSpeed) -> Result<(), DownloadError> {
+        let elapsed = self.window_start.elapsed().as_secs_f64();
+        if (self.window_bytes as f64) < min_speed.bytes_per_sec as f64 * elapsed {
+            return Err(DownloadError::TooSlow {
+                bytes_per_sec: min_speed.bytes_per_sec,
+                time: min_speed.time,
+            });
+        }
+        self.reset();
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use crate::{error::DownloadError, options::Allocation};
+use bytes::Bytes;
+use std::{io, path::Path, sync::Arc};
+use tokio::fs;
+
+/// Space that has to be left beyond the file itself, for the resume sidecar and file system overhead.
+const SPACE_MARGIN: u64 = 1024 * 1024;
+
+/// Fails with [`DownloadError::InsufficientDiskSpace`] if the file system `path` is on doesn't have
+/// room for `needed` more bytes.
+///
+/// Passes if the free space can't be determined.
+pub(crate) fn check_space(path: &Path, needed: u64) -> Result<(), DownloadError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let required = needed.saturating_add(SPACE_MARGIN);
+    match available_space(dir) {
+        Some(available) if available < required => Err(DownloadError::InsufficientDiskSpace {
+            path: path.to_owned(),
+            required,
+            available,
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn available_space(dir: &Path) -> Option<u64> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `dir` is a valid C string and `stat` is large enough for the result.
+    if unsafe { libc::statvfs(dir.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: `statvfs` succeeded, so it filled in `stat`.
+    let stat = unsafe { stat.assume_init() };
+    // The field types differ between targets.
+    #[allow(clippy::unnecessary_cast)]
+    let available = (stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64);
+    Some(available)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_space(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// Forces the contents and metadata of the file at `path` to disk.
+pub(crate) async fn sync(path: &Path) -> io::Result<()> {
+    let file = fs::OpenOptions::new().write(true).open(path).await?;
+    file.sync_all().await
+}
+
+/// Forces the contents of the file at `path` to disk, without metadata that isn't needed to read
+/// them back.
+pub(crate) async fn sync_data(path: &Path) -> io::Result<()> {
+    let file = fs::OpenOptions::new().write(true).open(path).await?;
+    file.sync_data().await
+}
+
+/// Forces the directory entry of the file at `path` to disk.
+#[cfg(unix)]
+pub(crate) async fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir).await?.sync_all().await
+}
+
+/// Directories can't be opened for syncing here, their entries are written with the file.
+#[cfg(not(unix))]
+pub(crate) async fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// An output file that all connections of a download write to, each at its own offset.
+///
+/// Writes are positional, so the connections don't share a file position or open the file again.
+#[derive(Clone)]
+pub(crate) struct SharedFile(Arc<std::fs::File>);
+
+impl SharedFile {
+    pub fn new(file: std::fs::File) -> Self {
+        Self(Arc::new(file))
+    }
+
+    #[cfg(any(all(target_os = "linux", feature = "io-uring"), feature = "mmap"))]
+    pub fn handle(&self) -> &Arc<std::fs::File> {
+        &self.0
+    }
+
+    /// Writes all of `buf` at `offset`, on tokio's blocking thread pool.
+    pub async fn write_all_at(&self, buf: Bytes, offset: u64) -> io::Result<()> {
+        let file = self.0.clone();
+        tokio::task::spawn_blocking(move || write_all_at(&file, &buf, offset)).await?
+    }
+
+    pub async fn sync_data(&self) -> io::Result<()> {
+        let file = self.0.clone();
+        tokio::task::spawn_blocking(move || file.sync_data()).await?
+    }
+
+    /// Reads exactly `buf.len()` bytes at `offset`, blocking the thread.
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read_exact_at(&self.0, buf, offset)
+    }
+
+    /// Returns where the first hole at or after `offset` starts, or the length of the file if
+    /// there's none.
+    pub fn next_hole(&self, offset: u64) -> io::Result<u64> {
+        next_hole(&self.0, offset)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn next_hole(file: &std::fs::File, offset: u64) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let len = file.metadata()?.len();
+    let Ok(start) = libc::off_t::try_from(offset) else {
+        return Ok(len);
+    };
+    if offset >= len {
+        return Ok(len);
+    }
+    // SAFETY: the descriptor stays valid because `file` is borrowed for the whole call.
+    let hole = unsafe { libc::lseek(file.as_raw_fd(), start, libc::SEEK_HOLE) };
+    if hole >= 0 {
+        return Ok(hole as u64);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // The file system doesn't tell where holes are.
+        Some(libc::EINVAL) => Ok(len),
+        _ => Err(err),
+    }
+}
+
+/// Holes can't be found here, so the whole file counts as data.
+#[cfg(not(target_os = "linux"))]
+fn next_hole(file: &std::fs::File, _offset: u64) -> io::Result<u64> {
+    Ok(file.metadata()?.len())
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &std::fs::File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &std::fs::File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &std::fs::File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    // `seek_write` moves the file position too, but no writer relies on it.
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => {
+                buf = &buf[written..];
+                offset += written as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Creates the output file and sizes it to `len` bytes before parallel writes start, returning
+/// the handle they write through.
+///
+/// With [`Allocation::Reserve`], disk blocks are reserved up front on Linux so running out of
+/// space fails here instead of partway through the download. Otherwise the file is only resized,
+/// which leaves it sparse on most file systems.
+pub(crate) async fn preallocate(
+    path: &Path,
+    len: u64,
+    allocation: Allocation,
+) -> io::Result<SharedFile> {
+    // Readable too, so the file can be memory-mapped.
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .await?;
+
+    if file.metadata().await?.len() != len {
+        file.set_len(len).await?;
+    }
+
+    let file = file.into_std().await;
+    if allocation == Allocation::Reserve {
+        return tokio::task::spawn_blocking(move || {
+            allocate_blocks(&file, len)?;
+            Ok(SharedFile::new(file))
+        })
+        .await?;
+    }
+
+    Ok(SharedFile::new(file))
+}
+
+#[cfg(target_os = "linux")]
+fn allocate_blocks(file: &std::fs::File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let Ok(len) = libc::off_t::try_from(len) else {
+        return Ok(());
+    };
+
+    // SAFETY: the descriptor stays valid because `file` is borrowed for the whole call.
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len) };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // The file system can't reserve blocks, the file is still sized correctly.
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(()),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate_blocks(_file: &std::fs::File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
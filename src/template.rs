@@ -0,0 +1,228 @@
+use crate::filename;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use std::{path::Path, time::SystemTime};
+
+/// The template of [`host_directories`](crate::DownloaderBuilder::host_directories).
+pub(crate) const HOST_DIRECTORIES: &str = "{host}/{path}/{filename}";
+
+/// Fills in the tokens of an output path template like `{host}/{date}/{filename}`.
+///
+/// Unknown tokens are kept as they are. Values are sanitized so they can't leave the output directory.
+pub(crate) fn render(template: &str, url: &str, filename: &str, headers: &HeaderMap) -> String {
+    let parsed = url::Url::parse(url).ok();
+    let mut out = String::new();
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let Some(close) = rest[open..].find('}') else {
+            break;
+        };
+        let token = &rest[open + 1..open + close];
+        rest = &rest[open + close + 1..];
+
+        match token {
+            "filename" => out.push_str(&component(filename)),
+            "stem" => out.push_str(&component(&stem(filename))),
+            "ext" => out.push_str(&component(&extension(filename, headers))),
+            "host" => out.push_str(&component(
+                parsed
+                    .as_ref()
+                    .and_then(|u| u.host_str())
+                    .unwrap_or("unknown"),
+            )),
+            "path" => out.push_str(&directories(parsed.as_ref())),
+            "date" => out.push_str(&today()),
+            "hash" => out.push_str(&filename::url_hash(url)),
+            _ => {
+                out.push('{');
+                out.push_str(token);
+                out.push('}');
+            }
+        }
+    }
+    out.push_str(rest);
+
+    // Drop empty components, e.g. from an empty `{path}`, and ones like `..`.
+    let components: Vec<_> = out
+        .split('/')
+        .map(filename::sanitize)
+        .filter(|c| !c.is_empty())
+        .collect();
+    if components.is_empty() {
+        component(filename)
+    } else {
+        components.join("/")
+    }
+}
+
+/// The directories at the start of `template` that don't contain tokens, which are the same for
+/// every file.
+pub(crate) fn fixed_directories(template: &str) -> Vec<String> {
+    let mut components: Vec<_> = template.split('/').collect();
+    components.pop();
+    components
+        .into_iter()
+        .take_while(|c| !c.contains('{'))
+        .map(filename::sanitize)
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+/// Makes `value` usable as a single path component.
+fn component(value: &str) -> String {
+    filename::sanitize(value)
+}
+
+fn stem(filename: &str) -> String {
+    Path::new(filename)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| filename.to_owned())
+}
+
+fn extension(filename: &str, headers: &HeaderMap) -> String {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(filename::extension_for_content_type)
+        .map(str::to_owned)
+        .or_else(|| {
+            Path::new(filename)
+                .extension()
+                .map(|e| e.to_string_lossy().into_owned())
+        })
+        .unwrap_or_default()
+}
+
+/// The decoded directories of the URL path, without the file name.
+fn directories(url: Option<&url::Url>) -> String {
+    let Some(mut segments) = url.and_then(|u| u.path_segments()) else {
+        return String::new();
+    };
+    segments.next_back();
+    segments
+        .filter_map(|segment| {
+            let decoded = percent_encoding::percent_decode_str(segment).decode_utf8_lossy();
+            filename::base_name(&decoded)
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Today's date in UTC as `YYYY-MM-DD`.
+fn today() -> String {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Converts days since 1970-01-01 to a date in the proleptic Gregorian calendar.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    const URL: &str = "https://files.example.com/pub/v1.2/report.pdf?x=1";
+
+    fn render_url(template: &str) -> String {
+        render(template, URL, "report.pdf", &HeaderMap::new())
+    }
+
+    #[test]
+    fn fills_in_tokens() {
+        assert_eq!(
+            render_url("{host}/{filename}"),
+            "files.example.com/report.pdf"
+        );
+        assert_eq!(
+            render_url("{stem}-{hash}.{ext}"),
+            format!("report-{}.pdf", filename::url_hash(URL))
+        );
+        assert_eq!(render_url("{path}/{filename}"), "pub/v1.2/report.pdf");
+        assert_eq!(
+            render_url("{host}/{unknown}/{filename}"),
+            "files.example.com/{unknown}/report.pdf"
+        );
+        assert_eq!(
+            render_url("{date}/{filename}").len(),
+            "YYYY-MM-DD/report.pdf".len()
+        );
+        assert_eq!(render_url("{filename"), "{filename");
+    }
+
+    #[test]
+    fn extension_from_the_content_type() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        assert_eq!(render("{stem}.{ext}", URL, "image", &headers), "image.png");
+        assert_eq!(
+            render("{stem}.{ext}", URL, "image", &HeaderMap::new()),
+            "image"
+        );
+    }
+
+    #[test]
+    fn stays_in_the_output_directory() {
+        let render = |template, url, filename| render(template, url, filename, &HeaderMap::new());
+        assert_eq!(render("../{filename}", URL, "a.txt"), "a.txt");
+        assert_eq!(
+            render("/{host}/../../{filename}", URL, "a.txt"),
+            "files.example.com/a.txt"
+        );
+        assert_eq!(
+            render(
+                "{path}/{filename}",
+                "https://example.com/a/%2e%2e/..%2F..%2Fb/c.txt",
+                "c.txt"
+            ),
+            "b/c.txt"
+        );
+        assert_eq!(render("{stem}", URL, "a/../b"), "b");
+        // Falls back to the file name if nothing is left.
+        assert_eq!(
+            render("{path}", "https://example.com/a.txt", "a.txt"),
+            "a.txt"
+        );
+        assert_eq!(
+            render("{host}/{filename}", "not a url", "a.txt"),
+            "unknown/a.txt"
+        );
+    }
+
+    #[test]
+    fn fixed_directories_before_tokens() {
+        assert_eq!(
+            fixed_directories("archive/raw/{host}/{filename}"),
+            ["archive", "raw"]
+        );
+        assert_eq!(fixed_directories("../out/{filename}"), ["out"]);
+        assert!(fixed_directories("{host}/fixed/{filename}").is_empty());
+        assert!(fixed_directories("{filename}").is_empty());
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(20_454), (2026, 1, 1));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::metrics::{self, ActiveConnection};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A token bucket limiting how many bytes per second pass through it.
+///
+/// Consumers may overdraw the bucket; they then wait until the debt has been paid off,
+/// so bursts larger than the bucket's capacity are still throttled correctly.
+pub(crate) struct RateLimiter {
+    bytes_per_sec: f64,
+    capacity: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a limiter that allows bursts of up to one second worth of data.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self {
+            bytes_per_sec,
+            capacity: bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` tokens from the bucket, waiting if it ran dry.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last_refill).as_secs_f64() * self.bytes_per_sec;
+            bucket.tokens = (bucket.tokens + refill).min(self.capacity) - bytes as f64;
+            bucket.last_refill = now;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Applies the global and the per-connection limit to a single connection.
+#[derive(Clone, Default)]
+pub(crate) struct Throttle {
+    global: Option<Arc<RateLimiter>>,
+    per_connection: Option<u64>,
+}
+
+impl Throttle {
+    pub fn new(global: Option<Arc<RateLimiter>>, per_connection: Option<u64>) -> Self {
+        Self {
+            global,
+            per_connection,
+        }
+    }
+
+    /// Returns the limiter for a new connection.
+    pub fn connection(&self) -> ConnectionThrottle {
+        ConnectionThrottle {
+            global: self.global.clone(),
+            own: self.per_connection.map(RateLimiter::new),
+            _active: ActiveConnection::open(),
+        }
+    }
+}
+
+/// Every connection that receives a file has one, and all of its bytes pass through it, so it
+/// also records them in the metrics.
+pub(crate) struct ConnectionThrottle {
+    global: Option<Arc<RateLimiter>>,
+    own: Option<RateLimiter>,
+    _active: ActiveConnection,
+}
+
+impl ConnectionThrottle {
+    /// Waits until `bytes` more bytes may be received on this connection.
+    pub async fn acquire(&self, bytes: usize) {
+        metrics::record_bytes(bytes);
+        if let Some(own) = &self.own {
+            own.acquire(bytes).await;
+        }
+        if let Some(global) = &self.global {
+            global.acquire(bytes).await;
+        }
+    }
+}
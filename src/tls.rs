@@ -0,0 +1,141 @@
+use crate::{
+    error::DownloadError,
+    pinning::{CertificatePins, PinVerifier},
+};
+use reqwest::{Certificate, ClientBuilder, Identity};
+use rustls::{client::WebPkiVerifier, ClientConfig, PrivateKey, RootCertStore};
+use std::sync::Arc;
+
+/// Certificates and verification settings for TLS connections.
+#[derive(Clone, Default)]
+pub(crate) struct TlsConfig {
+    /// PEM encoded CA certificates trusted in addition to the system's.
+    pub root_certificates: Vec<Vec<u8>>,
+    /// A PEM encoded client certificate chain and its PKCS #8 private key.
+    pub identity: Option<(Vec<u8>, Vec<u8>)>,
+    pub accept_invalid_certs: bool,
+}
+
+impl TlsConfig {
+    /// Applies the settings to an HTTP client, which uses rustls if `rustls` is set and native TLS
+    /// otherwise.
+    pub fn apply(
+        &self,
+        mut builder: ClientBuilder,
+        rustls: bool,
+    ) -> Result<ClientBuilder, DownloadError> {
+        for pem in &self.root_certificates {
+            for certificate in Certificate::from_pem_bundle(pem)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if let Some((cert, key)) = &self.identity {
+            // The identity has to be loaded for the TLS backend that uses it.
+            let identity = match rustls {
+                #[cfg(feature = "http3")]
+                true => Identity::from_pem(&[cert.as_slice(), b"\n", key].concat())?,
+                _ => Identity::from_pkcs8_pem(cert, key)?,
+            };
+            builder = builder.identity(identity);
+        }
+        if self.accept_invalid_certs {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder)
+    }
+
+    /// Creates the rustls configuration for clients that refuse servers whose certificate has no
+    /// key in `pins` during the handshake, with the same settings. Those of reqwest can't be
+    /// combined with a custom verifier.
+    pub fn pinned(
+        &self,
+        pins: Arc<CertificatePins>,
+        http2: bool,
+    ) -> Result<ClientConfig, DownloadError> {
+        let invalid = |e: &dyn std::fmt::Display| DownloadError::InvalidTls(e.to_string());
+        let mut roots = RootCertStore::empty();
+        // Like reqwest, skips system certificates rustls can't parse.
+        for certificate in rustls_native_certs::load_native_certs().unwrap_or_default() {
+            let _ = roots.add(&rustls::Certificate(certificate.0));
+        }
+        for pem in &self.root_certificates {
+            for certificate in rustls_pemfile::certs(&mut &pem[..]).map_err(|e| invalid(&e))? {
+                roots
+                    .add(&rustls::Certificate(certificate))
+                    .map_err(|e| invalid(&e))?;
+            }
+        }
+        let verifier = PinVerifier {
+            chain: (!self.accept_invalid_certs).then(|| WebPkiVerifier::new(roots, None)),
+            pins,
+        };
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier));
+        let mut config = match &self.identity {
+            Some((cert, key)) => {
+                let chain = rustls_pemfile::certs(&mut &cert[..]).map_err(|e| invalid(&e))?;
+                let key = rustls_pemfile::pkcs8_private_keys(&mut &key[..])
+                    .map_err(|e| invalid(&e))?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| invalid(&"no PKCS #8 private key"))?;
+                builder
+                    .with_client_auth_cert(
+                        chain.into_iter().map(rustls::Certificate).collect(),
+                        PrivateKey(key),
+                    )
+                    .map_err(|e| invalid(&e))?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = match http2 {
+            true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            false => vec![b"http/1.1".to_vec()],
+        };
+        Ok(config)
+    }
+
+    /// Creates a connector for FTPS connections with the same settings.
+    #[cfg(feature = "ftp")]
+    pub fn ftp_connector(
+        &self,
+    ) -> Result<suppaftp::async_native_tls::TlsConnector, suppaftp::FtpError> {
+        use suppaftp::{
+            async_native_tls::{Certificate, Identity, TlsConnector},
+            FtpError,
+        };
+
+        let invalid = |e: suppaftp::async_native_tls::Error| FtpError::SecureError(e.to_string());
+        let mut connector = TlsConnector::new();
+        for pem in &self.root_certificates {
+            for certificate in pem_blocks(pem) {
+                connector = connector
+                    .add_root_certificate(Certificate::from_pem(certificate).map_err(invalid)?);
+            }
+        }
+        if let Some((cert, key)) = &self.identity {
+            connector = connector.identity(Identity::from_pkcs8(cert, key).map_err(invalid)?);
+        }
+        if self.accept_invalid_certs {
+            connector = connector
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        Ok(connector)
+    }
+}
+
+/// Splits a PEM bundle into its certificates, which native TLS only parses one at a time.
+#[cfg(feature = "ftp")]
+fn pem_blocks(pem: &[u8]) -> impl Iterator<Item = &[u8]> {
+    const END: &[u8] = b"-----END CERTIFICATE-----";
+    let mut rest = pem;
+    std::iter::from_fn(move || {
+        let end = rest.windows(END.len()).position(|w| w == END)? + END.len();
+        let (block, next) = rest.split_at(end);
+        rest = next;
+        Some(block)
+    })
+}
@@ -0,0 +1,734 @@
+//! Downloads of torrents from their HTTP web seeds ([BEP 19](https://www.bittorrent.org/beps/bep_0019.html)),
+//! enabled with the `torrent` feature.
+//!
+//! Only the metadata of a torrent is used, not its peers: pieces are requested from the web seeds
+//! with range requests and verified against their SHA-1 hashes before they're written.
+
+use crate::{
+    checksum,
+    download::{self, Downloader},
+    error::{DownloadError, IoResultExt},
+    filename, metrics,
+    mirrors::Mirrors,
+    options::DownloadOptions,
+    progress::SpeedMeter,
+    segments::{Segment, MAX_SEGMENT_SIZE},
+};
+use futures::{stream, StreamExt};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha1::{Digest, Sha1};
+use std::{
+    io::SeekFrom,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+use url::Url;
+
+/// Characters that are percent-encoded in the path components of web seed URLs.
+const PATH_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Bencoded lists and dictionaries are only parsed this deep.
+const MAX_NESTING: usize = 64;
+
+/// The metadata of a torrent, as read from a `.torrent` file.
+#[derive(Debug, Clone)]
+pub struct Torrent {
+    /// Name of the file, or of the directory the files are in for multi-file torrents.
+    pub name: String,
+    pub piece_length: u64,
+    /// The files in the order their bytes follow each other in the pieces. Single-file torrents
+    /// have one, with an empty path.
+    pub files: Vec<TorrentFile>,
+    /// HTTP URLs serving the files, from the `url-list` of the torrent.
+    pub web_seeds: Vec<String>,
+    pieces: Vec<[u8; 20]>,
+    /// The name before it was sanitized, which web seeds serve the files under.
+    original_name: String,
+}
+
+/// A file of a [`Torrent`].
+#[derive(Debug, Clone)]
+pub struct TorrentFile {
+    /// Path of the file below the directory named after the torrent.
+    pub path: Vec<String>,
+    pub length: u64,
+    /// The path before its components were sanitized.
+    original_path: Vec<String>,
+    /// Padding files only align the next file to a piece, and consist of zeros nobody serves.
+    padding: bool,
+}
+
+impl Torrent {
+    /// Parses the contents of a `.torrent` file. Torrents that only have v2 metadata aren't
+    /// supported.
+    pub fn parse(bytes: &[u8]) -> Result<Self, DownloadError> {
+        let invalid = |reason: &str| DownloadError::InvalidTorrent(reason.to_owned());
+        let (root, _) = Value::parse(bytes).map_err(|e| invalid(&e))?;
+        let info = root
+            .get(b"info")
+            .ok_or_else(|| invalid("no info dictionary"))?;
+        let text =
+            |value: &Value, key: &[u8]| value.get(key).and_then(Value::as_str).map(str::to_owned);
+
+        let utf8_name = text(info, b"name.utf-8");
+        let original_name = utf8_name
+            .or_else(|| text(info, b"name"))
+            .ok_or_else(|| invalid("no name"))?;
+        let name = filename::base_name(&original_name).ok_or_else(|| invalid("invalid name"))?;
+        let piece_length = info
+            .get(b"piece length")
+            .and_then(Value::as_u64)
+            .filter(|&len| len > 0)
+            .ok_or_else(|| invalid("no piece length"))?;
+        // Pieces are verified in memory.
+        if piece_length > MAX_SEGMENT_SIZE {
+            return Err(invalid(&format!(
+                "pieces larger than {} bytes aren't supported",
+                MAX_SEGMENT_SIZE
+            )));
+        }
+        let pieces = info
+            .get(b"pieces")
+            .and_then(Value::as_bytes)
+            .filter(|pieces| pieces.len() % 20 == 0)
+            .ok_or_else(|| invalid("no v1 piece hashes"))?
+            .chunks_exact(20)
+            .map(|hash| hash.try_into().expect("hashes are 20 bytes"))
+            .collect();
+
+        let files = match (info.get(b"length"), info.get(b"files")) {
+            (Some(length), _) => vec![TorrentFile {
+                path: Vec::new(),
+                original_path: Vec::new(),
+                length: length.as_u64().ok_or_else(|| invalid("invalid length"))?,
+                padding: false,
+            }],
+            (None, Some(Value::List(files))) => files
+                .iter()
+                .map(|file| {
+                    let length = file.get(b"length").and_then(Value::as_u64);
+                    let path = file
+                        .get(b"path.utf-8")
+                        .or_else(|| file.get(b"path"))
+                        .and_then(Value::as_list);
+                    let (Some(length), Some(path)) = (length, path) else {
+                        return Err(invalid("file without a length and path"));
+                    };
+                    let original_path = path
+                        .iter()
+                        .map(|component| component.as_str().map(str::to_owned))
+                        .collect::<Option<Vec<_>>>()
+                        .filter(|path| !path.is_empty())
+                        .ok_or_else(|| invalid("invalid file path"))?;
+                    let path = original_path
+                        .iter()
+                        .map(|component| filename::base_name(component))
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| invalid("invalid file path"))?;
+                    let padding = file
+                        .get(b"attr")
+                        .and_then(Value::as_str)
+                        .is_some_and(|attr| attr.contains('p'));
+                    Ok(TorrentFile {
+                        path,
+                        original_path,
+                        length,
+                        padding,
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            (None, _) => return Err(invalid("no length or files")),
+        };
+
+        files
+            .iter()
+            .try_fold(0u64, |total, file| total.checked_add(file.length))
+            .ok_or_else(|| invalid("the files are too large"))?;
+
+        let web_seeds = match root.get(b"url-list") {
+            Some(Value::List(urls)) => urls.iter().filter_map(Value::as_str).collect(),
+            Some(url) => url.as_str().into_iter().collect(),
+            None => Vec::new(),
+        };
+        let web_seeds = web_seeds
+            .into_iter()
+            .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+            .map(str::to_owned)
+            .collect();
+
+        let torrent = Self {
+            name,
+            piece_length,
+            files,
+            web_seeds,
+            pieces,
+            original_name,
+        };
+        if torrent.piece_count() != torrent.pieces.len() as u64 {
+            return Err(invalid("the number of pieces doesn't match the length"));
+        }
+        Ok(torrent)
+    }
+
+    /// Total length of the files, including padding.
+    pub fn total_length(&self) -> u64 {
+        self.files
+            .iter()
+            .fold(0, |total, file| total.saturating_add(file.length))
+    }
+
+    fn piece_count(&self) -> u64 {
+        self.total_length().div_ceil(self.piece_length)
+    }
+
+    /// Returns the path of a file relative to the output directory.
+    fn relative_path(&self, file: &TorrentFile) -> String {
+        std::iter::once(self.name.as_str())
+            .chain(file.path.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Returns the URL a web seed serves `file` at.
+    fn file_url(&self, seed: &str, file: &TorrentFile) -> Result<Url, DownloadError> {
+        let invalid = || DownloadError::InvalidUrl(seed.to_owned());
+        let single = self.files.len() == 1 && file.path.is_empty();
+        if single && !seed.ends_with('/') {
+            return Url::parse(seed).map_err(|_| invalid());
+        }
+        let mut url = seed.to_owned();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        let components = std::iter::once(&self.original_name).chain(&file.original_path);
+        let encoded: Vec<_> = components
+            .map(|component| utf8_percent_encode(component, PATH_COMPONENT).to_string())
+            .collect();
+        url.push_str(&encoded.join("/"));
+        Url::parse(&url).map_err(|_| invalid())
+    }
+
+    /// Returns the pieces of the files that make up piece `index`: the index of each file, and
+    /// the offset and length within it.
+    fn piece_spans(&self, index: u64) -> Vec<(usize, u64, u64)> {
+        let total = self.total_length();
+        let start = index.saturating_mul(self.piece_length).min(total);
+        let end = start.saturating_add(self.piece_length).min(total);
+        let mut spans = Vec::new();
+        let mut file_start: u64 = 0;
+        for (i, file) in self.files.iter().enumerate() {
+            let file_end = file_start.saturating_add(file.length);
+            if file_end > start && file_start < end {
+                let from = start.max(file_start);
+                let to = end.min(file_end);
+                spans.push((i, from - file_start, to - from));
+            }
+            file_start = file_end;
+        }
+        spans
+    }
+}
+
+impl Downloader {
+    /// Downloads the files of the `.torrent` file at `url` from its web seeds. See
+    /// [`Downloader::download_web_seeds`].
+    pub async fn download_torrent(&self, url: &str) -> Result<Vec<PathBuf>, DownloadError> {
+        let torrent = Torrent::parse(&self.download_to_vec(url).await?)?;
+        self.download_web_seeds(&torrent).await
+    }
+
+    /// Downloads the files of `torrent` from its HTTP web seeds, below a directory named after
+    /// it for multi-file torrents, and returns their paths.
+    ///
+    /// Up to `conn_count` pieces are fetched at once, spreading them over the web seeds. Each
+    /// piece is checked against its hash before it's written, and fetched again, from another web
+    /// seed if there is one, according to the retry policy if it doesn't match.
+    pub async fn download_web_seeds(
+        &self,
+        torrent: &Torrent,
+    ) -> Result<Vec<PathBuf>, DownloadError> {
+        let Some((seed, others)) = torrent.web_seeds.split_first() else {
+            return Err(DownloadError::InvalidTorrent(
+                "no HTTP web seeds".to_owned(),
+            ));
+        };
+        let _running = self.shutdown.enter();
+        if self.shutdown.is_shut_down() {
+            return Err(DownloadError::Cancelled);
+        }
+        let options = &DownloadOptions::default().started(seed, self.shutdown.token());
+        let seeds = Mirrors::new(seed, others);
+        for file in &torrent.files {
+            self.check_size(seed, Some(file.length), options)?;
+        }
+
+        let mut paths = Vec::new();
+        let mut files = Vec::new();
+        let mut reservations = Vec::new();
+        for file in &torrent.files {
+            if file.padding {
+                files.push(None);
+                continue;
+            }
+            let reservation = self.reserve_output_path(&torrent.relative_path(file), options);
+            let path = reservation.path.clone();
+            let partial = self.partial_path(&path);
+            reservations.push(reservation);
+            download::create_parent_dir(&partial).await?;
+            let handle = fs::File::create(&partial).await.with_path(&partial)?;
+            handle.set_len(file.length).await.with_path(&partial)?;
+            paths.push(path);
+            files.push(Some((handle, partial)));
+        }
+
+        let result = self
+            .write_pieces(torrent, &seeds, &mut files, options)
+            .await;
+        for ((file, partial), path) in files.into_iter().flatten().zip(&paths) {
+            drop(file);
+            match &result {
+                Ok(()) => {
+                    self.make_durable(&partial).await?;
+                    self.move_into_place(&partial, path).await?;
+                }
+                Err(_) => download::remove_partial(&partial)
+                    .await
+                    .with_path(&partial)?,
+            }
+        }
+        result.map(|()| paths)
+    }
+
+    /// Fetches the pieces of `torrent` and writes each into the files it spans once it arrives.
+    async fn write_pieces(
+        &self,
+        torrent: &Torrent,
+        seeds: &Mirrors,
+        files: &mut [Option<(fs::File, PathBuf)>],
+        options: &DownloadOptions,
+    ) -> Result<(), DownloadError> {
+        let url = seeds.primary();
+        let total = torrent.total_length();
+        let mut pieces = stream::iter(0..torrent.piece_count())
+            .map(|index| async move {
+                let piece = self.fetch_piece(torrent, index, seeds, options).await;
+                (index, piece)
+            })
+            .buffer_unordered(self.conn_count.max(1));
+
+        let mut meter = SpeedMeter::new(0);
+        let mut last_report = Instant::now();
+        let mut written = 0;
+        while let Some((index, piece)) = pieces.next().await {
+            let piece = piece?;
+            let mut offset = 0;
+            for (file, start, len) in torrent.piece_spans(index) {
+                let bytes = &piece[offset..offset + len as usize];
+                offset += len as usize;
+                let Some((file, path)) = &mut files[file] else {
+                    continue;
+                };
+                file.seek(SeekFrom::Start(start)).await.with_path(&*path)?;
+                file.write_all(bytes).await.with_path(&*path)?;
+            }
+            written += piece.len() as u64;
+            if last_report.elapsed() >= self.progress_interval {
+                self.report_sequential_progress(url, options, written, Some(total), &mut meter);
+                last_report = Instant::now();
+            }
+        }
+        for (file, path) in files.iter_mut().flatten() {
+            file.flush().await.with_path(&*path)?;
+        }
+        self.report_sequential_progress(url, options, written, Some(total), &mut meter);
+        Ok(())
+    }
+
+    /// Fetches and verifies piece `index`, moving to another web seed after a failure.
+    async fn fetch_piece(
+        &self,
+        torrent: &Torrent,
+        index: u64,
+        seeds: &Mirrors,
+        options: &DownloadOptions,
+    ) -> Result<Vec<u8>, DownloadError> {
+        let mut seed = seeds.assign();
+        let mut attempt = 1;
+        let mut retry_after_waited = Duration::ZERO;
+        loop {
+            let error = match self
+                .fetch_piece_from(torrent, index, seeds.url(seed), options)
+                .await
+            {
+                Ok(piece) => return Ok(piece),
+                Err(e) => e,
+            };
+            match self
+                .retry
+                .retry_delay(attempt, &error, &mut retry_after_waited)
+            {
+                Some(delay) => {
+                    warn!(piece = index, seed = seeds.url(seed), error = %error, "piece failed, retrying");
+                    metrics::record_retry();
+                    options.or_cancelled(tokio::time::sleep(delay)).await?;
+                    seed = seeds.failover(seed);
+                    attempt += 1;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+
+    async fn fetch_piece_from(
+        &self,
+        torrent: &Torrent,
+        index: u64,
+        seed: &str,
+        options: &DownloadOptions,
+    ) -> Result<Vec<u8>, DownloadError> {
+        let mut piece = Vec::new();
+        let mut url = None;
+        for (file, start, len) in torrent.piece_spans(index) {
+            let file = &torrent.files[file];
+            if file.padding {
+                piece.resize(piece.len() + len as usize, 0);
+                continue;
+            }
+            let segment = Segment {
+                url: torrent.file_url(seed, file)?,
+                range: Some((start, len)),
+            };
+            piece.extend(self.fetch_segment_once(&segment, options).await?);
+            url = Some(segment.url);
+        }
+
+        let expected = &torrent.pieces[index as usize];
+        let actual: [u8; 20] = Sha1::digest(&piece).into();
+        if actual != *expected {
+            return Err(DownloadError::DigestMismatch {
+                url: url.map_or_else(|| seed.to_owned(), |url| url.to_string()),
+                expected: checksum::to_hex(expected),
+                actual: checksum::to_hex(&actual),
+            });
+        }
+        Ok(piece)
+    }
+}
+
+/// A bencoded value.
+enum Value<'a> {
+    Integer(i64),
+    Bytes(&'a [u8]),
+    List(Vec<Value<'a>>),
+    Dictionary(Vec<(&'a [u8], Value<'a>)>),
+}
+
+impl<'a> Value<'a> {
+    /// Parses the value at the start of `input`, returning it and what follows it.
+    fn parse(input: &'a [u8]) -> Result<(Self, &'a [u8]), String> {
+        Self::parse_nested(input, 0)
+    }
+
+    fn parse_nested(input: &'a [u8], depth: usize) -> Result<(Self, &'a [u8]), String> {
+        let truncated = || "truncated bencoded value".to_owned();
+        if depth > MAX_NESTING {
+            return Err("bencoded values are nested too deeply".to_owned());
+        }
+        match input.first().ok_or_else(truncated)? {
+            b'i' => {
+                let end = input
+                    .iter()
+                    .position(|&b| b == b'e')
+                    .ok_or_else(truncated)?;
+                let integer = std::str::from_utf8(&input[1..end])
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or("invalid bencoded integer")?;
+                Ok((Self::Integer(integer), &input[end + 1..]))
+            }
+            b'l' => {
+                let mut rest = &input[1..];
+                let mut items = Vec::new();
+                while rest.first().ok_or_else(truncated)? != &b'e' {
+                    let (item, after) = Self::parse_nested(rest, depth + 1)?;
+                    items.push(item);
+                    rest = after;
+                }
+                Ok((Self::List(items), &rest[1..]))
+            }
+            b'd' => {
+                let mut rest = &input[1..];
+                let mut entries = Vec::new();
+                while rest.first().ok_or_else(truncated)? != &b'e' {
+                    let (Self::Bytes(key), after) = Self::parse_nested(rest, depth + 1)? else {
+                        return Err("bencoded dictionary key is not a string".to_owned());
+                    };
+                    let (value, after) = Self::parse_nested(after, depth + 1)?;
+                    entries.push((key, value));
+                    rest = after;
+                }
+                Ok((Self::Dictionary(entries), &rest[1..]))
+            }
+            b'0'..=b'9' => {
+                let colon = input
+                    .iter()
+                    .position(|&b| b == b':')
+                    .ok_or_else(truncated)?;
+                let len: usize = std::str::from_utf8(&input[..colon])
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or("invalid bencoded string length")?;
+                let start = colon + 1;
+                let end = start.checked_add(len).ok_or_else(truncated)?;
+                let bytes = input.get(start..end).ok_or_else(truncated)?;
+                Ok((Self::Bytes(bytes), &input[end..]))
+            }
+            _ => Err("invalid bencoded value".to_owned()),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Self> {
+        match self {
+            Self::Dictionary(entries) => entries.iter().find(|(k, _)| *k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Integer(integer) => u64::try_from(*integer).ok(),
+            _ => None,
+        }
+    }
+
+    fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            Self::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&'a str> {
+        self.as_bytes()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    fn as_list(&self) -> Option<&[Self]> {
+        match self {
+            Self::List(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(bytes: &[u8]) -> Vec<u8> {
+        let mut encoded = format!("{}:", bytes.len()).into_bytes();
+        encoded.extend_from_slice(bytes);
+        encoded
+    }
+
+    fn int(value: i64) -> Vec<u8> {
+        format!("i{}e", value).into_bytes()
+    }
+
+    fn list(items: &[Vec<u8>]) -> Vec<u8> {
+        [b"l".to_vec(), items.concat(), b"e".to_vec()].concat()
+    }
+
+    fn dict(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let entries: Vec<_> = entries
+            .iter()
+            .flat_map(|(key, value)| [string(key.as_bytes()), value.clone()])
+            .collect();
+        [b"d".to_vec(), entries.concat(), b"e".to_vec()].concat()
+    }
+
+    fn torrent(info: &[(&str, Vec<u8>)], pieces: usize) -> Vec<u8> {
+        let mut info = info.to_vec();
+        info.push(("pieces", string(&vec![7; 20 * pieces])));
+        dict(&[
+            ("url-list", string(b"https://seed.example.com/files/")),
+            ("info", dict(&info)),
+        ])
+    }
+
+    fn file(path: &[&str], length: i64) -> Vec<u8> {
+        let path: Vec<_> = path.iter().map(|c| string(c.as_bytes())).collect();
+        dict(&[("length", int(length)), ("path", list(&path))])
+    }
+
+    #[test]
+    fn parses_bencoded_values() {
+        let (value, rest) = Value::parse(b"d3:keyli-3e4:spamee!").unwrap();
+        assert_eq!(rest, b"!");
+        let items = value.get(b"key").and_then(Value::as_list).unwrap();
+        assert!(matches!(items[0], Value::Integer(-3)));
+        assert_eq!(items[1].as_str(), Some("spam"));
+        assert_eq!(items[0].as_u64(), None);
+
+        for invalid in [
+            &b""[..],
+            b"i12",
+            b"ixe",
+            b"l",
+            b"5:ab",
+            b"18446744073709551615:a",
+            b"di1e1:ae",
+            b"x",
+        ] {
+            assert!(Value::parse(invalid).is_err(), "{:?}", invalid);
+        }
+        assert!(Value::parse(&[b'l'; MAX_NESTING + 2]).is_err());
+    }
+
+    #[test]
+    fn parses_single_file_torrents() {
+        let bytes = torrent(
+            &[
+                ("length", int(5)),
+                ("name", string(b"a.txt")),
+                ("piece length", int(4)),
+            ],
+            2,
+        );
+        let torrent = Torrent::parse(&bytes).unwrap();
+        assert_eq!(torrent.name, "a.txt");
+        assert_eq!(torrent.files.len(), 1);
+        assert!(torrent.files[0].path.is_empty());
+        assert_eq!(torrent.total_length(), 5);
+        assert_eq!(torrent.piece_spans(0), [(0, 0, 4)]);
+        assert_eq!(torrent.piece_spans(1), [(0, 4, 1)]);
+        assert_eq!(
+            torrent
+                .file_url(&torrent.web_seeds[0], &torrent.files[0])
+                .unwrap()
+                .as_str(),
+            "https://seed.example.com/files/a.txt"
+        );
+        assert_eq!(
+            torrent
+                .file_url("https://seed.example.com/a.txt", &torrent.files[0])
+                .unwrap()
+                .as_str(),
+            "https://seed.example.com/a.txt"
+        );
+    }
+
+    #[test]
+    fn parses_multi_file_torrents_with_padding() {
+        let padding = dict(&[
+            ("attr", string(b"p")),
+            ("length", int(1)),
+            ("path", list(&[string(b".pad"), string(b"1")])),
+        ]);
+        let info = [
+            ("name", string(b"my dir")),
+            ("piece length", int(4)),
+            (
+                "files",
+                list(&[file(&["a.txt"], 3), padding, file(&["sub", "b c.bin"], 6)]),
+            ),
+        ];
+        let torrent = Torrent::parse(&torrent(&info, 3)).unwrap();
+        assert_eq!(torrent.total_length(), 10);
+        assert!(torrent.files[1].padding);
+        assert!(!torrent.files[2].padding);
+        assert_eq!(
+            torrent.relative_path(&torrent.files[2]),
+            "my dir/sub/b c.bin"
+        );
+        assert_eq!(torrent.piece_spans(0), [(0, 0, 3), (1, 0, 1)]);
+        assert_eq!(torrent.piece_spans(1), [(2, 0, 4)]);
+        assert_eq!(torrent.piece_spans(2), [(2, 4, 2)]);
+        assert_eq!(
+            torrent
+                .file_url(&torrent.web_seeds[0], &torrent.files[2])
+                .unwrap()
+                .as_str(),
+            "https://seed.example.com/files/my%20dir/sub/b%20c.bin"
+        );
+    }
+
+    #[test]
+    fn refuses_malformed_torrents() {
+        let name = ("name", string(b"a"));
+        let piece_length = ("piece length", int(4));
+        let cases = [
+            torrent(&[name.clone(), piece_length.clone()], 1),
+            torrent(&[name.clone(), ("length", int(4))], 1),
+            torrent(
+                &[name.clone(), ("length", int(4)), ("piece length", int(0))],
+                1,
+            ),
+            torrent(&[name.clone(), piece_length.clone(), ("length", int(5))], 1),
+            torrent(
+                &[name.clone(), piece_length.clone(), ("length", int(-1))],
+                1,
+            ),
+            torrent(
+                &[
+                    name.clone(),
+                    piece_length.clone(),
+                    ("files", list(&[file(&[".."], 4)])),
+                ],
+                1,
+            ),
+            torrent(
+                &[
+                    name.clone(),
+                    piece_length.clone(),
+                    ("files", list(&[file(&[], 4)])),
+                ],
+                1,
+            ),
+            dict(&[("info", string(b"none"))]),
+            b"d4:infod".to_vec(),
+        ];
+        for bytes in cases {
+            assert!(
+                Torrent::parse(&bytes).is_err(),
+                "{}",
+                String::from_utf8_lossy(&bytes)
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_oversized_torrents() {
+        let reason = |bytes: &[u8]| match Torrent::parse(bytes) {
+            Err(DownloadError::InvalidTorrent(reason)) => reason,
+            _ => panic!("torrent wasn't refused"),
+        };
+        let huge_pieces = torrent(
+            &[
+                ("name", string(b"a")),
+                ("length", int(4)),
+                ("piece length", int(MAX_SEGMENT_SIZE as i64 + 1)),
+            ],
+            1,
+        );
+        assert!(reason(&huge_pieces).starts_with("pieces larger than"));
+
+        let huge_files = torrent(
+            &[
+                ("name", string(b"a")),
+                ("piece length", int(4)),
+                ("files", list(&vec![file(&["big"], i64::MAX); 3])),
+            ],
+            0,
+        );
+        assert_eq!(reason(&huge_files), "the files are too large");
+    }
+}
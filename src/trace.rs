@@ -0,0 +1,67 @@
+//! Logging macros that forward to `tracing` when the `tracing` feature is enabled and do nothing otherwise.
+//!
+//! With the `opentelemetry` feature, requests also carry the trace context of the current span.
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::info!($($arg)*);
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)*);
+    };
+}
+
+/// Keeps spans opened by `future` nested in the current span when it is spawned as a separate task.
+pub(crate) fn in_current_span<F: std::future::Future>(
+    future: F,
+) -> impl std::future::Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    let future = tracing::Instrument::in_current_span(future);
+    future
+}
+
+/// Adds the trace context of the current span to `request`, so the server's spans become part of
+/// the same trace.
+pub(crate) fn propagate(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    #[cfg(feature = "opentelemetry")]
+    let request = {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let context = tracing::Span::current().context();
+        let mut headers = HeaderInjector(reqwest::header::HeaderMap::new());
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut headers)
+        });
+        request.headers(headers.0)
+    };
+    request
+}
+
+#[cfg(feature = "opentelemetry")]
+struct HeaderInjector(reqwest::header::HeaderMap);
+
+#[cfg(feature = "opentelemetry")]
+impl opentelemetry::propagation::Injector for HeaderInjector {
+    fn set(&mut self, key: &str, value: String) {
+        use reqwest::header::{HeaderName, HeaderValue};
+
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Positional file writes through io_uring, enabled with the `io-uring` feature on Linux.
+//!
+//! A thread owns the ring and submits the writes of all chunks, so they don't contend for tokio's
+//! blocking thread pool or a shared file position.
+
+use bytes::{Buf, Bytes};
+use io_uring::{opcode, types, IoUring};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    os::fd::AsRawFd,
+    sync::{mpsc, Arc},
+};
+use tokio::sync::oneshot;
+
+/// How many writes are in flight at most.
+const QUEUE_DEPTH: u32 = 256;
+
+/// Sends writes to the thread that owns the ring. The thread exits once this is dropped.
+pub(crate) struct Uring {
+    requests: mpsc::Sender<Write>,
+}
+
+struct Write {
+    /// Keeps the file open until the write completes.
+    file: Arc<File>,
+    offset: u64,
+    /// Read by the kernel until the write completes.
+    buf: Bytes,
+    done: oneshot::Sender<io::Result<usize>>,
+}
+
+impl Uring {
+    pub fn new() -> io::Result<Self> {
+        let ring = IoUring::new(QUEUE_DEPTH)?;
+        let (requests, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("zusammen-io-uring".to_owned())
+            .spawn(move || run(ring, receiver))?;
+        Ok(Self { requests })
+    }
+
+    /// Writes all of `buf` to `file` at `offset`.
+    pub async fn write_all_at(
+        &self,
+        file: &Arc<File>,
+        mut buf: Bytes,
+        mut offset: u64,
+    ) -> io::Result<()> {
+        let stopped = || io::Error::other("the io_uring thread stopped");
+        while !buf.is_empty() {
+            let (done, written) = oneshot::channel();
+            let write = Write {
+                file: file.clone(),
+                offset,
+                buf: buf.clone(),
+                done,
+            };
+            self.requests.send(write).map_err(|_| stopped())?;
+            let written = written.await.map_err(|_| stopped())??;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            buf.advance(written);
+            offset += written as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Submits the writes received from `requests` and completes them, until the sender is dropped
+/// and nothing is in flight anymore.
+fn run(mut ring: IoUring, requests: mpsc::Receiver<Write>) {
+    let mut in_flight = HashMap::new();
+    let mut next_id = 0u64;
+    loop {
+        let mut received = Vec::new();
+        // Nothing completes while nothing is in flight, so wait for the next write instead.
+        if in_flight.is_empty() {
+            match requests.recv() {
+                Ok(write) => received.push(write),
+                Err(_) => return,
+            }
+        }
+        while in_flight.len() + received.len() < QUEUE_DEPTH as usize {
+            match requests.try_recv() {
+                Ok(write) => received.push(write),
+                Err(_) => break,
+            }
+        }
+
+        for write in received {
+            let len = write.buf.len().min(u32::MAX as usize) as u32;
+            let entry =
+                opcode::Write::new(types::Fd(write.file.as_raw_fd()), write.buf.as_ptr(), len)
+                    .offset(write.offset)
+                    .build()
+                    .user_data(next_id);
+            // SAFETY: the file and buffer are kept in `in_flight` until the write completes, and
+            // the queue has room since no more than `QUEUE_DEPTH` writes are in flight.
+            unsafe {
+                ring.submission()
+                    .push(&entry)
+                    .expect("io_uring submission queue is full");
+            }
+            in_flight.insert(next_id, write);
+            next_id += 1;
+        }
+
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                // The ring is unusable, fail everything that's waiting for it. The kernel may still
+                // read the buffers of writes it was given, so they are leaked rather than freed.
+                for (_, write) in in_flight.drain() {
+                    let _ = write
+                        .done
+                        .send(Err(io::Error::new(e.kind(), e.to_string())));
+                    std::mem::forget((write.file, write.buf));
+                }
+                return;
+            }
+        }
+
+        for entry in ring.completion() {
+            let Some(write) = in_flight.remove(&entry.user_data()) else {
+                continue;
+            };
+            let result = match entry.result() {
+                written if written >= 0 => Ok(written as usize),
+                errno => Err(io::Error::from_raw_os_error(-errno)),
+            };
+            let _ = write.done.send(result);
+        }
+    }
+}
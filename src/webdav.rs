@@ -0,0 +1,427 @@
+//! Mirroring of WebDAV collections, enabled with the `webdav` feature.
+//!
+//! Collections are listed with `PROPFIND` requests, and the size, `ETag` and modification time
+//! they report for each file decide whether the local copy is still current.
+
+use crate::{
+    conditional,
+    crawl::{self, CrawlOptions},
+    download::Downloader,
+    error::DownloadError,
+    filename,
+    options::{DownloadOptions, OverwritePolicy},
+    report::DownloadReport,
+};
+use reqwest::{header::CONTENT_TYPE, Method, StatusCode};
+use roxmltree::{Document, Node};
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, SystemTime},
+};
+use tokio::fs;
+use url::Url;
+
+const DAV: &str = "DAV:";
+
+/// Asks for the properties that tell whether a file changed.
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<propfind xmlns="DAV:">
+  <prop>
+    <resourcetype/>
+    <getcontentlength/>
+    <getetag/>
+    <getlastmodified/>
+  </prop>
+</propfind>"#;
+
+/// A member of a collection, as described by a `PROPFIND` response.
+struct Resource {
+    url: Url,
+    collection: bool,
+    size: Option<u64>,
+    etag: Option<String>,
+    modified: Option<SystemTime>,
+}
+
+impl Downloader {
+    /// Mirrors the WebDAV collection at `url`, and its subcollections, below the output
+    /// directory, downloading only the files that are missing or changed.
+    ///
+    /// A local file is current if it has the size the server reports and the `ETag` saved next
+    /// to it by the last sync matches, or, without one, it was modified after the remote file.
+    /// The results of current files have no bytes downloaded.
+    ///
+    /// Fails only if the globs of `crawl` are invalid or the collection at `url` can't be listed.
+    /// The results are those of the files that were downloaded, followed by those of the current
+    /// files and errors for the subcollections that couldn't be listed, in the order they were
+    /// found.
+    pub async fn sync_webdav(
+        &self,
+        url: &str,
+        crawl: &CrawlOptions,
+    ) -> Result<Vec<Result<DownloadReport, DownloadError>>, DownloadError> {
+        self.sync_webdav_with(url, crawl, &DownloadOptions::default())
+            .await
+    }
+
+    /// Like [`Downloader::sync_webdav`], with settings for the `PROPFIND` requests and every
+    /// file, like the credentials of a share, which are only sent to the host of `url`. Each file
+    /// is saved under its own name, below the directory `options` sets.
+    pub async fn sync_webdav_with(
+        &self,
+        url: &str,
+        crawl: &CrawlOptions,
+        options: &DownloadOptions,
+    ) -> Result<Vec<Result<DownloadReport, DownloadError>>, DownloadError> {
+        let filter = crawl.filter()?;
+        let root = crawl::directory_url(url)?;
+
+        let options = options.scoped(root.as_str());
+        let mut files = Vec::new();
+        let mut done = Vec::new();
+        let mut seen = HashSet::from([root.clone()]);
+        let mut collections = VecDeque::from([(root.clone(), 0)]);
+        while let Some((collection, depth)) = collections.pop_front() {
+            let members = match self.propfind(&collection, &options).await {
+                Ok(members) => members,
+                Err(e) if collection == root => return Err(e),
+                Err(e) => {
+                    warn!(url = %collection, error = %e, "failed to list WebDAV collection");
+                    done.push((collection.to_string(), Err(e)));
+                    continue;
+                }
+            };
+            for member in members {
+                // Responses describe the collection itself too.
+                if !seen.insert(member.url.clone()) {
+                    continue;
+                }
+                let Some(segments) = crawl::relative_segments(&root, &member.url) else {
+                    continue;
+                };
+                let Some((name, dirs)) = segments.split_last() else {
+                    continue;
+                };
+                if member.collection {
+                    if crawl.max_depth.is_none_or(|max| depth < max) {
+                        collections.push_back((member.url, depth + 1));
+                    }
+                    continue;
+                }
+
+                let wanted = filter.matches(&segments.join("/"), name);
+                let Some(name) = filename::base_name(name).filter(|_| wanted) else {
+                    continue;
+                };
+                let options = self
+                    .found_file(&options, &name, dirs)
+                    .overwrite_policy(OverwritePolicy::Overwrite);
+                match self.current_copy(&member, &options).await {
+                    Some(report) => done.push((member.url.to_string(), Ok(report))),
+                    None => files.push((member.url.to_string(), options)),
+                }
+            }
+        }
+        debug!(
+            changed = files.len(),
+            unchanged = done.len(),
+            "listed WebDAV collection"
+        );
+
+        let mut results = self.download_found(files, done).await;
+        for report in results.iter_mut() {
+            let Ok(downloaded) = report else {
+                continue;
+            };
+            if downloaded.bytes_downloaded == 0 {
+                continue;
+            }
+            if let Err(e) = conditional::save_validators(downloaded).await {
+                *report = Err(e);
+            }
+        }
+        Ok(results)
+    }
+
+    /// Lists the members of the collection at `url`, including itself.
+    async fn propfind(
+        &self,
+        url: &Url,
+        options: &DownloadOptions,
+    ) -> Result<Vec<Resource>, DownloadError> {
+        let method = Method::from_bytes(b"PROPFIND").expect("PROPFIND is a valid method");
+        let _permit = options
+            .or_cancelled(self.hosts.acquire(url.as_str()))
+            .await?;
+        let fetch = async {
+            let response = self
+                .send_request(url.as_str(), options, |client| {
+                    client
+                        .request(method.clone(), url.as_str())
+                        .header("Depth", "1")
+                        .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+                        .body(PROPFIND_BODY)
+                })
+                .await?;
+            if response.status() != StatusCode::MULTI_STATUS {
+                return Err(invalid(
+                    url,
+                    format!("expected 207 Multi-Status, got {}", response.status()),
+                ));
+            }
+            let base = response.url().clone();
+            let body = response.text().await?;
+            parse_multistatus(&base, &body).map_err(|reason| invalid(url, reason))
+        };
+        options.or_cancelled(fetch).await?
+    }
+
+    /// Returns a report for the local copy of `resource` if it's still current.
+    async fn current_copy(
+        &self,
+        resource: &Resource,
+        options: &DownloadOptions,
+    ) -> Option<DownloadReport> {
+        let name = self.output_name(
+            resource.url.as_str(),
+            options.filename.as_deref(),
+            &Default::default(),
+            options,
+        );
+        let path = self.get_output_path(&name, options);
+        let metadata = fs::metadata(&path).await.ok()?;
+        if resource.size != Some(metadata.len()) {
+            return None;
+        }
+        let saved = fs::read_to_string(conditional::etag_path(&path)).await.ok();
+        let current = match (saved, &resource.etag) {
+            (Some(saved), Some(etag)) => saved.trim() == etag,
+            _ => resource
+                .modified
+                .zip(metadata.modified().ok())
+                .is_some_and(|(remote, local)| local >= remote),
+        };
+        if !current {
+            return None;
+        }
+
+        debug!(path = %path.display(), "file is up to date, skipping download");
+        Some(DownloadReport {
+            path,
+            url: resource.url.to_string(),
+            final_url: resource.url.to_string(),
+            status: None,
+            headers: Default::default(),
+            size: metadata.len(),
+            bytes_downloaded: 0,
+            elapsed: Duration::ZERO,
+            retries: 0,
+            checksum: None,
+            checksum_source: None,
+            extracted_to: None,
+            chunks: Vec::new(),
+        })
+    }
+}
+
+fn invalid(url: &Url, reason: String) -> DownloadError {
+    DownloadError::InvalidWebDav {
+        url: url.to_string(),
+        reason,
+    }
+}
+
+/// Parses the `multistatus` document a `PROPFIND` of `base` responded with.
+fn parse_multistatus(base: &Url, xml: &str) -> Result<Vec<Resource>, String> {
+    let doc = Document::parse(xml).map_err(|e| e.to_string())?;
+    let root = doc.root_element();
+    if !is_dav(&root, "multistatus") {
+        return Err("root element is not <multistatus>".to_owned());
+    }
+
+    let mut resources = Vec::new();
+    for response in root.children().filter(|n| is_dav(n, "response")) {
+        let Some(href) = child(&response, "href").and_then(|n| n.text()) else {
+            continue;
+        };
+        let Ok(mut url) = base.join(href.trim()) else {
+            continue;
+        };
+        // Properties can be split across several `propstat`s, only the found ones count.
+        let props: Vec<_> = response
+            .children()
+            .filter(|n| is_dav(n, "propstat"))
+            .filter(|propstat| {
+                child(propstat, "status")
+                    .and_then(|n| n.text())
+                    .is_some_and(|status| status.split_whitespace().nth(1) == Some("200"))
+            })
+            .filter_map(|propstat| child(&propstat, "prop"))
+            .collect();
+        let prop = |name| props.iter().find_map(|prop| child(prop, name));
+        let text = |name| {
+            prop(name)
+                .and_then(|n| n.text())
+                .map(|v| v.trim().to_owned())
+        };
+
+        let collection = prop("resourcetype")
+            .is_some_and(|resourcetype| child(&resourcetype, "collection").is_some());
+        if collection && !url.path().ends_with('/') {
+            let path = format!("{}/", url.path());
+            url.set_path(&path);
+        }
+        resources.push(Resource {
+            url,
+            collection,
+            size: text("getcontentlength").and_then(|v| v.parse().ok()),
+            etag: text("getetag"),
+            modified: text("getlastmodified").and_then(|v| httpdate::parse_http_date(&v).ok()),
+        });
+    }
+    Ok(resources)
+}
+
+fn is_dav(node: &Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name && node.tag_name().namespace() == Some(DAV)
+}
+
+fn child<'a, 'input>(node: &Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| is_dav(n, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(xml: &str) -> Vec<Resource> {
+        let base = Url::parse("https://cloud.example.com/remote.php/dav/files/me/").unwrap();
+        parse_multistatus(&base, xml).unwrap()
+    }
+
+    #[test]
+    fn parses_split_propstats() {
+        let resources = parse(
+            r#"<d:multistatus xmlns:d="DAV:">
+              <d:response>
+                <d:href>/remote.php/dav/files/me/report.pdf</d:href>
+                <d:propstat>
+                  <d:prop><d:getcontentlength>1234</d:getcontentlength><d:resourcetype/></d:prop>
+                  <d:status>HTTP/1.1 200 OK</d:status>
+                </d:propstat>
+                <d:propstat>
+                  <d:prop><d:getetag> "abc" </d:getetag></d:prop>
+                  <d:status>HTTP/1.1 200 OK</d:status>
+                </d:propstat>
+                <d:propstat>
+                  <d:prop><d:getlastmodified>Sun, 30 Aug 2015 12:36:00 GMT</d:getlastmodified></d:prop>
+                  <d:status>HTTP/1.1 200 OK</d:status>
+                </d:propstat>
+              </d:response>
+            </d:multistatus>"#,
+        );
+        assert_eq!(resources.len(), 1);
+        let file = &resources[0];
+        assert_eq!(
+            file.url.as_str(),
+            "https://cloud.example.com/remote.php/dav/files/me/report.pdf"
+        );
+        assert!(!file.collection);
+        assert_eq!(file.size, Some(1234));
+        assert_eq!(file.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(
+            file.modified,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_440_938_160))
+        );
+    }
+
+    #[test]
+    fn ignores_properties_that_werent_found() {
+        let resources = parse(
+            r#"<multistatus xmlns="DAV:">
+              <response>
+                <href>notes.txt</href>
+                <propstat>
+                  <prop><getcontentlength>10</getcontentlength></prop>
+                  <status>HTTP/1.1 200 OK</status>
+                </propstat>
+                <propstat>
+                  <prop><getetag>"stale"</getetag><getcontentlength>99</getcontentlength></prop>
+                  <status>HTTP/1.1 404 Not Found</status>
+                </propstat>
+              </response>
+            </multistatus>"#,
+        );
+        assert_eq!(resources[0].size, Some(10));
+        assert_eq!(resources[0].etag, None);
+        assert_eq!(resources[0].modified, None);
+    }
+
+    #[test]
+    fn adds_slashes_to_collections() {
+        let resources = parse(
+            r#"<multistatus xmlns="DAV:">
+              <response>
+                <href>/remote.php/dav/files/me/Photos</href>
+                <propstat>
+                  <prop><resourcetype><collection/></resourcetype></prop>
+                  <status>HTTP/1.1 200 OK</status>
+                </propstat>
+              </response>
+              <response>
+                <href>/remote.php/dav/files/me/</href>
+                <propstat>
+                  <prop><resourcetype><collection/></resourcetype></prop>
+                  <status>HTTP/1.1 200 OK</status>
+                </propstat>
+              </response>
+            </multistatus>"#,
+        );
+        let urls: Vec<_> = resources
+            .iter()
+            .filter(|r| r.collection)
+            .map(|r| r.url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "https://cloud.example.com/remote.php/dav/files/me/Photos/",
+                "https://cloud.example.com/remote.php/dav/files/me/",
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_percent_encoded_hrefs() {
+        let resources = parse(
+            r#"<multistatus xmlns="DAV:">
+              <response><href>/remote.php/dav/files/me/My%20Files/caf%C3%A9.txt</href></response>
+              <response><href>https://cloud.example.com/remote.php/dav/files/me/a%2Fb</href></response>
+            </multistatus>"#,
+        );
+        let urls: Vec<_> = resources.iter().map(|r| r.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://cloud.example.com/remote.php/dav/files/me/My%20Files/caf%C3%A9.txt",
+                "https://cloud.example.com/remote.php/dav/files/me/a%2Fb",
+            ]
+        );
+        let root = Url::parse("https://cloud.example.com/remote.php/dav/files/me/").unwrap();
+        assert_eq!(
+            crawl::relative_segments(&root, &resources[0].url).unwrap(),
+            ["My Files", "café.txt"]
+        );
+    }
+
+    #[test]
+    fn refuses_other_documents() {
+        let base = Url::parse("https://cloud.example.com/dav/").unwrap();
+        assert!(parse_multistatus(&base, "<multistatus>").is_err());
+        assert!(parse_multistatus(&base, "<multistatus/>").is_err());
+        assert!(parse_multistatus(&base, r#"<html xmlns="DAV:"/>"#).is_err());
+        assert!(parse_multistatus(&base, r#"<multistatus xmlns="DAV:"/>"#)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{
+    checksum::ChecksumSource, error::DownloadError, hooks::DownloadHook, report::DownloadReport,
+};
+use futures::future::BoxFuture;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// How long the endpoint has to accept a notification.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A [`DownloadHook`] that POSTs a JSON description of every finished download to an endpoint.
+///
+/// Completed downloads are sent as
+///
+/// ```json
+/// {
+///   "event": "completed",
+///   "url": "https://example.com/file.iso",
+///   "final_url": "https://cdn.example.com/file.iso",
+///   "path": "downloads/file.iso",
+///   "status": 200,
+///   "size": 1048576,
+///   "bytes_downloaded": 1048576,
+///   "duration_secs": 1.5,
+///   "checksum": { "algorithm": "sha256", "expected": "…", "source": "options" }
+/// }
+/// ```
+///
+/// with a `checksum` of `null` if the file wasn't verified, and a `source` of `options`,
+/// `digest_header` or `sidecar`, along with the `sidecar_url`. Failed downloads are sent as
+/// `{"event": "failed", "url": …, "error": …, "status": …, "duration_secs": …}`.
+///
+/// Notifications that can't be delivered are logged and otherwise ignored, they never fail the
+/// download.
+#[derive(Clone)]
+pub struct Webhook {
+    client: reqwest::Client,
+    endpoint: String,
+    headers: HeaderMap,
+}
+
+impl Webhook {
+    /// Sends notifications to `endpoint`.
+    pub fn new(endpoint: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            endpoint: endpoint.to_owned(),
+            headers: HeaderMap::new(),
+        }
+    }
+
+    /// Sends `name: value` with every notification, for example to authenticate at the endpoint.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    fn send(&self, payload: Value) -> BoxFuture<'static, ()> {
+        let request = self
+            .client
+            .post(&self.endpoint)
+            .headers(self.headers.clone())
+            .timeout(WEBHOOK_TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload.to_string());
+        Box::pin(async move {
+            if let Err(_e) = request.send().await.and_then(|r| r.error_for_status()) {
+                warn!(error = %_e, "failed to deliver webhook notification");
+            }
+        })
+    }
+}
+
+impl DownloadHook for Webhook {
+    fn completed(&self, report: &DownloadReport) -> BoxFuture<'static, ()> {
+        let checksum = report.checksum.as_ref().map(|checksum| {
+            let mut checksum = json!({
+                "algorithm": checksum.algorithm.name(),
+                "expected": checksum.expected,
+            });
+            let (source, sidecar_url) = match &report.checksum_source {
+                Some(ChecksumSource::Options) | None => ("options", None),
+                Some(ChecksumSource::DigestHeader) => ("digest_header", None),
+                Some(ChecksumSource::Sidecar(url)) => ("sidecar", Some(url)),
+            };
+            checksum["source"] = json!(source);
+            if let Some(url) = sidecar_url {
+                checksum["sidecar_url"] = json!(url);
+            }
+            checksum
+        });
+        self.send(json!({
+            "event": "completed",
+            "url": report.url,
+            "final_url": report.final_url,
+            "path": report.path.to_string_lossy(),
+            "status": report.status.map(|status| status.as_u16()),
+            "size": report.size,
+            "bytes_downloaded": report.bytes_downloaded,
+            "duration_secs": report.elapsed.as_secs_f64(),
+            "checksum": checksum,
+        }))
+    }
+
+    fn failed(
+        &self,
+        url: &str,
+        error: &DownloadError,
+        elapsed: Duration,
+    ) -> BoxFuture<'static, ()> {
+        self.send(json!({
+            "event": "failed",
+            "url": url,
+            "error": error.to_string(),
+            "status": error.status().map(|status| status.as_u16()),
+            "duration_secs": elapsed.as_secs_f64(),
+        }))
+    }
+}
@@ -0,0 +1,832 @@
+//! Delta downloads with zsync control files, enabled with the `zsync` feature.
+//!
+//! A `.zsync` file lists a rolling checksum and an MD4 hash of every block of a file. Blocks
+//! found anywhere in an older version of the file are copied from it, and only the others are
+//! fetched, with range requests.
+
+use crate::{
+    checksum,
+    download::{self, Downloader},
+    error::{DownloadError, IoResultExt},
+    event::DownloadEvent,
+    filename, metrics,
+    mirrors::Mirrors,
+    options::DownloadOptions,
+    probe::Probe,
+    progress::SpeedMeter,
+    report::DownloadReport,
+    segments::Segment,
+};
+use futures::{stream, StreamExt};
+use md4::Md4;
+use reqwest::header::HeaderMap;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+use url::Url;
+
+/// Consecutive missing blocks are fetched in ranges of at most this many bytes.
+const MAX_RANGE: u64 = 4 * 1024 * 1024;
+
+/// How much of the old file is read at once while looking for blocks.
+const READ_SIZE: usize = 1024 * 1024;
+
+/// A parsed `.zsync` control file.
+struct ControlFile {
+    filename: Option<String>,
+    length: u64,
+    block_size: usize,
+    /// How many consecutive blocks must match before one counts as found.
+    seq_matches: usize,
+    /// How many bytes of the rolling checksum and of the MD4 hash of each block are listed.
+    rsum_len: usize,
+    checksum_len: usize,
+    /// Where the file can be downloaded from, resolved against the URL of the control file.
+    urls: Vec<String>,
+    /// The hex encoded SHA-1 hash of the file.
+    sha1: String,
+    blocks: Vec<BlockSums>,
+}
+
+struct BlockSums {
+    /// The two halves of the rolling checksum, with the bytes that aren't listed masked out.
+    rsum: u32,
+    checksum: Vec<u8>,
+}
+
+impl ControlFile {
+    fn parse(base: &Url, data: &[u8]) -> Result<Self, String> {
+        let end = data
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .ok_or("no end of the header")?;
+        let header = std::str::from_utf8(&data[..end]).map_err(|_| "header is not UTF-8")?;
+        let sums = &data[end + 2..];
+
+        let mut fields = HashMap::new();
+        let mut urls = Vec::new();
+        let mut compressed_only = false;
+        for line in header.lines() {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name {
+                "URL" => urls.push(
+                    base.join(value)
+                        .map_err(|_| format!("invalid URL `{}`", value))?
+                        .to_string(),
+                ),
+                "Z-URL" => compressed_only = true,
+                _ => {
+                    fields.insert(name, value);
+                }
+            }
+        }
+        if urls.is_empty() {
+            return Err(match compressed_only {
+                true => "only a compressed file is offered, which isn't supported".to_owned(),
+                false => "no URL".to_owned(),
+            });
+        }
+        let field = |name: &str| {
+            fields
+                .get(name)
+                .copied()
+                .ok_or_else(|| format!("no {} header", name))
+        };
+        let number = |name: &str| {
+            field(name)?
+                .parse::<u64>()
+                .map_err(|_| format!("invalid {} header", name))
+        };
+
+        let length = number("Length")?;
+        let block_size = usize::try_from(number("Blocksize")?)
+            .ok()
+            .filter(|&size| size > 0)
+            .ok_or("invalid Blocksize header")?;
+        let lengths: Vec<usize> = field("Hash-Lengths")?
+            .split(',')
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| "invalid Hash-Lengths header")?;
+        let [seq_matches, rsum_len, checksum_len] = lengths[..] else {
+            return Err("invalid Hash-Lengths header".to_owned());
+        };
+        if !(1..=2).contains(&seq_matches)
+            || !(1..=4).contains(&rsum_len)
+            || !(3..=16).contains(&checksum_len)
+        {
+            return Err("unsupported Hash-Lengths".to_owned());
+        }
+        let sha1 = field("SHA-1")?;
+        if sha1.len() != 40 || !sha1.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err("invalid SHA-1 header".to_owned());
+        }
+
+        let count = length.div_ceil(block_size as u64);
+        let entry = rsum_len + checksum_len;
+        let expected = count
+            .checked_mul(entry as u64)
+            .ok_or("invalid Length header")?;
+        if sums.len() as u64 != expected {
+            return Err(format!(
+                "lists {} bytes of block checksums, expected {}",
+                sums.len(),
+                expected
+            ));
+        }
+        let blocks = sums
+            .chunks(entry)
+            .map(|entry| {
+                let mut rsum = [0; 4];
+                rsum[4 - rsum_len..].copy_from_slice(&entry[..rsum_len]);
+                BlockSums {
+                    rsum: u32::from_be_bytes(rsum),
+                    checksum: entry[rsum_len..].to_vec(),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            filename: fields
+                .get("Filename")
+                .and_then(|name| filename::base_name(name)),
+            length,
+            block_size,
+            seq_matches,
+            rsum_len,
+            checksum_len,
+            urls,
+            sha1: sha1.to_ascii_lowercase(),
+            blocks,
+        })
+    }
+
+    fn rsum_mask(&self) -> u32 {
+        u32::MAX >> (8 * (4 - self.rsum_len))
+    }
+
+    /// The length of block `index`, which is shorter than the block size for the last one.
+    fn block_len(&self, index: usize) -> u64 {
+        let start = (index * self.block_size) as u64;
+        (self.length - start).min(self.block_size as u64)
+    }
+}
+
+impl Downloader {
+    /// Downloads the file described by the `.zsync` control file at `url`, reusing the blocks it
+    /// shares with `old`. See [`Downloader::download_zsync_with`].
+    pub async fn download_zsync(
+        &self,
+        url: &str,
+        old: impl AsRef<Path>,
+    ) -> Result<DownloadReport, DownloadError> {
+        self.download_zsync_with(url, old, &DownloadOptions::default())
+            .await
+    }
+
+    /// Like [`Downloader::download_zsync`], with settings that only apply to this download.
+    ///
+    /// `old` is searched for the blocks of the new file at any offset, so blocks that moved are
+    /// found too, and the ones that aren't there are fetched with range requests, from the other
+    /// URLs of the control file after failures. A missing `old` file means everything is
+    /// fetched. The new file is named after the control file unless `options` sets a filename,
+    /// and is only put in place once it matches the SHA-1 hash of the control file, so `old`
+    /// can be the file it replaces with [`OverwritePolicy::Overwrite`]. The report counts only
+    /// the fetched bytes as downloaded.
+    ///
+    /// [`OverwritePolicy::Overwrite`]: crate::OverwritePolicy::Overwrite
+    ///
+    /// Control files that only offer a compressed version of the file aren't supported.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "download_zsync", skip_all, fields(url = %url)))]
+    pub async fn download_zsync_with(
+        &self,
+        url: &str,
+        old: impl AsRef<Path>,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        let _running = self.shutdown.enter();
+        let result = match self.shutdown.is_shut_down() {
+            true => Err(DownloadError::Cancelled),
+            false => {
+                self.fetch_zsync(
+                    url,
+                    old.as_ref(),
+                    &options.started(url, self.shutdown.token()),
+                )
+                .await
+            }
+        };
+        self.hooks.run(url, &result, started.elapsed()).await;
+        metrics::record_outcome(&result);
+        result
+    }
+
+    async fn fetch_zsync(
+        &self,
+        url: &str,
+        old: &Path,
+        options: &DownloadOptions,
+    ) -> Result<DownloadReport, DownloadError> {
+        let started = Instant::now();
+        let parsed = Url::parse(url).map_err(|_| DownloadError::InvalidUrl(url.to_owned()))?;
+        let data = self
+            .fetch_segment_once(
+                &Segment {
+                    url: parsed.clone(),
+                    range: None,
+                },
+                options,
+            )
+            .await?;
+        let control =
+            Arc::new(ControlFile::parse(&parsed, &data).map_err(DownloadError::InvalidZsync)?);
+        self.check_size(url, Some(control.length), options)?;
+
+        let name = options
+            .filename
+            .clone()
+            .or_else(|| control.filename.clone())
+            .unwrap_or_else(|| filename::from_url(&control.urls[0]));
+        let name = self.output_name(url, Some(&name), &HeaderMap::new(), options);
+        let probe = Probe {
+            content_length: Some(control.length),
+            ..Default::default()
+        };
+        if let Some(report) = self.check_existing(url, &name, &probe, options).await? {
+            return Ok(report);
+        }
+        let reservation = self.reserve_output_path(&name, options);
+        let path = reservation.path.clone();
+        let temp = match &self.staging {
+            Some(_) => self.partial_path(&path),
+            None => {
+                let mut temp = path.as_os_str().to_owned();
+                temp.push(".zsync.tmp");
+                PathBuf::from(temp)
+            }
+        };
+        download::create_parent_dir(&temp).await?;
+        options.emit(|| DownloadEvent::Started {
+            path: path.clone(),
+            total: Some(control.length),
+        });
+
+        let result = self
+            .build_from_old(url, &control, old, &temp, options)
+            .await;
+        let (fetched, retries) = match result {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                download::remove_partial(&temp).await.with_path(&temp)?;
+                return Err(e);
+            }
+        };
+        self.move_into_place(&temp, &path).await?;
+        self.make_durable(&path).await?;
+
+        Ok(DownloadReport {
+            path,
+            url: url.to_owned(),
+            final_url: control.urls[0].clone(),
+            status: None,
+            headers: HeaderMap::new(),
+            size: control.length,
+            bytes_downloaded: fetched,
+            elapsed: started.elapsed(),
+            retries,
+            checksum: None,
+            checksum_source: None,
+            extracted_to: None,
+            chunks: Vec::new(),
+        })
+    }
+
+    /// Writes the new file to `temp` from the blocks found in `old` and fetched ones, and checks
+    /// it against the SHA-1 hash of the control file. Returns the number of fetched bytes and of
+    /// retried requests.
+    async fn build_from_old(
+        &self,
+        url: &str,
+        control: &Arc<ControlFile>,
+        old: &Path,
+        temp: &Path,
+        options: &DownloadOptions,
+    ) -> Result<(u64, u32), DownloadError> {
+        let file = fs::File::create(temp).await.with_path(temp)?;
+        file.set_len(control.length).await.with_path(temp)?;
+        let (control_, old_) = (control.clone(), old.to_path_buf());
+        let mut file = file.into_std().await;
+        let (found, reused, file) = tokio::task::spawn_blocking(move || {
+            let found = find_blocks(&control_, &old_)?;
+            let reused = copy_blocks(&control_, &found, &old_, &mut file)?;
+            Ok::<_, io::Error>((found, reused, file))
+        })
+        .await?
+        .with_path(old)?;
+        debug!(
+            reused,
+            missing = found.iter().filter(|block| block.is_none()).count(),
+            "matched blocks of the old file"
+        );
+
+        let (first, others) = control.urls.split_first().expect("URLs were checked");
+        let mirrors = &Mirrors::new(first, others);
+        let mut ranges = Vec::new();
+        let max_blocks = (MAX_RANGE as usize / control.block_size).max(1);
+        let mut index = 0;
+        while index < found.len() {
+            if found[index].is_some() {
+                index += 1;
+                continue;
+            }
+            let start = index;
+            while index < found.len() && found[index].is_none() && index - start < max_blocks {
+                index += 1;
+            }
+            let offset = (start * control.block_size) as u64;
+            let end = (index * control.block_size) as u64;
+            ranges.push((offset, end.min(control.length) - offset));
+        }
+
+        let mut file = fs::File::from_std(file);
+        let mut pieces = stream::iter(ranges)
+            .map(|(start, len)| async move {
+                let piece = self.fetch_zsync_range(mirrors, start, len, options).await;
+                (start, piece)
+            })
+            .buffer_unordered(self.conn_count.max(1));
+        let mut meter = SpeedMeter::new(0);
+        let mut last_report = Instant::now();
+        let mut fetched = 0;
+        let mut retries = 0;
+        while let Some((start, piece)) = pieces.next().await {
+            let (bytes, retried) = piece?;
+            file.seek(SeekFrom::Start(start)).await.with_path(temp)?;
+            file.write_all(&bytes).await.with_path(temp)?;
+            fetched += bytes.len() as u64;
+            retries += retried;
+            if last_report.elapsed() >= self.progress_interval {
+                let done = reused + fetched;
+                self.report_sequential_progress(
+                    url,
+                    options,
+                    done,
+                    Some(control.length),
+                    &mut meter,
+                );
+                last_report = Instant::now();
+            }
+        }
+        file.flush().await.with_path(temp)?;
+        drop(file);
+        self.report_sequential_progress(
+            url,
+            options,
+            reused + fetched,
+            Some(control.length),
+            &mut meter,
+        );
+
+        let actual = {
+            let temp = temp.to_path_buf();
+            tokio::task::spawn_blocking(move || sha1_file(&temp)).await?
+        }
+        .with_path(temp)?;
+        if actual != control.sha1 {
+            return Err(DownloadError::ChecksumMismatch {
+                expected: control.sha1.clone(),
+                actual,
+            });
+        }
+        Ok((fetched, retries))
+    }
+
+    /// Fetches `len` bytes of the new file from `start`, moving to another URL after a failure.
+    async fn fetch_zsync_range(
+        &self,
+        mirrors: &Mirrors,
+        start: u64,
+        len: u64,
+        options: &DownloadOptions,
+    ) -> Result<(Vec<u8>, u32), DownloadError> {
+        let mut mirror = mirrors.assign();
+        let mut attempt = 1;
+        let mut retry_after_waited = Duration::ZERO;
+        loop {
+            let segment = Segment {
+                url: Url::parse(mirrors.url(mirror))
+                    .map_err(|_| DownloadError::InvalidUrl(mirrors.url(mirror).to_owned()))?,
+                range: Some((start, len)),
+            };
+            let error = match self.fetch_segment_once(&segment, options).await {
+                Ok(bytes) => return Ok((bytes, attempt - 1)),
+                Err(e) => e,
+            };
+            match self
+                .retry
+                .retry_delay(attempt, &error, &mut retry_after_waited)
+            {
+                Some(delay) => {
+                    warn!(url = mirrors.url(mirror), start, error = %error, "range failed, retrying");
+                    metrics::record_retry();
+                    options.or_cancelled(tokio::time::sleep(delay)).await?;
+                    mirror = mirrors.failover(mirror);
+                    attempt += 1;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+}
+
+/// The rolling checksum of zsync, as its two 16-bit halves.
+fn rsum(block: &[u8]) -> (u16, u16) {
+    let len = block.len();
+    block
+        .iter()
+        .enumerate()
+        .fold((0u16, 0u16), |(a, b), (i, &byte)| {
+            let byte = u16::from(byte);
+            (
+                a.wrapping_add(byte),
+                b.wrapping_add(((len - i) as u16).wrapping_mul(byte)),
+            )
+        })
+}
+
+/// Moves the window of `sum` one byte on, from `out` to `new`.
+fn roll(sum: &mut (u16, u16), out: u8, new: u8, block_size: usize) {
+    sum.0 = sum
+        .0
+        .wrapping_sub(u16::from(out))
+        .wrapping_add(u16::from(new));
+    sum.1 = sum
+        .1
+        .wrapping_sub((block_size as u16).wrapping_mul(u16::from(out)))
+        .wrapping_add(sum.0);
+}
+
+fn packed(sum: (u16, u16), mask: u32) -> u32 {
+    ((u32::from(sum.0) << 16) | u32::from(sum.1)) & mask
+}
+
+fn pair_key(first: u32, second: u32) -> u64 {
+    (u64::from(first) << 32) | u64::from(second)
+}
+
+/// Looks for the blocks of `control` at every offset of the file at `old`, returning where each
+/// one was found.
+///
+/// The end of the file is padded with zeros like the last block of the new file is. Unless the
+/// control file says a single block is enough, a block only counts if the one after it matches
+/// too, which rules out the false matches its short checksums would allow otherwise.
+fn find_blocks(control: &ControlFile, old: &Path) -> io::Result<Vec<Option<u64>>> {
+    let count = control.blocks.len();
+    let mut found = vec![None; count];
+    let Some(last) = count.checked_sub(1) else {
+        return Ok(found);
+    };
+    let mut file = match std::fs::File::open(old) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(found),
+        Err(e) => return Err(e),
+    };
+
+    let size = control.block_size;
+    let mask = control.rsum_mask();
+    let pairs = control.seq_matches > 1;
+    // Blocks by their checksum, or by theirs and the next one's.
+    let mut index: HashMap<u64, Vec<usize>> = HashMap::new();
+    for i in 0..count {
+        let next = match (pairs, control.blocks.get(i + 1)) {
+            (true, Some(next)) => next.rsum,
+            (true, None) => continue,
+            (false, _) => 0,
+        };
+        index
+            .entry(pair_key(control.blocks[i].rsum, next))
+            .or_default()
+            .push(i);
+    }
+    let matches = |i: usize, block: &[u8]| {
+        Md4::digest(block)[..control.checksum_len] == control.blocks[i].checksum
+    };
+
+    let window = size * control.seq_matches;
+    let mut buffer = Vec::new();
+    // The offset of the start of `buffer` in the file.
+    let mut base = 0;
+    let mut eof = false;
+    let mut fill = |buffer: &mut Vec<u8>, needed: usize| -> io::Result<()> {
+        while buffer.len() < needed && !eof {
+            let len = buffer.len();
+            buffer.resize(len + READ_SIZE, 0);
+            let read = file.read(&mut buffer[len..])?;
+            buffer.truncate(len + read);
+            if read == 0 {
+                eof = true;
+                buffer.resize(len + size, 0);
+            }
+        }
+        Ok(())
+    };
+
+    let mut position = 0;
+    let mut sums = None;
+    loop {
+        if position >= READ_SIZE {
+            buffer.drain(..position);
+            base += position as u64;
+            position = 0;
+        }
+        fill(&mut buffer, position + window + 1)?;
+        if buffer.len() < position + size {
+            break;
+        }
+        let (first, second) = *sums.get_or_insert_with(|| {
+            let at = |start: usize| buffer.get(start..start + size).map(rsum);
+            let second = match pairs {
+                true => at(position + size),
+                false => None,
+            };
+            (at(position).expect("checked above"), second)
+        });
+
+        let block = &buffer[position..position + size];
+        let key = match (pairs, second) {
+            (true, Some(second)) => Some(pair_key(packed(first, mask), packed(second, mask))),
+            (true, None) => None,
+            (false, _) => Some(pair_key(packed(first, mask), 0)),
+        };
+        let mut matched = key
+            .and_then(|key| index.get(&key))
+            .into_iter()
+            .flatten()
+            .copied()
+            .find(|&i| {
+                matches(i, block)
+                    && (!pairs || matches(i + 1, &buffer[position + size..position + window]))
+            });
+        // The last block has no next one to pair with.
+        if matched.is_none()
+            && pairs
+            && found[last].is_none()
+            && packed(first, mask) == control.blocks[last].rsum
+            && matches(last, block)
+        {
+            matched = Some(last);
+        }
+
+        if let Some(i) = matched {
+            let offset = base + position as u64;
+            found[i].get_or_insert(offset);
+            if pairs && i != last {
+                found[i + 1].get_or_insert(offset + size as u64);
+            }
+            position += size;
+            sums = None;
+            continue;
+        }
+
+        let Some(&next) = buffer.get(position + size) else {
+            break;
+        };
+        let (mut first, mut second) = (first, second);
+        roll(&mut first, buffer[position], next, size);
+        second = match (second, buffer.get(position + window)) {
+            (Some(mut sum), Some(&new)) => {
+                roll(&mut sum, next, new, size);
+                Some(sum)
+            }
+            _ => None,
+        };
+        sums = Some((first, second));
+        position += 1;
+    }
+    Ok(found)
+}
+
+/// Copies the blocks that were `found` in `old` into their place in `file`, returning the
+/// number of bytes copied.
+fn copy_blocks(
+    control: &ControlFile,
+    found: &[Option<u64>],
+    old: &Path,
+    file: &mut std::fs::File,
+) -> io::Result<u64> {
+    if found.iter().all(Option::is_none) {
+        return Ok(0);
+    }
+    let mut old = std::fs::File::open(old)?;
+    let mut block = vec![0; control.block_size];
+    let mut copied = 0;
+    for (index, offset) in found.iter().enumerate() {
+        let Some(offset) = *offset else {
+            continue;
+        };
+        let len = control.block_len(index) as usize;
+        old.seek(SeekFrom::Start(offset))?;
+        // Blocks found in the padding after the end of the old file end with zeros, which the
+        // new file already has.
+        let mut read = 0;
+        while read < len {
+            match old.read(&mut block[read..len])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        file.seek(SeekFrom::Start((index * control.block_size) as u64))?;
+        file.write_all(&block[..read])?;
+        copied += len as u64;
+    }
+    Ok(copied)
+}
+
+fn sha1_file(path: &Path) -> io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0; READ_SIZE];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(checksum::to_hex(&hasher.finalize())),
+            n => hasher.update(&buffer[..n]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: usize = 16;
+
+    fn base() -> Url {
+        Url::parse("https://example.com/releases/new.bin.zsync").unwrap()
+    }
+
+    /// Builds the control file of `data` with `seq_matches` and 3-byte rolling checksums.
+    fn control_file(data: &[u8], seq_matches: usize) -> Vec<u8> {
+        let mut control = format!(
+            "zsync: 0.6.2\nFilename: new.bin\nBlocksize: {}\nLength: {}\n\
+             Hash-Lengths: {},3,8\nURL: new.bin\nSHA-1: {}\n\n",
+            BLOCK_SIZE,
+            data.len(),
+            seq_matches,
+            checksum::to_hex(&Sha1::digest(data))
+        )
+        .into_bytes();
+        for block in data.chunks(BLOCK_SIZE) {
+            let mut block = block.to_vec();
+            block.resize(BLOCK_SIZE, 0);
+            let (a, b) = rsum(&block);
+            control.extend_from_slice(&((u32::from(a) << 16) | u32::from(b)).to_be_bytes()[1..]);
+            control.extend_from_slice(&Md4::digest(&block)[..8]);
+        }
+        control
+    }
+
+    fn new_data() -> Vec<u8> {
+        (0..70u32).map(|i| (i * 37 + 11) as u8).collect()
+    }
+
+    /// Finds the blocks of `new` in `old`, copies them to a new file, and fetches the rest from
+    /// `new`. Returns where the blocks were found and the hash of the result.
+    fn reconstruct(new: &[u8], old: &[u8], seq_matches: usize) -> (Vec<Option<u64>>, String) {
+        let control = ControlFile::parse(&base(), &control_file(new, seq_matches)).unwrap();
+        let dir = std::env::temp_dir().join(format!(
+            "zusammen-zsync-{}-{}",
+            std::process::id(),
+            seq_matches
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (old_path, new_path) = (dir.join("old.bin"), dir.join("new.bin"));
+        std::fs::write(&old_path, old).unwrap();
+
+        let found = find_blocks(&control, &old_path).unwrap();
+        let mut file = std::fs::File::create(&new_path).unwrap();
+        file.set_len(control.length).unwrap();
+        copy_blocks(&control, &found, &old_path, &mut file).unwrap();
+        for (index, _) in found.iter().enumerate().filter(|(_, at)| at.is_none()) {
+            let start = index * BLOCK_SIZE;
+            let end = (start + BLOCK_SIZE).min(new.len());
+            file.seek(SeekFrom::Start(start as u64)).unwrap();
+            file.write_all(&new[start..end]).unwrap();
+        }
+        drop(file);
+        let sha1 = sha1_file(&new_path).unwrap();
+        assert_eq!(sha1, control.sha1);
+        std::fs::remove_dir_all(&dir).unwrap();
+        (found, sha1)
+    }
+
+    #[test]
+    fn parses_control_files() {
+        let data = new_data();
+        let control = ControlFile::parse(&base(), &control_file(&data, 2)).unwrap();
+        assert_eq!(control.filename.as_deref(), Some("new.bin"));
+        assert_eq!(control.length, 70);
+        assert_eq!(control.block_size, BLOCK_SIZE);
+        assert_eq!(
+            (control.seq_matches, control.rsum_len, control.checksum_len),
+            (2, 3, 8)
+        );
+        assert_eq!(control.urls, ["https://example.com/releases/new.bin"]);
+        assert_eq!(control.blocks.len(), 5);
+        assert_eq!(control.rsum_mask(), 0x00ff_ffff);
+        assert_eq!(control.block_len(0), 16);
+        assert_eq!(control.block_len(4), 6);
+    }
+
+    #[test]
+    fn refuses_invalid_control_files() {
+        let valid = control_file(&new_data(), 1);
+        let end = valid.windows(2).position(|w| w == b"\n\n").unwrap() + 2;
+        let (header, sums) = (std::str::from_utf8(&valid[..end]).unwrap(), &valid[end..]);
+        let replace = |from: &str, to: &str| [header.replace(from, to).as_bytes(), sums].concat();
+        let cases = [
+            (b"zsync: 0.6.2\n".to_vec(), "no end of the header"),
+            (
+                replace("URL: new.bin", "Z-URL: new.bin.gz"),
+                "only a compressed file is offered, which isn't supported",
+            ),
+            (replace("URL: new.bin", "X-URL: new.bin"), "no URL"),
+            (
+                replace("Blocksize: 16", "Blocksize: 0"),
+                "invalid Blocksize header",
+            ),
+            (replace("1,3,8", "1,3"), "invalid Hash-Lengths header"),
+            (replace("1,3,8", "3,3,8"), "unsupported Hash-Lengths"),
+            (replace("SHA-1: ", "SHA-1: x"), "invalid SHA-1 header"),
+            (
+                replace("Length: 70", "Length: 50"),
+                "lists 55 bytes of block checksums, expected 44",
+            ),
+            (
+                replace(
+                    "Blocksize: 16\nLength: 70",
+                    "Blocksize: 1\nLength: 18446744073709551615",
+                ),
+                "invalid Length header",
+            ),
+        ];
+        for (case, reason) in cases {
+            assert_eq!(ControlFile::parse(&base(), &case).err().unwrap(), reason);
+        }
+    }
+
+    #[test]
+    fn rolls_the_checksum() {
+        let data = new_data();
+        let mut sum = rsum(&data[..BLOCK_SIZE]);
+        for start in 1..data.len() - BLOCK_SIZE {
+            roll(
+                &mut sum,
+                data[start - 1],
+                data[start + BLOCK_SIZE - 1],
+                BLOCK_SIZE,
+            );
+            assert_eq!(sum, rsum(&data[start..start + BLOCK_SIZE]), "at {}", start);
+        }
+    }
+
+    #[test]
+    fn reuses_all_but_the_changed_block() {
+        let new = new_data();
+        // The old file has three more bytes in front and a different third block.
+        let mut old = b"abc".to_vec();
+        old.extend_from_slice(&new);
+        for byte in &mut old[3 + 2 * BLOCK_SIZE..3 + 3 * BLOCK_SIZE] {
+            *byte ^= 0x5a;
+        }
+
+        for seq_matches in [1, 2] {
+            let (found, _) = reconstruct(&new, &old, seq_matches);
+            assert_eq!(
+                found,
+                [Some(3), Some(19), None, Some(51), Some(67)],
+                "with {} sequential matches",
+                seq_matches
+            );
+        }
+    }
+
+    #[test]
+    fn fetches_everything_without_an_old_file() {
+        let control = ControlFile::parse(&base(), &control_file(&new_data(), 2)).unwrap();
+        let missing = std::env::temp_dir().join("zusammen-zsync-missing.bin");
+        assert_eq!(find_blocks(&control, &missing).unwrap(), [None; 5]);
+    }
+}